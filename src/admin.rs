@@ -0,0 +1,181 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, put},
+};
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{AppState, BucketConfig, BucketStatus, BucketStore, ProblemDetails, StoreError};
+
+/// Routes for inspecting and resetting buckets by identity, as operations
+/// tooling would:
+///
+/// - `GET /buckets/{key}` answers the stored bucket, or 404 if it's full.
+/// - `DELETE /buckets/{key}` refills the bucket, or answers 404 if it's
+///   already full.
+/// - `PUT /buckets/{key}/tokens` with `{"tokens": n}` sets how many tokens
+///   the bucket holds.
+/// - `GET /config` answers the default [`BucketConfig`], as JSON.
+/// - `PUT /config` with a `BucketConfig` limits requests with it from now
+///   on, see [`AppState::update_config`]. Only this instance is affected.
+/// - `GET /top?n=10` answers the `n` identities charged most in the current
+///   window, as `[{"key": ..., "count": ...}]`, see
+///   [`AppState::top_consumers`]. A 502 if the store keeps no leaderboard.
+///
+/// `key` is the identity the key extractor returns, and only its default
+/// bucket is covered. The router isn't protected in any way: mount it
+/// somewhere only operators can reach, behind your own auth.
+pub fn admin_router<S: BucketStore>(state: AppState<S>) -> Router {
+    Router::new()
+        .route(
+            "/buckets/{key}",
+            get(get_bucket::<S>).delete(delete_bucket::<S>),
+        )
+        .route("/buckets/{key}/tokens", put(put_tokens::<S>))
+        .route("/config", get(get_config::<S>).put(put_config::<S>))
+        .route("/top", get(get_top::<S>))
+        .with_state(state)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub(crate) struct BucketBody {
+    pub(crate) tokens: i64,
+    pub(crate) last_updated: DateTime<Utc>,
+    /// Rounded up.
+    pub(crate) seconds_to_full: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) banned_until: Option<DateTime<Utc>>,
+}
+
+impl From<BucketStatus> for BucketBody {
+    fn from(status: BucketStatus) -> Self {
+        Self {
+            tokens: status.tokens,
+            last_updated: status.last_updated,
+            seconds_to_full: status.time_to_full.as_millis().div_ceil(1000) as u64,
+            banned_until: status.banned_until,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokensBody {
+    tokens: i64,
+}
+
+#[derive(Deserialize)]
+struct TopQuery {
+    #[serde(default = "default_top")]
+    n: usize,
+}
+
+fn default_top() -> usize {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub(crate) struct Consumer {
+    pub(crate) key: String,
+    pub(crate) count: u64,
+}
+
+async fn get_bucket<S: BucketStore>(
+    State(state): State<AppState<S>>,
+    Path(key): Path<String>,
+) -> Response {
+    match state.get_bucket_status(&key).await {
+        Ok(Some(status)) => Json(BucketBody::from(status)).into_response(),
+        Ok(None) => not_found(),
+        Err(e) => store_failed(e),
+    }
+}
+
+async fn delete_bucket<S: BucketStore>(
+    State(state): State<AppState<S>>,
+    Path(key): Path<String>,
+) -> Response {
+    match state.reset_bucket(&key).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => not_found(),
+        Err(e) => store_failed(e),
+    }
+}
+
+async fn put_tokens<S: BucketStore>(
+    State(state): State<AppState<S>>,
+    Path(key): Path<String>,
+    Json(body): Json<TokensBody>,
+) -> Response {
+    let max_tokens = state.config().max_tokens;
+    if !(0..=max_tokens).contains(&body.tokens) {
+        return ProblemDetails::new(
+            "urn:leaky-bucket:invalid-tokens",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("A bucket holds between 0 and {max_tokens} tokens."),
+        )
+        .into_response();
+    }
+
+    match state.set_tokens(&key, body.tokens).await {
+        Ok(status) => Json(BucketBody::from(status)).into_response(),
+        Err(e) => store_failed(e),
+    }
+}
+
+async fn get_config<S: BucketStore>(State(state): State<AppState<S>>) -> Response {
+    Json(&*state.config()).into_response()
+}
+
+async fn put_config<S: BucketStore>(
+    State(state): State<AppState<S>>,
+    Json(config): Json<BucketConfig>,
+) -> Response {
+    if let Err(e) = config.validate() {
+        return ProblemDetails::new(
+            "urn:leaky-bucket:invalid-config",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            e.to_string(),
+        )
+        .into_response();
+    }
+    state.update_config(config.clone());
+    Json(config).into_response()
+}
+
+async fn get_top<S: BucketStore>(
+    State(state): State<AppState<S>>,
+    Query(query): Query<TopQuery>,
+) -> Response {
+    match state.top_consumers(query.n).await {
+        Ok(top) => {
+            let top = top
+                .into_iter()
+                .map(|(key, count)| Consumer { key, count })
+                .collect::<Vec<_>>();
+            Json(top).into_response()
+        }
+        Err(e) => store_failed(e),
+    }
+}
+
+fn not_found() -> Response {
+    ProblemDetails::new(
+        "urn:leaky-bucket:bucket-not-found",
+        StatusCode::NOT_FOUND,
+        "There is no stored bucket for this key, so it is full.",
+    )
+    .into_response()
+}
+
+/// The store is upstream of this service, so its failures are a 502.
+fn store_failed(e: StoreError) -> Response {
+    ProblemDetails::new(
+        "urn:leaky-bucket:store-error",
+        StatusCode::BAD_GATEWAY,
+        e.to_string(),
+    )
+    .into_response()
+}
@@ -0,0 +1,57 @@
+use std::collections::HashSet;
+
+/// Identities that aren't rate limited at all, such as internal services and
+/// monitoring probes. Matched against the raw identity, before it's hashed.
+#[derive(Clone, Debug, Default)]
+pub struct Allowlist {
+    identities: HashSet<String>,
+    prefixes: Vec<String>,
+}
+
+impl Allowlist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_identity(mut self, identity: impl Into<String>) -> Self {
+        self.identities.insert(identity.into());
+        self
+    }
+
+    /// Allows every identity starting with `prefix`, e.g. `internal-` for a
+    /// family of service tokens.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefixes.push(prefix.into());
+        self
+    }
+
+    pub fn contains(&self, identity: &str) -> bool {
+        self.identities.contains(identity)
+            || self
+                .prefixes
+                .iter()
+                .any(|prefix| identity.starts_with(prefix.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.identities.is_empty() && self.prefixes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Allowlist;
+
+    #[test]
+    fn test_matches_identities_and_prefixes() {
+        let allowlist = Allowlist::new()
+            .with_identity("probe")
+            .with_prefix("internal-");
+
+        assert!(allowlist.contains("probe"));
+        assert!(allowlist.contains("internal-billing"));
+        assert!(!allowlist.contains("probe-2"));
+        assert!(!allowlist.contains("external-internal-"));
+        assert!(Allowlist::new().is_empty());
+    }
+}
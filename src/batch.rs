@@ -0,0 +1,82 @@
+use axum::http::{HeaderMap, HeaderName};
+
+/// Charges a request one token per item of the batch it carries, by the
+/// count a header such as `X-Batch-Size` gives, so a bulk endpoint draws from
+/// the same bucket as the single requests it stands in for. Set with
+/// [`AppState::with_batch_cost`].
+///
+/// The whole batch is charged or none of it. A denied one is answered 429 as
+/// usual, with `X-RateLimit-Remaining` saying how many items would fit. A
+/// request without the header, or with one that isn't a positive number, is
+/// charged as if there were no batch cost. A [`RequestCost`] on the request
+/// takes precedence.
+///
+/// [`AppState::with_batch_cost`]: crate::AppState::with_batch_cost
+/// [`RequestCost`]: crate::RequestCost
+#[derive(Clone, Debug)]
+pub struct BatchCost {
+    header: HeaderName,
+    max: u32,
+}
+
+impl BatchCost {
+    /// One token per item, as counted by `header`, uncapped.
+    pub fn new(header: HeaderName) -> Self {
+        Self {
+            header,
+            max: u32::MAX,
+        }
+    }
+
+    /// Charges no batch more than `max` tokens, however many items it says
+    /// it has.
+    pub fn with_max(mut self, max: u32) -> Self {
+        self.max = max;
+        self
+    }
+
+    /// What a request with `headers` costs, or `None` if it doesn't say.
+    pub(crate) fn cost(&self, headers: &HeaderMap) -> Option<i64> {
+        let items = headers
+            .get(&self.header)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|items| *items > 0)?;
+        Some(items.min(u64::from(self.max.max(1))) as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderName, HeaderValue};
+
+    use super::BatchCost;
+
+    fn headers(items: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-batch-size", HeaderValue::from_static(items));
+        headers
+    }
+
+    #[test]
+    fn test_batch_costs_one_token_per_item() {
+        let batch = BatchCost::new(HeaderName::from_static("x-batch-size")).with_max(500);
+
+        assert_eq!(batch.cost(&headers("1")), Some(1));
+        assert_eq!(batch.cost(&headers(" 250 ")), Some(250));
+        assert_eq!(batch.cost(&headers("10000")), Some(500));
+    }
+
+    #[test]
+    fn test_batch_without_a_count_is_not_priced() {
+        let batch = BatchCost::new(HeaderName::from_static("x-batch-size"));
+
+        assert_eq!(batch.cost(&HeaderMap::new()), None);
+        assert_eq!(batch.cost(&headers("0")), None);
+        assert_eq!(batch.cost(&headers("-3")), None);
+        assert_eq!(batch.cost(&headers("lots")), None);
+    }
+}
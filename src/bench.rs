@@ -0,0 +1,23 @@
+//! Internals that the benchmarks in `benches/` measure and can't otherwise
+//! reach. Not part of the API, so anything here may change in any release.
+
+use chrono::{DateTime, Utc};
+
+use crate::{TokenPersistence, encoding};
+
+/// A bucket holding `tokens` as of `last_updated`, encoded as it's stored.
+pub fn encode_bucket(tokens: i64, last_updated: DateTime<Utc>) -> Vec<u8> {
+    encoding::encode(&TokenPersistence {
+        tokens,
+        last_updated,
+        penalty: None,
+    })
+}
+
+/// The tokens of a bucket [`encode_bucket`] wrote, or of any stored one.
+pub fn decode_bucket(bytes: &[u8]) -> Option<i64> {
+    encoding::decode(bytes)
+        .ok()
+        .flatten()
+        .map(|bucket| bucket.tokens)
+}
@@ -0,0 +1,59 @@
+use std::{collections::HashSet, sync::RwLock};
+
+use crate::KeyHasher;
+
+/// Identities whose requests are refused outright, whatever their bucket
+/// holds.
+///
+/// Only their hashed bucket keys are kept, like in the store, so the raw
+/// tokens of blocked clients don't linger in memory.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    keys: RwLock<HashSet<String>>,
+}
+
+impl Blocklist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether `identity` wasn't blocked yet.
+    pub fn insert(&self, identity: &str) -> bool {
+        self.insert_key(hashed(identity))
+    }
+
+    /// Returns whether `identity` was blocked.
+    pub fn remove(&self, identity: &str) -> bool {
+        self.remove_key(&hashed(identity))
+    }
+
+    pub fn contains(&self, identity: &str) -> bool {
+        self.contains_key(&hashed(identity))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn insert_key(&self, key: String) -> bool {
+        self.keys.write().unwrap().insert(key)
+    }
+
+    pub(crate) fn remove_key(&self, key: &str) -> bool {
+        self.keys.write().unwrap().remove(key)
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.keys.read().unwrap().contains(key)
+    }
+}
+
+/// `identity` hashed, as in its bucket key. These never leave the process,
+/// so there's no need for the prefix or a secret.
+fn hashed(identity: &str) -> String {
+    KeyHasher::default().hash(identity)
+}
@@ -0,0 +1,122 @@
+use axum::http::{HeaderMap, header};
+
+/// Charges a request by the size of its body, as given by `Content-Length`:
+/// one token per `unit` bytes or part of it, up to a cap. The body itself is
+/// never read. Set with [`AppState::with_body_cost`].
+///
+/// An empty body still costs one token, like any other request. A
+/// [`RequestCost`] on the request takes precedence.
+///
+/// [`AppState::with_body_cost`]: crate::AppState::with_body_cost
+/// [`RequestCost`]: crate::RequestCost
+#[derive(Clone, Debug)]
+pub struct BodyCost {
+    unit: u64,
+    max: u32,
+    missing: MissingLength,
+}
+
+/// What a request without a `Content-Length`, such as a chunked upload, is
+/// charged under [`BodyCost`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingLength {
+    /// A fixed cost.
+    Cost(u32),
+    /// Nothing: it's rejected with 411 Length Required.
+    Reject,
+}
+
+impl Default for MissingLength {
+    fn default() -> Self {
+        Self::Cost(1)
+    }
+}
+
+impl BodyCost {
+    /// One token per `unit` bytes, uncapped, with requests of unknown length
+    /// costing one.
+    pub fn new(unit: u64) -> Self {
+        Self {
+            unit: unit.max(1),
+            max: u32::MAX,
+            missing: MissingLength::default(),
+        }
+    }
+
+    /// Charges no request more than `max` tokens, however large its body.
+    pub fn with_max(mut self, max: u32) -> Self {
+        self.max = max;
+        self
+    }
+
+    pub fn with_missing(mut self, missing: MissingLength) -> Self {
+        self.missing = missing;
+        self
+    }
+
+    /// What a request with `headers` costs, or `None` if it's rejected. A
+    /// `Content-Length` that isn't a number counts as missing.
+    pub(crate) fn cost(&self, headers: &HeaderMap) -> Option<i64> {
+        let length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<u64>().ok());
+        match (length, self.missing) {
+            (Some(length), _) => {
+                let cost = length
+                    .div_ceil(self.unit)
+                    .clamp(1, u64::from(self.max.max(1)));
+                Some(cost as i64)
+            }
+            (None, MissingLength::Cost(cost)) => Some(i64::from(cost)),
+            (None, MissingLength::Reject) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue, header};
+
+    use super::{BodyCost, MissingLength};
+
+    fn sized(length: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_LENGTH,
+            HeaderValue::from_str(length).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_charges_per_unit_started() {
+        let cost = BodyCost::new(100 * 1024);
+
+        assert_eq!(cost.cost(&sized("0")), Some(1));
+        assert_eq!(cost.cost(&sized("1")), Some(1));
+        assert_eq!(cost.cost(&sized("102400")), Some(1));
+        assert_eq!(cost.cost(&sized("102401")), Some(2));
+        assert_eq!(cost.cost(&sized("1048576")), Some(11));
+        assert_eq!(
+            cost.cost(&sized("18446744073709551615")),
+            Some(i64::from(u32::MAX))
+        );
+    }
+
+    #[test]
+    fn test_cap_and_missing_length() {
+        let cost = BodyCost::new(10).with_max(5);
+        assert_eq!(cost.cost(&sized("49")), Some(5));
+        assert_eq!(cost.cost(&sized("18446744073709551615")), Some(5));
+        assert_eq!(cost.cost(&HeaderMap::new()), Some(1));
+        assert_eq!(cost.cost(&sized("ten")), Some(1));
+
+        let cost = cost.with_missing(MissingLength::Cost(3));
+        assert_eq!(cost.cost(&HeaderMap::new()), Some(3));
+        let cost = cost.with_missing(MissingLength::Reject);
+        assert_eq!(cost.cost(&HeaderMap::new()), None);
+        assert_eq!(cost.cost(&sized("-1")), None);
+        assert_eq!(cost.cost(&sized("10")), Some(1));
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// When the circuit breaker trips and how long it stays open.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive Redis errors that open the circuit.
+    pub failure_threshold: u32,
+    /// The errors only count if they all happen within this long.
+    pub window: Duration,
+    /// How long Redis is left alone before a probe request is let through.
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Redis is used normally.
+    Closed,
+    /// Redis is skipped and the failure policy applied straight away.
+    Open,
+    /// The cooldown is over and the next request probes Redis.
+    HalfOpen,
+}
+
+enum Inner {
+    Closed {
+        failures: u32,
+        first_failure: Option<Instant>,
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        probe_started: Instant,
+    },
+}
+
+/// Stops calling Redis after repeated errors so an outage doesn't cost every
+/// request a timeout.
+///
+/// While half-open a single probe is let through; its outcome closes or
+/// reopens the circuit. A probe that never reports back is replaced by a new
+/// one after another cooldown.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner::Closed {
+                failures: 0,
+                first_failure: None,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state_at(Instant::now())
+    }
+
+    fn state_at(&self, now: Instant) -> BreakerState {
+        match *self.inner.lock().unwrap() {
+            Inner::Closed { .. } => BreakerState::Closed,
+            Inner::Open { until } if now < until => BreakerState::Open,
+            Inner::Open { .. } | Inner::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether this request may use Redis.
+    pub(crate) fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match *inner {
+            Inner::Closed { .. } => true,
+            Inner::Open { until } if now < until => false,
+            Inner::HalfOpen { probe_started } if now < probe_started + self.config.cooldown => {
+                false
+            }
+            Inner::Open { .. } | Inner::HalfOpen { .. } => {
+                *inner = Inner::HalfOpen { probe_started: now };
+                true
+            }
+        }
+    }
+
+    pub(crate) fn record_success(&self) {
+        *self.inner.lock().unwrap() = Inner::Closed {
+            failures: 0,
+            first_failure: None,
+        };
+    }
+
+    pub(crate) fn record_failure(&self) {
+        self.record_failure_at(Instant::now())
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        let (failures, first_failure) = match *inner {
+            Inner::Closed {
+                failures,
+                first_failure: Some(first),
+            } if now.duration_since(first) <= self.config.window => (failures + 1, first),
+            Inner::Closed { .. } => (1, now),
+            Inner::Open { .. } | Inner::HalfOpen { .. } => (self.config.failure_threshold, now),
+        };
+
+        *inner = if failures >= self.config.failure_threshold {
+            Inner::Open {
+                until: now + self.config.cooldown,
+            }
+        } else {
+            Inner::Closed {
+                failures,
+                first_failure: Some(first_failure),
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{BreakerState, CircuitBreaker, CircuitBreakerConfig};
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 3,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        })
+    }
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let breaker = breaker();
+        let t0 = Instant::now();
+
+        breaker.record_failure_at(t0);
+        breaker.record_failure_at(t0 + Duration::from_secs(1));
+        assert_eq!(breaker.state_at(t0), BreakerState::Closed);
+
+        breaker.record_failure_at(t0 + Duration::from_secs(2));
+        assert_eq!(
+            breaker.state_at(t0 + Duration::from_secs(2)),
+            BreakerState::Open
+        );
+        assert!(!breaker.try_acquire_at(t0 + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_failures_outside_window_start_over() {
+        let breaker = breaker();
+        let t0 = Instant::now();
+
+        breaker.record_failure_at(t0);
+        breaker.record_failure_at(t0 + Duration::from_secs(1));
+        breaker.record_failure_at(t0 + Duration::from_secs(11));
+        assert_eq!(breaker.state_at(t0), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_success_resets_count() {
+        let breaker = breaker();
+        let t0 = Instant::now();
+
+        breaker.record_failure_at(t0);
+        breaker.record_failure_at(t0);
+        breaker.record_success();
+        breaker.record_failure_at(t0);
+        assert_eq!(breaker.state_at(t0), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_lets_one_probe_through() {
+        let breaker = breaker();
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at(t0);
+        }
+
+        let after_cooldown = t0 + Duration::from_secs(30);
+        assert_eq!(breaker.state_at(after_cooldown), BreakerState::HalfOpen);
+        assert!(breaker.try_acquire_at(after_cooldown));
+        assert!(!breaker.try_acquire_at(after_cooldown));
+
+        breaker.record_success();
+        assert_eq!(breaker.state_at(after_cooldown), BreakerState::Closed);
+        assert!(breaker.try_acquire_at(after_cooldown));
+    }
+
+    #[test]
+    fn test_failed_probe_reopens() {
+        let breaker = breaker();
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at(t0);
+        }
+
+        let probe = t0 + Duration::from_secs(30);
+        assert!(breaker.try_acquire_at(probe));
+        breaker.record_failure_at(probe);
+
+        assert_eq!(breaker.state_at(probe), BreakerState::Open);
+        assert!(!breaker.try_acquire_at(probe + Duration::from_secs(29)));
+        assert!(breaker.try_acquire_at(probe + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_lost_probe_is_replaced_after_cooldown() {
+        let breaker = breaker();
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at(t0);
+        }
+
+        let probe = t0 + Duration::from_secs(30);
+        assert!(breaker.try_acquire_at(probe));
+        assert!(!breaker.try_acquire_at(probe + Duration::from_secs(10)));
+        assert!(breaker.try_acquire_at(probe + Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,416 @@
+use std::{error::Error, fmt, time::Duration};
+
+use redis::{AsyncConnectionConfig, aio::MultiplexedConnection};
+
+use crate::{
+    AsyncRedisStore, BucketStore, Config, FailurePolicy, InvalidBucket, InvalidHashLen,
+    InvalidKeyPrefix, KeyPrefix, MissingKey, ParseCidrError, RateLimiterLayer,
+    ReconnectingConnection, Secret,
+};
+
+/// Where to start for a limiter with the usual settings, without putting
+/// an [`AppState`](crate::AppState) together by hand:
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use axum::{Router, routing::get};
+/// use leaky_bucket::RateLimiter;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let limiter = RateLimiter::builder()
+///     .redis_url("redis://localhost:6379")
+///     .key_prefix("myapp:prod:bucket:")
+///     .max_tokens(20)
+///     .refill(1, Duration::from_secs(60))
+///     .key_header("X-Api-Key")
+///     .fail_open(true)
+///     .build()
+///     .await
+///     .unwrap();
+/// let app: Router = Router::new()
+///     .route("/", get(|| async { "hello" }))
+///     .layer(limiter);
+/// # }
+/// ```
+///
+/// Anything the builder doesn't cover is set on the state itself, which is
+/// what [`RateLimiterLayer::new`] takes.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimiter;
+
+impl RateLimiter {
+    pub fn builder() -> RateLimiterBuilder {
+        RateLimiterBuilder::default()
+    }
+}
+
+/// See [`RateLimiter`]. Anything left unset is as [`Config::default`] has it.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimiterBuilder {
+    config: Config,
+    redis_url: Option<String>,
+    key_prefix: Option<String>,
+}
+
+impl RateLimiterBuilder {
+    /// The Redis server [`build`](Self::build) connects to,
+    /// `redis://localhost:6379` unless told otherwise.
+    pub fn redis_url(mut self, url: impl Into<String>) -> Self {
+        self.redis_url = Some(url.into());
+        self
+    }
+
+    /// How long to wait for Redis, connecting included, before handling it
+    /// as down, rather than [`DEFAULT_REDIS_TIMEOUT`](crate::DEFAULT_REDIS_TIMEOUT).
+    pub fn redis_timeout(mut self, timeout: Duration) -> Self {
+        self.config.redis_timeout = timeout;
+        self
+    }
+
+    /// What every key written to Redis starts with instead of `bucket:`, see
+    /// [`KeyPrefix`].
+    pub fn key_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.key_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Hashes identities into keys with HMAC-SHA256 keyed on `secret`, see
+    /// [`KeyHasher`](crate::KeyHasher).
+    pub fn key_secret(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.config.key_secret = Some(Secret::new(secret));
+        self
+    }
+
+    /// Keeps only the first `len` hex digits of hashes in keys.
+    pub fn key_hash_len(mut self, len: usize) -> Self {
+        self.config.key_hash_len = Some(len);
+        self
+    }
+
+    pub fn max_tokens(mut self, max_tokens: i64) -> Self {
+        self.config.default.max_tokens = max_tokens;
+        self
+    }
+
+    /// Gives back `tokens` every `every`.
+    pub fn refill(mut self, tokens: i64, every: Duration) -> Self {
+        self.config.default.refill_rate = tokens;
+        self.config.default.refill_interval = every;
+        self
+    }
+
+    /// Keys requests on the value of `header` instead of their bearer token,
+    /// see [`HeaderKeyExtractor`](crate::HeaderKeyExtractor).
+    pub fn key_header(mut self, header: impl Into<String>) -> Self {
+        self.config.key_header = Some(header.into());
+        self.config.key_headers.clear();
+        self
+    }
+
+    /// Keys requests on the first of `headers` they carry, such as
+    /// `["Authorization", "X-Api-Key"]`, see
+    /// [`HeaderKeyExtractor::chain`](crate::HeaderKeyExtractor::chain).
+    pub fn key_headers<H: Into<String>>(mut self, headers: impl IntoIterator<Item = H>) -> Self {
+        self.config.key_header = None;
+        self.config.key_headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// What happens to requests that carry no identity, 401 by default.
+    pub fn missing_key(mut self, missing_key: MissingKey) -> Self {
+        self.config.missing_key = missing_key;
+        self
+    }
+
+    /// The proxies in front of the server, as CIDRs, whose forwarding
+    /// headers are believed when keying on the client address. See
+    /// [`TrustedProxies`](crate::TrustedProxies).
+    pub fn trusted_proxies<N: Into<String>>(
+        mut self,
+        networks: impl IntoIterator<Item = N>,
+    ) -> Self {
+        self.config.trusted_proxies = networks.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Whether requests are let through while Redis is unavailable, rather
+    /// than answered with 503. See [`FailurePolicy`].
+    pub fn fail_open(mut self, enabled: bool) -> Self {
+        self.config.failure_policy = if enabled {
+            FailurePolicy::Open
+        } else {
+            FailurePolicy::Closed
+        };
+        self
+    }
+
+    /// Connects to Redis and returns the limiter, to add to a router with
+    /// `Router::layer`. The connection is opened again whenever it breaks,
+    /// see [`ReconnectingConnection`].
+    pub async fn build(
+        mut self,
+    ) -> Result<
+        RateLimiterLayer<AsyncRedisStore<ReconnectingConnection<MultiplexedConnection>>>,
+        BuildError,
+    > {
+        self.validate()?;
+        if let Some(url) = self.redis_url.take() {
+            self.config.redis_url = url;
+        }
+        let client =
+            redis::Client::open(self.config.redis_url.as_str()).map_err(BuildError::RedisUrl)?;
+        let timeout = self.config.redis_timeout;
+        let connection = AsyncConnectionConfig::new()
+            .set_connection_timeout(timeout)
+            .set_response_timeout(timeout);
+        let conn = ReconnectingConnection::open(client, connection)
+            .await
+            .map_err(BuildError::Connect)?;
+        let store = AsyncRedisStore::new(conn).with_timeout(timeout);
+        Ok(RateLimiterLayer::new(self.config.app_state(store)))
+    }
+
+    /// Returns the limiter with its buckets in `store` instead of Redis,
+    /// such as a [`MemoryStore`](crate::MemoryStore).
+    pub fn build_with_store<S: BucketStore>(
+        mut self,
+        store: S,
+    ) -> Result<RateLimiterLayer<S>, BuildError> {
+        if self.redis_url.is_some() {
+            return Err(BuildError::RedisUrlWithStore);
+        }
+        self.validate()?;
+        Ok(RateLimiterLayer::new(self.config.app_state(store)))
+    }
+
+    fn validate(&mut self) -> Result<(), BuildError> {
+        self.config.default.validate().map_err(BuildError::Bucket)?;
+        if self.config.redis_timeout.is_zero() {
+            return Err(BuildError::RedisTimeout);
+        }
+        if let Some(prefix) = self.key_prefix.take() {
+            self.config.key_prefix = KeyPrefix::new(prefix).map_err(BuildError::KeyPrefix)?;
+        }
+        self.config.key_hasher().map_err(BuildError::KeyHashLen)?;
+        self.config
+            .trusted_proxies()
+            .map_err(BuildError::TrustedProxies)?;
+        match self.config.key_header_names() {
+            Ok(_) => Ok(()),
+            Err(header) => Err(BuildError::KeyHeader(header.to_string())),
+        }
+    }
+}
+
+/// Why [`RateLimiterBuilder`] couldn't build a limiter.
+#[derive(Debug)]
+pub enum BuildError {
+    /// The bucket it describes could never let anything through or refill.
+    Bucket(InvalidBucket),
+    /// Not a header name.
+    KeyHeader(String),
+    KeyPrefix(InvalidKeyPrefix),
+    KeyHashLen(InvalidHashLen),
+    TrustedProxies(ParseCidrError),
+    /// A Redis timeout of zero, which nothing could be done within.
+    RedisTimeout,
+    RedisUrl(redis::RedisError),
+    Connect(redis::RedisError),
+    /// Given a Redis URL, but then a store of its own to build with.
+    RedisUrlWithStore,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bucket(e) => write!(f, "invalid bucket: {e}"),
+            Self::KeyHeader(header) => write!(f, "{header:?} isn't a header name"),
+            Self::KeyPrefix(e) => e.fmt(f),
+            Self::KeyHashLen(e) => e.fmt(f),
+            Self::TrustedProxies(e) => write!(f, "invalid trusted proxy: {e}"),
+            Self::RedisTimeout => f.write_str("redis timeout must be positive"),
+            Self::RedisUrl(e) => write!(f, "invalid redis url: {e}"),
+            Self::Connect(e) => write!(f, "couldn't connect to redis: {e}"),
+            Self::RedisUrlWithStore => {
+                f.write_str("a redis url was given, but the limiter was built with another store")
+            }
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Bucket(e) => Some(e),
+            Self::KeyPrefix(e) => Some(e),
+            Self::KeyHashLen(e) => Some(e),
+            Self::TrustedProxies(e) => Some(e),
+            Self::RedisUrl(e) | Self::Connect(e) => Some(e),
+            Self::KeyHeader(_) | Self::RedisTimeout | Self::RedisUrlWithStore => None,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        Router,
+        body::Body,
+        http::{HeaderName, Request, StatusCode},
+        routing::get,
+    };
+    use tower::ServiceExt;
+
+    use super::{BuildError, RateLimiter};
+    use crate::{
+        AppState, BucketConfig, FailurePolicy, HeaderKeyExtractor, InvalidBucket, InvalidHashLen,
+        InvalidKeyPrefix, MemoryStore, RateLimiterLayer,
+    };
+
+    async fn responses(app: Router) -> Vec<(StatusCode, Option<String>)> {
+        let mut responses = Vec::new();
+        for key in ["a", "a", "a", "b", "a"] {
+            let request = Request::builder()
+                .uri("/")
+                .header("X-Api-Key", key)
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let remaining = response
+                .headers()
+                .get("X-RateLimit-Remaining")
+                .map(|remaining| remaining.to_str().unwrap().to_string());
+            responses.push((response.status(), remaining));
+        }
+        responses
+    }
+
+    fn app(limiter: RateLimiterLayer<MemoryStore>) -> Router {
+        Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(limiter)
+    }
+
+    #[tokio::test]
+    async fn test_builds_the_same_limiter_as_by_hand() {
+        let built = RateLimiter::builder()
+            .max_tokens(2)
+            .refill(1, Duration::from_secs(60))
+            .key_header("X-Api-Key")
+            .fail_open(true)
+            .build_with_store(MemoryStore::new())
+            .unwrap();
+        let config = BucketConfig {
+            max_tokens: 2,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(60),
+            ..BucketConfig::default()
+        };
+        let state = AppState::new(MemoryStore::new(), config)
+            .with_key_extractor(HeaderKeyExtractor::new(HeaderName::from_static(
+                "x-api-key",
+            )))
+            .with_failure_policy(FailurePolicy::Open);
+        let by_hand = RateLimiterLayer::new(state);
+
+        let built = responses(app(built)).await;
+
+        assert_eq!(built, responses(app(by_hand)).await);
+        assert_eq!(built[2].0, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(built[3], (StatusCode::OK, Some("1".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_invalid_settings() {
+        let error = RateLimiter::builder()
+            .max_tokens(0)
+            .build_with_store(MemoryStore::new())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error,
+            BuildError::Bucket(InvalidBucket::MaxTokens(0))
+        ));
+
+        let error = RateLimiter::builder()
+            .refill(1, Duration::ZERO)
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error,
+            BuildError::Bucket(InvalidBucket::RefillInterval)
+        ));
+
+        let error = RateLimiter::builder()
+            .key_header("X Api Key")
+            .build_with_store(MemoryStore::new())
+            .err()
+            .unwrap();
+        assert!(matches!(error, BuildError::KeyHeader(_)));
+
+        let error = RateLimiter::builder()
+            .key_headers(["Authorization", "X Api Key"])
+            .build_with_store(MemoryStore::new())
+            .err()
+            .unwrap();
+        assert!(matches!(error, BuildError::KeyHeader(header) if header == "X Api Key"));
+
+        let error = RateLimiter::builder()
+            .trusted_proxies(["10.0.0.0/8", "proxy.internal"])
+            .build_with_store(MemoryStore::new())
+            .err()
+            .unwrap();
+        assert!(matches!(error, BuildError::TrustedProxies(_)));
+        assert_eq!(
+            error.to_string(),
+            "invalid trusted proxy: invalid CIDR `proxy.internal`"
+        );
+
+        let error = RateLimiter::builder()
+            .key_prefix("myapp:{prod}:")
+            .build_with_store(MemoryStore::new())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error,
+            BuildError::KeyPrefix(InvalidKeyPrefix::Reserved('{'))
+        ));
+
+        let error = RateLimiter::builder()
+            .key_secret("hunter2")
+            .key_hash_len(8)
+            .build_with_store(MemoryStore::new())
+            .err()
+            .unwrap();
+        assert!(matches!(error, BuildError::KeyHashLen(InvalidHashLen(8))));
+        assert!(!format!("{error:?}").contains("hunter2"));
+
+        let error = RateLimiter::builder()
+            .redis_url("redis://localhost:6379")
+            .build_with_store(MemoryStore::new())
+            .err()
+            .unwrap();
+        assert!(matches!(error, BuildError::RedisUrlWithStore));
+
+        let error = RateLimiter::builder()
+            .redis_timeout(Duration::ZERO)
+            .build_with_store(MemoryStore::new())
+            .err()
+            .unwrap();
+        assert!(matches!(error, BuildError::RedisTimeout));
+
+        let error = RateLimiter::builder()
+            .redis_url("http://localhost")
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, BuildError::RedisUrl(_)), "{error}");
+    }
+}
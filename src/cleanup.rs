@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use redis::{RedisResult, Value, aio};
+
+use crate::encoding;
+
+/// Deletes the key only if it still holds what the scan read, so a bucket that
+/// was charged in the meantime survives.
+const DELETE_IF_UNCHANGED: &str = "\
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0";
+
+/// Deletes buckets under `prefix` that were last charged more than `horizon`
+/// ago, for keys written before buckets were given a TTL. Returns how many
+/// were deleted.
+///
+/// Walks the keyspace with `SCAN` in batches of about `batch` keys and makes
+/// one read and one delete round trip per batch, so Redis is never blocked
+/// for long. `horizon` should be the longest [`BucketConfig::full_refill`] of
+/// any route, since a bucket that old is full again anyway. Values that don't
+/// parse as buckets are left alone.
+///
+/// [`BucketConfig::full_refill`]: crate::BucketConfig::full_refill
+pub async fn cleanup_stale_buckets<C>(
+    con: &mut C,
+    prefix: &str,
+    batch: usize,
+    horizon: Duration,
+) -> RedisResult<u64>
+where
+    C: aio::ConnectionLike,
+{
+    let cutoff = Utc::now() - horizon;
+    let pattern = format!("{prefix}*");
+    let mut cursor = 0u64;
+    let mut removed = 0;
+
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(&pattern)
+            .arg("COUNT")
+            .arg(batch)
+            .query_async(con)
+            .await?;
+
+        if !keys.is_empty() {
+            let values: Vec<Value> = redis::cmd("MGET").arg(&keys).query_async(con).await?;
+
+            let mut deletes = redis::pipe();
+            for (key, value) in keys.iter().zip(values) {
+                let Value::BulkString(raw) = value else {
+                    continue;
+                };
+                let Ok(Some(bucket)) = encoding::decode(&raw) else {
+                    continue;
+                };
+                if bucket.last_updated < cutoff {
+                    deletes
+                        .cmd("EVAL")
+                        .arg(DELETE_IF_UNCHANGED)
+                        .arg(1)
+                        .arg(key)
+                        .arg(raw);
+                }
+            }
+
+            if !deletes.is_empty() {
+                let deleted: Vec<u64> = deletes.query_async(con).await?;
+                removed += deleted.iter().sum::<u64>();
+            }
+        }
+
+        if next == 0 {
+            return Ok(removed);
+        }
+        cursor = next;
+    }
+}
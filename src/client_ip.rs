@@ -0,0 +1,302 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use axum::http::HeaderMap;
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A bare
+/// address is treated as a single-host network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCidrError(String);
+
+impl fmt::Display for ParseCidrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid CIDR `{}`", self.0)
+    }
+}
+
+impl std::error::Error for ParseCidrError {}
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseCidrError(s.to_string());
+
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| err())?.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().map_err(|_| err())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(err());
+        }
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Proxies whose forwarding headers are believed when resolving the client IP.
+///
+/// Forwarding headers are only read when the immediate peer is trusted, so
+/// clients connecting directly can't pick their own bucket by spoofing them.
+/// The default trusts nobody.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrustedProxies {
+    networks: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    pub fn new<I, S>(networks: I) -> Result<Self, ParseCidrError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let networks = networks
+            .into_iter()
+            .map(|n| n.as_ref().trim().parse())
+            .collect::<Result<_, _>>()?;
+        Ok(Self { networks })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|n| n.contains(ip))
+    }
+
+    /// Resolves the address of the client behind any trusted proxies.
+    ///
+    /// The forwarding chain is taken from `Forwarded`, then `X-Forwarded-For`,
+    /// then `X-Real-IP`, and walked from the nearest hop outwards until an
+    /// untrusted address is found. Entries that can't be parsed (`unknown`,
+    /// obfuscated identifiers) end the walk at the last address resolved.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+
+        let chain = forwarded_chain(headers);
+        let mut client = peer;
+        for hop in chain.iter().rev() {
+            match hop {
+                Some(ip) => client = *ip,
+                None => break,
+            }
+            if !self.contains(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded = values("forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|v| v.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    key.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value))
+                })
+            })
+            .collect();
+    }
+
+    let forwarded_for = values("x-forwarded-for");
+    if !forwarded_for.is_empty() {
+        return forwarded_for
+            .iter()
+            .flat_map(|v| v.split(','))
+            .map(parse_node)
+            .collect();
+    }
+
+    values("x-real-ip").into_iter().map(parse_node).collect()
+}
+
+/// Parses a node as found in forwarding headers: a bare address, a quoted
+/// value, `[v6]:port` or `v4:port`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{Cidr, TrustedProxies, parse_node};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new(["10.0.0.0/8", "fd00::/8"]).unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+
+        let host: Cidr = "192.0.2.7".parse().unwrap();
+        assert!(host.contains(ip("192.0.2.7")));
+        assert!(!host.contains(ip("192.0.2.8")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("203.0.113.1")));
+        assert!(!all.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn test_cidr_rejects_invalid() {
+        for s in ["10.0.0.0/33", "fd00::/129", "nope", "10.0.0.0/x", ""] {
+            assert!(s.parse::<Cidr>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_parse_node_formats() {
+        assert_eq!(parse_node(" 192.0.2.60 "), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node("\"192.0.2.43:47011\""), Some(ip("192.0.2.43")));
+        assert_eq!(
+            parse_node("\"[2001:db8:cafe::17]:4711\""),
+            Some(ip("2001:db8:cafe::17"))
+        );
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn test_untrusted_peer_headers_are_ignored() {
+        let spoofed = headers(&[
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-real-ip", "1.2.3.4"),
+            ("forwarded", "for=1.2.3.4"),
+        ]);
+
+        assert_eq!(
+            proxies().client_ip(&spoofed, ip("203.0.113.9")),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            TrustedProxies::default().client_ip(&spoofed, ip("10.0.0.1")),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_x_forwarded_for_walks_past_trusted_hops() {
+        // The leftmost entry was supplied by the client and must not win.
+        let h = headers(&[("x-forwarded-for", "6.6.6.6, 198.51.100.4, 10.0.0.3")]);
+
+        assert_eq!(proxies().client_ip(&h, ip("10.0.0.2")), ip("198.51.100.4"));
+    }
+
+    #[test]
+    fn test_x_forwarded_for_across_multiple_headers() {
+        let h = headers(&[
+            ("x-forwarded-for", "198.51.100.4"),
+            ("x-forwarded-for", "10.0.0.3"),
+        ]);
+
+        assert_eq!(proxies().client_ip(&h, ip("10.0.0.2")), ip("198.51.100.4"));
+    }
+
+    #[test]
+    fn test_forwarded_takes_precedence() {
+        let h = headers(&[
+            (
+                "forwarded",
+                "for=198.51.100.4;proto=https, for=\"[fd00::3]:8080\"",
+            ),
+            ("x-forwarded-for", "192.0.2.1"),
+        ]);
+
+        assert_eq!(proxies().client_ip(&h, ip("10.0.0.2")), ip("198.51.100.4"));
+    }
+
+    #[test]
+    fn test_x_real_ip() {
+        let h = headers(&[("x-real-ip", "198.51.100.4")]);
+
+        assert_eq!(proxies().client_ip(&h, ip("10.0.0.2")), ip("198.51.100.4"));
+    }
+
+    #[test]
+    fn test_unparseable_hop_stops_the_walk() {
+        let h = headers(&[("forwarded", "for=198.51.100.4, for=unknown, for=10.0.0.3")]);
+
+        assert_eq!(proxies().client_ip(&h, ip("10.0.0.2")), ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn test_no_forwarding_headers_uses_peer() {
+        assert_eq!(
+            proxies().client_ip(&HeaderMap::new(), ip("10.0.0.2")),
+            ip("10.0.0.2")
+        );
+    }
+}
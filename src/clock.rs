@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::BoxFuture;
+
+/// Where the middleware gets the current time from, for refills and rate
+/// limit headers.
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Waits out `duration`, for a request held until its tokens are back.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// The system's wall clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
@@ -0,0 +1,786 @@
+//! Limiter settings read from a TOML file, for deployments that would
+//! rather not build the [`AppState`] in code:
+//!
+//! ```toml
+//! redis_url = "redis://localhost:6379"
+//! redis_timeout_ms = 100
+//! redis_pool_size = 8
+//! bucket_format = "hash"
+//! key_prefix = "myapp:prod:bucket:"
+//! failure_policy = "open"
+//! exempt_paths = ["/healthz", "/internal/*"]
+//! key_headers = ["Authorization", "X-Api-Key"]
+//! missing_key = "client_ip"
+//! trusted_proxies = ["10.0.0.0/8"]
+//! leaderboard_window_secs = 3600
+//! mode = "shadow"
+//! shutdown_grace_secs = 25
+//! cleanup_interval_secs = 3600
+//!
+//! [default]
+//! max_tokens = 10
+//! refill_rate = 1
+//! refill_interval_ms = 3600000
+//!
+//! [[rules]]
+//! name = "search"
+//! paths = ["/search", "/suggest"]
+//! bucket = { max_tokens = 60, refill_rate = 1, refill_interval_ms = 1000 }
+//! ```
+//!
+//! Buckets are written like [`BucketConfig`] serializes. Anything left out
+//! takes its default, and anything the loader doesn't know is an error
+//! rather than silently ignored. Environment variables can override some of
+//! it, see [`Config::with_env`].
+
+use std::{
+    collections::HashSet, error::Error, fmt, fs, io, path::Path, str::FromStr, time::Duration,
+};
+
+use axum::http::HeaderName;
+use serde_derive::Deserialize;
+
+use crate::{
+    AppState, BearerTokenExtractor, BucketConfig, DEFAULT_REDIS_TIMEOUT, ExemptPaths,
+    FailurePolicy, HeaderKeyExtractor, InvalidHashLen, KeyHasher, KeyPrefix, Leaderboard,
+    MissingTokenPolicy, Mode, ParseCidrError, PeerIpExtractor, Secret, StorageFormat,
+    TrustedProxies, millis,
+};
+
+mod env;
+
+pub use env::EnvError;
+
+/// Everything the TOML file describes.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_redis_url")]
+    pub redis_url: String,
+    /// How long to wait for Redis before handling it as down, see
+    /// [`AsyncRedisStore::with_timeout`](crate::AsyncRedisStore::with_timeout).
+    #[serde(
+        default = "default_redis_timeout",
+        rename = "redis_timeout_ms",
+        with = "millis"
+    )]
+    pub redis_timeout: Duration,
+    /// How many connections to Redis the server keeps, at least one.
+    #[serde(default = "default_redis_pool_size")]
+    pub redis_pool_size: usize,
+    /// How buckets are laid out in Redis, see [`StorageFormat`].
+    #[serde(default)]
+    pub bucket_format: StorageFormat,
+    #[serde(default)]
+    pub failure_policy: FailurePolicy,
+    /// Paths that skip the limiter, as [`ExemptPaths`] takes them.
+    #[serde(default)]
+    pub exempt_paths: Vec<String>,
+    /// The header requests are keyed on, see [`HeaderKeyExtractor`]. Their
+    /// bearer token if there's none.
+    #[serde(default)]
+    pub key_header: Option<String>,
+    /// Headers tried in order, the first one a request carries keying it,
+    /// such as `["Authorization", "X-Api-Key"]`. Not to be set along with
+    /// `key_header`.
+    #[serde(default)]
+    pub key_headers: Vec<String>,
+    /// What happens to requests without any of the headers, or without a
+    /// bearer token if none are set.
+    #[serde(default)]
+    pub missing_key: MissingKey,
+    /// The proxies in front of the server, as CIDRs such as `10.0.0.0/8`,
+    /// whose forwarding headers are believed when keying on the client
+    /// address, see [`TrustedProxies`]. None by default, so requests are
+    /// keyed on the peer itself.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// What every key written to Redis starts with, `bucket:` by default.
+    #[serde(default)]
+    pub key_prefix: KeyPrefix,
+    /// Hashes identities into keys with HMAC-SHA256 keyed on this, see
+    /// [`KeyHasher`]. Rather set with `LEAKY_BUCKET_KEY_SECRET` than written
+    /// down here.
+    #[serde(default)]
+    pub key_secret: Option<Secret>,
+    /// How many hex digits of the hash keys keep, all 64 by default.
+    #[serde(default)]
+    pub key_hash_len: Option<usize>,
+    /// Counts charges per identity on a [`Leaderboard`] with windows this
+    /// many seconds long, under `{key_prefix}leaderboard:`. Not counted
+    /// unless set.
+    #[serde(default)]
+    pub leaderboard_window_secs: Option<u64>,
+    /// Whether denials are acted on, see [`Mode`].
+    #[serde(default)]
+    pub mode: Mode,
+    /// How many seconds requests in flight get to finish once the server is
+    /// asked to shut down.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+    /// Deletes buckets left behind without a TTL by older versions this often,
+    /// in seconds, see [`cleanup_stale_buckets`](crate::cleanup_stale_buckets).
+    /// Not done unless set.
+    #[serde(default)]
+    pub cleanup_interval_secs: Option<u64>,
+    /// The bucket of every route without a rule.
+    #[serde(default)]
+    pub default: BucketConfig,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// What [`Config::missing_key`] does with requests that carry no identity,
+/// see [`MissingTokenPolicy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingKey {
+    /// Answer with 401 Unauthorized.
+    #[default]
+    Reject,
+    /// Rate limit by the client address instead.
+    ClientIp,
+}
+
+/// A bucket per identity that the routes in `paths` all draw from, instead
+/// of the default one.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    /// Route patterns, as they're registered with the router, such as
+    /// `/users/{id}`.
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub bucket: BucketConfig,
+}
+
+fn default_redis_url() -> String {
+    "redis://localhost:6379".to_string()
+}
+
+fn default_redis_timeout() -> Duration {
+    DEFAULT_REDIS_TIMEOUT
+}
+
+fn default_redis_pool_size() -> usize {
+    8
+}
+
+/// Within the 30 seconds Kubernetes waits before killing a pod by default.
+fn default_shutdown_grace_secs() -> u64 {
+    25
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            redis_url: default_redis_url(),
+            redis_timeout: default_redis_timeout(),
+            redis_pool_size: default_redis_pool_size(),
+            bucket_format: StorageFormat::default(),
+            failure_policy: FailurePolicy::default(),
+            exempt_paths: Vec::new(),
+            key_header: None,
+            key_headers: Vec::new(),
+            missing_key: MissingKey::default(),
+            trusted_proxies: Vec::new(),
+            key_prefix: KeyPrefix::default(),
+            key_secret: None,
+            key_hash_len: None,
+            leaderboard_window_secs: None,
+            mode: Mode::default(),
+            shutdown_grace_secs: default_shutdown_grace_secs(),
+            cleanup_interval_secs: None,
+            default: BucketConfig::default(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let toml = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.display().to_string(),
+            source,
+        })?;
+        toml.parse()
+    }
+
+    /// A state limiting requests as configured, with buckets in `store`.
+    ///
+    /// Rules go by the route a request matched, so the middleware must be
+    /// added with `Router::route_layer` for them to apply.
+    pub fn app_state<S>(&self, store: S) -> AppState<S> {
+        let exempt_paths = self
+            .exempt_paths
+            .iter()
+            .fold(ExemptPaths::new(), |paths, path| paths.with_path(path));
+        let mut state = AppState::new(store, self.default.clone())
+            .with_failure_policy(self.failure_policy)
+            .with_exempt_paths(exempt_paths)
+            .with_key_prefix(self.key_prefix.clone())
+            .with_mode(self.mode);
+        match self.key_hasher() {
+            Ok(hasher) => state = state.with_key_hasher(hasher),
+            Err(e) => tracing::warn!(error = %e, "keeping whole hashes"),
+        }
+        let missing_key = match self.missing_key {
+            MissingKey::Reject => MissingTokenPolicy::Reject,
+            MissingKey::ClientIp => {
+                let trusted_proxies = self.trusted_proxies().unwrap_or_else(|e| {
+                    tracing::warn!(error = %e, "trusting no proxies");
+                    TrustedProxies::default()
+                });
+                MissingTokenPolicy::ClientIp(PeerIpExtractor::new(trusted_proxies))
+            }
+        };
+        let bearer = BearerTokenExtractor::default().with_missing_token(missing_key.clone());
+        match self.key_header_names() {
+            Ok(headers) if headers.is_empty() => state = state.with_key_extractor(bearer),
+            Ok(headers) => {
+                let extractor = HeaderKeyExtractor::chain(headers).with_missing_key(missing_key);
+                state = state.with_key_extractor(extractor);
+            }
+            Err(header) => {
+                tracing::warn!(
+                    key_header = header,
+                    "not a header name, keying on the bearer token"
+                );
+                state = state.with_key_extractor(bearer);
+            }
+        }
+        for rule in &self.rules {
+            for path in &rule.paths {
+                state = state.with_route_group(&rule.name, path, rule.bucket.clone());
+            }
+        }
+        state
+    }
+
+    /// The hasher `key_secret` and `key_hash_len` describe.
+    pub(crate) fn key_hasher(&self) -> Result<KeyHasher, InvalidHashLen> {
+        let mut hasher = KeyHasher::default();
+        if let Some(secret) = &self.key_secret {
+            hasher = hasher.with_secret(secret.clone());
+        }
+        match self.key_hash_len {
+            Some(len) => hasher.truncated(len),
+            None => Ok(hasher),
+        }
+    }
+
+    /// The headers `key_header` and `key_headers` name, in order, or the
+    /// first that isn't a header name.
+    pub(crate) fn key_header_names(&self) -> Result<Vec<HeaderName>, &str> {
+        self.key_header
+            .iter()
+            .chain(&self.key_headers)
+            .map(|header| HeaderName::try_from(header).map_err(|_| header.as_str()))
+            .collect()
+    }
+
+    /// The proxies `trusted_proxies` lists.
+    pub(crate) fn trusted_proxies(&self) -> Result<TrustedProxies, ParseCidrError> {
+        TrustedProxies::new(&self.trusted_proxies)
+    }
+
+    /// The leaderboard `leaderboard_window_secs` describes, if it's set.
+    pub fn leaderboard(&self) -> Option<Leaderboard> {
+        let window = self.leaderboard_window_secs?;
+        Some(Leaderboard::new(
+            &self.key_prefix,
+            Duration::from_secs(window),
+        ))
+    }
+
+    /// How long requests in flight get to finish on shutdown.
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+
+    /// How often stale buckets are cleaned up, if they are.
+    pub fn cleanup_interval(&self) -> Option<Duration> {
+        self.cleanup_interval_secs.map(Duration::from_secs)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.default
+            .validate()
+            .map_err(|e| ConfigError::Invalid(format!("default bucket: {e}")))?;
+
+        if self.redis_timeout.is_zero() {
+            return Err(ConfigError::Invalid(
+                "redis_timeout_ms must be positive".to_string(),
+            ));
+        }
+
+        if self.redis_pool_size == 0 {
+            return Err(ConfigError::Invalid(
+                "redis_pool_size must be positive".to_string(),
+            ));
+        }
+
+        if self.cleanup_interval_secs == Some(0) {
+            return Err(ConfigError::Invalid(
+                "cleanup_interval_secs must be positive".to_string(),
+            ));
+        }
+
+        if self.leaderboard_window_secs == Some(0) {
+            return Err(ConfigError::Invalid(
+                "leaderboard_window_secs must be positive".to_string(),
+            ));
+        }
+
+        if self.key_header.is_some() && !self.key_headers.is_empty() {
+            return Err(ConfigError::Invalid(
+                "key_header and key_headers can't both be set".to_string(),
+            ));
+        }
+        self.key_header_names().map_err(|header| {
+            ConfigError::Invalid(format!("key_header {header:?} isn't a header name"))
+        })?;
+        self.trusted_proxies()
+            .map_err(|e| ConfigError::Invalid(format!("trusted_proxies: {e}")))?;
+
+        if self.key_secret.as_ref().is_some_and(Secret::is_empty) {
+            return Err(ConfigError::Invalid("key_secret is empty".to_string()));
+        }
+        self.key_hasher()
+            .map_err(|e| ConfigError::Invalid(e.to_string()))?;
+
+        let mut names = HashSet::new();
+        let mut paths = HashSet::new();
+        for rule in &self.rules {
+            let invalid =
+                |problem: String| ConfigError::Invalid(format!("rule {:?}: {problem}", rule.name));
+            if rule.name.is_empty() {
+                return Err(ConfigError::Invalid("a rule has no name".to_string()));
+            }
+            if !names.insert(&rule.name) {
+                return Err(invalid("there's another rule by that name".to_string()));
+            }
+            if rule.paths.is_empty() {
+                return Err(invalid("no paths".to_string()));
+            }
+            if let Some(path) = rule.paths.iter().find(|path| !paths.insert(*path)) {
+                return Err(invalid(format!("{path} is covered by another rule")));
+            }
+            rule.bucket.validate().map_err(|e| invalid(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(toml: &str) -> Result<Self, Self::Err> {
+        let config: Self = toml::from_str(toml).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: String,
+        source: io::Error,
+    },
+    /// Not TOML, or not the shape of a [`Config`]. Says where in the file.
+    Parse(toml::de::Error),
+    /// Parsed, but not usable as it is.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read { path, source } => write!(f, "couldn't read {path}: {source}"),
+            Self::Parse(e) => write!(f, "invalid config: {e}"),
+            Self::Invalid(problem) => write!(f, "invalid config: {problem}"),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Read { source, .. } => Some(source),
+            Self::Parse(e) => Some(e),
+            Self::Invalid(_) => None,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use axum::{
+        Router,
+        body::Body,
+        extract::ConnectInfo,
+        http::{Request, StatusCode},
+        routing::get,
+    };
+    use tower::ServiceExt;
+
+    use super::{Config, ConfigError, MissingKey, Rule};
+    use crate::{
+        Algorithm, BucketConfig, FailurePolicy, Leaderboard, MemoryStore, Mode, RateLimiterLayer,
+        StorageFormat,
+    };
+
+    const SAMPLE: &str = r#"
+        redis_url = "redis://cache:6379"
+        redis_timeout_ms = 250
+        key_prefix = "myapp:prod:bucket:"
+        failure_policy = "open"
+        redis_pool_size = 4
+        bucket_format = "hash"
+        exempt_paths = ["/healthz"]
+        leaderboard_window_secs = 600
+        shutdown_grace_secs = 10
+        cleanup_interval_secs = 3600
+
+        [default]
+        max_tokens = 3
+
+        [[rules]]
+        name = "search"
+        paths = ["/search", "/suggest"]
+        bucket = { max_tokens = 2, refill_interval_ms = 1000 }
+
+        [[rules]]
+        name = "daily"
+        paths = ["/reports"]
+
+        [rules.bucket]
+        max_tokens = 100
+        refill_rate = 100
+        refill_interval_ms = 86400000
+        algorithm = { fixed_window = {} }
+    "#;
+
+    fn invalid(toml: &str) -> String {
+        let error = toml.parse::<Config>().unwrap_err();
+        assert!(
+            matches!(error, ConfigError::Parse(_) | ConfigError::Invalid(_)),
+            "{error:?}"
+        );
+        error.to_string()
+    }
+
+    #[test]
+    fn test_parses_a_sample_config() {
+        let config: Config = SAMPLE.parse().unwrap();
+
+        assert_eq!(config.redis_url, "redis://cache:6379");
+        assert_eq!(config.redis_timeout, Duration::from_millis(250));
+        assert_eq!(config.key_prefix.as_str(), "myapp:prod:bucket:");
+        assert_eq!(config.failure_policy, FailurePolicy::Open);
+        assert_eq!(config.redis_pool_size, 4);
+        assert_eq!(config.bucket_format, StorageFormat::Hash);
+        assert_eq!(config.exempt_paths, ["/healthz"]);
+        assert_eq!(config.shutdown_grace(), Duration::from_secs(10));
+        assert_eq!(
+            config.cleanup_interval(),
+            Some(Duration::from_secs(60 * 60))
+        );
+        assert_eq!(
+            config.leaderboard(),
+            Some(Leaderboard {
+                prefix: "myapp:prod:bucket:leaderboard:".to_string(),
+                window: Duration::from_secs(600),
+            })
+        );
+        assert_eq!(config.default.max_tokens, 3);
+        assert_eq!(config.default.refill_interval, Duration::from_secs(60 * 60));
+        assert_eq!(
+            config.rules[0],
+            Rule {
+                name: "search".to_string(),
+                paths: vec!["/search".to_string(), "/suggest".to_string()],
+                bucket: BucketConfig {
+                    max_tokens: 2,
+                    refill_interval: Duration::from_secs(1),
+                    ..BucketConfig::default()
+                },
+            }
+        );
+        assert_eq!(config.rules[1].bucket, BucketConfig::daily(100));
+        assert_eq!(
+            config.rules[1].bucket.algorithm,
+            Algorithm::FixedWindow {
+                offset: Duration::ZERO
+            }
+        );
+    }
+
+    #[test]
+    fn test_mode_reaches_the_state() {
+        let config: Config = "mode = \"shadow\"".parse().unwrap();
+
+        assert_eq!(config.mode, Mode::Shadow);
+        assert_eq!(config.app_state(MemoryStore::new()).mode, Mode::Shadow);
+    }
+
+    #[test]
+    fn test_shipped_config_parses() {
+        let config: Config = include_str!("../leaky-bucket.toml").parse().unwrap();
+
+        assert_eq!(config.default, BucketConfig::default());
+        assert_eq!(config.rules.len(), 2);
+    }
+
+    #[test]
+    fn test_empty_config_is_the_default() {
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_errors_say_what_is_wrong() {
+        let error = invalid("redis_host = \"redis://cache\"");
+        assert!(error.contains("unknown field `redis_host`"), "{error}");
+
+        let error = invalid("[default]\nmax_token = 5");
+        assert!(error.contains("unknown field `max_token`"), "{error}");
+        assert!(error.contains("line 2"), "{error}");
+
+        let error = invalid("failure_policy = \"sometimes\"");
+        assert!(error.contains("unknown variant `sometimes`"), "{error}");
+
+        let error = invalid("key_hash_len = 8");
+        assert_eq!(
+            error,
+            "invalid config: key hash length must be from 16 to 64, not 8"
+        );
+
+        let error = invalid("redis_timeout_ms = 0");
+        assert_eq!(error, "invalid config: redis_timeout_ms must be positive");
+
+        let error = invalid("redis_pool_size = 0");
+        assert_eq!(error, "invalid config: redis_pool_size must be positive");
+
+        let error = invalid("cleanup_interval_secs = 0");
+        assert_eq!(
+            error,
+            "invalid config: cleanup_interval_secs must be positive"
+        );
+
+        let error = invalid("bucket_format = \"xml\"");
+        assert!(error.contains("unknown variant `xml`"), "{error}");
+
+        let error = invalid("leaderboard_window_secs = 0");
+        assert_eq!(
+            error,
+            "invalid config: leaderboard_window_secs must be positive"
+        );
+
+        let error = invalid("key_secret = \"\"");
+        assert_eq!(error, "invalid config: key_secret is empty");
+
+        let error = invalid("key_prefix = \"myapp:*\"");
+        assert!(error.contains("key prefix can't contain '*'"), "{error}");
+
+        let error = invalid("[default]\nmax_tokens = 0");
+        assert_eq!(
+            error,
+            "invalid config: default bucket: max_tokens must be positive, not 0"
+        );
+
+        let error = invalid(
+            "[[rules]]\nname = \"search\"\npaths = [\"/search\"]\nbucket = { refill_rate = -1 }",
+        );
+        assert_eq!(
+            error,
+            "invalid config: rule \"search\": refill_rate must be positive, not -1"
+        );
+
+        let error = invalid(
+            "[[rules]]\nname = \"a\"\npaths = [\"/x\"]\n[[rules]]\nname = \"b\"\npaths = [\"/x\"]",
+        );
+        assert_eq!(
+            error,
+            "invalid config: rule \"b\": /x is covered by another rule"
+        );
+
+        let error = Config::from_path("/nonexistent/leaky-bucket.toml")
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("couldn't read /nonexistent/leaky-bucket.toml"));
+    }
+
+    async fn status(app: &Router, path: &str) -> StatusCode {
+        let request = Request::builder()
+            .uri(path)
+            .header("Authorization", "Bearer abc")
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_limits_routes_as_configured() {
+        let config: Config = SAMPLE.parse().unwrap();
+        let state = config.app_state(MemoryStore::new());
+        let app = ["/", "/search", "/suggest", "/reports", "/healthz"]
+            .into_iter()
+            .fold(Router::new(), |app, path| {
+                app.route(path, get(|| async { "ok" }))
+            })
+            .route_layer(RateLimiterLayer::new(state));
+
+        // The search rule's paths share its two tokens.
+        assert_eq!(status(&app, "/search").await, StatusCode::OK);
+        assert_eq!(status(&app, "/suggest").await, StatusCode::OK);
+        assert_eq!(status(&app, "/search").await, StatusCode::TOO_MANY_REQUESTS);
+
+        for _ in 0..3 {
+            assert_eq!(status(&app, "/").await, StatusCode::OK);
+        }
+        assert_eq!(status(&app, "/").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status(&app, "/reports").await, StatusCode::OK);
+        for _ in 0..5 {
+            assert_eq!(status(&app, "/healthz").await, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keys_on_the_configured_header() {
+        let config: Config = "key_header = \"X-Api-Key\"\n[default]\nmax_tokens = 1"
+            .parse()
+            .unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route_layer(RateLimiterLayer::new(config.app_state(MemoryStore::new())));
+        let send = |key: Option<&'static str>| {
+            let app = app.clone();
+            async move {
+                let mut request = Request::builder().header("Authorization", "Bearer abc");
+                if let Some(key) = key {
+                    request = request.header("X-Api-Key", key);
+                }
+                let request = request.body(Body::empty()).unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+
+        assert_eq!(send(Some("one")).await, StatusCode::OK);
+        assert_eq!(send(Some("one")).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(send(Some("two")).await, StatusCode::OK);
+        assert_eq!(send(None).await, StatusCode::UNAUTHORIZED);
+
+        let error = "key_header = \"X Api Key\"".parse::<Config>().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid config: key_header \"X Api Key\" isn't a header name"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keys_on_the_first_configured_header_present() {
+        let toml = "key_headers = [\"Authorization\", \"X-Api-Key\"]\nmissing_key = \"client_ip\"\n[default]\nmax_tokens = 1";
+        let config: Config = toml.parse().unwrap();
+        assert_eq!(config.missing_key, MissingKey::ClientIp);
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route_layer(RateLimiterLayer::new(config.app_state(MemoryStore::new())));
+        let send = |headers: &'static [(&'static str, &'static str)]| {
+            let app = app.clone();
+            async move {
+                let mut request = Request::builder()
+                    .extension(ConnectInfo(SocketAddr::from(([10, 1, 2, 3], 40000))));
+                for (name, value) in headers {
+                    request = request.header(*name, *value);
+                }
+                let request = request.body(Body::empty()).unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+
+        assert_eq!(send(&[("X-Api-Key", "one")]).await, StatusCode::OK);
+        // The bearer token comes first, so this isn't the key's bucket.
+        let both = &[("Authorization", "Bearer abc"), ("X-Api-Key", "one")];
+        assert_eq!(send(both).await, StatusCode::OK);
+        assert_eq!(send(both).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(send(&[]).await, StatusCode::OK);
+        assert_eq!(send(&[]).await, StatusCode::TOO_MANY_REQUESTS);
+
+        let error = "key_header = \"X-Api-Key\"\nkey_headers = [\"Authorization\"]"
+            .parse::<Config>()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid config: key_header and key_headers can't both be set"
+        );
+        let error = "key_headers = [\"Authorization\", \"X Api Key\"]"
+            .parse::<Config>()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid config: key_header \"X Api Key\" isn't a header name"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keys_on_the_forwarded_address_behind_trusted_proxies() {
+        let toml = "key_header = \"X-Api-Key\"\nmissing_key = \"client_ip\"\ntrusted_proxies = [\"10.0.0.0/8\"]\n[default]\nmax_tokens = 1";
+        let config: Config = toml.parse().unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route_layer(RateLimiterLayer::new(config.app_state(MemoryStore::new())));
+        let send = |peer: [u8; 4], forwarded_for: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = Request::builder()
+                    .extension(ConnectInfo(SocketAddr::from((peer, 40000))))
+                    .header("X-Forwarded-For", forwarded_for)
+                    .body(Body::empty())
+                    .unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+
+        // Two clients behind the same proxy get a bucket each.
+        assert_eq!(send([10, 1, 2, 3], "203.0.113.7").await, StatusCode::OK);
+        assert_eq!(send([10, 1, 2, 3], "203.0.113.8").await, StatusCode::OK);
+        assert_eq!(
+            send([10, 1, 2, 3], "203.0.113.7").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // A client connecting directly can't pick another's bucket.
+        assert_eq!(send([192, 0, 2, 1], "203.0.113.8").await, StatusCode::OK);
+        assert_eq!(
+            send([192, 0, 2, 1], "203.0.113.9").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        let error = "trusted_proxies = [\"10.0.0.0/33\"]"
+            .parse::<Config>()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid config: trusted_proxies: invalid CIDR `10.0.0.0/33`"
+        );
+    }
+
+    #[test]
+    fn test_hashes_keys_with_the_secret() {
+        let config: Config = "key_secret = \"hunter2\"\nkey_hash_len = 32"
+            .parse()
+            .unwrap();
+
+        let state = config.app_state(MemoryStore::new());
+        let plain = Config::default().app_state(MemoryStore::new());
+
+        let key = state.bucket_key("abc");
+        assert_ne!(key, plain.bucket_key("abc"));
+        assert_eq!(key.len(), "bucket:{}".len() + 32);
+        assert!(!format!("{config:?}").contains("hunter2"));
+    }
+}
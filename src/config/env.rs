@@ -0,0 +1,397 @@
+//! Settings read from environment variables, for containers that would
+//! rather not mount a config file:
+//!
+//! | Variable                             | Sets                              | Default          |
+//! |--------------------------------------|-----------------------------------|------------------|
+//! | `LEAKY_BUCKET_MAX_TOKENS`            | `max_tokens`                      | 10               |
+//! | `LEAKY_BUCKET_REFILL_RATE`           | `refill_rate`                     | 1                |
+//! | `LEAKY_BUCKET_REFILL_SECONDS`        | `refill_interval`, in seconds     | 3600             |
+//! | `LEAKY_BUCKET_FAIL_OPEN`             | `failure_policy`: `true` for open | `false`          |
+//! | `LEAKY_BUCKET_KEY_HEADER`            | `key_header`                      | the bearer token |
+//! | `LEAKY_BUCKET_KEY_PREFIX`            | `key_prefix`                      | `bucket:`        |
+//! | `LEAKY_BUCKET_KEY_SECRET`            | `key_secret`                      | none             |
+//! | `LEAKY_BUCKET_KEY_HASH_LEN`          | `key_hash_len`, 16 to 64          | 64               |
+//! | `LEAKY_BUCKET_REDIS_TIMEOUT_MS`      | `redis_timeout`, in milliseconds  | 100              |
+//! | `LEAKY_BUCKET_REDIS_POOL_SIZE`       | `redis_pool_size`                 | 8                |
+//! | `LEAKY_BUCKET_FORMAT`                | `bucket_format`: `json` or `hash` | `json`           |
+//! | `LEAKY_BUCKET_MODE`                  | `mode`: `enforce` or `shadow`     | `enforce`        |
+//! | `LEAKY_BUCKET_SHUTDOWN_GRACE_SECS`   | `shutdown_grace_secs`             | 25               |
+//! | `LEAKY_BUCKET_CLEANUP_INTERVAL_SECS` | `cleanup_interval_secs`           | none             |
+//!
+//! A variable that's unset or empty leaves the setting as it was. The secret
+//! is never repeated in errors.
+
+use std::{
+    env::{self, VarError},
+    error::Error,
+    fmt,
+    str::FromStr,
+    time::Duration,
+};
+
+use axum::http::HeaderName;
+
+use super::Config;
+use crate::{BucketConfig, FailurePolicy, KeyHasher, KeyPrefix, Mode, Secret, StorageFormat};
+
+const MAX_TOKENS: &str = "LEAKY_BUCKET_MAX_TOKENS";
+const REFILL_RATE: &str = "LEAKY_BUCKET_REFILL_RATE";
+const REFILL_SECONDS: &str = "LEAKY_BUCKET_REFILL_SECONDS";
+const FAIL_OPEN: &str = "LEAKY_BUCKET_FAIL_OPEN";
+const KEY_HEADER: &str = "LEAKY_BUCKET_KEY_HEADER";
+const KEY_PREFIX: &str = "LEAKY_BUCKET_KEY_PREFIX";
+const KEY_SECRET: &str = "LEAKY_BUCKET_KEY_SECRET";
+const KEY_HASH_LEN: &str = "LEAKY_BUCKET_KEY_HASH_LEN";
+const REDIS_TIMEOUT_MS: &str = "LEAKY_BUCKET_REDIS_TIMEOUT_MS";
+const REDIS_POOL_SIZE: &str = "LEAKY_BUCKET_REDIS_POOL_SIZE";
+const FORMAT: &str = "LEAKY_BUCKET_FORMAT";
+const MODE: &str = "LEAKY_BUCKET_MODE";
+const SHUTDOWN_GRACE_SECS: &str = "LEAKY_BUCKET_SHUTDOWN_GRACE_SECS";
+const CLEANUP_INTERVAL_SECS: &str = "LEAKY_BUCKET_CLEANUP_INTERVAL_SECS";
+
+/// A variable set to something its setting can't take.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EnvError {
+    pub var: &'static str,
+    pub value: String,
+    /// What it should have been, e.g. `a positive integer`.
+    pub expected: &'static str,
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}={:?} isn't valid: expected {}",
+            self.var, self.value, self.expected
+        )
+    }
+}
+
+impl Error for EnvError {}
+
+impl BucketConfig {
+    /// The default config, with whatever the `LEAKY_BUCKET_*` variables set
+    /// in place of the defaults. See [`Config::from_env`] for the variables.
+    pub fn from_env() -> Result<Self, EnvError> {
+        Self::default().with_env()
+    }
+
+    /// This config, with whatever the `LEAKY_BUCKET_*` variables set in place
+    /// of what it has.
+    pub fn with_env(mut self) -> Result<Self, EnvError> {
+        if let Some(max_tokens) = parsed(MAX_TOKENS, "a positive integer", |n: &i64| *n > 0)? {
+            self.max_tokens = max_tokens;
+        }
+        if let Some(rate) = parsed(REFILL_RATE, "a positive integer", |n: &i64| *n > 0)? {
+            self.refill_rate = rate;
+        }
+        if let Some(secs) = parsed(REFILL_SECONDS, "a positive integer", |n: &u64| *n > 0)? {
+            self.refill_interval = Duration::from_secs(secs);
+        }
+        Ok(self)
+    }
+}
+
+impl Config {
+    /// The default config, with whatever the `LEAKY_BUCKET_*` variables set
+    /// in place of the defaults:
+    ///
+    /// - `LEAKY_BUCKET_MAX_TOKENS`, `LEAKY_BUCKET_REFILL_RATE` and
+    ///   `LEAKY_BUCKET_REFILL_SECONDS` for the default bucket's
+    ///   `max_tokens`, `refill_rate` and `refill_interval` in seconds, all
+    ///   positive integers.
+    /// - `LEAKY_BUCKET_FAIL_OPEN`, `true` or `false`, for whether requests are
+    ///   let through while the store is down.
+    /// - `LEAKY_BUCKET_KEY_HEADER` for the header requests are keyed on
+    ///   instead of their bearer token.
+    /// - `LEAKY_BUCKET_KEY_PREFIX` for what keys in Redis start with, such as
+    ///   `myapp:staging:bucket:`.
+    /// - `LEAKY_BUCKET_KEY_SECRET` for the secret identities are hashed with,
+    ///   and `LEAKY_BUCKET_KEY_HASH_LEN` for how many hex digits of the hash
+    ///   keys keep. See [`KeyHasher`].
+    /// - `LEAKY_BUCKET_REDIS_TIMEOUT_MS` for how many milliseconds to wait
+    ///   for Redis before handling it as down, a positive integer.
+    /// - `LEAKY_BUCKET_REDIS_POOL_SIZE` for how many connections to Redis are
+    ///   kept, a positive integer.
+    /// - `LEAKY_BUCKET_FORMAT`, `json` or `hash`, for how buckets are laid
+    ///   out in Redis.
+    /// - `LEAKY_BUCKET_MODE`, `enforce` or `shadow`, for whether denials are
+    ///   acted on.
+    /// - `LEAKY_BUCKET_SHUTDOWN_GRACE_SECS` for how many seconds requests in
+    ///   flight get on shutdown, and `LEAKY_BUCKET_CLEANUP_INTERVAL_SECS` for
+    ///   how often stale buckets are cleaned up, a positive integer.
+    ///
+    /// Variables that are unset or empty leave the defaults as they are.
+    pub fn from_env() -> Result<Self, EnvError> {
+        Self::default().with_env()
+    }
+
+    /// This config, with whatever the `LEAKY_BUCKET_*` variables set in place
+    /// of what it has, e.g. to override a config file.
+    pub fn with_env(mut self) -> Result<Self, EnvError> {
+        self.default = self.default.with_env()?;
+        if let Some(open) = parsed(FAIL_OPEN, "true or false", |_: &bool| true)? {
+            self.failure_policy = if open {
+                FailurePolicy::Open
+            } else {
+                FailurePolicy::Closed
+            };
+        }
+        if let Some(header) = parsed(KEY_HEADER, "a header name", |_: &HeaderName| true)? {
+            self.key_header = Some(header.to_string());
+            self.key_headers.clear();
+        }
+        if let Some(prefix) = parsed(KEY_PREFIX, "a key prefix", |_: &KeyPrefix| true)? {
+            self.key_prefix = prefix;
+        }
+        if let Some(secret) = secret(KEY_SECRET)? {
+            self.key_secret = Some(secret);
+        }
+        let valid_len = |len: &usize| KeyHasher::default().truncated(*len).is_ok();
+        if let Some(len) = parsed(KEY_HASH_LEN, "a length from 16 to 64", valid_len)? {
+            self.key_hash_len = Some(len);
+        }
+        if let Some(ms) = parsed(REDIS_TIMEOUT_MS, "a positive integer", |n: &u64| *n > 0)? {
+            self.redis_timeout = Duration::from_millis(ms);
+        }
+        if let Some(size) = parsed(REDIS_POOL_SIZE, "a positive integer", |n: &usize| *n > 0)? {
+            self.redis_pool_size = size;
+        }
+        let formats = [("json", StorageFormat::Json), ("hash", StorageFormat::Hash)];
+        if let Some(format) = chosen(FORMAT, "json or hash", formats)? {
+            self.bucket_format = format;
+        }
+        let modes = [("enforce", Mode::Enforce), ("shadow", Mode::Shadow)];
+        if let Some(mode) = chosen(MODE, "enforce or shadow", modes)? {
+            self.mode = mode;
+        }
+        if let Some(secs) = parsed(SHUTDOWN_GRACE_SECS, "a number of seconds", |_: &u64| true)? {
+            self.shutdown_grace_secs = secs;
+        }
+        let positive = |n: &u64| *n > 0;
+        if let Some(secs) = parsed(CLEANUP_INTERVAL_SECS, "a positive integer", positive)? {
+            self.cleanup_interval_secs = Some(secs);
+        }
+        Ok(self)
+    }
+}
+
+/// The value of `var`, if it's set, without ever putting it in an error.
+fn secret(var: &'static str) -> Result<Option<Secret>, EnvError> {
+    match env::var(var) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => Ok(Some(Secret::new(value))),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => Err(EnvError {
+            var,
+            value: "(hidden)".to_string(),
+            expected: "UTF-8",
+        }),
+    }
+}
+
+/// Which of `options` `var` names, if it's set.
+fn chosen<T: Copy, const N: usize>(
+    var: &'static str,
+    expected: &'static str,
+    options: [(&str, T); N],
+) -> Result<Option<T>, EnvError> {
+    let valid = |name: &String| options.iter().any(|(option, _)| option == name);
+    let name = parsed(var, expected, valid)?;
+    Ok(name.and_then(|name| {
+        options
+            .iter()
+            .find(|(option, _)| *option == name)
+            .map(|(_, value)| *value)
+    }))
+}
+
+/// The value of `var`, if it's set, when it parses and is `valid`.
+fn parsed<T: FromStr>(
+    var: &'static str,
+    expected: &'static str,
+    valid: impl Fn(&T) -> bool,
+) -> Result<Option<T>, EnvError> {
+    let value = match env::var(var) {
+        Ok(value) if value.is_empty() => return Ok(None),
+        Ok(value) => value,
+        Err(VarError::NotPresent) => return Ok(None),
+        Err(VarError::NotUnicode(value)) => {
+            return Err(EnvError {
+                var,
+                value: value.to_string_lossy().into_owned(),
+                expected,
+            });
+        }
+    };
+    match value.trim().parse() {
+        Ok(parsed) if valid(&parsed) => Ok(Some(parsed)),
+        _ => Err(EnvError {
+            var,
+            value,
+            expected,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        sync::{Mutex, MutexGuard},
+        time::Duration,
+    };
+
+    use super::{
+        CLEANUP_INTERVAL_SECS, EnvError, FAIL_OPEN, FORMAT, KEY_HASH_LEN, KEY_HEADER, KEY_PREFIX,
+        KEY_SECRET, MAX_TOKENS, MODE, REDIS_POOL_SIZE, REDIS_TIMEOUT_MS, REFILL_RATE,
+        REFILL_SECONDS, SHUTDOWN_GRACE_SECS,
+    };
+    use crate::{BucketConfig, Config, FailurePolicy, Mode, Secret, StorageFormat};
+
+    /// Held by whichever test has the variables set.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// The `LEAKY_BUCKET_*` variables set to `vars` and the rest unset, until
+    /// dropped.
+    struct ScopedEnv {
+        _held: MutexGuard<'static, ()>,
+    }
+
+    impl ScopedEnv {
+        fn new(vars: &[(&str, &str)]) -> Self {
+            let held = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            clear();
+            for (var, value) in vars {
+                // SAFETY: only these tests touch the variables, one at a time.
+                unsafe { env::set_var(var, value) };
+            }
+            Self { _held: held }
+        }
+    }
+
+    impl Drop for ScopedEnv {
+        fn drop(&mut self) {
+            clear();
+        }
+    }
+
+    fn clear() {
+        for var in [
+            MAX_TOKENS,
+            REFILL_RATE,
+            REFILL_SECONDS,
+            FAIL_OPEN,
+            KEY_HEADER,
+            KEY_PREFIX,
+            KEY_SECRET,
+            KEY_HASH_LEN,
+            REDIS_TIMEOUT_MS,
+            REDIS_POOL_SIZE,
+            FORMAT,
+            MODE,
+            SHUTDOWN_GRACE_SECS,
+            CLEANUP_INTERVAL_SECS,
+        ] {
+            // SAFETY: as above.
+            unsafe { env::remove_var(var) };
+        }
+    }
+
+    #[test]
+    fn test_unset_variables_keep_the_defaults() {
+        let _env = ScopedEnv::new(&[(MAX_TOKENS, "")]);
+
+        assert_eq!(BucketConfig::from_env().unwrap(), BucketConfig::default());
+        assert_eq!(Config::from_env().unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_variables_take_precedence() {
+        let _env = ScopedEnv::new(&[
+            (MAX_TOKENS, "100"),
+            (REFILL_SECONDS, "60"),
+            (FAIL_OPEN, "true"),
+            (KEY_HEADER, "X-Api-Key"),
+            (KEY_PREFIX, "myapp:staging:bucket:"),
+            (KEY_SECRET, "hunter2"),
+            (KEY_HASH_LEN, "32"),
+            (REDIS_TIMEOUT_MS, "250"),
+            (REDIS_POOL_SIZE, "16"),
+            (FORMAT, "hash"),
+            (MODE, "shadow"),
+            (SHUTDOWN_GRACE_SECS, "0"),
+            (CLEANUP_INTERVAL_SECS, "600"),
+        ]);
+
+        let config = Config::from_env().unwrap();
+        assert_eq!(
+            config.default,
+            BucketConfig {
+                max_tokens: 100,
+                refill_interval: Duration::from_secs(60),
+                ..BucketConfig::default()
+            }
+        );
+        assert_eq!(config.failure_policy, FailurePolicy::Open);
+        assert_eq!(config.key_header.as_deref(), Some("x-api-key"));
+        assert_eq!(config.key_prefix.as_str(), "myapp:staging:bucket:");
+        assert_eq!(config.key_secret, Some(Secret::new("hunter2")));
+        assert_eq!(config.key_hash_len, Some(32));
+        assert_eq!(config.redis_timeout, Duration::from_millis(250));
+        assert_eq!(config.redis_pool_size, 16);
+        assert_eq!(config.bucket_format, StorageFormat::Hash);
+        assert_eq!(config.mode, Mode::Shadow);
+        assert_eq!(config.shutdown_grace(), Duration::ZERO);
+        assert_eq!(config.cleanup_interval(), Some(Duration::from_secs(600)));
+
+        // Over a config file's settings too.
+        let file: Config =
+            "failure_policy = \"closed\"\n[default]\nmax_tokens = 5\nrefill_rate = 2"
+                .parse()
+                .unwrap();
+        let config = file.with_env().unwrap();
+        assert_eq!(config.default.max_tokens, 100);
+        assert_eq!(config.default.refill_rate, 2);
+        assert_eq!(config.failure_policy, FailurePolicy::Open);
+    }
+
+    #[test]
+    fn test_errors_name_the_variable() {
+        for (var, value, expected) in [
+            (MAX_TOKENS, "ten", "a positive integer"),
+            (MAX_TOKENS, "0", "a positive integer"),
+            (REFILL_SECONDS, "-5", "a positive integer"),
+            (FAIL_OPEN, "yes", "true or false"),
+            (KEY_HEADER, "X Api Key", "a header name"),
+            (KEY_PREFIX, "myapp:*", "a key prefix"),
+            (KEY_HASH_LEN, "8", "a length from 16 to 64"),
+            (REDIS_TIMEOUT_MS, "0", "a positive integer"),
+            (REDIS_POOL_SIZE, "0", "a positive integer"),
+            (FORMAT, "xml", "json or hash"),
+            (MODE, "off", "enforce or shadow"),
+            (SHUTDOWN_GRACE_SECS, "-1", "a number of seconds"),
+            (CLEANUP_INTERVAL_SECS, "0", "a positive integer"),
+        ] {
+            let _env = ScopedEnv::new(&[(var, value)]);
+
+            let error = Config::from_env().unwrap_err();
+
+            assert_eq!(
+                error,
+                EnvError {
+                    var,
+                    value: value.to_string(),
+                    expected
+                }
+            );
+        }
+
+        let _env = ScopedEnv::new(&[(MAX_TOKENS, "ten")]);
+        assert_eq!(
+            BucketConfig::from_env().unwrap_err().to_string(),
+            "LEAKY_BUCKET_MAX_TOKENS=\"ten\" isn't valid: expected a positive integer"
+        );
+    }
+}
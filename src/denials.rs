@@ -0,0 +1,139 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+
+use crate::{RateLimitDecision, later};
+
+/// Denials the store made, see
+/// [`AppState::with_denial_cache`](crate::AppState::with_denial_cache), each
+/// kept by bucket key until the tokens the request asked for are due back.
+#[derive(Debug)]
+pub(crate) struct DenialCache {
+    capacity: usize,
+    entries: DashMap<String, Denial>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Denial {
+    pub(crate) decision: RateLimitDecision,
+    /// The tier the bucket went by, if it's an identity's.
+    pub(crate) tier: Option<String>,
+    cost: i64,
+    until: DateTime<Utc>,
+}
+
+impl DenialCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: DashMap::new(),
+        }
+    }
+
+    /// The denial of a charge of `cost` to `key` as of `now`, if one at
+    /// least as cheap was denied and its tokens aren't back yet.
+    pub(crate) fn get(&self, key: &str, cost: i64, now: DateTime<Utc>) -> Option<Denial> {
+        let denial = self.entries.get(key)?;
+        if denial.until <= now || cost < denial.cost {
+            return None;
+        }
+        let mut denial = denial.clone();
+        denial.decision.retry_after = Some((denial.until - now).to_std().unwrap_or_default());
+        Some(denial)
+    }
+
+    /// Remembers that charging `cost` to `key` was denied as of `now`. Only
+    /// denials that say when to retry are kept.
+    pub(crate) fn insert(
+        &self,
+        key: &str,
+        cost: i64,
+        decision: &RateLimitDecision,
+        tier: Option<String>,
+        now: DateTime<Utc>,
+    ) {
+        let Some(retry_after) = decision.retry_after.filter(|wait| !wait.is_zero()) else {
+            return;
+        };
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(key) {
+            self.entries.retain(|_, denial| denial.until > now);
+            if self.entries.len() >= self.capacity {
+                let soonest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|denial| denial.until)
+                    .map(|denial| denial.key().clone());
+                if let Some(soonest) = soonest {
+                    self.entries.remove(&soonest);
+                }
+            }
+        }
+        let denial = Denial {
+            decision: decision.clone(),
+            tier,
+            cost,
+            until: later(now, retry_after),
+        };
+        self.entries.insert(key.to_string(), denial);
+    }
+
+    pub(crate) fn invalidate(&self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use super::DenialCache;
+    use crate::RateLimitDecision;
+
+    fn denied(retry_after: Duration) -> RateLimitDecision {
+        RateLimitDecision {
+            allowed: false,
+            limit: 10,
+            remaining: 0,
+            reset_at: Utc::now(),
+            retry_after: Some(retry_after),
+        }
+    }
+
+    #[test]
+    fn test_cheaper_charges_are_not_denied() {
+        let cache = DenialCache::new(10);
+        let now = Utc::now();
+        cache.insert("a", 5, &denied(Duration::from_secs(30)), None, now);
+
+        assert!(cache.get("a", 5, now).is_some());
+        assert!(cache.get("a", 1, now).is_none());
+        let later = now + chrono::Duration::seconds(20);
+        let retry_after = cache.get("a", 8, later).unwrap().decision.retry_after;
+        assert_eq!(retry_after, Some(Duration::from_secs(10)));
+        assert!(
+            cache
+                .get("a", 5, now + chrono::Duration::seconds(30))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_evicts_the_denial_that_runs_out_first() {
+        let cache = DenialCache::new(2);
+        let now = Utc::now();
+        cache.insert("a", 1, &denied(Duration::from_secs(60)), None, now);
+        cache.insert("b", 1, &denied(Duration::from_secs(10)), None, now);
+
+        cache.insert("c", 1, &denied(Duration::from_secs(30)), None, now);
+
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.get("a", 1, now).is_some());
+        assert!(cache.get("b", 1, now).is_none());
+        assert!(cache.get("c", 1, now).is_some());
+    }
+}
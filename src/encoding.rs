@@ -0,0 +1,169 @@
+//! How a bucket is encoded as a single Redis string.
+//!
+//! Buckets are JSON by default. With the `msgpack` feature they are written as
+//! MessagePack instead, prefixed with [`MSGPACK`] so a reader can tell the two
+//! apart: JSON always starts with `{`. JSON is read either way, so a
+//! deployment can enable the feature without losing existing buckets.
+
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+
+use crate::{Penalty, TokenPersistence, timestamp};
+
+/// Leading byte of a MessagePack bucket. The take-token script checks for it
+/// too.
+pub(crate) const MSGPACK: u8 = 1;
+
+/// Layout written with every bucket. The take-token script writes it too.
+pub(crate) const VERSION: u64 = 1;
+
+pub(crate) type DecodeError = Box<dyn Error + Send + Sync>;
+
+#[derive(Serialize)]
+struct Current {
+    version: u64,
+    tokens: i64,
+    #[serde(with = "timestamp")]
+    last_updated: DateTime<Utc>,
+    /// Left out when there is none. Readers from before penalties ignore it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    penalty: Option<Penalty>,
+}
+
+impl From<&TokenPersistence> for Current {
+    fn from(bucket: &TokenPersistence) -> Self {
+        Self {
+            version: VERSION,
+            tokens: bucket.tokens,
+            last_updated: bucket.last_updated,
+            penalty: bucket.penalty.clone(),
+        }
+    }
+}
+
+#[cfg(not(feature = "msgpack"))]
+pub(crate) fn encode(bucket: &TokenPersistence) -> Vec<u8> {
+    serde_json::to_vec(&Current::from(bucket)).unwrap()
+}
+
+/// Fields are written by name, so the take-token script can unpack them into
+/// a table.
+#[cfg(feature = "msgpack")]
+pub(crate) fn encode(bucket: &TokenPersistence) -> Vec<u8> {
+    let mut out = vec![MSGPACK];
+    rmp_serde::encode::write_named(&mut out, &Current::from(bucket)).unwrap();
+    out
+}
+
+/// Reads a bucket written by this or an earlier version. `None` means it was
+/// written by a newer version with a layout this one doesn't know, and should
+/// be treated as missing.
+pub(crate) fn decode(bytes: &[u8]) -> Result<Option<TokenPersistence>, DecodeError> {
+    let stored = match bytes.split_first() {
+        Some((&MSGPACK, msgpack)) => decode_msgpack(msgpack)?,
+        _ => serde_json::from_slice(bytes)?,
+    };
+    upgrade(stored)
+}
+
+/// Brings a stored bucket up to the current layout. Fields this version
+/// doesn't know are ignored.
+fn upgrade(stored: serde_json::Value) -> Result<Option<TokenPersistence>, DecodeError> {
+    // A GCRA bucket is no more than its arrival time.
+    if let Some(tat) = stored.as_i64() {
+        return Ok(Some(TokenPersistence::from_tat(timestamp::from_millis(
+            tat,
+        ))));
+    }
+    // Buckets from before the version field have no version.
+    let version = match stored.get("version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or("bucket version isn't a number")?,
+    };
+    match version {
+        // Version 1 only added the version field itself.
+        0 | VERSION => Ok(Some(serde_json::from_value(stored)?)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(feature = "msgpack")]
+fn decode_msgpack(bytes: &[u8]) -> Result<serde_json::Value, DecodeError> {
+    Ok(rmp_serde::from_slice(bytes)?)
+}
+
+#[cfg(not(feature = "msgpack"))]
+fn decode_msgpack(_: &[u8]) -> Result<serde_json::Value, DecodeError> {
+    Err("bucket is MessagePack, which needs the `msgpack` feature".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::{MSGPACK, decode, encode};
+    use crate::TokenPersistence;
+
+    fn bucket() -> TokenPersistence {
+        TokenPersistence {
+            tokens: 7,
+            last_updated: "2025-03-01T12:00:00.123Z".parse::<DateTime<Utc>>().unwrap(),
+            penalty: None,
+        }
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let decoded = decode(&encode(&bucket())).unwrap().unwrap();
+
+        assert_eq!(decoded.tokens, 7);
+        assert_eq!(decoded.last_updated, bucket().last_updated);
+    }
+
+    #[test]
+    fn test_upgrades_unversioned_json() {
+        let json = br#"{"tokens":7,"last_updated":"2025-03-01T12:00:00.123Z"}"#;
+
+        let decoded = decode(json).unwrap().unwrap();
+
+        assert_eq!(decoded.tokens, 7);
+        assert_eq!(decoded.last_updated, bucket().last_updated);
+    }
+
+    #[test]
+    fn test_ignores_unknown_fields() {
+        let json = br#"{"version":1,"tokens":7,"last_updated":1740830400123,"owner":"x"}"#;
+
+        let decoded = decode(json).unwrap().unwrap();
+
+        assert_eq!(decoded.tokens, 7);
+    }
+
+    #[test]
+    fn test_newer_versions_read_as_missing() {
+        let json = br#"{"version":2,"remaining":7.5,"at":"soon"}"#;
+
+        assert!(decode(json).unwrap().is_none());
+        assert!(decode(br#"{"version":"two","tokens":7}"#).is_err());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_writes_msgpack_smaller_than_json() {
+        let encoded = encode(&bucket());
+
+        assert_eq!(encoded[0], MSGPACK);
+        assert_eq!(encoded.len(), 41);
+        let json = serde_json::to_vec(&super::Current::from(&bucket())).unwrap();
+        assert_eq!(json.len(), 53);
+        assert!(decode(&[MSGPACK, 0xc1]).is_err());
+    }
+
+    #[cfg(not(feature = "msgpack"))]
+    #[test]
+    fn test_msgpack_needs_the_feature() {
+        assert!(decode(&[MSGPACK, 0x80]).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashSet;
+
+/// Paths whose requests skip the limiter altogether, such as health checks
+/// and metrics scraped without a token. Matched against the path the client
+/// sent, before any identity is extracted.
+///
+/// A path with a `*` in it is a pattern, the `*` standing for any run of
+/// characters, slashes included: `/internal/*` covers everything under
+/// `/internal/`. A trailing slash makes no difference either way, so
+/// `/healthz` also covers `/healthz/`.
+#[derive(Clone, Debug, Default)]
+pub struct ExemptPaths {
+    paths: HashSet<String>,
+    patterns: Vec<String>,
+}
+
+impl ExemptPaths {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        let path = path.into();
+        if path.contains('*') {
+            self.patterns.push(trim_trailing_slash(&path).to_string());
+        } else {
+            self.paths.insert(trim_trailing_slash(&path).to_string());
+        }
+        self
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let path = trim_trailing_slash(path);
+        self.paths.contains(path)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| glob_matches(pattern, path))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.patterns.is_empty()
+    }
+}
+
+fn trim_trailing_slash(path: &str) -> &str {
+    match path.strip_suffix('/') {
+        Some(trimmed) if !trimmed.is_empty() => trimmed,
+        _ => path,
+    }
+}
+
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = parts.next().and_then(|first| path.strip_prefix(first)) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    // No `*` at all.
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExemptPaths;
+
+    #[test]
+    fn test_matches_paths_and_patterns() {
+        let exempt = ExemptPaths::new()
+            .with_path("/healthz")
+            .with_path("/internal/*")
+            .with_path("/v*/status");
+
+        assert!(exempt.matches("/healthz"));
+        assert!(exempt.matches("/healthz/"));
+        assert!(exempt.matches("/internal/jobs"));
+        assert!(exempt.matches("/internal/jobs/42/"));
+        assert!(exempt.matches("/v1/status"));
+        assert!(exempt.matches("/v2/status/"));
+        assert!(!exempt.matches("/healthz/deep"));
+        assert!(!exempt.matches("/healthzz"));
+        assert!(!exempt.matches("/internal"));
+        assert!(!exempt.matches("/api/internal/jobs"));
+        assert!(!exempt.matches("/v1/status/extra"));
+        assert!(!exempt.matches("/"));
+        assert!(ExemptPaths::new().is_empty());
+    }
+
+    #[test]
+    fn test_trailing_slash_in_the_config_is_ignored() {
+        let exempt = ExemptPaths::new().with_path("/metrics/").with_path("/");
+
+        assert!(exempt.matches("/metrics"));
+        assert!(exempt.matches("/"));
+        assert!(!exempt.matches("/other"));
+    }
+}
@@ -0,0 +1,228 @@
+use std::net::SocketAddr;
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header, request::Parts},
+    response::Response,
+};
+
+use crate::{BoxFuture, TrustedProxies};
+
+/// Derives the identity a request is rate limited under.
+///
+/// The returned string is hashed before it is used as a Redis key, so it may
+/// be a raw secret. Returning `Err` short circuits the middleware with that
+/// response.
+pub trait KeyExtractor: Send + Sync + 'static {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Result<String, Response>>;
+}
+
+/// What [`BearerTokenExtractor`] and [`HeaderKeyExtractor`] do with requests
+/// that carry no token at all.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum MissingTokenPolicy {
+    /// Answer with 401 Unauthorized.
+    #[default]
+    Reject,
+    /// Rate limit by the client address instead.
+    ClientIp(PeerIpExtractor),
+}
+
+/// Longest header value, in bytes, a token is read from. Anything longer is
+/// rejected before it gets hashed.
+pub const MAX_TOKEN_HEADER_LEN: usize = 4096;
+
+/// Keys requests on their bearer token.
+///
+/// The token is read from `Authorization: Bearer <token>`, falling back to the
+/// legacy `Bearer: <token>` header when `legacy_header` is set. A malformed
+/// `Authorization` header is rejected rather than skipped in favour of the
+/// fallback, as is a value that isn't visible ASCII or is longer than
+/// [`MAX_TOKEN_HEADER_LEN`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BearerTokenExtractor {
+    /// On by default for older clients.
+    pub legacy_header: bool,
+    pub missing_token: MissingTokenPolicy,
+}
+
+impl Default for BearerTokenExtractor {
+    fn default() -> Self {
+        Self {
+            legacy_header: true,
+            missing_token: MissingTokenPolicy::default(),
+        }
+    }
+}
+
+impl BearerTokenExtractor {
+    pub fn with_legacy_header(mut self, enabled: bool) -> Self {
+        self.legacy_header = enabled;
+        self
+    }
+
+    pub fn with_missing_token(mut self, policy: MissingTokenPolicy) -> Self {
+        self.missing_token = policy;
+        self
+    }
+}
+
+impl KeyExtractor for BearerTokenExtractor {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Result<String, Response>> {
+        Box::pin(async move {
+            match (
+                bearer_token(&parts.headers, self.legacy_header),
+                &self.missing_token,
+            ) {
+                (Ok(Some(token)), _) => Ok(token.to_string()),
+                (Ok(None), MissingTokenPolicy::ClientIp(peer_ip)) => peer_ip.extract(parts).await,
+                _ => Err(unauthorized()),
+            }
+        })
+    }
+}
+
+/// Keys requests on the client address.
+///
+/// Requires the app to be served with
+/// `into_make_service_with_connect_info::<SocketAddr>()`; forwarding headers
+/// are honoured only from [`TrustedProxies`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerIpExtractor {
+    pub trusted_proxies: TrustedProxies,
+}
+
+impl PeerIpExtractor {
+    pub fn new(trusted_proxies: TrustedProxies) -> Self {
+        Self { trusted_proxies }
+    }
+}
+
+impl KeyExtractor for PeerIpExtractor {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Result<String, Response>> {
+        Box::pin(async move {
+            match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+                Some(ConnectInfo(addr)) => Ok(self
+                    .trusted_proxies
+                    .client_ip(&parts.headers, addr.ip())
+                    .to_string()),
+                None => Err(unauthorized()),
+            }
+        })
+    }
+}
+
+/// Keys requests on the value of a header of their own, such as an API key
+/// in `X-Api-Key`, or on the first of several that a request carries, e.g.
+/// `Authorization` and then `X-Api-Key`. An `Authorization` header in the
+/// chain is read as a bearer token.
+///
+/// The first header present decides: one whose value is malformed, isn't
+/// visible ASCII or is longer than [`MAX_TOKEN_HEADER_LEN`] gets 401
+/// Unauthorized rather than being skipped for the next. Requests with none
+/// of them are handled by `missing_key`, rejected by default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderKeyExtractor {
+    /// Tried in order.
+    pub headers: Vec<HeaderName>,
+    pub missing_key: MissingTokenPolicy,
+}
+
+impl HeaderKeyExtractor {
+    pub fn new(header: HeaderName) -> Self {
+        Self::chain([header])
+    }
+
+    /// Keys requests on the first of `headers` they carry.
+    pub fn chain(headers: impl IntoIterator<Item = HeaderName>) -> Self {
+        Self {
+            headers: headers.into_iter().collect(),
+            missing_key: MissingTokenPolicy::default(),
+        }
+    }
+
+    /// Tries `header` after the ones already in the chain.
+    pub fn with_fallback(mut self, header: HeaderName) -> Self {
+        self.headers.push(header);
+        self
+    }
+
+    pub fn with_missing_key(mut self, policy: MissingTokenPolicy) -> Self {
+        self.missing_key = policy;
+        self
+    }
+}
+
+impl KeyExtractor for HeaderKeyExtractor {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Result<String, Response>> {
+        Box::pin(async move {
+            let present = self
+                .headers
+                .iter()
+                .find_map(|name| Some((name, parts.headers.get(name)?)));
+            let key = match present {
+                Some((name, value)) if name == header::AUTHORIZATION => authorization_token(value),
+                Some((_, value)) => header_str(value).and_then(|key| match key {
+                    "" => Err(()),
+                    key => Ok(key),
+                }),
+                None => {
+                    return match &self.missing_key {
+                        MissingTokenPolicy::ClientIp(peer_ip) => peer_ip.extract(parts).await,
+                        MissingTokenPolicy::Reject => Err(missing_key()),
+                    };
+                }
+            };
+            key.map(str::to_string).map_err(|()| missing_key())
+        })
+    }
+}
+
+pub(crate) fn bearer_token(headers: &HeaderMap, legacy_header: bool) -> Result<Option<&str>, ()> {
+    if let Some(value) = headers.get(header::AUTHORIZATION) {
+        return authorization_token(value).map(Some);
+    }
+
+    match headers.get("Bearer") {
+        Some(t) if legacy_header => header_str(t).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// The token of `Authorization: Bearer <token>`.
+fn authorization_token(value: &HeaderValue) -> Result<&str, ()> {
+    let (scheme, token) = header_str(value)?
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or(())?;
+    let token = token.trim();
+
+    if !scheme.eq_ignore_ascii_case("bearer") || token.is_empty() {
+        return Err(());
+    }
+    Ok(token)
+}
+
+fn header_str(value: &HeaderValue) -> Result<&str, ()> {
+    if value.len() > MAX_TOKEN_HEADER_LEN {
+        return Err(());
+    }
+    value.to_str().map_err(|_| ())
+}
+
+/// 401 without a challenge, since the key isn't necessarily a bearer token.
+fn missing_key() -> Response {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub(crate) fn unauthorized() -> Response {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::WWW_AUTHENTICATE, "Bearer")
+        .body(Body::empty())
+        .unwrap()
+}
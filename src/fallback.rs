@@ -0,0 +1,154 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+
+use crate::{BucketConfig, RateLimitDecision, TokenPersistence};
+
+/// In-process buckets used while Redis is unavailable, so an outage degrades
+/// to per-instance limits rather than none at all.
+///
+/// Buckets start full and are dropped once Redis answers again. At most
+/// `capacity` buckets are kept; past that the least recently charged one is
+/// evicted, which effectively refills it.
+pub struct LocalFallback {
+    capacity: usize,
+    buckets: Mutex<HashMap<String, TokenPersistence>>,
+}
+
+impl LocalFallback {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            buckets: Mutex::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[cfg(test)]
+    pub(crate) fn charge(
+        &self,
+        key: &str,
+        config: &BucketConfig,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> RateLimitDecision {
+        self.charge_all(&[(key, config)], cost, now).remove(0)
+    }
+
+    /// Charges several buckets at once, all or nothing, like the store would.
+    pub(crate) fn charge_all(
+        &self,
+        keys: &[(&str, &BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> Vec<RateLimitDecision> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let current = keys
+            .iter()
+            .map(|(key, config)| {
+                buckets.remove(*key).unwrap_or(TokenPersistence {
+                    tokens: config.max_tokens,
+                    last_updated: now,
+                    penalty: None,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let charged = TokenPersistence::charge_all(
+            current.iter().zip(keys.iter().map(|(_, config)| *config)),
+            cost,
+            now,
+        );
+        let mut decisions = Vec::with_capacity(charged.len());
+        for (((key, _), bucket), (decision, updated)) in keys.iter().zip(current).zip(charged) {
+            // Denials don't change the stored state, so put it back as it was.
+            let bucket = updated.unwrap_or(bucket);
+            if buckets.len() >= self.capacity {
+                evict_oldest(&mut buckets);
+            }
+            buckets.insert(key.to_string(), bucket);
+            decisions.push(decision);
+        }
+        decisions
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.is_empty() {
+            buckets.clear();
+        }
+    }
+}
+
+fn evict_oldest(buckets: &mut HashMap<String, TokenPersistence>) {
+    let oldest = buckets
+        .iter()
+        .min_by_key(|(_, bucket)| bucket.last_updated)
+        .map(|(key, _)| key.clone());
+    if let Some(key) = oldest {
+        buckets.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::BucketConfig;
+
+    use super::LocalFallback;
+
+    fn config() -> BucketConfig {
+        BucketConfig {
+            max_tokens: 2,
+            ..BucketConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_applies_bucket_limits() {
+        let fallback = LocalFallback::new(10);
+        let now = Utc::now();
+
+        assert!(fallback.charge("a", &config(), 1, now).allowed);
+        assert!(fallback.charge("a", &config(), 1, now).allowed);
+        assert!(!fallback.charge("a", &config(), 1, now).allowed);
+        assert!(fallback.charge("b", &config(), 1, now).allowed);
+
+        let later = now + chrono::Duration::hours(1);
+        assert!(fallback.charge("a", &config(), 1, later).allowed);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_charged() {
+        let fallback = LocalFallback::new(2);
+        let now = Utc::now();
+
+        fallback.charge("a", &config(), 2, now);
+        fallback.charge("b", &config(), 2, now + chrono::Duration::seconds(1));
+        fallback.charge("c", &config(), 1, now + chrono::Duration::seconds(2));
+
+        assert_eq!(fallback.len(), 2);
+        // "a" was evicted and starts over, "b" is still drained.
+        let later = now + chrono::Duration::seconds(3);
+        assert!(!fallback.charge("b", &config(), 1, later).allowed);
+        assert!(fallback.charge("a", &config(), 2, later).allowed);
+    }
+
+    #[test]
+    fn test_clear() {
+        let fallback = LocalFallback::new(2);
+        fallback.charge("a", &config(), 1, Utc::now());
+
+        fallback.clear();
+
+        assert!(fallback.is_empty());
+    }
+}
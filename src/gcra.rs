@@ -0,0 +1,198 @@
+//! The generic cell rate algorithm, selected with [`Algorithm::Gcra`].
+//!
+//! A GCRA bucket is nothing but its theoretical arrival time (TAT): when the
+//! client would have its whole burst back if it sent nothing more. Each
+//! request pushes it `cost` emission intervals further out, and is let
+//! through as long as that leaves it no more than a burst's worth of
+//! intervals ahead of now. The time is kept in `last_updated`, and stored as
+//! bare epoch milliseconds.
+//!
+//! [`Algorithm::Gcra`]: crate::Algorithm::Gcra
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::{BucketConfig, BucketStatus, RateLimitDecision, TokenPersistence, timestamp};
+
+impl TokenPersistence {
+    /// A GCRA bucket with its arrival time at `tat`.
+    pub(crate) fn from_tat(tat: DateTime<Utc>) -> Self {
+        Self {
+            tokens: 0,
+            last_updated: tat,
+            penalty: None,
+        }
+    }
+
+    pub(crate) fn gcra_take(
+        &self,
+        config: &BucketConfig,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> (RateLimitDecision, Option<TokenPersistence>) {
+        let interval = emission_interval_ms(config);
+        let now_ms = now.timestamp_millis();
+        // A bucket whose time has passed is as good as new.
+        let tat = self.last_updated.timestamp_millis().max(now_ms);
+        let charged = tat.saturating_add(interval.saturating_mul(cost));
+        let allowed_at = charged.saturating_sub(tolerance_ms(config));
+
+        if allowed_at > now_ms {
+            let decision = RateLimitDecision {
+                allowed: false,
+                retry_after: Some(Duration::from_millis((allowed_at - now_ms) as u64)),
+                ..decision(config, tat, now)
+            };
+            return (decision, None);
+        }
+        let updated = Self::from_tat(timestamp::from_millis(charged));
+        (decision(config, charged, now), Some(updated))
+    }
+
+    /// Moves the arrival time back by `cost` intervals, but not past now.
+    pub(crate) fn gcra_refund(&self, config: &BucketConfig, cost: i64, now: DateTime<Utc>) -> Self {
+        let tat = self
+            .last_updated
+            .timestamp_millis()
+            .saturating_sub(emission_interval_ms(config).saturating_mul(cost));
+        Self::from_tat(timestamp::from_millis(tat.max(now.timestamp_millis())))
+    }
+
+    pub(crate) fn gcra_status(&self, config: &BucketConfig, now: DateTime<Utc>) -> BucketStatus {
+        let tat = self
+            .last_updated
+            .timestamp_millis()
+            .max(now.timestamp_millis());
+        BucketStatus {
+            tokens: decision(config, tat, now).remaining,
+            last_updated: now,
+            time_to_full: self.gcra_time_to_full(now),
+            banned_until: None,
+        }
+    }
+
+    /// The arrival time is when the bucket is full again.
+    pub(crate) fn gcra_time_to_full(&self, now: DateTime<Utc>) -> Duration {
+        (self.last_updated - now).to_std().unwrap_or_default()
+    }
+}
+
+/// How far apart requests are let through at the sustained rate, rounded up
+/// so it's never exceeded.
+pub(crate) fn emission_interval_ms(config: &BucketConfig) -> i64 {
+    let interval_ms = i64::try_from(config.refill_interval.as_millis()).unwrap_or(i64::MAX);
+    (interval_ms.max(1) as u64).div_ceil(config.refill_rate.max(1) as u64) as i64
+}
+
+/// How far ahead of now the arrival time may be: a whole burst.
+fn tolerance_ms(config: &BucketConfig) -> i64 {
+    emission_interval_ms(config).saturating_mul(config.max_tokens.max(0))
+}
+
+/// Where a client whose arrival time is `tat` stands: how many more
+/// requests it could send right now, and when the next one after those
+/// becomes available.
+fn decision(config: &BucketConfig, tat: i64, now: DateTime<Utc>) -> RateLimitDecision {
+    let interval = emission_interval_ms(config);
+    let tolerance = tolerance_ms(config);
+    let now_ms = now.timestamp_millis();
+    let remaining = (now_ms.saturating_add(tolerance).saturating_sub(tat) / interval)
+        .clamp(0, config.max_tokens.max(0));
+    let reset_at = if tat <= now_ms {
+        now
+    } else {
+        let next = tat - tolerance + (remaining + 1).saturating_mul(interval);
+        timestamp::from_millis(next)
+    };
+    RateLimitDecision {
+        allowed: true,
+        limit: config.max_tokens,
+        remaining,
+        reset_at,
+        retry_after: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+
+    use crate::{Algorithm, BucketConfig, TokenPersistence};
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        rfc3339.parse().unwrap()
+    }
+
+    fn gcra(burst: i64, sustained: i64, per: Duration) -> BucketConfig {
+        BucketConfig {
+            algorithm: Algorithm::Gcra,
+            ..BucketConfig::burst(burst, sustained, per)
+        }
+    }
+
+    #[test]
+    fn test_spaces_requests_at_the_sustained_rate() {
+        let config = gcra(1, 10, Duration::from_secs(3600));
+        let now = at("2025-03-01T12:00:00Z");
+        let bucket = TokenPersistence::new(&config, now);
+
+        let (first, updated) = bucket.charge(&config, 1, now);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 0);
+        assert_eq!(first.reset_at, at("2025-03-01T12:06:00Z"));
+        let updated = updated.unwrap();
+        assert_eq!(updated.last_updated, at("2025-03-01T12:06:00Z"));
+
+        let (second, _) = updated.charge(&config, 1, now);
+        assert!(!second.allowed);
+        assert_eq!(second.retry_after, Some(Duration::from_secs(6 * 60)));
+
+        // A second early is still too early, not a whole interval.
+        let (early, _) = updated.charge(&config, 1, at("2025-03-01T12:05:59Z"));
+        assert_eq!(early.retry_after, Some(Duration::from_secs(1)));
+        let (on_time, _) = updated.charge(&config, 1, at("2025-03-01T12:06:00Z"));
+        assert!(on_time.allowed);
+    }
+
+    #[test]
+    fn test_burst_then_pacing() {
+        let config = gcra(3, 60, Duration::from_secs(60));
+        let now = at("2025-03-01T12:00:00Z");
+        let mut bucket = TokenPersistence::new(&config, now);
+
+        for remaining in [2, 1, 0] {
+            let (decision, updated) = bucket.charge(&config, 1, now);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+            bucket = updated.unwrap();
+        }
+        let (denied, none) = bucket.charge(&config, 1, now);
+        assert!(!denied.allowed);
+        assert!(none.is_none());
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(bucket.time_to_full(&config, now), Duration::from_secs(3));
+
+        // Half a second on, it's still half a second short.
+        let later = now + chrono::Duration::milliseconds(500);
+        let (denied, _) = bucket.charge(&config, 1, later);
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_cost_takes_several_intervals() {
+        let config = gcra(5, 5, Duration::from_secs(5));
+        let now = at("2025-03-01T12:00:00Z");
+        let bucket = TokenPersistence::new(&config, now);
+
+        let (decision, updated) = bucket.charge(&config, 4, now);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 1);
+        let (denied, _) = updated.unwrap().charge(&config, 3, now);
+        assert!(!denied.allowed);
+        assert_eq!(denied.remaining, 1);
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,186 @@
+//! The limiter for tonic services, drawing from the same buckets as the HTTP
+//! API: a client's token costs the same whichever way it calls in.
+//!
+//! Denials are answered with `RESOURCE_EXHAUSTED` and blocked identities with
+//! `PERMISSION_DENIED`, both carrying the rate limit headers and
+//! `retry-after` as metadata. Store failures under
+//! [`FailurePolicy::Closed`](crate::FailurePolicy) are still a plain 503,
+//! which gRPC clients read as `UNAVAILABLE`.
+
+use axum::{http::request::Parts, response::Response};
+use tonic::Status;
+use tower::Layer;
+
+use crate::{
+    AppState, BoxFuture, KeyExtractor, RateLimitDecision, RateLimiterLayer, RateLimiterService,
+    extract::bearer_token,
+};
+
+/// Keys gRPC calls on the bearer token in their `authorization` metadata,
+/// the same identity [`BearerTokenExtractor`](crate::BearerTokenExtractor)
+/// reads over HTTP. Calls without a usable token are answered with
+/// `UNAUTHENTICATED`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GrpcKeyExtractor;
+
+impl KeyExtractor for GrpcKeyExtractor {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Result<String, Response>> {
+        Box::pin(async move {
+            match bearer_token(&parts.headers, false) {
+                Ok(Some(token)) => Ok(token.to_string()),
+                Ok(None) => Err(Status::unauthenticated("missing bearer token").into_http()),
+                Err(()) => Err(Status::unauthenticated("malformed bearer token").into_http()),
+            }
+        })
+    }
+}
+
+fn resource_exhausted(_: &RateLimitDecision) -> Response {
+    Status::resource_exhausted("rate limit exceeded").into_http()
+}
+
+fn permission_denied() -> Response {
+    Status::permission_denied("blocked").into_http()
+}
+
+/// [`RateLimiterLayer`] for tonic services, e.g. through
+/// `Server::builder().layer(..)`.
+///
+/// The state's key extractor, rejection and blocked responses are replaced
+/// with gRPC ones; everything else about it applies as it would over HTTP.
+pub struct GrpcRateLimiterLayer<S> {
+    inner: RateLimiterLayer<S>,
+}
+
+impl<S> GrpcRateLimiterLayer<S> {
+    pub fn new(state: AppState<S>) -> Self {
+        let state = state
+            .with_key_extractor(GrpcKeyExtractor)
+            .with_rejection(resource_exhausted)
+            .with_blocked_response(permission_denied);
+        Self {
+            inner: RateLimiterLayer::new(state),
+        }
+    }
+}
+
+impl<S> Clone for GrpcRateLimiterLayer<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<I, S> Layer<I> for GrpcRateLimiterLayer<S> {
+    type Service = RateLimiterService<I, S>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        self.inner.layer(inner)
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use std::convert::Infallible;
+
+    use axum::{
+        body::Body,
+        http::{HeaderValue, Request, Response, header},
+    };
+    use chrono::Utc;
+    use tonic::{Code, Status};
+    use tower::{ServiceBuilder, ServiceExt};
+
+    use crate::{AppState, BucketConfig, MemoryStore, testing::ManualClock};
+
+    use super::GrpcRateLimiterLayer;
+
+    async fn say_hello(_: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let mut response = Status::new(Code::Ok, "").into_http::<Body>();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/grpc"),
+        );
+        Ok(response)
+    }
+
+    fn call(authorization: Option<&str>) -> Request<Body> {
+        let mut request = Request::post("/helloworld.Greeter/SayHello")
+            .header(header::CONTENT_TYPE, "application/grpc")
+            .header("te", "trailers");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn state() -> AppState<MemoryStore> {
+        let config = BucketConfig {
+            max_tokens: 2,
+            ..BucketConfig::default()
+        };
+        AppState::new(MemoryStore::new(), config).with_clock(ManualClock::new(Utc::now()))
+    }
+
+    #[tokio::test]
+    async fn test_denied_call_is_resource_exhausted() {
+        let svc = ServiceBuilder::new()
+            .layer(GrpcRateLimiterLayer::new(state()))
+            .service_fn(say_hello);
+
+        for _ in 0..2 {
+            let response = svc.clone().oneshot(call(Some("Bearer abc"))).await.unwrap();
+            let status = Status::from_header_map(response.headers()).unwrap();
+            assert_eq!(status.code(), Code::Ok);
+        }
+        let response = svc.oneshot(call(Some("Bearer abc"))).await.unwrap();
+
+        let status = Status::from_header_map(response.headers()).unwrap();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        let retry_after = status.metadata().get("retry-after").unwrap();
+        assert!(retry_after.to_str().unwrap().parse::<u64>().unwrap() > 0);
+        assert_eq!(status.metadata().get("x-ratelimit-remaining").unwrap(), "0");
+    }
+
+    #[tokio::test]
+    async fn test_calls_share_the_http_bucket() {
+        let state = state();
+        state.consume_tokens("abc", 2).await.unwrap();
+        let svc = ServiceBuilder::new()
+            .layer(GrpcRateLimiterLayer::new(state))
+            .service_fn(say_hello);
+
+        let response = svc.oneshot(call(Some("Bearer abc"))).await.unwrap();
+
+        let status = Status::from_header_map(response.headers()).unwrap();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn test_call_without_a_token_is_unauthenticated() {
+        let svc = ServiceBuilder::new()
+            .layer(GrpcRateLimiterLayer::new(state()))
+            .service_fn(say_hello);
+
+        for authorization in [None, Some("Basic abc")] {
+            let response = svc.clone().oneshot(call(authorization)).await.unwrap();
+            let status = Status::from_header_map(response.headers()).unwrap();
+            assert_eq!(status.code(), Code::Unauthenticated);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocked_identity_is_permission_denied() {
+        let state = state();
+        state.add_to_blocklist("abc").await.unwrap();
+        let svc = ServiceBuilder::new()
+            .layer(GrpcRateLimiterLayer::new(state))
+            .service_fn(say_hello);
+
+        let response = svc.oneshot(call(Some("Bearer abc"))).await.unwrap();
+
+        let status = Status::from_header_map(response.headers()).unwrap();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+}
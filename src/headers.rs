@@ -0,0 +1,159 @@
+use axum::http::{HeaderMap, HeaderValue, header};
+use chrono::{DateTime, Utc};
+
+use crate::{BucketConfig, RateLimitDecision};
+
+/// Which set of rate limit headers responses carry, both when allowed and
+/// when denied.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HeaderStyle {
+    /// `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`,
+    /// the latter being the Unix time of the next refill in seconds.
+    #[default]
+    Legacy,
+    /// `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset` and
+    /// `RateLimit-Policy` from draft-ietf-httpapi-ratelimit-headers. Reset is
+    /// the number of seconds until the next refill.
+    Draft,
+    /// Both of the above.
+    Both,
+}
+
+/// Which of the buckets charged for a request its rate limit headers describe,
+/// sent as `X-RateLimit-Scope` whenever there's more than one: the one that
+/// denied it or, if none did, the one with the fewest tokens left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitScope {
+    /// The identity's own bucket.
+    Token,
+    /// The client address's, see [`AppState::with_ip_limit`](crate::AppState::with_ip_limit).
+    Ip,
+    /// The one every client shares, see
+    /// [`AppState::with_global_limit`](crate::AppState::with_global_limit).
+    Global,
+    /// The identity's failed attempts, see
+    /// [`Refunds::with_failures`](crate::Refunds::with_failures).
+    Failures,
+    /// The identity's quota, see
+    /// [`AppState::with_quota`](crate::AppState::with_quota).
+    Quota,
+    /// The identity's requests in flight, see
+    /// [`AppState::with_max_in_flight`](crate::AppState::with_max_in_flight).
+    Concurrency,
+}
+
+impl LimitScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Token => "token",
+            Self::Ip => "ip",
+            Self::Global => "global",
+            Self::Failures => "failures",
+            Self::Quota => "quota",
+            Self::Concurrency => "concurrency",
+        }
+    }
+}
+
+pub(crate) fn insert_rate_limit_headers(
+    headers: &mut HeaderMap,
+    style: HeaderStyle,
+    decision: &RateLimitDecision,
+    config: &BucketConfig,
+    now: DateTime<Utc>,
+) {
+    if matches!(style, HeaderStyle::Legacy | HeaderStyle::Both) {
+        // Round up so clients never retry before the token is actually there.
+        let reset = (decision.reset_at.timestamp_millis() + 999).div_euclid(1000);
+
+        headers.insert("x-ratelimit-limit", HeaderValue::from(decision.limit));
+        headers.insert(
+            "x-ratelimit-remaining",
+            HeaderValue::from(decision.remaining),
+        );
+        headers.insert("x-ratelimit-reset", HeaderValue::from(reset));
+    }
+
+    if matches!(style, HeaderStyle::Draft | HeaderStyle::Both) {
+        let reset_ms = (decision.reset_at - now).num_milliseconds().max(0);
+
+        headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+        headers.insert(
+            "ratelimit-reset",
+            HeaderValue::from((reset_ms + 999) / 1000),
+        );
+        headers.insert(
+            "ratelimit-policy",
+            HeaderValue::from_str(&policy(config)).unwrap(),
+        );
+    }
+}
+
+pub(crate) fn insert_scope(headers: &mut HeaderMap, scope: LimitScope) {
+    headers.insert(
+        "x-ratelimit-scope",
+        HeaderValue::from_static(scope.as_str()),
+    );
+}
+
+/// Names the tier the identity's bucket went by, see
+/// [`AppState::with_tier`](crate::AppState::with_tier). A tier that isn't a
+/// valid header value is left out.
+pub(crate) fn insert_tier(headers: &mut HeaderMap, tier: &str) {
+    if let Ok(tier) = HeaderValue::from_str(tier) {
+        headers.insert("x-ratelimit-tier", tier);
+    }
+}
+
+/// Marks a response to an allowlisted identity, which carries no other rate
+/// limit headers.
+pub(crate) fn insert_bypass(headers: &mut HeaderMap) {
+    headers.insert("x-ratelimit-bypass", HeaderValue::from_static("true"));
+}
+
+/// Marks a response that was let through in [`Mode::Shadow`](crate::Mode)
+/// although the bucket was empty.
+pub(crate) fn insert_would_block(headers: &mut HeaderMap) {
+    headers.insert("x-ratelimit-would-block", HeaderValue::from_static("true"));
+}
+
+/// The quota policy as `<max_tokens>;w=<seconds>`, where the window is the
+/// time an empty bucket takes to refill completely, e.g. `10;w=36000` for the
+/// default of ten tokens at one per hour.
+fn policy(config: &BucketConfig) -> String {
+    let refills = (config.max_tokens + config.refill_rate - 1) / config.refill_rate.max(1);
+    let window = config.refill_interval.as_secs() as i64 * refills;
+
+    format!("{};w={}", config.max_tokens, window)
+}
+
+/// Adds `Retry-After` (in whole seconds, rounded up) to a denied response.
+pub(crate) fn insert_retry_after(headers: &mut HeaderMap, decision: &RateLimitDecision) {
+    if let Some(retry_after) = decision.retry_after {
+        let seconds = retry_after.as_millis().div_ceil(1000) as u64;
+        headers.insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::BucketConfig;
+
+    use super::policy;
+
+    #[test]
+    fn test_policy_window_is_full_refill_time() {
+        assert_eq!(policy(&BucketConfig::default()), "10;w=36000");
+
+        let config = BucketConfig {
+            max_tokens: 100,
+            refill_rate: 30,
+            refill_interval: Duration::from_secs(60),
+            ..BucketConfig::default()
+        };
+        assert_eq!(policy(&config), "100;w=240");
+    }
+}
@@ -0,0 +1,130 @@
+use std::time::Duration;
+
+use axum::{
+    Json, Router,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde_json::json;
+
+use crate::{AppState, BucketStore, ProblemDetails, StoreError};
+
+/// The longest `/readyz` waits for the store, whatever timeout it has of its
+/// own, so probes get their answer before they time out themselves.
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Routes for an orchestrator's probes:
+///
+/// - `GET /healthz` answers 200 as long as the process is serving at all.
+/// - `GET /readyz` pings the store, see [`BucketStore::ping`], and answers
+///   200 if it answered, or 503 with the reason as [`ProblemDetails`] if it
+///   didn't.
+///
+/// Probes carry no token, so mount it outside the rate limited routes, as
+/// with `Router::route_layer`, or add both paths to the state's
+/// [`ExemptPaths`](crate::ExemptPaths).
+pub fn health_router<S: BucketStore>(state: AppState<S>) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz::<S>))
+        .with_state(state)
+}
+
+async fn healthz() -> Json<serde_json::Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+async fn readyz<S: BucketStore>(State(state): State<AppState<S>>) -> Response {
+    let ping = tokio::time::timeout(PING_TIMEOUT, state.store.ping())
+        .await
+        .unwrap_or(Err(StoreError::Timeout(PING_TIMEOUT)));
+    match ping {
+        Ok(()) => Json(json!({ "status": "ready" })).into_response(),
+        Err(e) => {
+            tracing::warn!(error = %e, "store isn't ready");
+            ProblemDetails::new(
+                "urn:leaky-bucket:backend-unavailable",
+                StatusCode::SERVICE_UNAVAILABLE,
+                e.to_string(),
+            )
+            .into_response()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "memory", feature = "redis"))]
+mod tests {
+    use axum::{
+        Router,
+        body::Body,
+        http::{Request, StatusCode},
+        routing::get,
+    };
+    use http_body_util::BodyExt;
+    use redis::{ErrorKind, RedisError};
+    use redis_test::{MockCmd, MockRedisConnection};
+    use tower::ServiceExt;
+
+    use super::health_router;
+    use crate::{AppState, AsyncRedisStore, BucketConfig, MemoryStore, RateLimiterLayer};
+
+    async fn probe(app: Router, path: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn redis_state(
+        ping: Result<&'static str, RedisError>,
+    ) -> AppState<AsyncRedisStore<MockRedisConnection>> {
+        let conn = MockRedisConnection::new(vec![MockCmd::new(redis::cmd("PING"), ping)]);
+        AppState::new(AsyncRedisStore::new(conn), BucketConfig::default())
+    }
+
+    #[tokio::test]
+    async fn test_ready_while_redis_answers() {
+        let app = health_router(redis_state(Ok("PONG")));
+
+        let (status, body) = probe(app.clone(), "/readyz").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+        assert_eq!(probe(app, "/healthz").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_while_redis_fails() {
+        let refused = RedisError::from((ErrorKind::IoError, "connection refused"));
+        let app = health_router(redis_state(Err(refused)));
+
+        let (status, body) = probe(app.clone(), "/readyz").await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["type"], "urn:leaky-bucket:backend-unavailable");
+        let reason = body["detail"].as_str().unwrap();
+        assert!(reason.contains("connection refused"), "{reason}");
+        // Alive all the same.
+        assert_eq!(probe(app, "/healthz").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_probes_skip_the_limiter_on_the_same_app() {
+        let state = AppState::new(MemoryStore::new(), BucketConfig::default());
+        let app = Router::new()
+            .route("/", get(|| async { "hello" }))
+            .route_layer(RateLimiterLayer::new(state.clone()))
+            .merge(health_router(state));
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        for _ in 0..3 {
+            assert_eq!(probe(app.clone(), "/healthz").await.0, StatusCode::OK);
+            assert_eq!(probe(app.clone(), "/readyz").await.0, StatusCode::OK);
+        }
+    }
+}
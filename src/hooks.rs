@@ -0,0 +1,71 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::BoxFuture;
+
+/// What a [`RateLimitHooks`] callback is told about a decision.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecisionCtx {
+    /// The bucket charged, which holds the identity hashed, never the
+    /// identity itself.
+    pub bucket_key: String,
+    /// The route pattern the request matched, if it went through a router.
+    pub route: Option<String>,
+    pub remaining: i64,
+    /// How long a denied client has to wait.
+    pub retry_after: Option<Duration>,
+    /// The identity's tier, if its bucket went by one, see
+    /// [`AppState::with_tier`](crate::AppState::with_tier).
+    pub tier: Option<String>,
+}
+
+/// Callbacks run after the middleware has decided on a request, for feeding
+/// decisions into alerting or auditing. Both do nothing unless overridden.
+///
+/// Requests let through in [`Mode::Shadow`](crate::Mode) that would have been
+/// denied count as denied. Requests that never get as far as a decision, such
+/// as allowlisted or unauthorized ones, don't get a callback.
+pub trait RateLimitHooks: Send + Sync + 'static {
+    fn on_allowed<'a>(&'a self, ctx: &'a DecisionCtx) -> BoxFuture<'a, ()> {
+        let _ = ctx;
+        Box::pin(async {})
+    }
+
+    fn on_denied<'a>(&'a self, ctx: &'a DecisionCtx) -> BoxFuture<'a, ()> {
+        let _ = ctx;
+        Box::pin(async {})
+    }
+}
+
+/// How the middleware runs [`RateLimitHooks`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HookDispatch {
+    /// On a task of their own, so slow hooks never hold up the response.
+    #[default]
+    Spawned,
+    /// Before responding, so the hooks are done by the time the client hears
+    /// back, at the cost of waiting for them.
+    Awaited,
+}
+
+pub(crate) async fn notify(
+    hooks: &Arc<dyn RateLimitHooks>,
+    dispatch: HookDispatch,
+    allowed: bool,
+    ctx: DecisionCtx,
+) {
+    match dispatch {
+        HookDispatch::Awaited => run(hooks, allowed, &ctx).await,
+        HookDispatch::Spawned => {
+            let hooks = Arc::clone(hooks);
+            tokio::spawn(async move { run(&hooks, allowed, &ctx).await });
+        }
+    }
+}
+
+async fn run(hooks: &Arc<dyn RateLimitHooks>, allowed: bool, ctx: &DecisionCtx) {
+    if allowed {
+        hooks.on_allowed(ctx).await;
+    } else {
+        hooks.on_denied(ctx).await;
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use axum::{body::Body, response::Response};
+use dashmap::DashMap;
+use http_body_util::BodyExt;
+
+/// Requests being handled per bucket key on this instance, see
+/// [`AppState::with_max_in_flight`](crate::AppState::with_max_in_flight).
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    max: usize,
+    counts: DashMap<String, usize>,
+}
+
+/// A request's place among those in flight for its key, given up when it's
+/// dropped: after the response body is, or when the handler panics or the
+/// request is cancelled.
+#[derive(Debug)]
+pub(crate) struct InFlightGuard {
+    in_flight: Arc<InFlight>,
+    key: String,
+}
+
+impl InFlight {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            max,
+            counts: DashMap::new(),
+        }
+    }
+
+    pub(crate) fn max(&self) -> usize {
+        self.max
+    }
+
+    /// A place for one more request to `key`, unless it has as many in
+    /// flight as it may.
+    pub(crate) fn acquire(self: &Arc<Self>, key: &str) -> Option<InFlightGuard> {
+        let mut count = self.counts.entry(key.to_string()).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(InFlightGuard {
+            in_flight: Arc::clone(self),
+            key: key.to_string(),
+        })
+    }
+
+    #[cfg(all(test, feature = "memory", feature = "redis"))]
+    pub(crate) fn count(&self, key: &str) -> usize {
+        self.counts.get(key).map_or(0, |count| *count)
+    }
+}
+
+impl InFlightGuard {
+    /// `response`, keeping the request in flight until its body has been
+    /// sent or dropped.
+    pub(crate) fn hold(self, response: Response) -> Response {
+        response.map(|body| {
+            Body::new(body.map_frame(move |frame| {
+                let _ = &self;
+                frame
+            }))
+        })
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.in_flight.counts.get_mut(&self.key) {
+            *count = count.saturating_sub(1);
+        }
+        self.in_flight
+            .counts
+            .remove_if(&self.key, |_, count| *count == 0);
+    }
+}
@@ -0,0 +1,46 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{FromRequestParts, OptionalFromRequestParts},
+    http::{StatusCode, request::Parts},
+};
+use chrono::{DateTime, Utc};
+
+/// Where the caller stands with its bucket, for handlers that adapt to how
+/// much quota is left, say by returning fewer results when nearly throttled.
+///
+/// The middleware puts it in the request extensions whenever it lets a
+/// charged request through, so it's missing for allowlisted and free requests
+/// and for ones let through because the store failed. Take it as
+/// `Option<RateLimitInfo>` where that can happen; taking it bare answers 500
+/// when it's missing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimitInfo {
+    pub limit: i64,
+    /// Tokens left after this request was charged.
+    pub remaining: i64,
+    /// When the next token is put back, or now if the bucket is already full.
+    pub reset_at: DateTime<Utc>,
+    /// The bucket charged, which holds the identity hashed, never the
+    /// identity itself.
+    pub key_hash: String,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RateLimitInfo {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Self>().cloned().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "request was not charged by the rate limiter",
+        ))
+    }
+}
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for RateLimitInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Option<Self>, Infallible> {
+        Ok(parts.extensions.get::<Self>().cloned())
+    }
+}
@@ -0,0 +1,257 @@
+//! The middleware against a real Redis in a container, serving requests over
+//! TCP, for what the scripted connections can't tell: the shapes of EXEC and
+//! script replies, WATCH conflicts, TTLs and Redis going away.
+//!
+//! They need Docker, so they're ignored unless asked for:
+//!
+//! ```text
+//! cargo test it:: -- --ignored
+//! ```
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{Router, http::StatusCode, middleware, routing::get};
+use redis::{AsyncConnectionConfig, aio::MultiplexedConnection};
+use testcontainers::{
+    ContainerAsync, GenericImage, ImageExt,
+    core::{IntoContainerPort, WaitFor},
+    runners::AsyncRunner,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    AppState, AsyncRedisStore, BucketConfig, BucketStore, ConnectionPool, FailurePolicy,
+    ReconnectingConnection, RedisStore, TransactionRetry, rate_limiter_middleware,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A Redis of its own, on a host port that stays the same across restarts.
+struct Redis {
+    container: ContainerAsync<GenericImage>,
+    client: redis::Client,
+}
+
+impl Redis {
+    async fn start() -> Self {
+        // Docker picks another port on every start unless told which.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let container = GenericImage::new("redis", "7-alpine")
+            .with_exposed_port(6379.tcp())
+            .with_wait_for(WaitFor::message_on_stdout("Ready to accept connections"))
+            .with_mapped_port(port, 6379.tcp())
+            .start()
+            .await
+            .expect("couldn't start redis, is docker running?");
+        let client = redis::Client::open(format!("redis://127.0.0.1:{port}")).unwrap();
+        Self { container, client }
+    }
+
+    async fn connection(&self) -> MultiplexedConnection {
+        self.client
+            .get_multiplexed_async_connection()
+            .await
+            .unwrap()
+    }
+
+    /// A store on `n` connections that are opened again whenever they break.
+    async fn async_store(
+        &self,
+        n: usize,
+    ) -> AsyncRedisStore<ReconnectingConnection<MultiplexedConnection>> {
+        let config = AsyncConnectionConfig::new()
+            .set_connection_timeout(TIMEOUT)
+            .set_response_timeout(TIMEOUT);
+        let mut connections = Vec::with_capacity(n);
+        for _ in 0..n {
+            let conn = ReconnectingConnection::open(self.client.clone(), config.clone()).await;
+            connections.push(conn.unwrap());
+        }
+        AsyncRedisStore::from_pool(ConnectionPool::new(connections)).with_timeout(TIMEOUT)
+    }
+
+    /// Waits for Redis to answer again after a restart.
+    async fn ready(&self) {
+        for _ in 0..50 {
+            let pinged = match self.client.get_multiplexed_async_connection().await {
+                Ok(mut conn) => redis::cmd("PING").exec_async(&mut conn).await.is_ok(),
+                Err(_) => false,
+            };
+            if pinged {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("redis didn't come back");
+    }
+}
+
+/// Serves a limited app with `state` on an ephemeral port.
+async fn serve<S: BucketStore>(state: AppState<S>) -> SocketAddr {
+    let app = Router::new()
+        .route("/", get(|| async { "hello" }))
+        .route_layer(middleware::from_fn_with_state(
+            state,
+            rate_limiter_middleware::<S>,
+        ));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    addr
+}
+
+/// The status of `GET /` with `token` as the bearer token.
+async fn status(addr: SocketAddr, token: &str) -> StatusCode {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: test\r\nAuthorization: Bearer {token}\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8_lossy(&response);
+    let code = response
+        .split(' ')
+        .nth(1)
+        .unwrap_or_else(|| panic!("not a response: {response}"));
+    StatusCode::from_bytes(code.as_bytes()).unwrap()
+}
+
+fn small(max_tokens: i64, refill_interval: Duration) -> BucketConfig {
+    BucketConfig {
+        max_tokens,
+        refill_interval,
+        ..BucketConfig::default()
+    }
+}
+
+async fn statuses(addr: SocketAddr, token: &str, n: usize) -> Vec<StatusCode> {
+    let mut statuses = Vec::with_capacity(n);
+    for _ in 0..n {
+        statuses.push(status(addr, token).await);
+    }
+    statuses
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn test_allows_then_denies() {
+    let redis = Redis::start().await;
+    let config = small(3, Duration::from_secs(60 * 60));
+    let sync = RedisStore::connect(&redis.client, 2, TIMEOUT).unwrap();
+    let sync = serve(AppState::new(sync, config.clone())).await;
+    let scripted = serve(AppState::new(redis.async_store(2).await, config)).await;
+
+    for (addr, token) in [(sync, "watched"), (scripted, "scripted")] {
+        let ok = StatusCode::OK;
+        let denied = StatusCode::TOO_MANY_REQUESTS;
+        assert_eq!(statuses(addr, token, 4).await, [ok, ok, ok, denied]);
+        // Others have their own buckets.
+        assert_eq!(status(addr, "someone-else").await, ok);
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn test_refills_and_expires() {
+    let redis = Redis::start().await;
+    let config = small(2, Duration::from_millis(300));
+    let state = AppState::new(redis.async_store(1).await, config.clone());
+    let key = state.bucket_key("abc");
+    let addr = serve(state).await;
+
+    let ok = StatusCode::OK;
+    let denied = StatusCode::TOO_MANY_REQUESTS;
+    assert_eq!(statuses(addr, "abc", 3).await, [ok, ok, denied]);
+
+    // Kept no longer than it takes to refill.
+    let mut conn = redis.connection().await;
+    let ttl: i64 = redis::cmd("PTTL")
+        .arg(&key)
+        .query_async(&mut conn)
+        .await
+        .unwrap();
+    let full = config.full_refill().as_millis() as i64;
+    assert!(0 < ttl && ttl <= full + 1000, "{ttl}");
+
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    assert_eq!(statuses(addr, "abc", 2).await, [ok, denied]);
+
+    // TTLs are set in whole seconds.
+    tokio::time::sleep(config.full_refill() + Duration::from_secs(2)).await;
+    let exists: bool = redis::cmd("EXISTS")
+        .arg(&key)
+        .query_async(&mut conn)
+        .await
+        .unwrap();
+    assert!(!exists);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "needs docker"]
+async fn test_contention_on_one_key_never_overspends() {
+    let redis = Redis::start().await;
+    let config = small(10, Duration::from_secs(60 * 60));
+    let retry = TransactionRetry {
+        max_retries: 100,
+        base_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(20),
+    };
+    let sync = RedisStore::connect(&redis.client, 8, TIMEOUT)
+        .unwrap()
+        .with_retry(retry);
+    let sync = serve(AppState::new(sync, config.clone())).await;
+    let scripted = serve(AppState::new(redis.async_store(8).await, config)).await;
+
+    for addr in [sync, scripted] {
+        let requests = (0..50)
+            .map(|_| tokio::spawn(status(addr, "contended")))
+            .collect::<Vec<_>>();
+        let mut allowed = 0;
+        for request in requests {
+            match request.await.unwrap() {
+                StatusCode::OK => allowed += 1,
+                StatusCode::TOO_MANY_REQUESTS => {}
+                other => panic!("{other}"),
+            }
+        }
+        assert_eq!(allowed, 10);
+        redis::cmd("FLUSHDB")
+            .exec_async(&mut redis.connection().await)
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs docker"]
+async fn test_recovers_once_redis_is_back() {
+    let redis = Redis::start().await;
+    let state = AppState::new(redis.async_store(2).await, BucketConfig::default())
+        .with_failure_policy(FailurePolicy::Closed);
+    let addr = serve(state).await;
+    assert_eq!(status(addr, "abc").await, StatusCode::OK);
+
+    redis.container.stop().await.unwrap();
+    assert_eq!(status(addr, "abc").await, StatusCode::SERVICE_UNAVAILABLE);
+
+    redis.container.start().await.unwrap();
+    redis.ready().await;
+    // The connections that broke are opened again by the requests after.
+    let mut recovered = false;
+    for _ in 0..10 {
+        if status(addr, "abc").await == StatusCode::OK {
+            recovered = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(recovered);
+}
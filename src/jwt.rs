@@ -0,0 +1,257 @@
+use std::{fmt, sync::Arc};
+
+use axum::{http::request::Parts, response::Response};
+use base64::{
+    Engine,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{
+    BearerTokenExtractor, BoxFuture, KeyExtractor,
+    extract::{bearer_token, unauthorized},
+};
+
+/// JWTs are base64url, which they're meant to send unpadded.
+const BASE64URL: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// What [`JwtClaimExtractor`] does with a bearer token that isn't a JWT it
+/// can key on: one that doesn't parse, fails verification or lacks the
+/// claim.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidJwt {
+    /// Key the request on the token itself, as [`BearerTokenExtractor`]
+    /// would. The identity is prefixed with `token:`, so a token can't pass
+    /// itself off as a claim value.
+    #[default]
+    RawToken,
+    /// Answer with 401 Unauthorized.
+    Reject,
+}
+
+/// Keys requests on a claim of their bearer token, a JWT, so every token
+/// minted for a user shares the user's bucket. The claim is `sub` unless
+/// told otherwise, and a string or a number.
+///
+/// Tokens are only decoded unless an HS256 secret is given with
+/// [`with_hs256_secret`](Self::with_hs256_secret), in which case their
+/// signature is checked too. Anyone can mint an unsigned token with someone
+/// else's claim, so without one a client can spend another's tokens. Expiry
+/// isn't checked either way; that's for whatever authenticates the request.
+///
+/// The token is read like [`BearerTokenExtractor`] reads it, and requests
+/// without one are handled by its [`MissingTokenPolicy`](crate::MissingTokenPolicy).
+#[derive(Clone)]
+pub struct JwtClaimExtractor {
+    pub claim: String,
+    pub invalid: InvalidJwt,
+    pub bearer: BearerTokenExtractor,
+    secret: Option<Arc<[u8]>>,
+}
+
+impl Default for JwtClaimExtractor {
+    fn default() -> Self {
+        Self::new("sub")
+    }
+}
+
+// Never prints the secret.
+impl fmt::Debug for JwtClaimExtractor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtClaimExtractor")
+            .field("claim", &self.claim)
+            .field("invalid", &self.invalid)
+            .field("bearer", &self.bearer)
+            .field("verified", &self.secret.is_some())
+            .finish()
+    }
+}
+
+impl JwtClaimExtractor {
+    pub fn new(claim: impl Into<String>) -> Self {
+        Self {
+            claim: claim.into(),
+            invalid: InvalidJwt::default(),
+            bearer: BearerTokenExtractor::default(),
+            secret: None,
+        }
+    }
+
+    pub fn with_invalid(mut self, invalid: InvalidJwt) -> Self {
+        self.invalid = invalid;
+        self
+    }
+
+    pub fn with_bearer(mut self, bearer: BearerTokenExtractor) -> Self {
+        self.bearer = bearer;
+        self
+    }
+
+    /// Only trusts tokens signed with HS256 under `secret`.
+    pub fn with_hs256_secret(mut self, secret: impl AsRef<[u8]>) -> Self {
+        self.secret = Some(Arc::from(secret.as_ref()));
+        self
+    }
+
+    /// The claim of `token`, if it's a JWT that has it and, with a secret,
+    /// is signed with it.
+    fn claim(&self, token: &str) -> Option<String> {
+        let mut segments = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) = (
+            segments.next(),
+            segments.next(),
+            segments.next(),
+            segments.next(),
+        ) else {
+            return None;
+        };
+
+        if let Some(secret) = &self.secret {
+            let header: Value = serde_json::from_slice(&BASE64URL.decode(header).ok()?).ok()?;
+            if header.get("alg").and_then(Value::as_str) != Some("HS256") {
+                return None;
+            }
+            // Everything up to the signature's dot.
+            let signed = &token[..token.len() - signature.len() - 1];
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret).ok()?;
+            mac.update(signed.as_bytes());
+            // In constant time, so a signature can't be guessed a byte at a
+            // time.
+            mac.verify_slice(&BASE64URL.decode(signature).ok()?).ok()?;
+        }
+
+        let payload: Value = serde_json::from_slice(&BASE64URL.decode(payload).ok()?).ok()?;
+        match payload.get(&self.claim)? {
+            Value::String(value) => Some(value.clone()),
+            Value::Number(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl KeyExtractor for JwtClaimExtractor {
+    fn extract<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Result<String, Response>> {
+        Box::pin(async move {
+            let token = match bearer_token(&parts.headers, self.bearer.legacy_header) {
+                Ok(Some(token)) => token,
+                Ok(None) => return self.bearer.extract(parts).await,
+                Err(()) => return Err(unauthorized()),
+            };
+            match (self.claim(token), self.invalid) {
+                (Some(claim), _) => Ok(claim),
+                (None, InvalidJwt::RawToken) => Ok(format!("token:{token}")),
+                (None, InvalidJwt::Reject) => Err(unauthorized()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Request, StatusCode, request::Parts};
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::{InvalidJwt, JwtClaimExtractor};
+    use crate::KeyExtractor;
+
+    /// `{"alg":"none"}` over `{"sub":"user-42","iat":1700000000}`, unsigned.
+    const USER_42: &str = "eyJhbGciOiJub25lIn0.eyJzdWIiOiJ1c2VyLTQyIiwiaWF0IjoxNzAwMDAwMDAwfQ.";
+    /// The same user, minted later.
+    const USER_42_AGAIN: &str =
+        "eyJhbGciOiJub25lIn0.eyJzdWIiOiJ1c2VyLTQyIiwiaWF0IjoxNzAwMDAzNjAwfQ.";
+    /// `{"alg":"none"}` over `{"iat":1700000000}`, with no `sub`.
+    const NO_SUBJECT: &str = "eyJhbGciOiJub25lIn0.eyJpYXQiOjE3MDAwMDAwMDB9.";
+    const MALFORMED: &str = "eyJhbGciOiJub25lIn0.not-json.";
+
+    fn signed(payload: &str, secret: &[u8]) -> String {
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#),
+            URL_SAFE_NO_PAD.encode(payload)
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(signed.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{signed}.{signature}")
+    }
+
+    fn bearer(token: &str) -> Parts {
+        let request = Request::builder()
+            .header("Authorization", format!("Bearer {token}"))
+            .body(())
+            .unwrap();
+        request.into_parts().0
+    }
+
+    async fn extract(extractor: &JwtClaimExtractor, token: &str) -> Result<String, StatusCode> {
+        extractor
+            .extract(&bearer(token))
+            .await
+            .map_err(|response| response.status())
+    }
+
+    #[tokio::test]
+    async fn test_every_token_of_a_user_shares_a_key() {
+        let extractor = JwtClaimExtractor::default();
+
+        assert_eq!(extract(&extractor, USER_42).await.unwrap(), "user-42");
+        assert_eq!(extract(&extractor, USER_42_AGAIN).await.unwrap(), "user-42");
+
+        let by_issued = JwtClaimExtractor::new("iat");
+        assert_eq!(extract(&by_issued, USER_42).await.unwrap(), "1700000000");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_tokens_fall_back_or_are_rejected() {
+        let extractor = JwtClaimExtractor::default();
+        for token in [NO_SUBJECT, MALFORMED, "opaque-token"] {
+            assert_eq!(
+                extract(&extractor, token).await.unwrap(),
+                format!("token:{token}")
+            );
+        }
+
+        let strict = extractor.with_invalid(InvalidJwt::Reject);
+        for token in [NO_SUBJECT, MALFORMED, "opaque-token"] {
+            assert_eq!(extract(&strict, token).await, Err(StatusCode::UNAUTHORIZED));
+        }
+        assert_eq!(extract(&strict, USER_42).await.unwrap(), "user-42");
+    }
+
+    #[tokio::test]
+    async fn test_secret_only_trusts_tokens_it_signed() {
+        let extractor = JwtClaimExtractor::default()
+            .with_invalid(InvalidJwt::Reject)
+            .with_hs256_secret("secret");
+
+        let token = signed(r#"{"sub":"user-42"}"#, b"secret");
+        assert_eq!(extract(&extractor, &token).await.unwrap(), "user-42");
+
+        let forged = signed(r#"{"sub":"user-42"}"#, b"guessed");
+        assert_eq!(
+            extract(&extractor, &forged).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            extract(&extractor, USER_42).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert!(!format!("{extractor:?}").contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_token_is_unauthorized() {
+        let parts = Request::builder().body(()).unwrap().into_parts().0;
+
+        let response = JwtClaimExtractor::default().extract(&parts).await;
+
+        assert_eq!(response.unwrap_err().status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,180 @@
+use std::{error::Error, fmt, fmt::Write, sync::Arc};
+
+use hmac::{Hmac, Mac};
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// The shortest hash [`KeyHasher::truncated`] keeps, in hex digits.
+pub const MIN_KEY_HASH_LEN: usize = 16;
+
+/// The whole of a SHA-256 hash, in hex digits.
+const FULL_LEN: usize = 64;
+
+/// How identities are hashed into bucket keys: SHA-256 by default, so raw
+/// tokens never reach the store.
+///
+/// Anyone who can read the keyspace can still tell whether a token they hold
+/// has a bucket by hashing it, so [`with_secret`](Self::with_secret) keys the
+/// hash with HMAC-SHA256 instead. Changing the secret or the length changes
+/// every key, which starts every identity over with a full bucket and leaves
+/// the old buckets, tiers and overrides behind.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyHasher {
+    secret: Option<Secret>,
+    len: usize,
+}
+
+impl KeyHasher {
+    /// Hashes identities with HMAC-SHA256 keyed on `secret`.
+    pub fn with_secret(mut self, secret: Secret) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Keeps only the first `len` hex digits of the hash, for shorter keys.
+    /// Any fewer than [`MIN_KEY_HASH_LEN`] would make two identities sharing
+    /// a bucket a real possibility.
+    pub fn truncated(mut self, len: usize) -> Result<Self, InvalidHashLen> {
+        if !(MIN_KEY_HASH_LEN..=FULL_LEN).contains(&len) {
+            return Err(InvalidHashLen(len));
+        }
+        self.len = len;
+        Ok(self)
+    }
+
+    /// `identity`'s hash, in hex.
+    pub(crate) fn hash(&self, identity: &str) -> String {
+        let digest: [u8; 32] = match &self.secret {
+            Some(secret) => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&secret.0)
+                    .expect("HMAC takes keys of any length");
+                mac.update(identity.as_bytes());
+                mac.finalize().into_bytes().into()
+            }
+            None => Sha256::digest(identity.as_bytes()).into(),
+        };
+        let mut hex = String::with_capacity(FULL_LEN);
+        for byte in digest {
+            write!(hex, "{byte:02x}").unwrap();
+        }
+        hex.truncate(self.len);
+        hex
+    }
+}
+
+impl Default for KeyHasher {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            len: FULL_LEN,
+        }
+    }
+}
+
+/// A key for [`KeyHasher::with_secret`]. It's never printed, `Debug`
+/// included.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub struct Secret(Arc<[u8]>);
+
+impl Secret {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self(secret.into().into())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Self::new(secret)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// A length [`KeyHasher::truncated`] won't cut hashes to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidHashLen(pub usize);
+
+impl fmt::Display for InvalidHashLen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "key hash length must be from {MIN_KEY_HASH_LEN} to {FULL_LEN}, not {}",
+            self.0
+        )
+    }
+}
+
+impl Error for InvalidHashLen {}
+
+#[cfg(test)]
+mod tests {
+    use super::{InvalidHashLen, KeyHasher, Secret};
+
+    #[test]
+    fn test_default_is_plain_sha256() {
+        assert_eq!(
+            KeyHasher::default().hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_secrets_change_every_key() {
+        let plain = KeyHasher::default();
+        let one = KeyHasher::default().with_secret(Secret::new("one"));
+        let two = KeyHasher::default().with_secret(Secret::new("two"));
+
+        assert_ne!(one.hash("abc"), plain.hash("abc"));
+        assert_ne!(one.hash("abc"), two.hash("abc"));
+        assert_eq!(one.hash("abc"), one.clone().hash("abc"));
+        assert_eq!(one.hash("abc").len(), 64);
+    }
+
+    #[test]
+    fn test_secrets_key_hmac_sha256() {
+        // RFC 4231 test cases 2 and 6.
+        let jefe = KeyHasher::default().with_secret(Secret::new("Jefe"));
+        assert_eq!(
+            jefe.hash("what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // A key longer than a block is hashed first.
+        let long = KeyHasher::default().with_secret(Secret::new([0xaa; 131]));
+        assert_eq!(
+            long.hash("Test Using Larger Than Block-Size Key - Hash Key First"),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_truncates_hashes() {
+        let full = KeyHasher::default().hash("abc");
+
+        let hasher = KeyHasher::default().truncated(16).unwrap();
+
+        assert_eq!(hasher.hash("abc"), full[..16]);
+        assert_eq!(KeyHasher::default().truncated(15), Err(InvalidHashLen(15)));
+        assert_eq!(KeyHasher::default().truncated(65), Err(InvalidHashLen(65)));
+        assert_eq!(
+            InvalidHashLen(8).to_string(),
+            "key hash length must be from 16 to 64, not 8"
+        );
+    }
+
+    #[test]
+    fn test_secret_is_never_printed() {
+        let hasher = KeyHasher::default().with_secret(Secret::new("hunter2"));
+
+        assert!(!format!("{hasher:?}").contains("hunter2"));
+        assert!(!format!("{hasher:#?}").contains("hunter2"));
+    }
+}
@@ -0,0 +1,154 @@
+use std::task::{Context, Poll};
+
+use axum::{
+    BoxError,
+    body::{Bytes, HttpBody},
+    http::{Request, Response},
+};
+use tower::{Layer, Service};
+
+use crate::{AppState, BoxFuture, BucketStore};
+
+/// Rate limits the service it wraps, for use in any tower stack:
+///
+#[cfg_attr(feature = "memory", doc = "```")]
+#[cfg_attr(not(feature = "memory"), doc = "```ignore")]
+/// use std::convert::Infallible;
+///
+/// use axum::{body::Body, http::{Request, Response}};
+/// use leaky_bucket::{AppState, BucketConfig, MemoryStore, RateLimiterLayer};
+/// use tower::ServiceBuilder;
+///
+/// let state = AppState::new(MemoryStore::new(), BucketConfig::default());
+/// let service = ServiceBuilder::new()
+///     .layer(RateLimiterLayer::new(state))
+///     .service_fn(|_: Request<Body>| async {
+///         Ok::<_, Infallible>(Response::new(Body::from("hello")))
+///     });
+/// ```
+///
+/// Requests may carry any body. Responses come back with an axum
+/// [`Body`](axum::body::Body), whether they're the wrapped service's or the
+/// limiter's own rejections.
+pub struct RateLimiterLayer<S> {
+    state: AppState<S>,
+}
+
+impl<S> RateLimiterLayer<S> {
+    pub fn new(state: AppState<S>) -> Self {
+        Self { state }
+    }
+}
+
+impl<S> Clone for RateLimiterLayer<S> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<I, S> Layer<I> for RateLimiterLayer<S> {
+    type Service = RateLimiterService<I, S>;
+
+    fn layer(&self, inner: I) -> Self::Service {
+        RateLimiterService::new(inner, self.state.clone())
+    }
+}
+
+/// The service [`RateLimiterLayer`] wraps others in.
+pub struct RateLimiterService<I, S> {
+    inner: I,
+    state: AppState<S>,
+}
+
+impl<I, S> RateLimiterService<I, S> {
+    pub fn new(inner: I, state: AppState<S>) -> Self {
+        Self { inner, state }
+    }
+}
+
+impl<I: Clone, S> Clone for RateLimiterService<I, S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<I, S, B, ResBody> Service<Request<B>> for RateLimiterService<I, S>
+where
+    S: BucketStore,
+    I: Service<Request<B>, Response = Response<ResBody>> + Clone + Send + 'static,
+    I::Future: Send,
+    B: Send + 'static,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = axum::response::Response;
+    type Error = I::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, I::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), I::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        // Take the clone that was polled ready and leave a fresh one behind.
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(crate::middleware::limit(self.state.clone(), request, inner))
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use std::convert::Infallible;
+
+    use axum::{body::Bytes, http::StatusCode};
+    use chrono::Utc;
+    use http_body_util::{BodyExt, Full};
+    use tower::{ServiceBuilder, ServiceExt};
+
+    use crate::{AppState, BucketConfig, MemoryStore, testing::ManualClock};
+
+    use super::RateLimiterLayer;
+
+    async fn hello(
+        _: hyper::Request<Full<Bytes>>,
+    ) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+        Ok(hyper::Response::new(Full::new(Bytes::from_static(
+            b"hello",
+        ))))
+    }
+
+    fn request() -> hyper::Request<Full<Bytes>> {
+        hyper::Request::builder()
+            .header("Bearer", "abc")
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_layer_limits_a_plain_hyper_service() {
+        let config = BucketConfig {
+            max_tokens: 1,
+            ..BucketConfig::default()
+        };
+        let state =
+            AppState::new(MemoryStore::new(), config).with_clock(ManualClock::new(Utc::now()));
+        let svc = ServiceBuilder::new()
+            .layer(RateLimiterLayer::new(state))
+            .service_fn(hello);
+
+        let response = svc.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-RateLimit-Remaining"], "0");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello");
+
+        let response = svc.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,241 @@
+//! The leaky bucket, selected with [`Algorithm::LeakyBucket`].
+//!
+//! Requests queue up to `max_tokens` deep, each adding its cost, and the
+//! queue drains continuously at `refill_rate` per `refill_interval`. A request
+//! that would overflow it is turned away. The bucket is stored like a token
+//! bucket, with `tokens` holding the queue's level and `last_updated` when it
+//! last drained.
+//!
+//! [`Algorithm::LeakyBucket`]: crate::Algorithm::LeakyBucket
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::{BucketConfig, BucketStatus, RateLimitDecision, TokenPersistence, later};
+
+impl TokenPersistence {
+    /// A queue at `level` as of `now`.
+    pub(crate) fn from_level(level: i64, now: DateTime<Utc>) -> Self {
+        Self {
+            tokens: level,
+            last_updated: now,
+            penalty: None,
+        }
+    }
+
+    /// Drains the queue up to `now`, returning its level and when it last
+    /// drained. Only the time that drained whole units is used up, so the
+    /// rest carries over to the next request.
+    fn drained(&self, config: &BucketConfig, now: DateTime<Utc>) -> (i64, DateTime<Utc>) {
+        let level = self.tokens.max(0);
+        // Like a token bucket, one from our future is left as it is.
+        let elapsed_ms = (now - self.last_updated).num_milliseconds().max(0);
+        if elapsed_ms >= drain_ms(config, level) {
+            return (0, now.max(self.last_updated));
+        }
+        let drained =
+            i128::from(elapsed_ms) * i128::from(rate(config)) / i128::from(interval_ms(config));
+        let drained = drained as i64;
+        (
+            level - drained,
+            after(self.last_updated, drain_ms(config, drained)),
+        )
+    }
+
+    pub(crate) fn leaky_take(
+        &self,
+        config: &BucketConfig,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> (RateLimitDecision, Option<TokenPersistence>) {
+        let (level, last_drained) = self.drained(config, now);
+        let queued = level.saturating_add(cost);
+
+        if queued > config.max_tokens {
+            let room_at = after(last_drained, drain_ms(config, queued - config.max_tokens));
+            let decision = RateLimitDecision {
+                allowed: false,
+                retry_after: Some((room_at - now).to_std().unwrap_or_default()),
+                ..decision(config, level, last_drained, now)
+            };
+            return (decision, None);
+        }
+        let updated = TokenPersistence {
+            tokens: queued,
+            last_updated: last_drained,
+            penalty: self.penalty.clone(),
+        };
+        (decision(config, queued, last_drained, now), Some(updated))
+    }
+
+    /// Takes `cost` back out of the queue, but never below empty.
+    pub(crate) fn leaky_refund(
+        &self,
+        config: &BucketConfig,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let (level, last_drained) = self.drained(config, now);
+        TokenPersistence {
+            tokens: level.saturating_sub(cost).max(0),
+            last_updated: last_drained,
+            penalty: self.penalty.clone(),
+        }
+    }
+
+    pub(crate) fn leaky_status(&self, config: &BucketConfig, now: DateTime<Utc>) -> BucketStatus {
+        let (level, last_drained) = self.drained(config, now);
+        BucketStatus {
+            tokens: (config.max_tokens - level).max(0),
+            last_updated: last_drained,
+            time_to_full: self.leaky_time_to_full(config, now),
+            banned_until: self.banned(config, now).map(|decision| decision.reset_at),
+        }
+    }
+
+    /// How long until the queue is empty, and the bucket as good as new.
+    pub(crate) fn leaky_time_to_full(&self, config: &BucketConfig, now: DateTime<Utc>) -> Duration {
+        let empty_at = after(self.last_updated, drain_ms(config, self.tokens.max(0)));
+        (empty_at - now).to_std().unwrap_or_default()
+    }
+}
+
+fn rate(config: &BucketConfig) -> i64 {
+    config.refill_rate.max(1)
+}
+
+fn interval_ms(config: &BucketConfig) -> i64 {
+    i64::try_from(config.refill_interval.as_millis())
+        .unwrap_or(i64::MAX)
+        .max(1)
+}
+
+/// How long the queue takes to drain `units`, rounded up to the millisecond.
+fn drain_ms(config: &BucketConfig, units: i64) -> i64 {
+    let rate = i128::from(rate(config));
+    let ms = (i128::from(units) * i128::from(interval_ms(config)) + rate - 1) / rate;
+    i64::try_from(ms).unwrap_or(i64::MAX)
+}
+
+fn after(at: DateTime<Utc>, ms: i64) -> DateTime<Utc> {
+    later(at, Duration::from_millis(ms.max(0) as u64))
+}
+
+/// Where a client whose queue stands at `level` is: how much more it could
+/// send right now, and when the queue next drains a unit.
+fn decision(
+    config: &BucketConfig,
+    level: i64,
+    last_drained: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> RateLimitDecision {
+    let reset_at = if level > 0 {
+        after(last_drained, drain_ms(config, 1))
+    } else {
+        now
+    };
+    RateLimitDecision {
+        allowed: true,
+        limit: config.max_tokens,
+        remaining: (config.max_tokens - level).max(0),
+        reset_at,
+        retry_after: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+
+    use crate::{Algorithm, BucketConfig, TokenPersistence};
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        rfc3339.parse().unwrap()
+    }
+
+    fn leaky(capacity: i64, rate: i64, per: Duration) -> BucketConfig {
+        BucketConfig {
+            max_tokens: capacity,
+            refill_rate: rate,
+            refill_interval: per,
+            algorithm: Algorithm::LeakyBucket,
+            ..BucketConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_drains_continuously() {
+        // Three a second, so a unit every 333⅓ms.
+        let config = leaky(10, 3, Duration::from_secs(1));
+        let start = at("2025-03-01T12:00:00Z");
+        let bucket = TokenPersistence::from_level(9, start);
+
+        let drained = |ms| bucket.drained(&config, start + chrono::Duration::milliseconds(ms));
+        assert_eq!(drained(0), (9, start));
+        assert_eq!(drained(333), (9, start));
+        assert_eq!(drained(334), (8, at("2025-03-01T12:00:00.334Z")));
+        // The two thirds of a millisecond left over carry on.
+        assert_eq!(drained(1000), (6, at("2025-03-01T12:00:01Z")));
+        assert_eq!(drained(2999), (1, at("2025-03-01T12:00:02.667Z")));
+        assert_eq!(drained(3000), (0, at("2025-03-01T12:00:03Z")));
+        assert_eq!(drained(60_000), (0, at("2025-03-01T12:01:00Z")));
+    }
+
+    #[test]
+    fn test_future_bucket_neither_drains_nor_moves_back() {
+        let config = leaky(10, 1, Duration::from_secs(1));
+        let bucket = TokenPersistence::from_level(4, at("2025-03-01T12:00:10Z"));
+
+        assert_eq!(
+            bucket.drained(&config, at("2025-03-01T12:00:00Z")),
+            (4, at("2025-03-01T12:00:10Z"))
+        );
+    }
+
+    #[test]
+    fn test_rejects_what_would_overflow_the_queue() {
+        let config = leaky(5, 1, Duration::from_secs(2));
+        let now = at("2025-03-01T12:00:00Z");
+        let bucket = TokenPersistence::from_level(3, now);
+
+        let (decision, updated) = bucket.charge(&config, 2, now);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.reset_at, at("2025-03-01T12:00:02Z"));
+        let full = updated.unwrap();
+        assert_eq!(full.tokens, 5);
+
+        // Three over capacity, drained two seconds apiece.
+        let (denied, none) = full.charge(&config, 3, now);
+        assert!(!denied.allowed);
+        assert!(none.is_none());
+        assert_eq!(denied.remaining, 0);
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(6)));
+
+        let later = at("2025-03-01T12:00:05Z");
+        let (denied, _) = full.charge(&config, 3, later);
+        assert_eq!(denied.remaining, 2);
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(1)));
+        assert!(
+            full.charge(&config, 3, at("2025-03-01T12:00:06Z"))
+                .0
+                .allowed
+        );
+    }
+
+    #[test]
+    fn test_empty_queue_is_as_good_as_new() {
+        let config = leaky(5, 1, Duration::from_secs(2));
+        let now = at("2025-03-01T12:00:00Z");
+        let bucket = TokenPersistence::from_level(3, now);
+
+        assert_eq!(bucket.time_to_full(&config, now), Duration::from_secs(6));
+        let status = bucket.status(&config, at("2025-03-01T12:00:03Z"));
+        assert_eq!(status.tokens, 3);
+        assert_eq!(status.time_to_full, Duration::from_secs(3));
+        assert_eq!(TokenPersistence::new(&config, now).tokens, 0);
+    }
+}
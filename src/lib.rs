@@ -1,293 +1,749 @@
-use std::sync::Arc;
-
-use axum::{
-    body::Body,
-    extract::{Request, State},
-    http::StatusCode,
-    middleware::Next,
-    response::Response,
-};
+// Without a store, the bucket math is only there for one to be added.
+#![cfg_attr(not(any(feature = "memory", feature = "redis")), allow(dead_code))]
+
+use std::{error::Error, fmt, future::Future, pin::Pin, time::Duration};
+
 use chrono::Utc;
-use redis::{ConnectionLike, FromRedisValue, RedisError, ToRedisArgs};
+use refill::refill;
 use serde_derive::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use tokio::sync::Mutex;
-
-fn generate_bucket_key(ip: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(ip.as_bytes());
-    let hash_result = hasher.finalize();
-    format!("bucket:{:x}", hash_result)
-}
 
-#[derive(Serialize, Deserialize, Debug)]
+#[cfg(feature = "axum")]
+mod admin;
+#[cfg(feature = "axum")]
+mod allowlist;
+#[cfg(feature = "axum")]
+mod batch;
+#[doc(hidden)]
+pub mod bench;
+mod blocklist;
+#[cfg(feature = "axum")]
+mod body_cost;
+#[cfg(feature = "axum")]
+mod breaker;
+#[cfg(feature = "config")]
+mod builder;
+#[cfg(feature = "redis")]
+mod cleanup;
+#[cfg(feature = "axum")]
+mod client_ip;
+mod clock;
+#[cfg(feature = "config")]
+mod config;
+#[cfg(feature = "axum")]
+mod denials;
+mod encoding;
+#[cfg(feature = "axum")]
+mod exempt;
+#[cfg(feature = "axum")]
+mod extract;
+#[cfg(feature = "axum")]
+mod fallback;
+mod gcra;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "axum")]
+mod headers;
+#[cfg(feature = "axum")]
+mod health;
+#[cfg(feature = "axum")]
+mod hooks;
+#[cfg(feature = "axum")]
+mod in_flight;
+#[cfg(feature = "axum")]
+mod info;
+#[cfg(all(test, feature = "axum", feature = "redis"))]
+mod it;
+#[cfg(feature = "jwt")]
+mod jwt;
+mod key_hash;
+#[cfg(feature = "axum")]
+mod layer;
+mod leaky;
+#[cfg(feature = "axum")]
+mod middleware;
+mod millis;
+#[cfg(feature = "axum")]
+mod overrides;
+#[cfg(feature = "redis")]
+mod pool;
+mod prefix;
+#[cfg(feature = "axum")]
+mod problem;
+#[cfg(feature = "axum")]
+mod prometheus;
+mod quota;
+#[cfg(feature = "redis")]
+mod reconnect;
+mod refill;
+#[cfg(feature = "axum")]
+mod refund;
+#[cfg(feature = "axum")]
+mod router;
+#[cfg(feature = "axum")]
+mod shutdown;
+mod store;
+#[cfg(feature = "axum")]
+mod telemetry;
+pub mod testing;
+mod timestamp;
+
+#[cfg(feature = "axum")]
+pub use admin::admin_router;
+#[cfg(feature = "axum")]
+pub use allowlist::Allowlist;
+#[cfg(feature = "axum")]
+pub use batch::BatchCost;
+pub use blocklist::Blocklist;
+#[cfg(feature = "axum")]
+pub use body_cost::{BodyCost, MissingLength};
+#[cfg(feature = "axum")]
+pub use breaker::{BreakerState, CircuitBreaker, CircuitBreakerConfig};
+#[cfg(feature = "config")]
+pub use builder::{BuildError, RateLimiter, RateLimiterBuilder};
+#[cfg(feature = "redis")]
+pub use cleanup::cleanup_stale_buckets;
+#[cfg(feature = "axum")]
+pub use client_ip::{Cidr, ParseCidrError, TrustedProxies};
+pub use clock::{Clock, SystemClock};
+#[cfg(feature = "config")]
+pub use config::{Config, ConfigError, EnvError, MissingKey, Rule};
+#[cfg(feature = "axum")]
+pub use exempt::ExemptPaths;
+#[cfg(feature = "axum")]
+pub use extract::{
+    BearerTokenExtractor, HeaderKeyExtractor, KeyExtractor, MAX_TOKEN_HEADER_LEN,
+    MissingTokenPolicy, PeerIpExtractor,
+};
+#[cfg(feature = "axum")]
+pub use fallback::LocalFallback;
+#[cfg(feature = "grpc")]
+pub use grpc::{GrpcKeyExtractor, GrpcRateLimiterLayer};
+#[cfg(feature = "axum")]
+pub use headers::{HeaderStyle, LimitScope};
+#[cfg(feature = "axum")]
+pub use health::health_router;
+#[cfg(feature = "axum")]
+pub use hooks::{DecisionCtx, HookDispatch, RateLimitHooks};
+#[cfg(feature = "axum")]
+pub use info::RateLimitInfo;
+#[cfg(feature = "jwt")]
+pub use jwt::{InvalidJwt, JwtClaimExtractor};
+pub use key_hash::{InvalidHashLen, KeyHasher, MIN_KEY_HASH_LEN, Secret};
+#[cfg(feature = "axum")]
+pub use layer::{RateLimiterLayer, RateLimiterService};
+#[cfg(feature = "axum")]
+pub use middleware::{
+    AppState, ConsumeError, GLOBAL_BUCKET_KEY, Grant, IpLimit, MAX_IDEMPOTENCY_KEY_LEN,
+    RequestCost, Verdict, default_blocked, default_rejection, rate_limiter_middleware,
+};
+#[cfg(feature = "redis")]
+pub use pool::ConnectionPool;
+pub use prefix::{InvalidKeyPrefix, KeyPrefix};
+#[cfg(feature = "axum")]
+pub use problem::{PROBLEM_JSON, ProblemDetails, problem_rejection, quota_rejection};
+#[cfg(feature = "axum")]
+pub use prometheus::metrics_router;
+#[cfg(feature = "redis")]
+pub use reconnect::{BlockingReconnectingConnection, ReconnectingConnection};
+#[cfg(feature = "axum")]
+pub use refund::Refunds;
+#[cfg(feature = "axum")]
+pub use router::RateLimitedRouterExt;
+#[cfg(feature = "axum")]
+pub use shutdown::{serve_with_shutdown, shutdown_signal};
+#[cfg(feature = "memory")]
+pub use store::MemoryStore;
+#[cfg(feature = "redis")]
+pub use store::{
+    AsyncRedisStore, DEFAULT_REDIS_TIMEOUT, DenialLog, Leaderboard, RedisStore, TransactionRetry,
+};
+pub use store::{
+    BucketStore, CachedStore, DEFAULT_FLUSH_INTERVAL, DEFAULT_MAX_DRIFT, ShardedStore,
+    StorageFormat, StoreError, Tiered,
+};
+#[cfg(feature = "axum")]
+pub use telemetry::{OutcomeCounts, Stats, StatsSnapshot};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// How far in the future a stored bucket may be before it's worth logging
+/// about clock skew.
+const SKEW_WARNING_MS: i64 = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 struct TokenPersistence {
     tokens: i64,
+    #[serde(with = "timestamp")]
     last_updated: chrono::DateTime<Utc>,
+    /// Only there once the client has been denied under a [`PenaltyConfig`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    penalty: Option<Penalty>,
 }
 
-enum TokenPersistenceReturn {
-    Okay,
-    Nil,
-    Token(TokenPersistence),
+/// A client's record of denials, kept with its bucket.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+struct Penalty {
+    /// Denials since `since`.
+    violations: u32,
+    #[serde(with = "timestamp")]
+    since: chrono::DateTime<Utc>,
+    /// Bans so far, which makes the next one longer.
+    bans: u32,
+    #[serde(with = "timestamp")]
+    banned_until: chrono::DateTime<Utc>,
 }
 
-impl FromRedisValue for TokenPersistenceReturn {
-    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
-        match v {
-            redis::Value::Array(v) if v.len() == 1 => {
-                TokenPersistenceReturn::from_redis_value(&v[0])
+impl TokenPersistence {
+    fn new(config: &BucketConfig, now: chrono::DateTime<Utc>) -> Self {
+        Self::holding(config, config.max_tokens, now)
+    }
+
+    /// A bucket holding `tokens` as of `now`.
+    fn holding(config: &BucketConfig, tokens: i64, now: chrono::DateTime<Utc>) -> Self {
+        match config.algorithm {
+            Algorithm::TokenBucket => Self {
+                tokens,
+                last_updated: now,
+                penalty: None,
+            },
+            Algorithm::Gcra => {
+                let missing = (config.max_tokens - tokens).max(0);
+                let ahead = gcra::emission_interval_ms(config).saturating_mul(missing);
+                Self::from_tat(now + chrono::Duration::milliseconds(ahead))
             }
-            redis::Value::BulkString(v) => Ok(TokenPersistenceReturn::Token(
-                serde_json::from_slice(v).unwrap(),
-            )),
-            redis::Value::Nil => Ok(Self::Nil),
-            redis::Value::Okay => Ok(Self::Okay),
-            _ => unreachable!(),
+            Algorithm::LeakyBucket => Self::from_level(config.max_tokens - tokens, now),
+            Algorithm::FixedWindow { .. } => Self::from_window(config, tokens, now),
         }
     }
-}
 
-impl ToRedisArgs for TokenPersistence {
-    fn write_redis_args<W>(&self, out: &mut W)
-    where
-        W: ?Sized + redis::RedisWrite,
-    {
-        out.write_arg(serde_json::to_string(self).unwrap().as_bytes())
+    /// Refills the bucket up to `now` and takes `cost` tokens out of it.
+    ///
+    /// Returns the decision and, if anything changed, the state to store:
+    /// always when the request is allowed, and when it's denied only under a
+    /// [`PenaltyConfig`], to count the violation.
+    fn charge(
+        &self,
+        config: &BucketConfig,
+        cost: i64,
+        now: chrono::DateTime<Utc>,
+    ) -> (RateLimitDecision, Option<TokenPersistence>) {
+        if config.algorithm == Algorithm::Gcra {
+            return self.gcra_take(config, cost, now);
+        }
+        if let Some(decision) = self.banned(config, now) {
+            return (decision, None);
+        }
+        match (&config.penalty, self.take(config, cost, now)) {
+            (Some(penalty), (decision, None)) => self.violate(config, penalty, decision, now),
+            (_, charged) => charged,
+        }
     }
-}
 
-impl TokenPersistence {
-    fn new() -> Self {
-        Self {
-            tokens: 10,
-            last_updated: Utc::now(),
-        }
+    /// The decision for a client that's serving a ban, if it is.
+    fn banned(
+        &self,
+        config: &BucketConfig,
+        now: chrono::DateTime<Utc>,
+    ) -> Option<RateLimitDecision> {
+        config.penalty.as_ref()?;
+        let banned_until = self.penalty.as_ref()?.banned_until;
+        (now < banned_until).then(|| RateLimitDecision {
+            allowed: false,
+            limit: config.max_tokens,
+            remaining: 0,
+            reset_at: banned_until,
+            retry_after: Some((banned_until - now).to_std().unwrap_or_default()),
+        })
     }
-}
 
-pub struct AppState<C>
-where
-    C: ConnectionLike + Send + Sync + 'static,
-{
-    pub redis_conn: Arc<Mutex<C>>,
-}
+    /// Counts a denial against the client, banning them once they've had
+    /// too many. The bucket itself is stored as it was.
+    fn violate(
+        &self,
+        config: &BucketConfig,
+        penalty_config: &PenaltyConfig,
+        decision: RateLimitDecision,
+        now: chrono::DateTime<Utc>,
+    ) -> (RateLimitDecision, Option<TokenPersistence>) {
+        let mut penalty = self.penalty.clone().unwrap_or_default();
+        let window =
+            chrono::Duration::from_std(penalty_config.window).unwrap_or(chrono::Duration::MAX);
+        if penalty.violations == 0 || now.signed_duration_since(penalty.since) >= window {
+            penalty.violations = 0;
+            penalty.since = now;
+        }
+        penalty.violations += 1;
 
-impl<C> Clone for AppState<C>
-where
-    C: ConnectionLike + Send + Sync + 'static,
-{
-    fn clone(&self) -> Self {
-        Self {
-            redis_conn: Arc::clone(&self.redis_conn),
+        if penalty.violations >= penalty_config.violations {
+            let ban = penalty_config.ban_after(penalty.bans);
+            penalty.violations = 0;
+            penalty.bans = penalty.bans.saturating_add(1);
+            penalty.banned_until = later(now, ban);
         }
+
+        let updated = TokenPersistence {
+            tokens: self.tokens,
+            last_updated: self.last_updated,
+            penalty: Some(penalty),
+        };
+        let decision = updated.banned(config, now).unwrap_or(decision);
+        (decision, Some(updated))
     }
-}
 
-pub async fn rate_limiter_middleware<C>(
-    State(state): State<AppState<C>>,
-    request: Request,
-    next: Next,
-) -> Response
-where
-    C: ConnectionLike + Send + Sync + 'static,
-{
-    let bearer_token = match &request.headers().get("Bearer") {
-        Some(t) => t.to_str().unwrap(),
-        None => {
-            return Response::builder()
-                .status(StatusCode::UNAUTHORIZED)
-                .body(Body::empty())
-                .unwrap();
+    /// Charges `cost` to each bucket under its config, all or nothing: unless
+    /// every one of them allows it, none of them is charged, though denials
+    /// still count against clients under a [`PenaltyConfig`].
+    ///
+    /// Each decision is the bucket's own, so a bucket that would have allowed
+    /// the charge says so even when another denied it.
+    fn charge_all<'a>(
+        buckets: impl IntoIterator<Item = (&'a TokenPersistence, &'a BucketConfig)>,
+        cost: i64,
+        now: chrono::DateTime<Utc>,
+    ) -> Vec<(RateLimitDecision, Option<TokenPersistence>)> {
+        let mut charged = buckets
+            .into_iter()
+            .map(|(bucket, config)| bucket.charge(config, cost, now))
+            .collect::<Vec<_>>();
+        if !charged.iter().all(|(decision, _)| decision.allowed) {
+            for (decision, updated) in &mut charged {
+                if decision.allowed {
+                    *updated = None;
+                }
+            }
         }
-    };
-
-    let redis_key = generate_bucket_key(bearer_token);
+        charged
+    }
 
-    let mut conn = state.redis_conn.lock().await;
+    /// [`charge`](Self::charge) without the penalties.
+    fn take(
+        &self,
+        config: &BucketConfig,
+        cost: i64,
+        now: chrono::DateTime<Utc>,
+    ) -> (RateLimitDecision, Option<TokenPersistence>) {
+        match config.algorithm {
+            Algorithm::TokenBucket => {}
+            Algorithm::Gcra => return self.gcra_take(config, cost, now),
+            Algorithm::LeakyBucket => return self.leaky_take(config, cost, now),
+            Algorithm::FixedWindow { .. } => return self.window_take(config, cost, now),
+        }
+        let elapsed_ms = now
+            .signed_duration_since(self.last_updated)
+            .num_milliseconds();
+        // A bucket last charged in our future means our clock is behind
+        // whoever wrote it. That's no reason to take tokens away, or to move
+        // its timestamp back.
+        if elapsed_ms < -SKEW_WARNING_MS {
+            tracing::warn!(
+                skew_ms = -elapsed_ms,
+                "bucket was last charged in the future"
+            );
+        }
+        let interval_ms = config.refill_interval.as_millis().max(1) as i64;
+        let (tokens_available, last_updated) = refill(self.tokens, self.last_updated, now, config);
+
+        // Under an overdraft a charge may take the bucket that far below
+        // zero, but only from a positive balance: debt is paid back before
+        // anything else is let through.
+        let needed = cost
+            .saturating_sub(config.overdraft.max(0))
+            .max(cost.min(1));
+        if tokens_available < needed {
+            let missing_intervals = needed
+                .saturating_sub(tokens_available)
+                .saturating_add(config.refill_rate - 1)
+                / config.refill_rate.max(1);
+            let wait =
+                chrono::Duration::milliseconds(missing_intervals.saturating_mul(interval_ms));
+            // A charge that could never fit waits as long as there is.
+            let available_at = last_updated
+                .checked_add_signed(wait)
+                .unwrap_or(chrono::DateTime::<Utc>::MAX_UTC);
+
+            let decision = RateLimitDecision {
+                allowed: false,
+                limit: config.max_tokens,
+                remaining: tokens_available.max(0),
+                reset_at: last_updated + chrono::Duration::milliseconds(interval_ms),
+                retry_after: Some((available_at - now).to_std().unwrap_or_default()),
+            };
+            return (decision, None);
+        }
 
-    let transaction = redis::transaction(&mut *conn, &[&redis_key], |con, pipe| {
-        let token_model_result = pipe
-            .get(&redis_key)
-            .query(con)
-            .unwrap_or(TokenPersistenceReturn::Token(TokenPersistence::new()));
+        // Never more than full, whatever `cost` is.
+        let updated_tokens = (tokens_available - cost).min(config.max_tokens);
 
-        let token_model = match token_model_result {
-            TokenPersistenceReturn::Token(tp) => tp,
-            _ => TokenPersistence::new(),
+        let reset_at = if updated_tokens >= config.max_tokens {
+            now
+        } else {
+            last_updated + chrono::Duration::milliseconds(interval_ms)
         };
 
-        let last_updated = token_model.last_updated;
+        let decision = RateLimitDecision {
+            allowed: true,
+            limit: config.max_tokens,
+            remaining: updated_tokens.max(0),
+            reset_at,
+            retry_after: None,
+        };
+        let updated = TokenPersistence {
+            last_updated,
+            tokens: updated_tokens,
+            penalty: self.penalty.clone(),
+        };
+        (decision, Some(updated))
+    }
 
-        let now = Utc::now();
+    /// Puts `cost` tokens back into the bucket as of `now`, for a request
+    /// that shouldn't have been charged. However much it refilled since,
+    /// it never ends up more than full.
+    fn refund(&self, config: &BucketConfig, cost: i64, now: chrono::DateTime<Utc>) -> Self {
+        match config.algorithm {
+            Algorithm::TokenBucket => {}
+            Algorithm::Gcra => return self.gcra_refund(config, cost, now),
+            Algorithm::LeakyBucket => return self.leaky_refund(config, cost, now),
+            Algorithm::FixedWindow { .. } => return self.window_refund(config, cost, now),
+        }
+        let (tokens, last_updated) = refill(self.tokens, self.last_updated, now, config);
+        let tokens = tokens.saturating_add(cost);
+        // Refilled to the brim, like any bucket that's full.
+        let (tokens, last_updated) = if tokens >= config.max_tokens {
+            (config.max_tokens, now.max(last_updated))
+        } else {
+            (tokens, last_updated)
+        };
+        TokenPersistence {
+            tokens,
+            last_updated,
+            penalty: self.penalty.clone(),
+        }
+    }
 
-        let max_tokens = 10;
-        let refill_rate_per_hour = 1;
+    /// What [`charge`](Self::charge) would decide for one token, with
+    /// `remaining` counting the token as still in the bucket.
+    fn peek(&self, config: &BucketConfig, now: chrono::DateTime<Utc>) -> RateLimitDecision {
+        if let Some(decision) = self.banned(config, now) {
+            return decision;
+        }
+        let (mut decision, _) = self.take(config, 1, now);
+        if decision.allowed {
+            decision.remaining += 1;
+        }
+        if decision.remaining >= config.max_tokens {
+            decision.reset_at = now;
+        }
+        decision
+    }
 
-        let elapsed_hours = now.signed_duration_since(last_updated).num_hours();
+    fn status(&self, config: &BucketConfig, now: chrono::DateTime<Utc>) -> BucketStatus {
+        match config.algorithm {
+            Algorithm::TokenBucket => {}
+            Algorithm::Gcra => return self.gcra_status(config, now),
+            Algorithm::LeakyBucket => return self.leaky_status(config, now),
+            Algorithm::FixedWindow { .. } => return self.window_status(config, now),
+        }
+        BucketStatus {
+            tokens: self.tokens,
+            last_updated: self.last_updated,
+            time_to_full: self.time_to_full(config, now),
+            banned_until: self.banned(config, now).map(|decision| decision.reset_at),
+        }
+    }
 
-        let tokens_available =
-            (token_model.tokens + elapsed_hours * refill_rate_per_hour).min(max_tokens);
+    /// How long a store should keep the bucket: until it's full, and the
+    /// client's record, if any, no longer counts against them.
+    fn expires_in(&self, config: &BucketConfig, now: chrono::DateTime<Utc>) -> Duration {
+        let remembered_for = self.penalty.as_ref().map_or(Duration::ZERO, |penalty| {
+            let (window, max_ban) = config
+                .penalty
+                .as_ref()
+                .map_or((Duration::ZERO, Duration::ZERO), |p| (p.window, p.max_ban));
+            let mut until = penalty.banned_until.max(later(penalty.since, window));
+            if penalty.bans > 0 {
+                until = until.max(later(penalty.banned_until, max_ban));
+            }
+            (until - now).to_std().unwrap_or_default()
+        });
+        self.time_to_full(config, now).max(remembered_for)
+    }
 
-        if tokens_available < 1 {
-            return Err(RedisError::from((
-                redis::ErrorKind::ClientError,
-                "Too many requests",
-            )));
+    /// How long until the bucket has refilled to `max_tokens`. From then on
+    /// it's indistinguishable from a missing one, so a store can drop it.
+    fn time_to_full(&self, config: &BucketConfig, now: chrono::DateTime<Utc>) -> Duration {
+        match config.algorithm {
+            Algorithm::TokenBucket => {}
+            Algorithm::Gcra => return self.gcra_time_to_full(now),
+            Algorithm::LeakyBucket => return self.leaky_time_to_full(config, now),
+            Algorithm::FixedWindow { .. } => return self.window_time_to_full(config, now),
         }
+        let missing = (config.max_tokens - self.tokens).max(0);
+        let rate = config.refill_rate.max(1);
+        let interval_ms = config.refill_interval.as_millis().max(1) as i64;
+        let full_at = self.last_updated
+            + chrono::Duration::milliseconds((missing + rate - 1) / rate * interval_ms);
+        (full_at - now).to_std().unwrap_or_default()
+    }
+}
 
-        let updated_tokens = (tokens_available - 1).max(0);
+/// `by` after `at`, or the end of time if that's past it.
+fn later(at: chrono::DateTime<Utc>, by: Duration) -> chrono::DateTime<Utc> {
+    chrono::Duration::from_std(by)
+        .ok()
+        .and_then(|by| at.checked_add_signed(by))
+        .unwrap_or(chrono::DateTime::<Utc>::MAX_UTC)
+}
 
-        let updated_token_model = TokenPersistence {
-            last_updated: now,
-            tokens: updated_tokens,
-        };
+/// Capacity and refill settings for a bucket.
+///
+/// Every `refill_interval` that elapses puts `refill_rate` tokens back into the
+/// bucket, up to `max_tokens`. Partial intervals don't refill anything.
+///
+/// `max_tokens` is the burst a client that's been idle long enough can
+/// spend at once; the refill is the rate it settles at after that. See
+/// [`burst`](Self::burst) to set them apart.
+///
+/// Serialized with its durations in milliseconds, as `refill_interval_ms`
+/// and so on. Fields left out take their default.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BucketConfig {
+    pub max_tokens: i64,
+    pub refill_rate: i64,
+    #[serde(rename = "refill_interval_ms", with = "millis")]
+    pub refill_interval: Duration,
+    /// Bans clients that keep going after being denied. None by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub penalty: Option<PenaltyConfig>,
+    pub algorithm: Algorithm,
+    /// How far below zero a charge may take a token bucket, as a soft
+    /// limit. Only a client with at least one token can overdraw, so debt
+    /// is paid back by the refill before anything else is let through.
+    /// Zero by default.
+    pub overdraft: i64,
+}
 
-        let _ = pipe
-            .set(&redis_key, updated_token_model)
-            .ignore()
-            .query::<TokenPersistenceReturn>(con);
+/// How a bucket decides whether a request fits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    /// Stores the tokens left and tops them up a whole interval at a time.
+    #[default]
+    TokenBucket,
+    /// The generic cell rate algorithm: stores only when the client would
+    /// have its whole burst back, and paces requests one
+    /// `refill_interval / refill_rate` apart once the burst is spent, with a
+    /// retry-after to the millisecond. Penalties don't apply.
+    Gcra,
+    /// A queue `max_tokens` deep that each request adds its cost to, drained
+    /// continuously at `refill_rate` per `refill_interval`. A request is
+    /// denied when it would overflow the queue, until enough has drained.
+    LeakyBucket,
+    /// Up to `max_tokens` per window `refill_interval` long, all of them back
+    /// at once when the next window starts. Windows are aligned to the Unix
+    /// epoch, moved along by `offset`, so daily ones start at midnight UTC
+    /// with none. `refill_rate` doesn't apply.
+    FixedWindow {
+        #[serde(default, rename = "offset_ms", with = "millis")]
+        offset: Duration,
+    },
+}
 
-        Ok(Some(()))
-    });
+impl Default for BucketConfig {
+    fn default() -> Self {
+        Self {
+            max_tokens: 10,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(60 * 60),
+            penalty: None,
+            algorithm: Algorithm::TokenBucket,
+            overdraft: 0,
+        }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
 
-    dbg!(&transaction);
+/// Escalation for clients that keep hammering their bucket after being
+/// denied.
+///
+/// `violations` denials within `window` get the client banned: until the ban
+/// is over every request is denied, however full the bucket, and none of
+/// them count as further violations. The first ban lasts `ban`, and every
+/// one after it twice as long as the one before, up to `max_ban`. A ban is
+/// remembered for `max_ban` after it's over, so a client that stays away that
+/// long, and until its bucket is full again, starts over with a clean slate.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PenaltyConfig {
+    pub violations: u32,
+    #[serde(rename = "window_ms", with = "millis")]
+    pub window: Duration,
+    #[serde(rename = "ban_ms", with = "millis")]
+    pub ban: Duration,
+    #[serde(rename = "max_ban_ms", with = "millis")]
+    pub max_ban: Duration,
+}
 
-    match transaction {
-        Err(e) => {
-            return Response::builder()
-                .status(StatusCode::TOO_MANY_REQUESTS)
-                .body(Body::empty())
-                .unwrap();
+impl Default for PenaltyConfig {
+    fn default() -> Self {
+        Self {
+            violations: 10,
+            window: Duration::from_secs(60),
+            ban: Duration::from_secs(5 * 60),
+            max_ban: Duration::from_secs(60 * 60),
         }
-        _ => {}
     }
+}
 
-    let response = next.run(request).await;
-
-    response
+impl PenaltyConfig {
+    /// How long the ban after `bans` earlier ones lasts.
+    fn ban_after(&self, bans: u32) -> Duration {
+        self.ban
+            .saturating_mul(2u32.saturating_pow(bans))
+            .min(self.max_ban)
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::sync::Arc;
-
-    use axum::{
-        body::Body,
-        http::{Request, Response, StatusCode},
-        middleware,
-    };
-    use chrono::Utc;
-    use redis::{Value, cmd};
-    use redis_test::{MockCmd, MockRedisConnection};
-    use tokio::sync::Mutex;
-    use tower::{ServiceBuilder, ServiceExt};
-
-    use crate::{AppState, TokenPersistence, generate_bucket_key, rate_limiter_middleware};
-
-    #[tokio::test]
-    async fn test_rate_limiter_allows_request_via_servicebuilder() {
-        let starting = TokenPersistence::new();
-        let json = serde_json::to_string(&starting).unwrap();
-
-        let mock = MockRedisConnection::new(vec![
-            MockCmd::new(
-                cmd("WATCH").arg(generate_bucket_key("127.0.0.1")),
-                Ok(Value::Okay),
-            ),
-            MockCmd::new(cmd("MULTI"), Ok(Value::Okay)),
-            MockCmd::new(
-                cmd("GET").arg(generate_bucket_key("127.0.0.1")),
-                Ok(Value::Nil),
-            ),
-            MockCmd::new(cmd("UNWATCH"), Ok(Value::Okay)),
-            MockCmd::new(
-                cmd("SET")
-                    .arg(generate_bucket_key("127.0.0.1"))
-                    .arg(json.clone().to_string()),
-                Ok(Value::Okay),
-            ),
-        ]);
-        let state = AppState {
-            redis_conn: Arc::new(Mutex::new(mock.clone())),
-        };
+impl BucketConfig {
+    /// A bucket that lets an idle client spend `burst` tokens at once, then
+    /// gives back `sustained` tokens every `per`, e.g. bursts of 20 settling
+    /// at 10 an hour.
+    ///
+    /// Tokens are put back as evenly as whole milliseconds allow, one every
+    /// six minutes for 10 an hour, rather than all at once at the end of
+    /// `per`. The default config is 10 at 1 an hour.
+    pub fn burst(burst: i64, sustained: i64, per: Duration) -> Self {
+        let per_ms = u64::try_from(per.as_millis()).unwrap_or(u64::MAX).max(1);
+        let sustained = sustained.max(1);
+        let common = gcd(per_ms, sustained as u64);
+        Self {
+            max_tokens: burst,
+            refill_rate: sustained / common as i64,
+            refill_interval: Duration::from_millis(per_ms / common),
+            penalty: None,
+            algorithm: Algorithm::TokenBucket,
+            overdraft: 0,
+        }
+    }
 
-        let inner = tower::service_fn(|_req: Request<Body>| async {
-            Ok::<_, std::convert::Infallible>(
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-        });
+    /// A quota of `limit` a day, starting over at midnight UTC.
+    pub fn daily(limit: i64) -> Self {
+        Self {
+            max_tokens: limit,
+            refill_rate: limit,
+            refill_interval: Duration::from_secs(24 * 60 * 60),
+            algorithm: Algorithm::FixedWindow {
+                offset: Duration::ZERO,
+            },
+            ..Self::default()
+        }
+    }
 
-        let svc = ServiceBuilder::new()
-            .layer(middleware::from_fn_with_state(
-                state.clone(),
-                rate_limiter_middleware::<MockRedisConnection>,
-            ))
-            .service(inner);
-
-        let response = svc
-            .oneshot(
-                Request::builder()
-                    .header("Bearer", "127.0.0.1")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::OK);
+    /// How long an empty bucket takes to fill up again. Past this, a stored
+    /// bucket is indistinguishable from a new one.
+    pub fn full_refill(&self) -> Duration {
+        if let Algorithm::FixedWindow { .. } = self.algorithm {
+            return self.refill_interval;
+        }
+        let rate = self.refill_rate.max(1);
+        let intervals = (self.max_tokens.max(0) + rate - 1) / rate;
+        self.refill_interval
+            .saturating_mul(u32::try_from(intervals).unwrap_or(u32::MAX))
     }
+}
 
-    #[tokio::test]
-    async fn test_rate_limiter_denies_request() {
-        let mut starting = TokenPersistence::new();
-        starting.tokens = 0;
-        let json = serde_json::to_string(&starting).unwrap();
-
-        let mock = MockRedisConnection::new(vec![
-            MockCmd::new(
-                cmd("WATCH").arg(generate_bucket_key("127.0.0.1")),
-                Ok(Value::Okay),
-            ),
-            MockCmd::new(cmd("MULTI"), Ok(Value::Okay)),
-            MockCmd::new(
-                cmd("GET").arg(generate_bucket_key("127.0.0.1")),
-                Ok(json.clone().to_string()),
-            ),
-        ]);
-        let state = AppState {
-            redis_conn: Arc::new(Mutex::new(mock.clone())),
-        };
+impl BucketConfig {
+    /// Whether the bucket could ever let anything through and refill.
+    pub fn validate(&self) -> Result<(), InvalidBucket> {
+        if self.max_tokens <= 0 {
+            return Err(InvalidBucket::MaxTokens(self.max_tokens));
+        }
+        if self.refill_rate <= 0 {
+            return Err(InvalidBucket::RefillRate(self.refill_rate));
+        }
+        if self.refill_interval.is_zero() {
+            return Err(InvalidBucket::RefillInterval);
+        }
+        if self.overdraft < 0 {
+            return Err(InvalidBucket::Overdraft(self.overdraft));
+        }
+        Ok(())
+    }
+}
 
-        let inner = tower::service_fn(|_req: Request<Body>| async {
-            Ok::<_, std::convert::Infallible>(
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-        });
+/// What's wrong with a [`BucketConfig`], see [`BucketConfig::validate`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidBucket {
+    MaxTokens(i64),
+    RefillRate(i64),
+    RefillInterval,
+    Overdraft(i64),
+}
 
-        let svc = ServiceBuilder::new()
-            .layer(middleware::from_fn_with_state(
-                state.clone(),
-                rate_limiter_middleware::<MockRedisConnection>,
-            ))
-            .service(inner);
-
-        let response = svc
-            .oneshot(
-                Request::builder()
-                    .header("Bearer", "127.0.0.1")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-
-        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+impl fmt::Display for InvalidBucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MaxTokens(n) => write!(f, "max_tokens must be positive, not {n}"),
+            Self::RefillRate(n) => write!(f, "refill_rate must be positive, not {n}"),
+            Self::RefillInterval => f.write_str("refill_interval_ms must be positive"),
+            Self::Overdraft(n) => write!(f, "overdraft can't be negative, not {n}"),
+        }
     }
 }
+
+impl Error for InvalidBucket {}
+
+/// Outcome of charging a bucket, as computed by the [`BucketStore`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: i64,
+    pub remaining: i64,
+    /// When the next token is put back, or now if the bucket is already full.
+    pub reset_at: chrono::DateTime<Utc>,
+    /// How long a denied request has to wait until enough tokens are back.
+    pub retry_after: Option<Duration>,
+}
+
+/// A bucket as it's stored, for looking into why a client is being limited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BucketStatus {
+    /// Tokens left as of `last_updated`, before anything refilled since.
+    pub tokens: i64,
+    pub last_updated: chrono::DateTime<Utc>,
+    /// How long until the bucket is full again, at which point it's as good
+    /// as gone.
+    pub time_to_full: Duration,
+    /// Until when the client is banned, if they are; see [`PenaltyConfig`].
+    pub banned_until: Option<chrono::DateTime<Utc>>,
+}
+
+/// What to do with requests when the store can't be reached or errors out.
+///
+/// This only covers infrastructure failures; an empty bucket is always a 429.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Let requests through unlimited.
+    Open,
+    /// Answer 503 Service Unavailable with a `Retry-After` of one second.
+    #[default]
+    Closed,
+}
+
+/// Whether denials are acted on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Answer denied requests with the rejection response.
+    #[default]
+    Enforce,
+    /// Charge buckets as usual but let every request through, marking the
+    /// ones that would have been denied with `X-RateLimit-Would-Block: true`
+    /// and logging them. For seeing what limits would do before turning them
+    /// on.
+    Shadow,
+}
@@ -1,31 +1,213 @@
-use std::{env, sync::Arc};
+use std::{env, fmt, process, sync::Arc, time::Duration};
 
-use axum::{Router, middleware, routing::get};
-use leaky_bucket::{AppState, rate_limiter_middleware};
+use axum::{Router, routing::get};
+use leaky_bucket::{
+    AppState, AsyncRedisStore, BucketStore, Config, ConnectionPool, RateLimiterLayer,
+    ReconnectingConnection, ShardedStore, cleanup_stale_buckets, health_router, metrics_router,
+    serve_with_shutdown, shutdown_signal,
+};
+use redis::{
+    AsyncConnectionConfig,
+    aio::MultiplexedConnection,
+    cluster::ClusterClient,
+    sentinel::{SentinelClient, SentinelServerType},
+};
 use tokio::sync::Mutex;
 
+/// The limits used without a CONFIG_PATH.
+const DEFAULT_CONFIG: &str = include_str!("../leaky-bucket.toml");
+
 #[tokio::main]
 async fn main() {
-    let redis_host = env::var("REDIS_HOST").unwrap_or("redis://localhost:6379".to_string());
+    tracing_subscriber::fmt::init();
+
+    let config = match env::var("CONFIG_PATH") {
+        Ok(path) => Config::from_path(&path),
+        Err(_) => DEFAULT_CONFIG.parse(),
+    };
+    let config = config.unwrap_or_else(|e| {
+        tracing::error!(error = %e, "couldn't load the config");
+        process::exit(1);
+    });
+    // LEAKY_BUCKET_* variables take precedence over the file.
+    let config = config.with_env().unwrap_or_else(|e| {
+        tracing::error!(error = %e, "couldn't load the config");
+        process::exit(1);
+    });
+    let pool_size = config.redis_pool_size;
+
+    // A comma-separated list of cluster nodes takes precedence over
+    // REDIS_HOST.
+    if let Ok(nodes) = env::var("REDIS_CLUSTER_NODES") {
+        let nodes = nodes.split(',').map(str::trim).collect::<Vec<_>>();
+
+        tracing::info!(nodes = %nodes.join(","), "connecting to redis cluster");
+
+        let client = or_exit(
+            ClusterClient::new(nodes),
+            "not a list of redis cluster node urls",
+        );
+        let mut connections = Vec::with_capacity(pool_size);
+        for _ in 0..pool_size {
+            let conn = client.get_async_connection().await;
+            connections.push(or_exit(conn, "couldn't connect to redis cluster"));
+        }
+
+        let store = redis_store(ConnectionPool::new(connections), &config).with_cluster(true);
+        serve(config.app_state(store), &config).await;
+        return;
+    }
+
+    // Comma-separated URLs of standalone instances that each hold a share of
+    // the buckets. Their order decides which, so only ever append to it.
+    if let Ok(urls) = env::var("REDIS_SHARDS") {
+        let urls = urls.split(',').map(str::trim).collect::<Vec<_>>();
+
+        tracing::info!(shards = %urls.join(","), "connecting to redis shards");
+
+        let mut shards = Vec::with_capacity(urls.len());
+        for url in urls {
+            let client = or_exit(redis::Client::open(url), "not a redis url");
+            let connections = connect(&client, pool_size, config.redis_timeout).await;
+            spawn_cleanup(
+                connect(&client, 1, config.redis_timeout).await.remove(0),
+                &config,
+            );
+            shards.push(redis_store(ConnectionPool::new(connections), &config));
+        }
+
+        serve(config.app_state(ShardedStore::new(shards)), &config).await;
+        return;
+    }
+
+    // Sentinel addresses, comma-separated, with the name of the monitored
+    // master in REDIS_SENTINEL_SERVICE.
+    if let Ok(sentinels) = env::var("REDIS_SENTINELS") {
+        let sentinels = sentinels.split(',').map(str::trim).collect::<Vec<_>>();
+        let service = env::var("REDIS_SENTINEL_SERVICE").unwrap_or("mymaster".to_string());
+
+        tracing::info!(sentinels = %sentinels.join(","), %service, "connecting through redis sentinel");
+
+        let client = SentinelClient::build(sentinels, service, None, SentinelServerType::Master);
+        let client = or_exit(client, "not a list of redis sentinel urls");
+        let client = Arc::new(Mutex::new(client));
+        // The pool connects lazily, so ask for the master once up front.
+        let mut conn = ReconnectingConnection::sentinel(Arc::clone(&client));
+        let ping = redis::cmd("PING").exec_async(&mut conn).await;
+        or_exit(
+            ping,
+            "couldn't reach the redis master through the sentinels",
+        );
+        let connections =
+            (0..pool_size).map(|_| ReconnectingConnection::sentinel(Arc::clone(&client)));
+        spawn_cleanup(conn, &config);
 
-    println!("{}", redis_host);
+        let store = redis_store(ConnectionPool::new(connections), &config);
+        serve(config.app_state(store), &config).await;
+        return;
+    }
 
-    let redis_conn = Arc::new(Mutex::new(
-        redis::Client::open(redis_host)
-            .unwrap()
-            .get_connection()
-            .unwrap(),
-    ));
+    let redis_host = env::var("REDIS_HOST").unwrap_or(config.redis_url.clone());
 
-    let state = AppState { redis_conn };
+    tracing::info!(%redis_host, "connecting to redis");
 
+    let client = or_exit(redis::Client::open(redis_host), "not a redis url");
+    let connections = connect(&client, pool_size, config.redis_timeout).await;
+    spawn_cleanup(
+        connect(&client, 1, config.redis_timeout).await.remove(0),
+        &config,
+    );
+
+    let store = redis_store(ConnectionPool::new(connections), &config);
+    serve(config.app_state(store), &config).await;
+}
+
+/// What `result` holds, or exits having logged its error as `what` went
+/// wrong, for settings the server can't start without.
+fn or_exit<T, E: fmt::Display>(result: Result<T, E>, what: &str) -> T {
+    result.unwrap_or_else(|e| {
+        tracing::error!(error = %e, "{what}");
+        process::exit(1);
+    })
+}
+
+/// A store on `pool`, in the format and keeping the leaderboard the config
+/// asks for, if any.
+fn redis_store<C>(pool: ConnectionPool<C>, config: &Config) -> AsyncRedisStore<C> {
+    let store = AsyncRedisStore::from_pool(pool)
+        .with_format(config.bucket_format)
+        .with_timeout(config.redis_timeout)
+        .with_key_prefix(&config.key_prefix);
+    match config.leaderboard() {
+        Some(leaderboard) => store.with_leaderboard(leaderboard),
+        None => store,
+    }
+}
+
+/// `n` connections to `client` that are opened again whenever they break, so
+/// a restarted Redis doesn't take a restart of the server too.
+async fn connect(
+    client: &redis::Client,
+    n: usize,
+    timeout: Duration,
+) -> Vec<ReconnectingConnection<MultiplexedConnection>> {
+    let config = AsyncConnectionConfig::new()
+        .set_connection_timeout(timeout)
+        .set_response_timeout(timeout);
+    let mut connections = Vec::with_capacity(n);
+    for _ in 0..n {
+        let conn = ReconnectingConnection::open(client.clone(), config.clone()).await;
+        connections.push(or_exit(conn, "couldn't connect to redis"));
+    }
+    connections
+}
+
+/// Every `cleanup_interval_secs`, if set, deletes buckets left behind under
+/// the key prefix without a TTL by older versions that would have refilled
+/// by now.
+/// Not available on a cluster, where SCAN only covers one node.
+fn spawn_cleanup<C>(mut conn: C, config: &Config)
+where
+    C: redis::aio::ConnectionLike + Send + 'static,
+{
+    let Some(every) = config.cleanup_interval() else {
+        return;
+    };
+    let prefix = config.key_prefix.clone();
+    let horizon = config.default.full_refill();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            match cleanup_stale_buckets(&mut conn, prefix.as_str(), 1000, horizon).await {
+                Ok(removed) => tracing::info!(removed, "removed stale buckets"),
+                Err(e) => tracing::error!(error = %e, "bucket cleanup failed"),
+            }
+        }
+    });
+}
+
+async fn serve<S: BucketStore>(state: AppState<S>, config: &Config) {
+    // The config's rules go by the matched route, so the limiter only
+    // covers routes.
     let app = Router::new()
         .route("/", get(|| async { "Hello, World!" }))
-        .layer(middleware::from_fn_with_state(
-            state.clone(),
-            rate_limiter_middleware,
-        ));
+        .route("/search", get(|| async { "results" }))
+        .route("/suggest", get(|| async { "suggestions" }))
+        .route("/reports", get(|| async { "report" }))
+        .route("/exports", get(|| async { "export" }))
+        .route_layer(RateLimiterLayer::new(state.clone()))
+        .merge(health_router(state.clone()))
+        .merge(metrics_router(state));
+
+    // How long requests in flight get to finish after SIGTERM.
+    let grace = config.shutdown_grace();
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    serve_with_shutdown(listener, app, shutdown_signal(), grace)
+        .await
+        .unwrap();
+    // The store's connections close as the router holding it is dropped.
+    tracing::info!("shut down");
 }
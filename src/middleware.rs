@@ -0,0 +1,8786 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    BoxError,
+    body::{Body, Bytes, HttpBody},
+    extract::{MatchedPath, OriginalUri, Request, State},
+    http::{self, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use chrono::Utc;
+use tower::{Service, ServiceExt};
+use tracing::Instrument;
+
+use crate::{
+    Algorithm, Allowlist, BatchCost, BearerTokenExtractor, Blocklist, BodyCost, BreakerState,
+    BucketConfig, BucketStatus, BucketStore, CircuitBreaker, CircuitBreakerConfig, Clock,
+    DecisionCtx, ExemptPaths, FailurePolicy, HeaderStyle, HookDispatch, KeyExtractor, KeyHasher,
+    KeyPrefix, LimitScope, LocalFallback, Mode, PeerIpExtractor, ProblemDetails, RateLimitDecision,
+    RateLimitHooks, RateLimitInfo, RateLimiterService, Refunds, Stats, StatsSnapshot, StoreError,
+    SystemClock, Tiered, TokenPersistence, TrustedProxies, denials::DenialCache, headers, hooks,
+    in_flight::InFlight, later, overrides::OverrideCache, problem, problem_rejection,
+    quota_rejection, router::RouteLimit, store, telemetry, telemetry::Outcome,
+};
+
+/// The key of the failed attempts bucket of the identity whose own bucket is
+/// at `bucket_key`, see [`Refunds::with_failures`].
+fn failures_key(bucket_key: &str) -> String {
+    format!("{bucket_key}:failures")
+}
+
+/// The key of the quota of the identity whose bucket is at `bucket_key`, see
+/// [`AppState::with_quota`].
+fn quota_key(bucket_key: &str) -> String {
+    format!("{bucket_key}:quota")
+}
+
+/// Where the bucket set up with [`AppState::with_global_limit`] is stored,
+/// under the default [`KeyPrefix`].
+pub const GLOBAL_BUCKET_KEY: &str = "bucket:__global__";
+
+/// The header [`AppState::with_idempotency`] deduplicates charges by.
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Charges [`AppState::consume_up_to`] makes before giving up on a bucket
+/// other requests keep draining.
+const CONSUME_UP_TO_TRIES: usize = 4;
+
+/// The longest `Idempotency-Key` that's remembered. Longer ones are charged
+/// as if they weren't there.
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// A bucket per client address, charged together with the identity's own.
+#[derive(Clone, Debug)]
+pub struct IpLimit {
+    /// Works out the address, honouring forwarding headers only from
+    /// trusted proxies.
+    pub extractor: PeerIpExtractor,
+    pub config: BucketConfig,
+}
+
+pub struct AppState<S> {
+    pub store: Arc<S>,
+    /// The default config, shared with every clone of the state so it can be
+    /// swapped on all of them. See [`config`](Self::config).
+    config: Arc<RwLock<Arc<BucketConfig>>>,
+    pub key_extractor: Arc<dyn KeyExtractor>,
+    /// Configs for specific route patterns, as reported by [`MatchedPath`].
+    /// Each of these routes gets its own bucket per identity.
+    pub routes: Arc<HashMap<String, BucketConfig>>,
+    /// The groups some of those routes share a bucket in, by route pattern.
+    pub route_groups: Arc<HashMap<String, String>>,
+    /// Configs for the tiers identities may be in, by name. See
+    /// [`with_tier`](Self::with_tier).
+    pub tiers: Arc<HashMap<String, BucketConfig>>,
+    /// Configs of identities' own, read from the store. See
+    /// [`with_overrides`](Self::with_overrides).
+    pub(crate) overrides: Option<Arc<OverrideCache>>,
+    /// Denials remembered so they're answered without the store. See
+    /// [`with_denial_cache`](Self::with_denial_cache).
+    pub(crate) denials: Option<Arc<DenialCache>>,
+    /// How many requests each identity may have in flight at once. See
+    /// [`with_max_in_flight`](Self::with_max_in_flight).
+    pub(crate) in_flight: Option<Arc<InFlight>>,
+    pub header_style: HeaderStyle,
+    /// Builds the response for denied requests. Rate limit headers and
+    /// `Retry-After` are added to whatever it returns.
+    pub rejection: Arc<dyn Fn(&RateLimitDecision) -> Response + Send + Sync>,
+    /// Whether the middleware's own error responses carry RFC 7807 bodies.
+    pub problem_details: bool,
+    pub failure_policy: FailurePolicy,
+    pub mode: Mode,
+    pub breaker: Option<Arc<CircuitBreaker>>,
+    /// Used instead of the failure policy while the store is unavailable.
+    pub fallback: Option<Arc<LocalFallback>>,
+    /// A bucket every client draws from as well as their own, stored at
+    /// [`GLOBAL_BUCKET_KEY`] under the default prefix.
+    pub global: Option<BucketConfig>,
+    /// A bucket per client address every request is charged to as well.
+    pub ip_limit: Option<Arc<IpLimit>>,
+    /// Further windows of the default bucket, each kept as a bucket of its
+    /// own.
+    pub windows: Arc<Vec<BucketConfig>>,
+    /// How long a denied request is held for its tokens to come back before
+    /// it's answered with 429. None by default.
+    pub max_wait: Duration,
+    /// Responses that give the client back what the request cost. None by
+    /// default.
+    pub refunds: Option<Arc<Refunds>>,
+    /// How long a request's `Idempotency-Key` is remembered for, so retries
+    /// aren't charged again. Not at all by default.
+    pub idempotency_ttl: Option<Duration>,
+    /// Charges requests by the size of their body instead of one token each.
+    pub body_cost: Option<Arc<BodyCost>>,
+    /// Charges requests by the items of the batch they carry, ahead of the
+    /// size of their body.
+    pub batch_cost: Option<Arc<BatchCost>>,
+    /// A quota per identity, such as so many requests a day, charged along
+    /// with its bucket.
+    pub quota: Option<BucketConfig>,
+    /// Builds the response for requests denied by the quota.
+    pub quota_rejection: Arc<dyn Fn(&RateLimitDecision) -> Response + Send + Sync>,
+    pub clock: Arc<dyn Clock>,
+    /// Paths let through without looking at the request at all.
+    pub exempt_paths: Arc<ExemptPaths>,
+    /// Whether CORS preflights are let through the same way. On by default.
+    pub exempt_preflight: bool,
+    /// Identities let through without charging them or checking the
+    /// blocklist.
+    pub allowlist: Arc<Allowlist>,
+    /// Identities refused with 403 Forbidden before their bucket is looked at.
+    pub blocklist: Arc<Blocklist>,
+    /// Whether the store's blocklist is checked too, for identities blocked
+    /// by another instance.
+    pub shared_blocklist: bool,
+    /// Builds the response for blocked identities.
+    pub blocked: Arc<dyn Fn() -> Response + Send + Sync>,
+    /// Whether metrics are labelled with the matched route.
+    pub metrics_route_label: bool,
+    pub stats: Arc<Stats>,
+    pub hooks: Option<Arc<dyn RateLimitHooks>>,
+    pub hook_dispatch: HookDispatch,
+    /// What the keys of everything stored start with.
+    pub key_prefix: KeyPrefix,
+    /// How identities are hashed into their keys.
+    pub key_hasher: KeyHasher,
+}
+
+impl<S> AppState<S> {
+    pub fn new(store: S, config: BucketConfig) -> Self {
+        Self {
+            store: Arc::new(store),
+            config: Arc::new(RwLock::new(Arc::new(config))),
+            key_extractor: Arc::new(BearerTokenExtractor::default()),
+            routes: Arc::default(),
+            route_groups: Arc::default(),
+            tiers: Arc::default(),
+            overrides: None,
+            denials: None,
+            in_flight: None,
+            header_style: HeaderStyle::default(),
+            rejection: Arc::new(default_rejection),
+            problem_details: false,
+            failure_policy: FailurePolicy::default(),
+            mode: Mode::default(),
+            breaker: None,
+            fallback: None,
+            global: None,
+            ip_limit: None,
+            windows: Arc::default(),
+            max_wait: Duration::ZERO,
+            refunds: None,
+            idempotency_ttl: None,
+            body_cost: None,
+            batch_cost: None,
+            quota: None,
+            quota_rejection: Arc::new(quota_rejection),
+            clock: Arc::new(SystemClock),
+            exempt_paths: Arc::default(),
+            exempt_preflight: true,
+            allowlist: Arc::default(),
+            blocklist: Arc::default(),
+            shared_blocklist: false,
+            blocked: Arc::new(default_blocked),
+            metrics_route_label: false,
+            stats: Arc::default(),
+            hooks: None,
+            hook_dispatch: HookDispatch::default(),
+            key_prefix: KeyPrefix::default(),
+            key_hasher: KeyHasher::default(),
+        }
+    }
+
+    /// The default config as of now. Each request goes by the one it started
+    /// with, whatever happens to it in the meantime.
+    pub fn config(&self) -> Arc<BucketConfig> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    /// What the middleware has decided since the start, in total and by
+    /// matched route, as counted in [`stats`](Self::stats).
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Like [`stats()`](Self::stats()), since the last call instead, for
+    /// scrapers that want deltas. See [`Stats::snapshot_and_reset`].
+    pub fn stats_and_reset(&self) -> StatsSnapshot {
+        self.stats.snapshot_and_reset()
+    }
+
+    /// Limits requests with `config` instead of the default one from now on,
+    /// through this state and every clone of it, e.g. to tighten limits
+    /// during an incident without a restart. Buckets keep the tokens they
+    /// have, up to what the new config lets them hold.
+    pub fn update_config(&self, config: BucketConfig) {
+        *self.config.write().unwrap() = Arc::new(config);
+        if let Some(denials) = &self.denials {
+            denials.clear();
+        }
+    }
+
+    pub fn with_key_extractor(mut self, extractor: impl KeyExtractor) -> Self {
+        self.key_extractor = Arc::new(extractor);
+        self
+    }
+
+    pub fn with_header_style(mut self, style: HeaderStyle) -> Self {
+        self.header_style = style;
+        self
+    }
+
+    pub fn with_rejection<F>(mut self, rejection: F) -> Self
+    where
+        F: Fn(&RateLimitDecision) -> Response + Send + Sync + 'static,
+    {
+        self.rejection = Arc::new(rejection);
+        self
+    }
+
+    /// Answers 401, 403, 411, 413, 429 and 503 with `application/problem+json`
+    /// bodies (see [`ProblemDetails`]) instead of empty ones.
+    ///
+    /// This replaces the rejection and blocked builders, so call
+    /// [`with_rejection`](Self::with_rejection) or
+    /// [`with_blocked_response`](Self::with_blocked_response) afterwards to
+    /// override 429s or 403s.
+    pub fn with_problem_details(mut self, enabled: bool) -> Self {
+        self.problem_details = enabled;
+        if enabled {
+            self.rejection = Arc::new(problem_rejection);
+            self.blocked = Arc::new(|| ProblemDetails::blocked().into_response());
+        } else {
+            self.rejection = Arc::new(default_rejection);
+            self.blocked = Arc::new(default_blocked);
+        }
+        self
+    }
+
+    /// Lets requests to `paths`, such as health checks, skip the limiter
+    /// entirely: they need no identity and get no rate limit headers.
+    pub fn with_exempt_paths(mut self, paths: ExemptPaths) -> Self {
+        self.exempt_paths = Arc::new(paths);
+        self
+    }
+
+    /// Whether CORS preflights, `OPTIONS` requests with an
+    /// `Access-Control-Request-Method` header, skip the limiter like exempt
+    /// paths do. Browsers never send credentials with them, so limiting them
+    /// answers every one with 401 and blocks the request it's for.
+    pub fn with_exempt_preflight(mut self, enabled: bool) -> Self {
+        self.exempt_preflight = enabled;
+        self
+    }
+
+    /// Caps the whole service as well as each client: every charged request
+    /// also takes its cost out of one bucket shared by all of them, in the
+    /// same transaction as the client's own. A request has to fit in both,
+    /// and when either is out neither is charged, so a client isn't billed
+    /// for requests the service as a whole turned away.
+    ///
+    /// Denials and rate limit headers go by whichever bucket is stricter.
+    pub fn with_global_limit(mut self, config: BucketConfig) -> Self {
+        self.global = Some(config);
+        self
+    }
+
+    /// Limits each client address as well as each identity, so a token
+    /// spread across many machines, or many tokens from one, still runs
+    /// into a limit. Every request is charged to both buckets in the same
+    /// transaction; when either is out neither is charged. The address
+    /// comes from the connection, so serve the app with
+    /// `into_make_service_with_connect_info::<SocketAddr>()`; requests
+    /// without it are answered with 401 Unauthorized.
+    ///
+    /// The address bucket is usually the larger, as several clients may
+    /// share an address behind a NAT.
+    pub fn with_ip_limit(mut self, trusted_proxies: TrustedProxies, config: BucketConfig) -> Self {
+        self.ip_limit = Some(Arc::new(IpLimit {
+            extractor: PeerIpExtractor::new(trusted_proxies),
+            config,
+        }));
+        self
+    }
+
+    /// Gives every identity a quota, usually a [`BucketConfig::daily`] one,
+    /// on top of its bucket: every request is charged to both in the same
+    /// transaction, and when either is out neither is charged. The quota is
+    /// kept per identity across all routes, under its key plus `:quota`.
+    ///
+    /// Requests denied by it are answered with
+    /// [`with_quota_rejection`](Self::with_quota_rejection)'s response, by
+    /// default a `quota_exceeded` [`ProblemDetails`], and a `Retry-After`
+    /// pointing at the reset.
+    pub fn with_quota(mut self, config: BucketConfig) -> Self {
+        self.quota = Some(config);
+        self
+    }
+
+    pub fn with_quota_rejection<F>(mut self, rejection: F) -> Self
+    where
+        F: Fn(&RateLimitDecision) -> Response + Send + Sync + 'static,
+    {
+        self.quota_rejection = Arc::new(rejection);
+        self
+    }
+
+    /// Limits identities over several windows at once, say 3 a minute as
+    /// well as the default bucket's 10 an hour, so they can't spend a whole
+    /// hour's worth in one burst. Each window is a bucket of its own, stored
+    /// under the identity's key plus how long it takes to refill, like
+    /// `bucket:{..}:60s`, so no two windows may take equally long. They're
+    /// charged together with the default bucket, and when any of them is out
+    /// none is charged.
+    ///
+    /// Routes with limits of their own aren't affected.
+    pub fn with_windows(mut self, windows: impl IntoIterator<Item = BucketConfig>) -> Self {
+        self.windows = Arc::new(windows.into_iter().collect());
+        self
+    }
+
+    /// Lets identities in `allowlist` through untouched: no store round trip,
+    /// no rate limit headers, just `X-RateLimit-Bypass: true`.
+    pub fn with_allowlist(mut self, allowlist: Allowlist) -> Self {
+        self.allowlist = Arc::new(allowlist);
+        self
+    }
+
+    pub fn with_blocked_response<F>(mut self, blocked: F) -> Self
+    where
+        F: Fn() -> Response + Send + Sync + 'static,
+    {
+        self.blocked = Arc::new(blocked);
+        self
+    }
+
+    /// Also refuses identities in the store's blocklist, so blocking one on
+    /// any instance blocks it on all of them. Costs a lookup per request.
+    pub fn with_shared_blocklist(mut self, enabled: bool) -> Self {
+        self.shared_blocklist = enabled;
+        self
+    }
+
+    pub fn with_failure_policy(mut self, policy: FailurePolicy) -> Self {
+        self.failure_policy = policy;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Labels the metrics reported with the `metrics` feature with the
+    /// route pattern the request matched, as reported by [`MatchedPath`].
+    /// Off by default, as every route multiplies the number of series.
+    pub fn with_metrics_route_label(mut self, enabled: bool) -> Self {
+        self.metrics_route_label = enabled;
+        self
+    }
+
+    /// Runs `hooks` after every decision, on a task of their own unless
+    /// [`with_hook_dispatch`](Self::with_hook_dispatch) says otherwise.
+    pub fn with_hooks(mut self, hooks: impl RateLimitHooks) -> Self {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    pub fn with_hook_dispatch(mut self, dispatch: HookDispatch) -> Self {
+        self.hook_dispatch = dispatch;
+        self
+    }
+
+    /// Skips the store and applies the failure policy straight away while it
+    /// keeps erroring; see [`CircuitBreaker`].
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.breaker = Some(Arc::new(CircuitBreaker::new(config)));
+        self
+    }
+
+    /// The circuit breaker's state, if there is one, e.g. for health checks.
+    pub fn breaker_state(&self) -> Option<BreakerState> {
+        self.breaker.as_ref().map(|breaker| breaker.state())
+    }
+
+    /// Rate limits in process, keeping at most `capacity` buckets, whenever
+    /// the store errors or the circuit breaker is open. See [`LocalFallback`].
+    pub fn with_local_fallback(mut self, capacity: usize) -> Self {
+        self.fallback = Some(Arc::new(LocalFallback::new(capacity)));
+        self
+    }
+
+    /// Holds a request its bucket can't pay for until the tokens are back,
+    /// as long as that's within `max_wait`, and charges it again then,
+    /// instead of answering 429 straight away. Nothing is checked out of the
+    /// store while it waits. In [`Mode::Shadow`] requests are never held.
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// Gives a request back what it was charged, in every bucket it was
+    /// charged to, once the limited service answers it with one of
+    /// `refunds`' statuses. [`Refunds::default`] covers server errors.
+    ///
+    /// That takes a second trip to the store after the response. A refund
+    /// that fails is only logged, and a bucket never ends up more than full.
+    pub fn with_refunds(mut self, refunds: Refunds) -> Self {
+        self.refunds = Some(Arc::new(refunds));
+        self
+    }
+
+    /// Charges a request with an `Idempotency-Key` header only once for as
+    /// long as `ttl`: retries with the same key, from the same identity and
+    /// to the same bucket, are let through without another charge. The key
+    /// is kept in the store as `<bucket key>:idem:<key>`, in the same atomic
+    /// step as the charge, so duplicates racing each other are charged once.
+    ///
+    /// A denied request leaves nothing to retry against, and a retried
+    /// request is never refunded. Keys longer than
+    /// [`MAX_IDEMPOTENCY_KEY_LEN`] are ignored.
+    pub fn with_idempotency(mut self, ttl: Duration) -> Self {
+        self.idempotency_ttl = Some(ttl);
+        self
+    }
+
+    /// Charges each request by the size of its body, see [`BodyCost`].
+    /// Requests carrying a [`RequestCost`] are still charged that instead.
+    pub fn with_body_cost(mut self, body_cost: BodyCost) -> Self {
+        self.body_cost = Some(Arc::new(body_cost));
+        self
+    }
+
+    /// Charges each request one token per item of its batch, see
+    /// [`BatchCost`]. Requests carrying a [`RequestCost`] are still charged
+    /// that instead, and ones that don't say how many items they carry are
+    /// charged as they would be without it.
+    pub fn with_batch_cost(mut self, batch_cost: BatchCost) -> Self {
+        self.batch_cost = Some(Arc::new(batch_cost));
+        self
+    }
+
+    /// Reads the time from `clock` instead of the system clock, e.g. a
+    /// [`ManualClock`](testing::ManualClock) in tests.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Limits requests matching the route `path` (e.g. `/users/{id}`) with
+    /// `config` instead of the default one.
+    ///
+    /// The route is only known once the router has matched it, so the
+    /// middleware must be added with `Router::route_layer` for this to apply.
+    pub fn with_route(mut self, path: impl Into<String>, config: BucketConfig) -> Self {
+        let path = path.into();
+        if self.route_groups.contains_key(&path) {
+            Arc::make_mut(&mut self.route_groups).remove(&path);
+        }
+        Arc::make_mut(&mut self.routes).insert(path, config);
+        self
+    }
+
+    /// Like [`with_route`](Self::with_route), but the route draws from one
+    /// bucket per identity with every other route in group `name`, including
+    /// routes limited with
+    /// [`rate_limit_group`](RateLimitedRouterExt::rate_limit_group) under
+    /// that name.
+    pub fn with_route_group(
+        mut self,
+        name: impl Into<String>,
+        path: impl Into<String>,
+        config: BucketConfig,
+    ) -> Self {
+        let path = path.into();
+        Arc::make_mut(&mut self.route_groups).insert(path.clone(), name.into());
+        Arc::make_mut(&mut self.routes).insert(path, config);
+        self
+    }
+
+    /// Lets identities have a config of their own, stored at
+    /// `bucket:config:{hash}` as JSON, that their default bucket goes by
+    /// instead of the default one or their tier's. Identities without one
+    /// aren't affected.
+    ///
+    /// Each identity's override is read once every `ttl` at most, e.g. 30
+    /// seconds, so one changed by hand or by another instance takes that long
+    /// to apply here. [`set_override`](Self::set_override) and
+    /// [`clear_override`](Self::clear_override) apply on this instance
+    /// straight away. An override that can't be read is logged and the
+    /// default config used.
+    pub fn with_overrides(mut self, ttl: Duration) -> Self {
+        self.overrides = Some(Arc::new(OverrideCache::new(ttl)));
+        self
+    }
+
+    /// Answers a request to a bucket the store denied a request to with 429
+    /// locally, until the tokens that request asked for are due back, instead
+    /// of asking the store just to be told no again. Up to `capacity` buckets
+    /// are remembered, by their hashed key.
+    ///
+    /// A request costing less than the denied one still goes to the store,
+    /// and so do requests with an `Idempotency-Key`, which may have been paid
+    /// for already. Denials under a [`PenaltyConfig`] aren't remembered, so
+    /// they all count. [`reset_bucket`](Self::reset_bucket),
+    /// [`set_tokens`](Self::set_tokens), refunds and
+    /// [`update_config`](Self::update_config) forget the denials they affect
+    /// on this instance, but a bucket refilled by another instance is only
+    /// looked at again once the denial runs out.
+    pub fn with_denial_cache(mut self, capacity: usize) -> Self {
+        self.denials = Some(Arc::new(DenialCache::new(capacity)));
+        self
+    }
+
+    /// Lets each identity have at most `max` requests in flight on this
+    /// instance, on top of its bucket, so a client can't tie up the service
+    /// with slow requests that each pass the token check. A request over it
+    /// is answered 429 with an `X-RateLimit-Scope` of `concurrency` before
+    /// its bucket is charged.
+    ///
+    /// A request counts from before its bucket is charged until its
+    /// response body has been sent, or until it's dropped, as when the
+    /// handler panics or the client goes away.
+    pub fn with_max_in_flight(mut self, max: usize) -> Self {
+        self.in_flight = Some(Arc::new(InFlight::new(max)));
+        self
+    }
+
+    /// Limits identities in tier `name` with `config` instead of the default
+    /// one. An identity's tier is whatever is stored at `tier:{hash}` (see
+    /// [`KeyPrefix`]), with the same hash as its bucket, so a billing system can move it between
+    /// tiers at any time; see [`BucketStore::set_tier`]. It's read in the
+    /// same round trip as the bucket where the store allows.
+    ///
+    /// Identities without a tier, or in one that isn't set up here, get the
+    /// default config. Responses name the tier that applied in
+    /// `X-RateLimit-Tier`. Routes with limits of their own aren't affected.
+    pub fn with_tier(mut self, name: impl Into<String>, config: BucketConfig) -> Self {
+        Arc::make_mut(&mut self.tiers).insert(name.into(), config);
+        self
+    }
+
+    /// Starts every key this state reads or writes with `prefix` instead of
+    /// `bucket:`, to share a Redis with other environments or services.
+    /// Changing it leaves the buckets under the old prefix behind, to expire.
+    /// The keys a Redis store keeps for itself, such as its blocklist, take
+    /// the prefix from the store's own `with_key_prefix`.
+    pub fn with_key_prefix(mut self, prefix: KeyPrefix) -> Self {
+        self.key_prefix = prefix;
+        self
+    }
+
+    /// Hashes identities into keys with `hasher`, such as with a secret so
+    /// the keyspace gives nothing away about tokens. See [`KeyHasher`] for
+    /// what changing it does to existing buckets.
+    pub fn with_key_hasher(mut self, hasher: KeyHasher) -> Self {
+        self.key_hasher = hasher;
+        self
+    }
+
+    /// The key of `identity`'s bucket, which the keys of its tier and
+    /// override are derived from. With a secret, there's no working it out
+    /// without one.
+    pub fn bucket_key(&self, identity: &str) -> String {
+        self.key_prefix.bucket(&self.key_hasher.hash(identity))
+    }
+}
+
+impl<S: BucketStore> AppState<S> {
+    /// Charges `cost` tokens to the bucket for `key` outside of an HTTP
+    /// request, e.g. per WebSocket message or background job.
+    ///
+    /// This is what the middleware does for each request, against the default
+    /// [`BucketConfig`]: `key` is an identity as a [`KeyExtractor`] would
+    /// return it, so charging a client's token here draws from the same bucket
+    /// as their requests. The circuit breaker and local fallback apply as
+    /// usual; the failure policy is left to the caller, as an error.
+    ///
+    /// A `cost` below zero or above what the buckets hold is refused with a
+    /// [`ConsumeError`] without asking the store.
+    ///
+    #[cfg_attr(feature = "memory", doc = "```")]
+    #[cfg_attr(not(feature = "memory"), doc = "```ignore")]
+    /// use leaky_bucket::{AppState, BucketConfig, MemoryStore};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let config = BucketConfig {
+    ///     max_tokens: 5,
+    ///     ..BucketConfig::default()
+    /// };
+    /// let state = AppState::new(MemoryStore::new(), config);
+    ///
+    /// let decision = state.consume_tokens("job-runner", 3).await.unwrap();
+    /// assert!(decision.allowed);
+    /// assert_eq!(decision.remaining, 2);
+    ///
+    /// let decision = state.consume_tokens("job-runner", 3).await.unwrap();
+    /// assert!(!decision.allowed);
+    /// assert!(decision.retry_after.is_some());
+    /// # }
+    /// ```
+    pub async fn consume_tokens(
+        &self,
+        key: &str,
+        cost: i64,
+    ) -> Result<RateLimitDecision, ConsumeError> {
+        if cost < 0 {
+            return Err(ConsumeError::Negative(cost));
+        }
+        let key = self.bucket_key(key);
+        let windows = self
+            .windows
+            .iter()
+            .map(|window| (window.window_key(&key), window))
+            .collect::<Vec<_>>();
+        let config_override = self.config_override(&key).await;
+        let default = self.config();
+        let config = config_override.as_ref().unwrap_or(&default);
+        let mut buckets = vec![(LimitScope::Token, key.as_str(), config)];
+        for (window_key, window) in &windows {
+            buckets.push((LimitScope::Token, window_key, window));
+        }
+        // No amount of waiting would let this through, as in `resolve`.
+        let capacity = buckets
+            .iter()
+            .map(|(_, _, config)| config.capacity())
+            .min()
+            .unwrap_or(config.max_tokens);
+        if cost > capacity {
+            return Err(ConsumeError::ExceedsCapacity { cost, capacity });
+        }
+        let tier_key = (config_override.is_none() && !self.tiers.is_empty())
+            .then(|| self.key_prefix.tier(&key));
+        let charged = self
+            .charge(&buckets, cost, None, tier_key.as_deref())
+            .await?;
+        Ok(charged.decision)
+    }
+
+    /// Decides on a request with `parts` as the middleware would, charging
+    /// the same buckets, but without the rest of what it does: there are no
+    /// headers, hooks, stats or waiting, and the failure policy is left to
+    /// the caller, as an error.
+    ///
+    #[cfg_attr(feature = "memory", doc = "```")]
+    #[cfg_attr(not(feature = "memory"), doc = "```ignore")]
+    /// use axum::http::Request;
+    /// use leaky_bucket::{AppState, BucketConfig, MemoryStore, Verdict};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let state = AppState::new(MemoryStore::new(), BucketConfig::default());
+    /// let (parts, _) = Request::get("/")
+    ///     .header("Authorization", "Bearer abc")
+    ///     .body(())
+    ///     .unwrap()
+    ///     .into_parts();
+    ///
+    /// let Verdict::Charged(decision) = state.decide(&parts).await.unwrap() else {
+    ///     panic!("not charged");
+    /// };
+    /// assert_eq!(decision.remaining, 9);
+    /// # }
+    /// ```
+    pub async fn decide(&self, parts: &http::request::Parts) -> Result<Verdict, StoreError> {
+        let (redis_key, others, config, tiered, cost) = match resolve(self, parts).await {
+            Ok(Resolved::Charge {
+                redis_key,
+                others,
+                config,
+                tiered,
+                cost,
+            }) => (redis_key, others, config, tiered, cost),
+            Ok(Resolved::Bypass) => return Ok(Verdict::Free),
+            Err((_, response)) => return Ok(Verdict::Refused(response)),
+        };
+        if cost == 0 {
+            return Ok(Verdict::Free);
+        }
+        let mut buckets = vec![(LimitScope::Token, redis_key.as_str(), &*config)];
+        for (scope, key, config) in &others {
+            buckets.push((*scope, key, config));
+        }
+        let tier_key = tiered.then(|| self.key_prefix.tier(&redis_key));
+        let charged = self
+            .charge(&buckets, cost, None, tier_key.as_deref())
+            .await?;
+        Ok(Verdict::Charged(charged.decision))
+    }
+
+    /// Takes `n` tokens from `key`'s buckets at once, as for a batch of `n`
+    /// items, or none at all if they don't all fit. It's
+    /// [`consume_tokens`](Self::consume_tokens) in one atomic charge, with a
+    /// refusal saying how many tokens there are, so the caller can process
+    /// that much of the batch instead. A batch bigger than the buckets hold is
+    /// a [`ConsumeError::ExceedsCapacity`], as it would never fit.
+    ///
+    #[cfg_attr(feature = "memory", doc = "```")]
+    #[cfg_attr(not(feature = "memory"), doc = "```ignore")]
+    /// use leaky_bucket::{AppState, BucketConfig, MemoryStore};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let state = AppState::new(MemoryStore::new(), BucketConfig::default());
+    /// state.consume_tokens("importer", 7).await.unwrap();
+    ///
+    /// let grant = state.consume_many("importer", 5).await.unwrap();
+    /// assert_eq!((grant.granted, grant.available), (0, 3));
+    /// # }
+    /// ```
+    pub async fn consume_many(&self, key: &str, n: i64) -> Result<Grant, ConsumeError> {
+        let decision = self.consume_tokens(key, n).await?;
+        Ok(Grant {
+            granted: if decision.allowed { n } else { 0 },
+            available: decision.remaining,
+            decision,
+        })
+    }
+
+    /// Takes as many of `n` tokens from `key`'s buckets as they have, for
+    /// processing as much of a batch as fits.
+    ///
+    /// It's one atomic charge of `n`, and if that's refused, one of what the
+    /// refusal says there is. If another request takes some of that in
+    /// between, it tries again with what's left, a few times, so the buckets
+    /// are never overdrawn. Under a [`PenaltyConfig`], the refusals count as
+    /// violations. An `n` bigger than the buckets hold asks for all they hold.
+    ///
+    #[cfg_attr(feature = "memory", doc = "```")]
+    #[cfg_attr(not(feature = "memory"), doc = "```ignore")]
+    /// use leaky_bucket::{AppState, BucketConfig, MemoryStore};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let state = AppState::new(MemoryStore::new(), BucketConfig::default());
+    /// state.consume_tokens("importer", 7).await.unwrap();
+    ///
+    /// let grant = state.consume_up_to("importer", 5).await.unwrap();
+    /// assert_eq!((grant.granted, grant.available), (3, 0));
+    /// # }
+    /// ```
+    pub async fn consume_up_to(&self, key: &str, n: i64) -> Result<Grant, ConsumeError> {
+        let mut asked = n;
+        let mut grant = match self.consume_many(key, asked).await {
+            // More than the buckets hold is never granted, but all they hold can be.
+            Err(ConsumeError::ExceedsCapacity { capacity, .. }) => {
+                asked = capacity;
+                self.consume_many(key, asked).await?
+            }
+            grant => grant?,
+        };
+        for _ in 1..CONSUME_UP_TO_TRIES {
+            if grant.granted > 0 || grant.available <= 0 || grant.available >= asked {
+                break;
+            }
+            asked = grant.available;
+            grant = self.consume_many(key, asked).await?;
+        }
+        Ok(grant)
+    }
+
+    /// How many tokens `key` has left, without using one up. The bucket isn't
+    /// written to, so checking is free for the client however often it's done.
+    ///
+    /// `allowed` says whether a one-token request would go through right now.
+    /// This goes straight to the store, past the circuit breaker and local
+    /// fallback.
+    ///
+    #[cfg_attr(feature = "memory", doc = "```")]
+    #[cfg_attr(not(feature = "memory"), doc = "```ignore")]
+    /// use leaky_bucket::{AppState, BucketConfig, MemoryStore};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let state = AppState::new(MemoryStore::new(), BucketConfig::default());
+    /// state.consume_tokens("dashboard", 3).await.unwrap();
+    ///
+    /// let left = state.check_tokens("dashboard").await.unwrap();
+    /// assert_eq!(left.remaining, 7);
+    /// assert_eq!(state.check_tokens("dashboard").await.unwrap(), left);
+    /// # }
+    /// ```
+    pub async fn check_tokens(&self, key: &str) -> Result<RateLimitDecision, StoreError> {
+        self.store
+            .peek(&self.bucket_key(key), &self.config(), self.clock.now())
+            .await
+    }
+
+    /// The stored bucket for `key`, or `None` if it has none, i.e. it's full.
+    ///
+    /// Like [`consume_tokens`](Self::consume_tokens), `key` is the identity and
+    /// this is its default bucket, not any per-route one.
+    pub async fn get_bucket_status(&self, key: &str) -> Result<Option<BucketStatus>, StoreError> {
+        self.store
+            .status(&self.bucket_key(key), &self.config(), self.clock.now())
+            .await
+    }
+
+    /// The `n` identities charged most in the current window of the store's
+    /// leaderboard, by their default bucket's key, most first, with how many
+    /// charges each had. See [`BucketStore::top_consumers`].
+    pub async fn top_consumers(&self, n: usize) -> Result<Vec<(String, u64)>, StoreError> {
+        self.store.top_consumers(n, self.clock.now()).await
+    }
+
+    /// Refills `key`'s default bucket by deleting it. Returns whether there
+    /// was one. Its override is read again on its next request.
+    pub async fn reset_bucket(&self, key: &str) -> Result<bool, StoreError> {
+        let bucket_key = self.bucket_key(key);
+        if let Some(cache) = &self.overrides {
+            cache.invalidate(&self.key_prefix.config(&bucket_key));
+        }
+        if let Some(denials) = &self.denials {
+            denials.invalidate(&bucket_key);
+        }
+        self.store.reset(&bucket_key).await
+    }
+
+    /// Limits `key`'s default bucket with `config` from now on, on every
+    /// instance using [`with_overrides`](Self::with_overrides). The bucket
+    /// keeps the tokens it has.
+    pub async fn set_override(&self, key: &str, config: &BucketConfig) -> Result<(), StoreError> {
+        self.store_override(key, Some(config)).await
+    }
+
+    /// Puts `key`'s default bucket back on the config it'd have without an
+    /// override.
+    pub async fn clear_override(&self, key: &str) -> Result<(), StoreError> {
+        self.store_override(key, None).await
+    }
+
+    async fn store_override(
+        &self,
+        key: &str,
+        config: Option<&BucketConfig>,
+    ) -> Result<(), StoreError> {
+        let key = self.key_prefix.config(&self.bucket_key(key));
+        self.store.set_config_override(&key, config).await?;
+        if let Some(cache) = &self.overrides {
+            cache.invalidate(&key);
+        }
+        Ok(())
+    }
+
+    /// The override of the identity whose bucket is at `bucket_key`, if
+    /// overrides are on and it has one.
+    async fn config_override(&self, bucket_key: &str) -> Option<BucketConfig> {
+        let cache = self.overrides.as_ref()?;
+        let key = self.key_prefix.config(bucket_key);
+        let now = self.clock.now();
+        if let Some(config) = cache.get(&key, now) {
+            return config;
+        }
+        match self.store.config_override(&key).await {
+            Ok(config) => {
+                cache.insert(&key, config.clone(), now);
+                config
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "couldn't read a config override, going by the default");
+                None
+            }
+        }
+    }
+
+    /// Sets `key`'s default bucket to hold `tokens`, clamped to what it can
+    /// hold, as of now. Returns the bucket as stored.
+    pub async fn set_tokens(&self, key: &str, tokens: i64) -> Result<BucketStatus, StoreError> {
+        let now = self.clock.now();
+        let config = self.config();
+        let tokens = tokens.clamp(0, config.max_tokens.max(0));
+        let bucket = TokenPersistence::holding(&config, tokens, now);
+        let bucket_key = self.bucket_key(key);
+        self.store
+            .set_tokens(&bucket_key, tokens, &config, now)
+            .await?;
+        if let Some(denials) = &self.denials {
+            denials.invalidate(&bucket_key);
+        }
+        Ok(bucket.status(&config, now))
+    }
+
+    /// Refuses `key`'s requests with 403 Forbidden from now on, on this
+    /// instance and, with a shared blocklist, in the store.
+    pub async fn add_to_blocklist(&self, key: &str) -> Result<(), StoreError> {
+        self.blocklist.insert(key);
+        if self.shared_blocklist {
+            self.store.set_blocked(&self.bucket_key(key), true).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_from_blocklist(&self, key: &str) -> Result<(), StoreError> {
+        self.blocklist.remove(key);
+        if self.shared_blocklist {
+            self.store.set_blocked(&self.bucket_key(key), false).await?;
+        }
+        Ok(())
+    }
+
+    /// A shared blocklist that can't be read doesn't block anyone; the charge
+    /// that follows runs into the same store problem anyway.
+    async fn is_blocked(&self, identity: &str) -> bool {
+        if self.blocklist.contains(identity) {
+            return true;
+        }
+        if !self.shared_blocklist {
+            return false;
+        }
+        self.store
+            .is_blocked(&self.bucket_key(identity))
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!(error = %e, "couldn't check the shared blocklist");
+                false
+            })
+    }
+
+    /// Charges the client's buckets, and the global one if there is one,
+    /// all or nothing. With a `tier_key`, the first bucket goes by the
+    /// identity's tier.
+    async fn charge<'a>(
+        &'a self,
+        scoped: &[(LimitScope, &str, &'a BucketConfig)],
+        cost: i64,
+        receipt: Option<&str>,
+        tier_key: Option<&str>,
+    ) -> Result<Charged<'a>, StoreError> {
+        let mut scopes = scoped.iter().map(|(scope, ..)| *scope).collect::<Vec<_>>();
+        let mut buckets = scoped
+            .iter()
+            .map(|(_, key, config)| (*key, *config))
+            .collect::<Vec<_>>();
+        let global_key = self.key_prefix.global();
+        if let Some(global) = &self.global {
+            scopes.push(LimitScope::Global);
+            buckets.push((&global_key, global));
+        }
+        let denied = match receipt {
+            Some(_) => None,
+            None => self.cached_denial(&buckets, &scopes, cost),
+        };
+        if let Some(denied) = denied {
+            return Ok(denied);
+        }
+
+        let transaction = match &self.breaker {
+            Some(breaker) if !breaker.try_acquire() => Err(StoreError::CircuitOpen),
+            breaker => {
+                let transaction = self.transact(&buckets, cost, receipt, tier_key).await;
+                if let Some(breaker) = breaker {
+                    match transaction {
+                        Ok(_) => breaker.record_success(),
+                        Err(_) => breaker.record_failure(),
+                    }
+                }
+                transaction
+            }
+        };
+
+        let stored = transaction.is_ok();
+        let (tier, decisions) = match (transaction, &self.fallback) {
+            (Ok(charged), fallback) => {
+                if let Some(fallback) = fallback {
+                    fallback.clear();
+                }
+                charged
+            }
+            (Err(_), Some(fallback)) => (
+                None,
+                Some(fallback.charge_all(&buckets, cost, self.clock.now())),
+            ),
+            (Err(e), None) => return Err(e),
+        };
+        let tier = tier.filter(|tier| {
+            let known = self.tiers.contains_key(tier);
+            if !known {
+                tracing::warn!(%tier, "unknown tier, going by the default limits");
+            }
+            known
+        });
+        if let Some(config) = tier.as_ref().and_then(|tier| self.tiers.get(tier)) {
+            buckets[0].1 = config;
+        }
+        let Some(mut decisions) = decisions else {
+            // Paid for already, so let through as the client's own bucket
+            // stands.
+            let (key, config) = buckets[0];
+            let decision = self.store.peek(key, config, self.clock.now()).await?;
+            return Ok(Charged {
+                decision: RateLimitDecision {
+                    allowed: true,
+                    retry_after: None,
+                    ..decision
+                },
+                config,
+                scope: (buckets.len() > 1).then_some(scopes[0]),
+                tier,
+                replayed: true,
+            });
+        };
+        let strictest = RateLimitDecision::strictest(&decisions);
+        let (key, config) = buckets[strictest];
+        match &self.denials {
+            Some(denials)
+                if stored && !decisions[strictest].allowed && config.penalty.is_none() =>
+            {
+                let tier = tier.clone().filter(|_| strictest == 0);
+                denials.insert(key, cost, &decisions[strictest], tier, self.clock.now());
+            }
+            _ => {}
+        }
+        Ok(Charged {
+            decision: decisions.swap_remove(strictest),
+            config,
+            scope: (buckets.len() > 1).then_some(scopes[strictest]),
+            tier,
+            replayed: false,
+        })
+    }
+
+    /// The denial remembered for any of `buckets`, if a charge of `cost`
+    /// would still be denied by it.
+    fn cached_denial<'a>(
+        &'a self,
+        buckets: &[(&str, &'a BucketConfig)],
+        scopes: &[LimitScope],
+        cost: i64,
+    ) -> Option<Charged<'a>> {
+        let denials = self.denials.as_ref()?;
+        let now = self.clock.now();
+        buckets
+            .iter()
+            .zip(scopes)
+            .find_map(|((key, config), scope)| {
+                let denial = denials.get(key, cost, now)?;
+                let config = denial
+                    .tier
+                    .as_ref()
+                    .and_then(|tier| self.tiers.get(tier))
+                    .unwrap_or(config);
+                Some(Charged {
+                    decision: denial.decision,
+                    config,
+                    scope: (buckets.len() > 1).then_some(*scope),
+                    tier: denial.tier,
+                    replayed: false,
+                })
+            })
+    }
+
+    /// Asks the store for the charge [`charge`](Self::charge) makes, and the
+    /// tier at `tier_key` if there is one. The decisions are `None` for a
+    /// receipt kept already.
+    async fn transact(
+        &self,
+        buckets: &[(&str, &BucketConfig)],
+        cost: i64,
+        receipt: Option<&str>,
+        tier_key: Option<&str>,
+    ) -> Result<(Option<String>, Option<Vec<RateLimitDecision>>), StoreError> {
+        let now = self.clock.now();
+        match (receipt.zip(self.idempotency_ttl), tier_key) {
+            (Some((receipt, ttl)), tier_key) => {
+                let tier = match tier_key {
+                    Some(key) => self.store.tier(key).await?,
+                    None => None,
+                };
+                let buckets = store::tiered(buckets, tier.as_deref(), &self.tiers);
+                let charged = self
+                    .store
+                    .take_tokens_once(&buckets, cost, now, receipt, ttl)
+                    .await?;
+                Ok((tier, charged))
+            }
+            (None, Some(tier_key)) => {
+                let Tiered { tier, decisions } = self
+                    .store
+                    .take_tokens_tiered(buckets, tier_key, &self.tiers, cost, now)
+                    .await?;
+                Ok((tier, Some(decisions)))
+            }
+            (None, None) => Ok((
+                None,
+                Some(self.store.take_tokens(buckets, cost, now).await?),
+            )),
+        }
+    }
+
+    /// Gives back what [`charge`](Self::charge) took from the buckets.
+    async fn refund(&self, scoped: &[(LimitScope, &str, &BucketConfig)], cost: i64) {
+        if self.breaker_open() {
+            return;
+        }
+        let mut buckets = scoped
+            .iter()
+            .map(|(_, key, config)| (*key, *config))
+            .collect::<Vec<_>>();
+        let global_key = self.key_prefix.global();
+        if let Some(global) = &self.global {
+            buckets.push((&global_key, global));
+        }
+        if let Some(denials) = &self.denials {
+            for (key, _) in &buckets {
+                denials.invalidate(key);
+            }
+        }
+        if let Err(e) = self.store.refund(&buckets, cost, self.clock.now()).await {
+            tracing::warn!(error = %e, "couldn't refund a request");
+        }
+    }
+
+    /// Counts a failed request against the failed attempts bucket at `key`,
+    /// if there is one.
+    async fn count_failure(&self, key: &str) {
+        let Some(failures) = self.refunds.as_ref().and_then(|refunds| refunds.failures()) else {
+            return;
+        };
+        if self.breaker_open() {
+            return;
+        }
+        if let Err(e) = self
+            .store
+            .take_token(key, 1, failures, self.clock.now())
+            .await
+        {
+            tracing::warn!(error = %e, "couldn't count a failed attempt");
+        }
+    }
+
+    /// What turns the identity away before it's charged, if it's out of
+    /// failed attempts at `key`. In [`Mode::Shadow`] nobody is.
+    async fn out_of_attempts(&self, key: &str) -> Option<Charged<'_>> {
+        let failures = self.refunds.as_ref()?.failures()?;
+        if self.mode == Mode::Shadow || self.breaker_open() {
+            return None;
+        }
+        match self.store.peek(key, failures, self.clock.now()).await {
+            Ok(decision) if !decision.allowed => Some(Charged {
+                decision,
+                config: failures,
+                scope: Some(LimitScope::Failures),
+                tier: None,
+                replayed: false,
+            }),
+            Ok(_) => None,
+            // The charge that follows has the store's failure to deal with.
+            Err(_) => None,
+        }
+    }
+
+    /// Whether to leave the store be for now, as it's known to be down. No
+    /// point holding a response up for it.
+    fn breaker_open(&self) -> bool {
+        self.breaker
+            .as_ref()
+            .is_some_and(|breaker| breaker.state() == BreakerState::Open)
+    }
+
+    /// How long to hold a denied request before charging it again: until
+    /// its tokens are back, if that's no later than `deadline`.
+    fn worth_waiting(
+        &self,
+        transaction: &Result<Charged<'_>, StoreError>,
+        deadline: chrono::DateTime<Utc>,
+    ) -> Option<Duration> {
+        let Ok(Charged { decision, .. }) = transaction else {
+            return None;
+        };
+        if decision.allowed || self.mode == Mode::Shadow {
+            return None;
+        }
+        let wait = decision.retry_after.filter(|wait| !wait.is_zero())?;
+        (later(self.clock.now(), wait) <= deadline).then_some(wait)
+    }
+}
+
+impl<S> Clone for AppState<S> {
+    fn clone(&self) -> Self {
+        Self {
+            store: Arc::clone(&self.store),
+            config: Arc::clone(&self.config),
+            key_extractor: Arc::clone(&self.key_extractor),
+            routes: Arc::clone(&self.routes),
+            route_groups: Arc::clone(&self.route_groups),
+            tiers: Arc::clone(&self.tiers),
+            overrides: self.overrides.clone(),
+            denials: self.denials.clone(),
+            in_flight: self.in_flight.clone(),
+            header_style: self.header_style,
+            rejection: Arc::clone(&self.rejection),
+            problem_details: self.problem_details,
+            failure_policy: self.failure_policy,
+            mode: self.mode,
+            breaker: self.breaker.clone(),
+            fallback: self.fallback.clone(),
+            global: self.global.clone(),
+            ip_limit: self.ip_limit.clone(),
+            windows: Arc::clone(&self.windows),
+            max_wait: self.max_wait,
+            refunds: self.refunds.clone(),
+            idempotency_ttl: self.idempotency_ttl,
+            body_cost: self.body_cost.clone(),
+            batch_cost: self.batch_cost.clone(),
+            quota: self.quota.clone(),
+            quota_rejection: Arc::clone(&self.quota_rejection),
+            clock: Arc::clone(&self.clock),
+            exempt_paths: Arc::clone(&self.exempt_paths),
+            exempt_preflight: self.exempt_preflight,
+            allowlist: Arc::clone(&self.allowlist),
+            blocklist: Arc::clone(&self.blocklist),
+            shared_blocklist: self.shared_blocklist,
+            blocked: Arc::clone(&self.blocked),
+            metrics_route_label: self.metrics_route_label,
+            stats: Arc::clone(&self.stats),
+            hooks: self.hooks.clone(),
+            hook_dispatch: self.hook_dispatch,
+            key_prefix: self.key_prefix.clone(),
+            key_hasher: self.key_hasher.clone(),
+        }
+    }
+}
+
+/// What [`AppState::consume_many`] or [`AppState::consume_up_to`] took.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Grant {
+    /// Tokens taken.
+    pub granted: i64,
+    /// Tokens left. When none were granted, that's how many could have been
+    /// taken instead.
+    pub available: i64,
+    /// The decision for the last charge, with when the buckets refill.
+    pub decision: RateLimitDecision,
+}
+
+/// Why [`AppState::consume_tokens`], [`consume_many`](AppState::consume_many)
+/// or [`consume_up_to`](AppState::consume_up_to) didn't decide.
+#[derive(Debug)]
+pub enum ConsumeError {
+    /// Fewer than no tokens, which would put them back instead.
+    Negative(i64),
+    /// More tokens than the buckets can ever hold, so no amount of waiting
+    /// would let them through.
+    ExceedsCapacity {
+        cost: i64,
+        capacity: i64,
+    },
+    Store(StoreError),
+}
+
+impl fmt::Display for ConsumeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Negative(cost) => write!(f, "can't charge {cost} tokens"),
+            Self::ExceedsCapacity { cost, capacity } => {
+                write!(f, "can't charge {cost} tokens to buckets of {capacity}")
+            }
+            Self::Store(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for ConsumeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Store(e) => Some(e),
+            Self::Negative(_) | Self::ExceedsCapacity { .. } => None,
+        }
+    }
+}
+
+impl From<StoreError> for ConsumeError {
+    fn from(e: StoreError) -> Self {
+        Self::Store(e)
+    }
+}
+
+/// What [`AppState::decide`] made of a request.
+#[derive(Debug)]
+pub enum Verdict {
+    /// Let through without a charge: the identity is allowlisted, or the
+    /// request costs nothing.
+    Free,
+    /// Charged, and allowed or not as the decision says.
+    Charged(RateLimitDecision),
+    /// Answered with this response without a charge: there's no identity,
+    /// it's blocked, or the request costs more than its buckets can hold.
+    Refused(Response),
+}
+
+impl BucketConfig {
+    /// The most a single request can ever be charged.
+    fn capacity(&self) -> i64 {
+        match self.algorithm {
+            Algorithm::TokenBucket => self.max_tokens.saturating_add(self.overdraft.max(0)),
+            Algorithm::Gcra | Algorithm::LeakyBucket | Algorithm::FixedWindow { .. } => {
+                self.max_tokens
+            }
+        }
+    }
+
+    /// The key of this window of the bucket at `bucket_key`, e.g.
+    /// `bucket:{..}:60s`, named after how long it takes to refill.
+    fn window_key(&self, bucket_key: &str) -> String {
+        format!("{bucket_key}:{}s", self.full_refill().as_secs())
+    }
+}
+
+impl RateLimitDecision {
+    /// Which of the decisions for a request charged against several buckets
+    /// it's answered with: the denial with the longest wait or, if they all
+    /// allowed it, the one with the fewest tokens left.
+    fn strictest(decisions: &[RateLimitDecision]) -> usize {
+        decisions
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, decision)| {
+                (
+                    decision.allowed,
+                    std::cmp::Reverse(decision.retry_after),
+                    decision.remaining,
+                )
+            })
+            .map_or(0, |(i, _)| i)
+    }
+}
+
+/// What a request was answered with, out of the buckets it was charged to.
+struct Charged<'a> {
+    decision: RateLimitDecision,
+    /// The config of the bucket the decision is for.
+    config: &'a BucketConfig,
+    /// Which bucket that is, when there's more than one.
+    scope: Option<LimitScope>,
+    /// The identity's tier, if its bucket went by one.
+    tier: Option<String>,
+    /// Whether the request was let through without a charge, having been
+    /// charged for under the same idempotency key already.
+    replayed: bool,
+}
+
+/// A bare 403 Forbidden.
+pub fn default_blocked() -> Response {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// A bare 429 Too Many Requests.
+pub fn default_rejection(_: &RateLimitDecision) -> Response {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .body(Body::empty())
+        .unwrap()
+}
+
+const BACKEND_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+fn backend_unavailable(problem_details: bool) -> Response {
+    let mut response = if problem_details {
+        ProblemDetails::backend_unavailable(BACKEND_RETRY_AFTER).into_response()
+    } else {
+        Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::empty())
+            .unwrap()
+    };
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(BACKEND_RETRY_AFTER.as_secs()),
+    );
+    response
+}
+
+fn too_many_in_flight(problem_details: bool, max: usize) -> Response {
+    let mut response = if problem_details {
+        ProblemDetails::too_many_in_flight(max).into_response()
+    } else {
+        Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .body(Body::empty())
+            .unwrap()
+    };
+    headers::insert_scope(response.headers_mut(), LimitScope::Concurrency);
+    response
+}
+
+fn length_required(problem_details: bool) -> Response {
+    if problem_details {
+        return ProblemDetails::length_required().into_response();
+    }
+    Response::builder()
+        .status(StatusCode::LENGTH_REQUIRED)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Number of tokens a request costs, one when absent.
+///
+/// Insert it into the request extensions from a layer that runs before the
+/// rate limiter. Requests costing zero skip the store entirely; ones costing more
+/// than the bucket can ever hold are rejected with 413 Payload Too Large.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestCost(pub u32);
+
+/// The limiter as an axum middleware, for
+/// [`middleware::from_fn_with_state`](axum::middleware::from_fn_with_state).
+/// [`RateLimiterLayer`] does the same for any tower stack.
+pub async fn rate_limiter_middleware<S>(
+    State(state): State<AppState<S>>,
+    request: Request,
+    next: Next,
+) -> Response
+where
+    S: BucketStore,
+{
+    match RateLimiterService::new(next, state).oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+pub(crate) async fn limit<S, B, I, ResBody>(
+    state: AppState<S>,
+    request: http::Request<B>,
+    inner: I,
+) -> Result<Response, I::Error>
+where
+    S: BucketStore,
+    I: Service<http::Request<B>, Response = http::Response<ResBody>>,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    // Nested routers strip their prefix from the URI, so go by the one the
+    // client sent.
+    let path = match request.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri.path(),
+        None => request.uri().path(),
+    };
+    if state.exempt_paths.matches(path) || state.exempt_preflight && is_preflight(&request) {
+        return forward(inner, request).await;
+    }
+
+    let matched_path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned());
+    let route = matched_path
+        .as_deref()
+        .filter(|_| state.metrics_route_label);
+
+    let (parts, body) = request.into_parts();
+    let resolved = resolve(&state, &parts).await;
+    let request = http::Request::from_parts(parts, body);
+    let (redis_key, others, config, tiered, cost) = match resolved {
+        Ok(Resolved::Charge {
+            redis_key,
+            others,
+            config,
+            tiered,
+            cost,
+        }) => (redis_key, others, config, tiered, cost),
+        Ok(Resolved::Bypass) => {
+            telemetry::record_outcome(
+                &state.stats,
+                Outcome::Allowed,
+                matched_path.as_deref(),
+                route,
+            );
+            let mut response = forward(inner, request).await?;
+            headers::insert_bypass(response.headers_mut());
+            return Ok(response);
+        }
+        Err((outcome, response)) => {
+            telemetry::record_outcome(&state.stats, outcome, matched_path.as_deref(), route);
+            return Ok(response);
+        }
+    };
+    if cost == 0 {
+        telemetry::record_outcome(
+            &state.stats,
+            Outcome::Allowed,
+            matched_path.as_deref(),
+            route,
+        );
+        return forward(inner, request).await;
+    }
+    let failures_key = failures_key(&redis_key);
+    if let Some(charged) = state.out_of_attempts(&failures_key).await {
+        telemetry::record_outcome(
+            &state.stats,
+            Outcome::Denied,
+            matched_path.as_deref(),
+            route,
+        );
+        return respond(&state, charged, redis_key, request, inner).await;
+    }
+    let in_flight = match &state.in_flight {
+        Some(in_flight) => match in_flight.acquire(&redis_key) {
+            Some(guard) => Some(guard),
+            None => {
+                telemetry::record_outcome(
+                    &state.stats,
+                    Outcome::Denied,
+                    matched_path.as_deref(),
+                    route,
+                );
+                return Ok(too_many_in_flight(state.problem_details, in_flight.max()));
+            }
+        },
+        None => None,
+    };
+
+    // Only the hashed key goes into the span; the identity itself is never
+    // logged.
+    let span = tracing::info_span!(
+        "rate_limit",
+        bucket = %redis_key,
+        cost,
+        allowed = tracing::field::Empty,
+        remaining = tracing::field::Empty,
+        store_ms = tracing::field::Empty,
+        waited_ms = tracing::field::Empty,
+    );
+    let mut buckets = vec![(LimitScope::Token, redis_key.as_str(), &*config)];
+    for (scope, key, config) in &others {
+        buckets.push((*scope, key, config));
+    }
+    let receipt = state
+        .idempotency_ttl
+        .and_then(|_| request.headers().get(IDEMPOTENCY_KEY))
+        .and_then(|key| key.to_str().ok())
+        .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+        .map(|key| format!("{redis_key}:idem:{key}"));
+    let tier_key = tiered.then(|| state.key_prefix.tier(&redis_key));
+    let started = Instant::now();
+    let deadline = later(state.clock.now(), state.max_wait);
+    let mut waited = Duration::ZERO;
+    // The store is let go of between tries, so a held request ties nothing
+    // up but itself.
+    let transaction = loop {
+        let transaction = state
+            .charge(&buckets, cost, receipt.as_deref(), tier_key.as_deref())
+            .instrument(span.clone())
+            .await;
+        let Some(wait) = state.worth_waiting(&transaction, deadline) else {
+            break transaction;
+        };
+        state.clock.sleep(wait).await;
+        waited += wait;
+    };
+    let elapsed = started.elapsed().saturating_sub(waited);
+    telemetry::record_store_duration(elapsed, route);
+    span.record("store_ms", elapsed.as_secs_f64() * 1000.0);
+    if !waited.is_zero() {
+        span.record("waited_ms", waited.as_millis() as u64);
+    }
+
+    match &transaction {
+        Ok(Charged { decision, .. }) => {
+            span.record("allowed", decision.allowed);
+            span.record("remaining", decision.remaining);
+            if !decision.allowed {
+                span.in_scope(|| {
+                    tracing::warn!(
+                        retry_after_ms =
+                            decision.retry_after.unwrap_or_default().as_millis() as u64,
+                        shadow = state.mode == Mode::Shadow,
+                        "rate limit exceeded"
+                    );
+                });
+            }
+        }
+        Err(e) => span.in_scope(|| tracing::error!(error = %e, "bucket store failed")),
+    }
+    // The span covers the transaction, not the handler that runs after it.
+    drop(span);
+
+    let response = match transaction {
+        Ok(charged) => {
+            let decision = &charged.decision;
+            telemetry::record_decision(decision);
+            if let Some(hooks) = &state.hooks {
+                let ctx = DecisionCtx {
+                    bucket_key: redis_key.clone(),
+                    route: matched_path.clone(),
+                    remaining: decision.remaining,
+                    retry_after: decision.retry_after,
+                    tier: charged.tier.clone(),
+                };
+                hooks::notify(hooks, state.hook_dispatch, decision.allowed, ctx).await;
+            }
+            let outcome = if decision.allowed {
+                Outcome::Allowed
+            } else {
+                Outcome::Denied
+            };
+            telemetry::record_outcome(&state.stats, outcome, matched_path.as_deref(), route);
+            telemetry::record_remaining(decision.remaining, route);
+            let charged_for = decision.allowed && !charged.replayed;
+            let tier = charged.tier.clone();
+            let response = respond(&state, charged, redis_key.clone(), request, inner).await?;
+            let (refunded, failed) = state.refunds.as_ref().map_or((false, false), |refunds| {
+                (
+                    refunds.matches(response.status()),
+                    refunds.failed(response.status()),
+                )
+            });
+            if charged_for && refunded {
+                if let Some(config) = tier.as_ref().and_then(|tier| state.tiers.get(tier)) {
+                    buckets[0].2 = config;
+                }
+                state.refund(&buckets, cost).await;
+            }
+            if charged_for && failed {
+                state.count_failure(&failures_key).await;
+            }
+            Ok(response)
+        }
+        // The store failing says nothing about the client, so don't answer 429.
+        Err(_) => {
+            telemetry::record_outcome(&state.stats, Outcome::Error, matched_path.as_deref(), route);
+            match state.failure_policy {
+                FailurePolicy::Open => forward(inner, request).await,
+                FailurePolicy::Closed => Ok(backend_unavailable(state.problem_details)),
+            }
+        }
+    }?;
+    Ok(match in_flight {
+        Some(guard) => guard.hold(response),
+        None => response,
+    })
+}
+
+enum Resolved<'a> {
+    /// The identity is allowlisted.
+    Bypass,
+    Charge {
+        redis_key: String,
+        /// The other buckets charged with it, with their keys: its other
+        /// windows, the client address's under an [`IpLimit`] and the
+        /// identity's quota.
+        others: Vec<(LimitScope, String, &'a BucketConfig)>,
+        config: Cow<'a, BucketConfig>,
+        /// Whether the bucket goes by the identity's tier.
+        tiered: bool,
+        cost: i64,
+    },
+}
+
+/// Works out who is charged how much against which bucket. Requests that
+/// can't be charged at all are answered right away.
+async fn resolve<'a, S: BucketStore>(
+    state: &'a AppState<S>,
+    parts: &http::request::Parts,
+) -> Result<Resolved<'a>, (Outcome, Response)> {
+    let identity = match state.key_extractor.extract(parts).await {
+        Ok(identity) => identity,
+        Err(response) if state.problem_details => {
+            return Err((Outcome::Unauthorized, problem::fill_unauthorized(response)));
+        }
+        Err(response) => return Err((Outcome::Unauthorized, response)),
+    };
+    if state.allowlist.contains(&identity) {
+        return Ok(Resolved::Bypass);
+    }
+    if state.is_blocked(&identity).await {
+        return Err((Outcome::Denied, (state.blocked)()));
+    }
+    let ip_bucket = match &state.ip_limit {
+        Some(ip_limit) => match ip_limit.extractor.extract(parts).await {
+            Ok(ip) => Some((
+                LimitScope::Ip,
+                state.key_prefix.ip_bucket(&state.key_hasher.hash(&ip)),
+                &ip_limit.config,
+            )),
+            Err(response) if state.problem_details => {
+                return Err((Outcome::Unauthorized, problem::fill_unauthorized(response)));
+            }
+            Err(response) => return Err((Outcome::Unauthorized, response)),
+        },
+        None => None,
+    };
+
+    let matched_path = parts.extensions.get::<MatchedPath>();
+    let route = matched_path.and_then(|path| state.routes.get_key_value(path.as_str()));
+    let bucket_key = state.bucket_key(&identity);
+    let quota = state
+        .quota
+        .as_ref()
+        .map(|quota| (LimitScope::Quota, quota_key(&bucket_key), quota));
+    let (redis_key, config, mut others, tiered) =
+        match (parts.extensions.get::<RouteLimit>(), route) {
+            (Some(limit), _) => {
+                let namespace = match (&limit.group, matched_path) {
+                    (Some(group), _) => Some(format!("group:{group}")),
+                    (None, path) => path.map(|path| path.as_str().to_owned()),
+                };
+                let redis_key = match namespace {
+                    Some(namespace) => format!("{bucket_key}:{namespace}"),
+                    None => bucket_key,
+                };
+                (
+                    redis_key,
+                    Cow::Owned(limit.config.clone()),
+                    Vec::new(),
+                    false,
+                )
+            }
+            (None, Some((path, config))) => {
+                let redis_key = match state.route_groups.get(path) {
+                    Some(group) => format!("{bucket_key}:group:{group}"),
+                    None => format!("{bucket_key}:{path}"),
+                };
+                (redis_key, Cow::Borrowed(config), Vec::new(), false)
+            }
+            (None, None) => {
+                let windows = state
+                    .windows
+                    .iter()
+                    .map(|window| (LimitScope::Token, window.window_key(&bucket_key), window))
+                    .collect();
+                match state.config_override(&bucket_key).await {
+                    Some(config) => (bucket_key, Cow::Owned(config), windows, false),
+                    None => {
+                        let tiered = !state.tiers.is_empty();
+                        let config = Cow::Owned(BucketConfig::clone(&state.config()));
+                        (bucket_key, config, windows, tiered)
+                    }
+                }
+            }
+        };
+
+    let batch = state
+        .batch_cost
+        .as_ref()
+        .and_then(|batch_cost| batch_cost.cost(&parts.headers));
+    let cost = match (
+        parts.extensions.get::<RequestCost>(),
+        batch,
+        &state.body_cost,
+    ) {
+        (Some(RequestCost(cost)), ..) => i64::from(*cost),
+        (None, Some(cost), _) => cost,
+        (None, None, Some(body_cost)) => match body_cost.cost(&parts.headers) {
+            Some(cost) => cost,
+            None => return Err((Outcome::Denied, length_required(state.problem_details))),
+        },
+        (None, None, None) => 1,
+    };
+    others.extend(ip_bucket.into_iter().chain(quota));
+
+    // No amount of waiting would let this request through.
+    let capacity = [
+        Some(config.capacity()),
+        others.iter().map(|(_, _, other)| other.capacity()).min(),
+        state.global.as_ref().map(BucketConfig::capacity),
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+    .unwrap_or(config.max_tokens);
+    if cost > capacity {
+        if state.problem_details {
+            return Err((
+                Outcome::Denied,
+                ProblemDetails::cost_exceeds_capacity(cost, capacity).into_response(),
+            ));
+        }
+        return Err((
+            Outcome::Denied,
+            Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Body::empty())
+                .unwrap(),
+        ));
+    }
+
+    Ok(Resolved::Charge {
+        redis_key,
+        others,
+        config,
+        tiered,
+        cost,
+    })
+}
+
+async fn respond<S, B, I, ResBody>(
+    state: &AppState<S>,
+    charged: Charged<'_>,
+    redis_key: String,
+    mut request: http::Request<B>,
+    inner: I,
+) -> Result<Response, I::Error>
+where
+    I: Service<http::Request<B>, Response = http::Response<ResBody>>,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    let Charged {
+        decision,
+        config,
+        scope,
+        tier,
+        ..
+    } = charged;
+    let would_block = !decision.allowed && state.mode == Mode::Shadow;
+    if !decision.allowed && !would_block {
+        let mut response = match scope {
+            Some(LimitScope::Quota) => (state.quota_rejection)(&decision),
+            _ => (state.rejection)(&decision),
+        };
+        headers::insert_rate_limit_headers(
+            response.headers_mut(),
+            state.header_style,
+            &decision,
+            config,
+            state.clock.now(),
+        );
+        if let Some(scope) = scope {
+            headers::insert_scope(response.headers_mut(), scope);
+        }
+        if let Some(tier) = &tier {
+            headers::insert_tier(response.headers_mut(), tier);
+        }
+        headers::insert_retry_after(response.headers_mut(), &decision);
+        return Ok(response);
+    }
+
+    request.extensions_mut().insert(RateLimitInfo {
+        limit: decision.limit,
+        remaining: decision.remaining,
+        reset_at: decision.reset_at,
+        key_hash: redis_key,
+    });
+    let mut response = forward(inner, request).await?;
+    headers::insert_rate_limit_headers(
+        response.headers_mut(),
+        state.header_style,
+        &decision,
+        config,
+        state.clock.now(),
+    );
+    if let Some(scope) = scope {
+        headers::insert_scope(response.headers_mut(), scope);
+    }
+    if let Some(tier) = &tier {
+        headers::insert_tier(response.headers_mut(), tier);
+    }
+    if would_block {
+        headers::insert_would_block(response.headers_mut());
+    }
+    Ok(response)
+}
+
+fn is_preflight<B>(request: &http::Request<B>) -> bool {
+    request.method() == http::Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Hands the request on to the service being limited.
+async fn forward<B, I, ResBody>(inner: I, request: http::Request<B>) -> Result<Response, I::Error>
+where
+    I: Service<http::Request<B>, Response = http::Response<ResBody>>,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    let response = inner.oneshot(request).await?;
+    Ok(response.map(Body::new))
+}
+
+#[cfg(all(test, feature = "memory", feature = "redis"))]
+mod tests {
+    use std::{
+        collections::{HashMap, VecDeque},
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{
+            Arc, Mutex as StdMutex,
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+        },
+        time::{Duration, Instant},
+    };
+
+    use axum::{
+        Router,
+        body::Body,
+        extract::ConnectInfo,
+        http::{HeaderName, HeaderValue, Request, Response, StatusCode, header, request::Parts},
+        middleware,
+        routing::get,
+    };
+    use chrono::Utc;
+    #[cfg(feature = "metrics")]
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    #[cfg(feature = "otel")]
+    use opentelemetry::trace::TracerProvider;
+    #[cfg(feature = "otel")]
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+    use redis::{
+        ConnectionLike, ErrorKind, RedisError, RedisFuture, RedisResult, Value,
+        cluster_routing::get_slot, cmd,
+    };
+    use redis_test::{MockCmd, MockRedisConnection};
+    use tower::{Service, ServiceBuilder, ServiceExt};
+    #[cfg(feature = "otel")]
+    use tracing::Instrument;
+    #[cfg(feature = "otel")]
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::{
+        Algorithm, Allowlist, AppState, AsyncRedisStore, BatchCost, BearerTokenExtractor,
+        BlockingReconnectingConnection, BodyCost, BoxFuture, BreakerState, BucketConfig,
+        BucketStore, CircuitBreakerConfig, Clock, ConnectionPool, ConsumeError,
+        DEFAULT_REDIS_TIMEOUT, DecisionCtx, DenialLog, ExemptPaths, FailurePolicy,
+        GLOBAL_BUCKET_KEY, HeaderKeyExtractor, HeaderStyle, HookDispatch, KeyExtractor, KeyHasher,
+        KeyPrefix, Leaderboard, MAX_IDEMPOTENCY_KEY_LEN, MAX_TOKEN_HEADER_LEN, MemoryStore,
+        MissingLength, MissingTokenPolicy, Mode, OutcomeCounts, PROBLEM_JSON, PeerIpExtractor,
+        Penalty, PenaltyConfig, ProblemDetails, RateLimitHooks, RateLimitInfo, RateLimiterLayer,
+        ReconnectingConnection, RedisStore, Refunds, RequestCost, StorageFormat, StoreError,
+        Tiered, TokenPersistence, TransactionRetry, TrustedProxies, Verdict,
+        admin::BucketBody,
+        admin_router, cleanup_stale_buckets, encoding, metrics_router, rate_limiter_middleware,
+        testing::{self, ManualClock},
+    };
+
+    use super::quota_key;
+
+    fn generate_bucket_key(identity: &str) -> String {
+        testing::bucket_key(identity)
+    }
+
+    fn generate_ip_bucket_key(ip: &str) -> String {
+        KeyPrefix::default().ip_bucket(&KeyHasher::default().hash(ip))
+    }
+
+    fn tier_key(bucket_key: &str) -> String {
+        KeyPrefix::default().tier(bucket_key)
+    }
+
+    /// Connection double that answers commands by name only and records what it
+    /// receives, for flows whose writes depend on `Utc::now()` and therefore
+    /// can't be matched byte-for-byte by `MockRedisConnection`.
+    #[derive(Clone)]
+    struct ScriptedConnection {
+        replies: Arc<StdMutex<VecDeque<(&'static str, Value)>>>,
+        received: Arc<StdMutex<Vec<Vec<String>>>>,
+        /// Values of `SET` commands as sent, which may not be UTF-8.
+        sets: Arc<StdMutex<Vec<Vec<u8>>>>,
+        down: Arc<AtomicBool>,
+        broken: Arc<AtomicBool>,
+        delay: Duration,
+    }
+
+    impl ScriptedConnection {
+        fn new(replies: Vec<(&'static str, Value)>) -> Self {
+            Self {
+                replies: Arc::new(StdMutex::new(replies.into())),
+                received: Arc::default(),
+                sets: Arc::default(),
+                down: Arc::default(),
+                broken: Arc::default(),
+                delay: Duration::ZERO,
+            }
+        }
+
+        /// Makes every reply take `delay`, asleep on the async path and
+        /// blocking the thread on the sync one.
+        fn with_delay(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+
+        /// Makes every command fail with a connection error while `down`.
+        fn set_down(&self, down: bool) {
+            self.down.store(down, Ordering::SeqCst);
+        }
+
+        /// Makes the next command fail as if Redis had restarted under it.
+        fn break_pipe(&self) {
+            self.broken.store(true, Ordering::SeqCst);
+        }
+
+        fn received(&self) -> Vec<Vec<String>> {
+            self.received.lock().unwrap().clone()
+        }
+
+        /// The bucket state written by the last `SET` command.
+        fn written(&self) -> TokenPersistence {
+            let sets = self.sets.lock().unwrap();
+            encoding::decode(sets.last().expect("no SET issued"))
+                .unwrap()
+                .unwrap()
+        }
+
+        /// The raw value written by the last `SET` command.
+        fn written_raw(&self) -> Vec<u8> {
+            self.sets
+                .lock()
+                .unwrap()
+                .last()
+                .expect("no SET issued")
+                .clone()
+        }
+
+        fn reply(&mut self, commands: Vec<Vec<Vec<u8>>>) -> RedisResult<Value> {
+            self.sets.lock().unwrap().extend(
+                commands
+                    .iter()
+                    .filter(|command| command[0] == b"SET")
+                    .map(|command| command[2].clone()),
+            );
+            let commands = commands
+                .into_iter()
+                .map(|command| {
+                    command
+                        .iter()
+                        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let names = commands
+                .iter()
+                .map(|command| command[0].as_str())
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.received.lock().unwrap().extend(commands);
+            if self.down.load(Ordering::SeqCst) {
+                return Err(refused());
+            }
+            if self.broken.swap(false, Ordering::SeqCst) {
+                return Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe).into());
+            }
+
+            match self.replies.lock().unwrap().pop_front() {
+                Some((expected, value)) if expected == names => Ok(value),
+                other => Err(RedisError::from((
+                    ErrorKind::ClientError,
+                    "unexpected command",
+                    format!("expected={:?}, actual={}", other.map(|(n, _)| n), names),
+                ))),
+            }
+        }
+    }
+
+    fn parse_packed(mut bytes: &[u8]) -> Vec<Vec<Vec<u8>>> {
+        fn line<'a>(bytes: &mut &'a [u8]) -> &'a [u8] {
+            let end = bytes.windows(2).position(|w| w == b"\r\n").unwrap();
+            let (line, rest) = bytes.split_at(end);
+            *bytes = &rest[2..];
+            line
+        }
+        fn number(line: &[u8]) -> usize {
+            std::str::from_utf8(&line[1..]).unwrap().parse().unwrap()
+        }
+
+        let mut commands = Vec::new();
+        while !bytes.is_empty() {
+            let args = number(line(&mut bytes));
+            let command = (0..args)
+                .map(|_| {
+                    let len = number(line(&mut bytes));
+                    let arg = bytes[..len].to_vec();
+                    bytes = &bytes[len + 2..];
+                    arg
+                })
+                .collect();
+            commands.push(command);
+        }
+        commands
+    }
+
+    impl ConnectionLike for ScriptedConnection {
+        fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+            std::thread::sleep(self.delay);
+            self.reply(parse_packed(cmd))
+        }
+
+        /// A pipeline expecting several replies takes them from a scripted
+        /// array.
+        fn req_packed_commands(
+            &mut self,
+            cmd: &[u8],
+            _offset: usize,
+            count: usize,
+        ) -> RedisResult<Vec<Value>> {
+            std::thread::sleep(self.delay);
+            self.reply(parse_packed(cmd)).map(|value| match value {
+                Value::Array(values) if count > 1 => values,
+                value => vec![value],
+            })
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+
+        fn check_connection(&mut self) -> bool {
+            true
+        }
+
+        fn is_open(&self) -> bool {
+            true
+        }
+    }
+
+    impl redis::aio::ConnectionLike for ScriptedConnection {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a redis::Cmd) -> RedisFuture<'a, Value> {
+            let reply = self.reply(parse_packed(&cmd.get_packed_command()));
+            let delay = self.delay;
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                reply
+            })
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            cmd: &'a redis::Pipeline,
+            offset: usize,
+            count: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            // Plain pipelines are scripted as an array with one reply per
+            // command; transactions as the single `EXEC` reply. Like
+            // `MultiplexedConnection`, a server error fails the whole pipeline.
+            let reply = self
+                .reply(parse_packed(&cmd.get_packed_pipeline()))
+                .and_then(|value| match value {
+                    Value::Array(replies) if offset == 0 && replies.len() == count => Ok(replies),
+                    value => value.extract_error().map(|value| vec![value]),
+                });
+            let delay = self.delay;
+            Box::pin(async move {
+                tokio::time::sleep(delay).await;
+                reply
+            })
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    fn limited<S>(
+        state: AppState<S>,
+    ) -> impl Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone
+    where
+        S: BucketStore,
+    {
+        answering(state, StatusCode::OK)
+    }
+
+    /// [`limited`], with the service answering every request with `status`.
+    fn answering<S>(
+        state: AppState<S>,
+        status: StatusCode,
+    ) -> impl Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone
+    where
+        S: BucketStore,
+    {
+        let inner = tower::service_fn(move |_req: Request<Body>| async move {
+            Ok::<_, Infallible>(
+                Response::builder()
+                    .status(status)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        });
+
+        ServiceBuilder::new()
+            .layer(RateLimiterLayer::new(state))
+            .service(inner)
+    }
+
+    async fn send<S>(svc: S, token: &str) -> Response<Body>
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    {
+        send_with(svc, "Bearer", token).await
+    }
+
+    async fn send_with<S>(svc: S, header: &str, value: &str) -> Response<Body>
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    {
+        call(svc, Request::builder().header(header, value)).await
+    }
+
+    async fn call<S>(svc: S, request: axum::http::request::Builder) -> Response<Body>
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    {
+        svc.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn header_i64(response: &Response<Body>, name: &str) -> i64 {
+        response.headers()[name].to_str().unwrap().parse().unwrap()
+    }
+
+    fn stored(bucket: &TokenPersistence) -> Value {
+        Value::BulkString(serde_json::to_vec(bucket).unwrap())
+    }
+
+    fn committed() -> Value {
+        Value::Array(vec![Value::Okay])
+    }
+
+    fn allow_script(bucket: Option<&TokenPersistence>) -> ScriptedConnection {
+        ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", bucket.map_or(Value::Nil, stored)),
+            ("MULTI SET EXEC", committed()),
+        ])
+    }
+
+    fn deny_script(bucket: &TokenPersistence) -> ScriptedConnection {
+        ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", stored(bucket)),
+            ("UNWATCH", Value::Okay),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_allows_request_via_servicebuilder() {
+        let config = BucketConfig::default();
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn.clone()), config);
+
+        let response = send(limited(state), "127.0.0.1").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.written().tokens, 9);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+        let next_refill = (Utc::now() + chrono::Duration::hours(1)).timestamp();
+        assert!((header_i64(&response, "X-RateLimit-Reset") - next_refill).abs() <= 2);
+        assert!(
+            conn.received()
+                .iter()
+                .all(|command| command[0] != "127.0.0.1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_denies_request() {
+        let clock = ManualClock::default();
+        let config = BucketConfig::default();
+        let mut commands = testing::mock_bucket(&generate_bucket_key("127.0.0.1"), 0, clock.now());
+        commands.push(MockCmd::new(cmd("UNWATCH"), Ok(Value::Okay)));
+        let mock = MockRedisConnection::new(commands);
+        let state = AppState::new(RedisStore::new(mock), config).with_clock(clock);
+
+        let response = send(limited(state), "127.0.0.1").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_mock_sequences_match_the_transaction() {
+        let clock = ManualClock::default();
+        let config = BucketConfig {
+            max_tokens: 2,
+            ..BucketConfig::default()
+        };
+        let key = generate_bucket_key("127.0.0.1");
+        let start = clock.now();
+        let mut commands = testing::mock_allow_sequence(&key, &config, None, start);
+        commands.extend(testing::mock_allow_sequence(
+            &key,
+            &config,
+            Some((1, start)),
+            start,
+        ));
+        commands.extend(testing::mock_deny_sequence(&key, &config, 0, start, start));
+        let later = start + chrono::Duration::hours(1);
+        commands.extend(testing::mock_allow_sequence(
+            &key,
+            &config,
+            Some((0, start)),
+            later,
+        ));
+        let state = AppState::new(RedisStore::new(MockRedisConnection::new(commands)), config)
+            .with_clock(clock.clone());
+        let app = limited(state);
+
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            statuses.push(send(app.clone(), "127.0.0.1").await.status());
+        }
+        clock.advance(Duration::from_secs(60 * 60));
+        statuses.push(send(app, "127.0.0.1").await.status());
+
+        let (ok, denied) = (StatusCode::OK, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(statuses, [ok, ok, denied, ok]);
+    }
+
+    fn conflicting(attempts: usize) -> Vec<(&'static str, Value)> {
+        (0..attempts)
+            .flat_map(|_| {
+                [
+                    ("WATCH", Value::Okay),
+                    ("GET", Value::Nil),
+                    ("MULTI SET EXEC", Value::Nil),
+                ]
+            })
+            .collect()
+    }
+
+    fn no_backoff(max_retries: u32) -> TransactionRetry {
+        TransactionRetry {
+            max_retries,
+            base_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transaction_retries_after_conflicts() {
+        let mut script = conflicting(2);
+        script.extend([
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            ("MULTI SET EXEC", committed()),
+        ]);
+        let conn = ScriptedConnection::new(script);
+        let state = AppState::new(
+            RedisStore::new(conn.clone()).with_retry(no_backoff(5)),
+            BucketConfig::default(),
+        );
+
+        let response = send(limited(state.clone()), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.store.conflicts(), 2);
+        assert_eq!(
+            conn.received().iter().filter(|c| c[0] == "WATCH").count(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exhausted_retries_are_a_backend_failure() {
+        let conn = ScriptedConnection::new(conflicting(3));
+        let state = AppState::new(
+            RedisStore::new(conn.clone()).with_retry(no_backoff(2)),
+            BucketConfig::default(),
+        );
+
+        let response = send(limited(state.clone()), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(state.store.conflicts(), 3);
+
+        let conn = ScriptedConnection::new(conflicting(3));
+        let state = AppState::new(
+            RedisStore::new(conn).with_retry(no_backoff(2)),
+            BucketConfig::default(),
+        )
+        .with_failure_policy(FailurePolicy::Open);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// Keys shared by [`WatchingConnection`]s, each with how many times it's
+    /// been written.
+    type WatchedKeys = Arc<StdMutex<HashMap<Vec<u8>, (Vec<u8>, u64)>>>;
+
+    /// Just enough of Redis for the sync store's transaction, down to `EXEC`
+    /// failing once a watched key has been written by someone else. Every
+    /// connection waits at `read` after its first `GET`, so that the requests
+    /// on them all read before any writes.
+    struct WatchingConnection {
+        keys: WatchedKeys,
+        watched: Vec<(Vec<u8>, u64)>,
+        read: Option<Arc<std::sync::Barrier>>,
+    }
+
+    impl WatchingConnection {
+        fn version(keys: &HashMap<Vec<u8>, (Vec<u8>, u64)>, key: &[u8]) -> u64 {
+            keys.get(key).map_or(0, |(_, version)| *version)
+        }
+
+        fn execute(&mut self, commands: Vec<Vec<Vec<u8>>>) -> RedisResult<Value> {
+            let mut keys = self.keys.lock().unwrap();
+            let command = &commands[0];
+            match command[0].as_slice() {
+                b"WATCH" => {
+                    for key in &command[1..] {
+                        self.watched.push((key.clone(), Self::version(&keys, key)));
+                    }
+                    Ok(Value::Okay)
+                }
+                b"UNWATCH" => {
+                    self.watched.clear();
+                    Ok(Value::Okay)
+                }
+                b"GET" => {
+                    let value = keys
+                        .get(&command[1])
+                        .map_or(Value::Nil, |(value, _)| Value::BulkString(value.clone()));
+                    drop(keys);
+                    if let Some(read) = self.read.take() {
+                        read.wait();
+                    }
+                    Ok(value)
+                }
+                b"MULTI" => {
+                    let watched = std::mem::take(&mut self.watched);
+                    if watched
+                        .iter()
+                        .any(|(key, version)| Self::version(&keys, key) != *version)
+                    {
+                        return Ok(Value::Nil);
+                    }
+                    let queued = &commands[1..commands.len() - 1];
+                    for set in queued {
+                        assert_eq!(set[0], b"SET");
+                        let version = Self::version(&keys, &set[1]) + 1;
+                        keys.insert(set[1].clone(), (set[2].clone(), version));
+                    }
+                    Ok(Value::Array(vec![Value::Okay; queued.len()]))
+                }
+                other => panic!("unexpected {}", String::from_utf8_lossy(other)),
+            }
+        }
+    }
+
+    impl ConnectionLike for WatchingConnection {
+        fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+            self.execute(parse_packed(cmd))
+        }
+
+        fn req_packed_commands(
+            &mut self,
+            cmd: &[u8],
+            _offset: usize,
+            _count: usize,
+        ) -> RedisResult<Vec<Value>> {
+            self.execute(parse_packed(cmd)).map(|value| vec![value])
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+
+        fn check_connection(&mut self) -> bool {
+            true
+        }
+
+        fn is_open(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_for_the_last_token() {
+        let clock = ManualClock::default();
+        let config = BucketConfig::default();
+        let key = generate_bucket_key("127.0.0.1");
+        let last = TokenPersistence::holding(&config, 1, clock.now());
+        let keys = WatchedKeys::default();
+        keys.lock()
+            .unwrap()
+            .insert(key.clone().into_bytes(), (encoding::encode(&last), 1));
+        let read = Arc::new(std::sync::Barrier::new(2));
+        let connections = (0..2).map(|_| WatchingConnection {
+            keys: Arc::clone(&keys),
+            watched: Vec::new(),
+            read: Some(Arc::clone(&read)),
+        });
+        let store = RedisStore::from_pool(ConnectionPool::new(connections))
+            .with_retry(no_backoff(3))
+            .with_timeout(Duration::from_secs(5));
+        let state = AppState::new(store, config).with_clock(clock);
+        let app = limited(state.clone());
+
+        let (first, second) = tokio::join!(send(app.clone(), "127.0.0.1"), send(app, "127.0.0.1"));
+
+        let mut statuses = [first.status(), second.status()];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::TOO_MANY_REQUESTS]);
+        // The loser read the last token too, and lost it at EXEC.
+        assert_eq!(state.store.conflicts(), 1);
+        let (stored, version) = keys.lock().unwrap()[key.as_bytes()].clone();
+        assert_eq!(encoding::decode(&stored).unwrap().unwrap().tokens, 0);
+        assert_eq!(version, 2);
+    }
+
+    #[tokio::test]
+    async fn test_written_bucket_expires_once_refilled() {
+        let conn = allow_script(Some(&TokenPersistence {
+            tokens: 6,
+            last_updated: Utc::now(),
+            penalty: None,
+        }));
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        send(limited(state), "abc").await;
+
+        let received = conn.received();
+        let set = received.iter().find(|c| c[0] == "SET").unwrap();
+        // Five tokens short at one per hour.
+        assert_eq!(set[3..], ["EX", "18000"]);
+    }
+
+    #[tokio::test]
+    async fn test_gcra_bucket_is_stored_as_its_arrival_time() {
+        let conn = allow_script(None);
+        let config = BucketConfig {
+            algorithm: Algorithm::Gcra,
+            ..BucketConfig::burst(1, 10, Duration::from_secs(3600))
+        };
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = AppState::new(RedisStore::new(conn.clone()), config).with_clock(clock);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let received = conn.received();
+        let set = received.iter().find(|c| c[0] == "SET").unwrap();
+        let tat = "2025-03-01T12:06:00Z"
+            .parse::<chrono::DateTime<Utc>>()
+            .unwrap();
+        assert_eq!(
+            set[2..],
+            [
+                tat.timestamp_millis().to_string(),
+                "EX".into(),
+                "360".into()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_bucket_is_rewritten_in_the_built_encoding() {
+        let legacy = TokenPersistence {
+            tokens: 4,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = allow_script(Some(&legacy));
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let leading = if cfg!(feature = "msgpack") {
+            encoding::MSGPACK
+        } else {
+            b'{'
+        };
+        assert_eq!(conn.written_raw()[0], leading);
+        assert_eq!(conn.written().tokens, 3);
+        assert_eq!(
+            conn.written().last_updated.timestamp_millis(),
+            legacy.last_updated.timestamp_millis()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unversioned_bucket_is_upgraded() {
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            (
+                "GET",
+                Value::BulkString(
+                    br#"{"tokens":4,"last_updated":"2025-03-01T12:00:00Z"}"#.to_vec(),
+                ),
+            ),
+            ("MULTI SET EXEC", committed()),
+        ]);
+        // Without refills, the stored tokens show through however old it is.
+        let config = BucketConfig {
+            refill_rate: 0,
+            ..BucketConfig::default()
+        };
+        let state = AppState::new(RedisStore::new(conn.clone()), config);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 3);
+        assert_eq!(conn.written().tokens, 3);
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_bucket_starts_fresh() {
+        for stored in [
+            &br#"{"tokens":0,"last_upd"#[..],
+            &[0xff, 0xfe, 0x00, 0x7b],
+            br#"{"tokens":"none","last_updated":[2025]}"#,
+        ] {
+            let conn = ScriptedConnection::new(vec![
+                ("WATCH", Value::Okay),
+                ("GET", Value::BulkString(stored.to_vec())),
+                ("MULTI SET EXEC", committed()),
+            ]);
+            let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+            let response = send(limited(state), "abc").await;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(conn.written().tokens, 9);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bucket_from_a_newer_version_starts_fresh() {
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            (
+                "GET",
+                Value::BulkString(br#"{"version":2,"remaining":0.5,"window":[1,2]}"#.to_vec()),
+            ),
+            ("MULTI SET EXEC", committed()),
+        ]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+        assert_eq!(conn.written().tokens, 9);
+    }
+
+    fn hash_committed() -> Value {
+        Value::Array(vec![Value::Int(0), Value::Int(2), Value::Int(1)])
+    }
+
+    fn hset_fields(conn: &ScriptedConnection) -> Vec<String> {
+        let received = conn.received();
+        let hset = received.iter().rev().find(|c| c[0] == "HSET").unwrap();
+        hset[2..].to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_hash_format_writes_new_bucket_as_a_hash() {
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("TYPE", Value::SimpleString("none".into())),
+            ("MULTI DEL HSET EXPIRE EXEC", hash_committed()),
+        ]);
+        let store = RedisStore::new(conn.clone()).with_format(StorageFormat::Hash);
+        let state = AppState::new(store, BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let fields = hset_fields(&conn);
+        assert_eq!(fields[..3], ["tokens", "9", "last_updated"]);
+        let last_updated: i64 = fields[3].parse().unwrap();
+        assert!((Utc::now().timestamp_millis() - last_updated).abs() < 2_000);
+        let received = conn.received();
+        let expire = received.iter().find(|c| c[0] == "EXPIRE").unwrap();
+        assert_eq!(expire[2], "3600");
+    }
+
+    #[tokio::test]
+    async fn test_hash_format_reads_hash_buckets() {
+        let last_updated = Utc::now() - chrono::Duration::minutes(90);
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("TYPE", Value::SimpleString("hash".into())),
+            (
+                "HGETALL",
+                Value::Map(vec![
+                    (
+                        Value::BulkString(b"tokens".to_vec()),
+                        Value::BulkString(b"2".to_vec()),
+                    ),
+                    (
+                        Value::BulkString(b"last_updated".to_vec()),
+                        Value::BulkString(last_updated.timestamp_millis().to_string().into_bytes()),
+                    ),
+                ]),
+            ),
+            ("MULTI DEL HSET EXPIRE EXEC", hash_committed()),
+        ]);
+        let store = RedisStore::new(conn.clone()).with_format(StorageFormat::Hash);
+        let state = AppState::new(store, BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // One interval has passed since the stored update.
+        assert_eq!(hset_fields(&conn)[1], "2");
+    }
+
+    #[tokio::test]
+    async fn test_hash_format_migrates_json_buckets() {
+        let legacy = TokenPersistence {
+            tokens: 4,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("TYPE", Value::SimpleString("string".into())),
+            ("GET", stored(&legacy)),
+            ("MULTI DEL HSET EXPIRE EXEC", hash_committed()),
+        ]);
+        let store = RedisStore::new(conn.clone()).with_format(StorageFormat::Hash);
+        let state = AppState::new(store, BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let fields = hset_fields(&conn);
+        assert_eq!(fields[1], "3");
+        assert_eq!(
+            fields[3],
+            legacy.last_updated.timestamp_millis().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configs_differ_in_refill_rate() {
+        let drained = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now() - chrono::Duration::minutes(3 * 60 + 30),
+            penalty: None,
+        };
+        let hourly = BucketConfig::default();
+        let slow = BucketConfig {
+            refill_interval: Duration::from_secs(4 * 60 * 60),
+            ..BucketConfig::default()
+        };
+
+        let conn = allow_script(Some(&drained));
+        let response = send(
+            limited(AppState::new(RedisStore::new(conn.clone()), hourly)),
+            "client",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.written().tokens, 2);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 2);
+        // Three of the three and a half hours were refilled, so the next
+        // token is due in half an hour.
+        let next_refill = drained.last_updated + chrono::Duration::hours(4);
+        assert_eq!(
+            header_i64(&response, "X-RateLimit-Reset"),
+            (next_refill.timestamp_millis() + 999).div_euclid(1000)
+        );
+
+        let conn = deny_script(&drained);
+        let response = send(
+            limited(AppState::new(RedisStore::new(conn), slow)),
+            "client",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_configs_differ_in_capacity() {
+        let small = BucketConfig {
+            max_tokens: 1,
+            ..BucketConfig::default()
+        };
+        let large = BucketConfig {
+            max_tokens: 100,
+            refill_rate: 50,
+            ..BucketConfig::default()
+        };
+        let first_use = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now() - chrono::Duration::minutes(2 * 60 + 30),
+            penalty: None,
+        };
+
+        let conn = allow_script(None);
+        let response = send(
+            limited(AppState::new(RedisStore::new(conn.clone()), small.clone())),
+            "client",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.written().tokens, 0);
+
+        let conn = allow_script(Some(&first_use));
+        let response = send(
+            limited(AppState::new(RedisStore::new(conn.clone()), small)),
+            "client",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.written().tokens, 0);
+
+        let conn = allow_script(Some(&first_use));
+        let response = send(
+            limited(AppState::new(RedisStore::new(conn.clone()), large)),
+            "client",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.written().tokens, 99);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 100);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 99);
+    }
+
+    async fn tokens_after(elapsed: chrono::Duration) -> Option<i64> {
+        let config = BucketConfig {
+            max_tokens: 100,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(90),
+            ..BucketConfig::default()
+        };
+        let drained = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now() - elapsed,
+            penalty: None,
+        };
+
+        let conn = allow_script(Some(&drained));
+        let response = send(
+            limited(AppState::new(RedisStore::new(conn.clone()), config)),
+            "client",
+        )
+        .await;
+
+        (response.status() == StatusCode::OK).then(|| conn.written().tokens + 1)
+    }
+
+    #[tokio::test]
+    async fn test_partial_interval_restores_nothing() {
+        assert_eq!(tokens_after(chrono::Duration::seconds(30)).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_single_interval_restores_one_token() {
+        assert_eq!(tokens_after(chrono::Duration::seconds(90)).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_hour_restores_one_token_per_interval() {
+        assert_eq!(
+            tokens_after(chrono::Duration::seconds(3600)).await,
+            Some(40)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_steady_traffic_still_refills() {
+        let config = BucketConfig {
+            max_tokens: 20,
+            ..BucketConfig::default()
+        };
+        let clock = ManualClock::default();
+        let mut bucket = TokenPersistence {
+            tokens: 12,
+            last_updated: clock.now(),
+            penalty: None,
+        };
+
+        // A request every 10 minutes for two hours.
+        for _ in 0..12 {
+            clock.advance(Duration::from_secs(10 * 60));
+
+            let conn = allow_script(Some(&bucket));
+            let state = AppState::new(RedisStore::new(conn.clone()), config.clone())
+                .with_clock(clock.clone());
+            let response = send(limited(state), "client").await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            bucket = conn.written();
+        }
+
+        // Twelve requests spent, two tokens refilled.
+        assert_eq!(bucket.tokens, 2);
+    }
+
+    #[tokio::test]
+    async fn test_drained_bucket_refills_one_token_per_interval() {
+        let clock = ManualClock::default();
+        let state =
+            AppState::new(MemoryStore::new(), BucketConfig::default()).with_clock(clock.clone());
+        let svc = limited(state);
+
+        for _ in 0..10 {
+            assert_eq!(send(svc.clone(), "abc").await.status(), StatusCode::OK);
+        }
+        assert_eq!(
+            send(svc.clone(), "abc").await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        clock.advance(Duration::from_secs(60 * 60 - 1));
+        assert_eq!(
+            send(svc.clone(), "abc").await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        clock.advance(Duration::from_secs(1));
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+
+        clock.advance(Duration::from_secs(10 * 60 * 60));
+        for _ in 0..10 {
+            assert_eq!(send(svc.clone(), "abc").await.status(), StatusCode::OK);
+        }
+        assert_eq!(
+            send(svc, "abc").await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_headers_are_relative_to_the_clock() {
+        let start = "2025-03-01T12:00:00Z".parse().unwrap();
+        let clock = ManualClock::new(start);
+        let state =
+            AppState::new(MemoryStore::new(), BucketConfig::default()).with_clock(clock.clone());
+        let svc = limited(state);
+
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(
+            header_i64(&response, "X-RateLimit-Reset"),
+            (start + chrono::Duration::hours(1)).timestamp()
+        );
+
+        clock.advance(Duration::from_secs(25 * 60));
+        let response = send(svc, "abc").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 8);
+        // The partial interval still counts from the first request.
+        assert_eq!(
+            header_i64(&response, "X-RateLimit-Reset"),
+            (start + chrono::Duration::hours(1)).timestamp()
+        );
+    }
+
+    async fn charged_at(
+        clock: &ManualClock,
+        bucket: &TokenPersistence,
+        config: BucketConfig,
+    ) -> (Response<Body>, TokenPersistence) {
+        let conn = allow_script(Some(bucket));
+        let state = AppState::new(RedisStore::new(conn.clone()), config).with_clock(clock.clone());
+        let response = send(limited(state), "client").await;
+        (response, conn.written())
+    }
+
+    #[tokio::test]
+    async fn test_bucket_from_the_future_keeps_its_tokens() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let ahead = TokenPersistence {
+            tokens: 3,
+            last_updated: clock.now() + chrono::Duration::minutes(150),
+            penalty: None,
+        };
+
+        let (response, written) = charged_at(&clock, &ahead, BucketConfig::default()).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(written.tokens, 2);
+        assert_eq!(written.last_updated, ahead.last_updated);
+    }
+
+    #[tokio::test]
+    async fn test_full_bucket_from_the_future_is_not_moved_back() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let ahead = TokenPersistence {
+            tokens: 10,
+            last_updated: clock.now() + chrono::Duration::seconds(30),
+            penalty: None,
+        };
+
+        let (response, written) = charged_at(&clock, &ahead, BucketConfig::default()).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(written.tokens, 9);
+        assert_eq!(written.last_updated, ahead.last_updated);
+    }
+
+    #[test]
+    fn test_burst_config_separates_burst_from_sustained_rate() {
+        let hour = Duration::from_secs(3600);
+        assert_eq!(BucketConfig::burst(10, 1, hour), BucketConfig::default());
+
+        let config = BucketConfig::burst(20, 10, hour);
+        assert_eq!(config.max_tokens, 20);
+        assert_eq!(config.refill_rate, 1);
+        assert_eq!(config.refill_interval, Duration::from_secs(6 * 60));
+
+        // Seven doesn't divide an hour into whole milliseconds.
+        let config = BucketConfig::burst(5, 7, hour);
+        assert_eq!((config.refill_rate, config.refill_interval), (7, hour));
+    }
+
+    fn overdrawn(overdraft: i64) -> BucketConfig {
+        BucketConfig {
+            max_tokens: 3,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(60),
+            overdraft,
+            ..BucketConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_overdraft_lets_the_balance_go_negative() {
+        let config = overdrawn(5);
+        let now = Utc::now();
+        let bucket = TokenPersistence::new(&config, now);
+
+        let (decision, updated) = bucket.charge(&config, 7, now);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(updated.unwrap().tokens, -4);
+
+        // Anything further than the overdraft is denied, even from full.
+        let (decision, _) = bucket.charge(&config, 9, now);
+        assert!(!decision.allowed);
+    }
+
+    #[test]
+    fn test_overdrawn_bucket_is_blocked_until_the_debt_is_paid() {
+        let config = overdrawn(5);
+        let now = Utc::now();
+        let bucket = TokenPersistence {
+            tokens: 1,
+            last_updated: now,
+            penalty: None,
+        };
+        let (_, updated) = bucket.charge(&config, 6, now);
+        let floored = updated.unwrap();
+        assert_eq!(floored.tokens, -5);
+
+        // Five minutes to pay back the debt, and one more for a token.
+        let (decision, _) = floored.charge(&config, 1, now);
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.retry_after, Some(Duration::from_secs(6 * 60)));
+
+        // Out of the debt but with nothing to spend is still too early.
+        let paid = now + chrono::Duration::minutes(5);
+        assert!(!floored.charge(&config, 1, paid).0.allowed);
+
+        let recovered = now + chrono::Duration::minutes(6);
+        let (decision, updated) = floored.charge(&config, 1, recovered);
+        assert!(decision.allowed);
+        assert_eq!(updated.unwrap().tokens, 0);
+        // A bigger request can overdraw again from there.
+        let (decision, updated) = floored.charge(&config, 4, recovered);
+        assert!(decision.allowed);
+        assert_eq!(updated.unwrap().tokens, -3);
+    }
+
+    #[test]
+    fn test_overdrawn_bucket_refills_past_its_debt() {
+        let config = overdrawn(5);
+        let now = Utc::now();
+        let floored = TokenPersistence {
+            tokens: -5,
+            last_updated: now,
+            penalty: None,
+        };
+
+        assert_eq!(
+            floored.time_to_full(&config, now),
+            Duration::from_secs(8 * 60)
+        );
+        let full = now + chrono::Duration::minutes(8);
+        let (decision, _) = floored.charge(&config, 1, full);
+        assert_eq!(decision.remaining, 2);
+    }
+
+    #[test]
+    fn test_refund_never_overfills() {
+        let config = BucketConfig::default();
+        let now = Utc::now();
+        let bucket = TokenPersistence {
+            tokens: 7,
+            last_updated: now - chrono::Duration::minutes(150),
+            penalty: None,
+        };
+
+        // Nine by now, so only one of the two fits.
+        let refunded = bucket.refund(&config, 2, now);
+        assert_eq!(refunded.tokens, 10);
+        assert_eq!(refunded.last_updated, now);
+
+        // Eight by then, with ten minutes toward the next carried over.
+        let refunded = bucket.refund(&config, 1, now - chrono::Duration::minutes(80));
+        assert_eq!(refunded.tokens, 9);
+        assert_eq!(refunded.last_updated, now - chrono::Duration::minutes(90));
+    }
+
+    #[test]
+    fn test_refund_undoes_a_charge_under_every_algorithm() {
+        let now = Utc::now();
+        for algorithm in [
+            Algorithm::TokenBucket,
+            Algorithm::Gcra,
+            Algorithm::LeakyBucket,
+        ] {
+            let config = BucketConfig {
+                max_tokens: 5,
+                algorithm,
+                ..BucketConfig::burst(5, 10, Duration::from_secs(60))
+            };
+            let (_, charged) = TokenPersistence::new(&config, now).charge(&config, 3, now);
+            let charged = charged.unwrap();
+
+            let refunded = charged.refund(&config, 3, now);
+            assert_eq!(refunded.peek(&config, now).remaining, 5, "{algorithm:?}");
+            let refunded = charged.refund(&config, 50, now);
+            assert_eq!(refunded.peek(&config, now).remaining, 5, "{algorithm:?}");
+            assert_eq!(
+                refunded.time_to_full(&config, now),
+                Duration::ZERO,
+                "{algorithm:?}"
+            );
+        }
+    }
+
+    /// Charges one token every `gaps` apart, returning how many were let
+    /// through after each request along with the time it was made.
+    fn replay(
+        config: &BucketConfig,
+        gaps: impl IntoIterator<Item = Duration>,
+    ) -> Vec<(Duration, i64)> {
+        let start = Utc::now();
+        let mut bucket = TokenPersistence::new(config, start);
+        let mut elapsed = Duration::ZERO;
+        let mut allowed = 0;
+        let mut history = Vec::new();
+        for gap in gaps {
+            elapsed += gap;
+            let now = start + chrono::Duration::from_std(elapsed).unwrap();
+            let (decision, updated) = bucket.charge(config, 1, now);
+            if decision.allowed {
+                allowed += 1;
+            }
+            bucket = updated.unwrap_or(bucket);
+            history.push((elapsed, allowed));
+        }
+        history
+    }
+
+    #[test]
+    fn test_idle_client_bursts_then_settles_at_the_sustained_rate() {
+        let config = BucketConfig::burst(20, 10, Duration::from_secs(3600));
+
+        // 30 at once, then one a minute for ten hours.
+        let gaps = std::iter::repeat_n(Duration::ZERO, 30)
+            .chain(std::iter::repeat_n(Duration::from_secs(60), 600));
+        let history = replay(&config, gaps);
+
+        assert_eq!(history[29].1, 20);
+        for hour in 1..=10 {
+            let (elapsed, allowed) = history[29 + hour * 60];
+            assert_eq!(elapsed, Duration::from_secs(3600 * hour as u64));
+            assert_eq!(allowed, 20 + 10 * hour as i64);
+        }
+    }
+
+    #[test]
+    fn test_long_run_throughput_never_exceeds_the_sustained_rate() {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+
+        let hour = Duration::from_secs(3600);
+        let configs = [
+            BucketConfig::default(),
+            BucketConfig::burst(20, 10, hour),
+            BucketConfig::burst(5, 7, hour),
+            BucketConfig::burst(3, 100, Duration::from_secs(60)),
+        ];
+
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for config in &configs {
+                let per = config.refill_interval.as_millis() as i64;
+                let max_gap = config.refill_interval.as_millis() as u64 * 3;
+                let gaps = (0..2000)
+                    .map(|_| match rng.random_range(0..4) {
+                        // Mostly hammering, with the odd pause.
+                        0 => Duration::from_millis(rng.random_range(0..=max_gap)),
+                        _ => Duration::from_millis(rng.random_range(0..=max_gap / 50)),
+                    })
+                    .collect::<Vec<_>>();
+
+                for (elapsed, allowed) in replay(config, gaps) {
+                    let refills = elapsed.as_millis() as i64 / per;
+                    let ceiling = config.max_tokens + refills * config.refill_rate;
+                    assert!(
+                        allowed <= ceiling,
+                        "seed {seed}, {config:?}: {allowed} in {elapsed:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ancient_bucket_refill_saturates() {
+        // Some 2.5e14 intervals at a million tokens each.
+        let clock = ManualClock::new("9999-12-31T23:59:59Z".parse().unwrap());
+        let ancient = TokenPersistence {
+            tokens: 0,
+            last_updated: chrono::DateTime::UNIX_EPOCH,
+            penalty: None,
+        };
+        let config = BucketConfig {
+            refill_rate: 1_000_000,
+            refill_interval: Duration::from_millis(1),
+            ..BucketConfig::default()
+        };
+
+        let (response, written) = charged_at(&clock, &ancient, config).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(written.tokens, 9);
+        assert_eq!(written.last_updated, clock.now());
+    }
+
+    #[tokio::test]
+    async fn test_partial_interval_carries_over() {
+        let drained = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now() - chrono::Duration::minutes(90),
+            penalty: None,
+        };
+
+        let conn = allow_script(Some(&drained));
+        let response = send(
+            limited(AppState::new(
+                RedisStore::new(conn.clone()),
+                BucketConfig::default(),
+            )),
+            "client",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // Stored to the millisecond.
+        assert_eq!(
+            conn.written().last_updated.timestamp_millis(),
+            (drained.last_updated + chrono::Duration::hours(1)).timestamp_millis()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idle_time_at_capacity_is_not_banked() {
+        let idle = TokenPersistence {
+            tokens: 10,
+            last_updated: Utc::now() - chrono::Duration::hours(30),
+            penalty: None,
+        };
+
+        let conn = allow_script(Some(&idle));
+        let response = send(
+            limited(AppState::new(
+                RedisStore::new(conn.clone()),
+                BucketConfig::default(),
+            )),
+            "client",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let written = conn.written();
+        assert_eq!(written.tokens, 9);
+        assert!(Utc::now() - written.last_updated < chrono::Duration::seconds(5));
+    }
+
+    async fn bucket_key_for(header: &str, value: &str) -> String {
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = send_with(limited(state), header, value).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        conn.received()[0][1].clone()
+    }
+
+    #[tokio::test]
+    async fn test_authorization_header_is_case_insensitive() {
+        assert_eq!(
+            bucket_key_for("authorization", "Bearer abc").await,
+            generate_bucket_key("abc")
+        );
+        assert_eq!(
+            bucket_key_for("Authorization", "Bearer abc").await,
+            generate_bucket_key("abc")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authorization_scheme_is_case_insensitive_and_trimmed() {
+        assert_eq!(
+            bucket_key_for("Authorization", "  bEaReR   abc  ").await,
+            generate_bucket_key("abc")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authorization_token_keeps_padding() {
+        assert_eq!(
+            bucket_key_for("Authorization", "Bearer dG9rZW4+/w==").await,
+            generate_bucket_key("dG9rZW4+/w==")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_authorization_is_rejected() {
+        for value in ["Basic dXNlcjpwYXNz", "Bearer", "Bearer    ", "abc"] {
+            let conn = ScriptedConnection::new(vec![]);
+            let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+            let response = send_with(limited(state), "Authorization", value).await;
+
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{value}");
+            assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+            assert!(conn.received().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_non_ascii_token_is_rejected() {
+        for (name, value) in [
+            ("Bearer", &b"abc\xff"[..]),
+            ("Authorization", b"Bearer abc\xff"),
+        ] {
+            let conn = ScriptedConnection::new(vec![]);
+            let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+            let value = HeaderValue::from_bytes(value).unwrap();
+
+            let response = call(limited(state), Request::builder().header(name, value)).await;
+
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{name}");
+            assert!(conn.received().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_token_is_rejected() {
+        let longest = "a".repeat(MAX_TOKEN_HEADER_LEN);
+        assert_eq!(
+            bucket_key_for("Bearer", &longest).await,
+            generate_bucket_key(&longest)
+        );
+
+        for (name, value) in [
+            ("Bearer", "a".repeat(MAX_TOKEN_HEADER_LEN + 1)),
+            ("Authorization", format!("Bearer {longest}")),
+        ] {
+            let conn = ScriptedConnection::new(vec![]);
+            let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+            let response = send_with(limited(state), name, &value).await;
+
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{name}");
+            assert!(conn.received().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_legacy_bearer_header_can_be_disabled() {
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(vec![])),
+            BucketConfig::default(),
+        )
+        .with_key_extractor(BearerTokenExtractor::default().with_legacy_header(false));
+
+        let response = send(limited(state), "127.0.0.1").await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    fn ip_fallback<const N: usize>(trusted: [&str; N]) -> BearerTokenExtractor {
+        let peer_ip = PeerIpExtractor::new(TrustedProxies::new(trusted).unwrap());
+        BearerTokenExtractor::default().with_missing_token(MissingTokenPolicy::ClientIp(peer_ip))
+    }
+
+    fn peer(ip: &str) -> ConnectInfo<SocketAddr> {
+        ConnectInfo(SocketAddr::new(ip.parse().unwrap(), 40000))
+    }
+
+    #[tokio::test]
+    async fn test_missing_token_is_rejected_by_default() {
+        let conn = ScriptedConnection::new(vec![]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = call(
+            limited(state),
+            Request::builder().extension(peer("10.1.2.3")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(conn.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_missing_token_falls_back_to_client_ip() {
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_key_extractor(ip_fallback([]));
+
+        let response = call(
+            limited(state),
+            Request::builder().extension(peer("10.1.2.3")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.received()[0][1], generate_bucket_key("10.1.2.3"));
+    }
+
+    #[tokio::test]
+    async fn test_client_ip_fallback_still_rejects_malformed_authorization() {
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(vec![])),
+            BucketConfig::default(),
+        )
+        .with_key_extractor(ip_fallback([]));
+
+        let response = call(
+            limited(state),
+            Request::builder()
+                .header("Authorization", "Basic dXNlcjpwYXNz")
+                .extension(peer("10.1.2.3")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_client_ip_fallback_without_connect_info_is_rejected() {
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(vec![])),
+            BucketConfig::default(),
+        )
+        .with_key_extractor(ip_fallback([]));
+
+        let response = call(limited(state), Request::builder()).await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    fn key_chain() -> HeaderKeyExtractor {
+        HeaderKeyExtractor::chain([header::AUTHORIZATION, HeaderName::from_static("x-api-key")])
+    }
+
+    #[tokio::test]
+    async fn test_header_chain_keys_on_the_first_header_present() {
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_key_extractor(key_chain());
+
+        let response = call(
+            limited(state),
+            Request::builder().header("X-Api-Key", "key-123"),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.received()[0][1], generate_bucket_key("key-123"));
+    }
+
+    #[tokio::test]
+    async fn test_header_chain_prefers_earlier_headers() {
+        for (chain, identity) in [
+            (key_chain(), "abc"),
+            (
+                HeaderKeyExtractor::new(HeaderName::from_static("x-api-key"))
+                    .with_fallback(header::AUTHORIZATION),
+                "key-123",
+            ),
+        ] {
+            let conn = allow_script(None);
+            let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+                .with_key_extractor(chain);
+
+            let response = call(
+                limited(state),
+                Request::builder()
+                    .header("Authorization", "Bearer abc")
+                    .header("X-Api-Key", "key-123"),
+            )
+            .await;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(conn.received()[0][1], generate_bucket_key(identity));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_header_chain_rejects_a_malformed_header_instead_of_skipping_it() {
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(vec![])),
+            BucketConfig::default(),
+        )
+        .with_key_extractor(key_chain());
+
+        let response = call(
+            limited(state),
+            Request::builder()
+                .header("Authorization", "Basic dXNlcjpwYXNz")
+                .header("X-Api-Key", "key-123"),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_header_chain_without_any_header() {
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(vec![])),
+            BucketConfig::default(),
+        )
+        .with_key_extractor(key_chain());
+        let response = call(
+            limited(state),
+            Request::builder().extension(peer("10.1.2.3")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let conn = allow_script(None);
+        let peer_ip = PeerIpExtractor::default();
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_key_extractor(
+                key_chain().with_missing_key(MissingTokenPolicy::ClientIp(peer_ip)),
+            );
+        let response = call(
+            limited(state),
+            Request::builder().extension(peer("10.1.2.3")),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.received()[0][1], generate_bucket_key("10.1.2.3"));
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_for_from_untrusted_peer_is_ignored() {
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_key_extractor(ip_fallback(["10.0.0.0/8"]));
+
+        let response = call(
+            limited(state),
+            Request::builder()
+                .header("X-Forwarded-For", "1.2.3.4")
+                .extension(peer("203.0.113.9")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.received()[0][1], generate_bucket_key("203.0.113.9"));
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_for_from_trusted_proxy_is_used() {
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_key_extractor(ip_fallback(["10.0.0.0/8"]));
+
+        let response = call(
+            limited(state),
+            Request::builder()
+                .header("X-Forwarded-For", "1.2.3.4, 198.51.100.4")
+                .extension(peer("10.0.0.2")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.received()[0][1], generate_bucket_key("198.51.100.4"));
+    }
+
+    struct TenantExtractor;
+
+    impl KeyExtractor for TenantExtractor {
+        fn extract<'a>(
+            &'a self,
+            parts: &'a Parts,
+        ) -> BoxFuture<'a, Result<String, Response<Body>>> {
+            Box::pin(async move {
+                parts
+                    .headers
+                    .get("X-Tenant-Id")
+                    .and_then(|v| v.to_str().ok())
+                    .map(|tenant| format!("tenant:{tenant}"))
+                    .ok_or_else(|| {
+                        Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::empty())
+                            .unwrap()
+                    })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_key_extractor() {
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_key_extractor(TenantExtractor);
+
+        let response = send_with(limited(state), "X-Tenant-Id", "acme").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.received()[0][1], generate_bucket_key("tenant:acme"));
+    }
+
+    #[tokio::test]
+    async fn test_custom_key_extractor_rejection() {
+        let conn = ScriptedConnection::new(vec![]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_key_extractor(TenantExtractor);
+
+        let response = send(limited(state), "some-token").await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(conn.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_peer_ip_extractor() {
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_key_extractor(PeerIpExtractor::default());
+
+        // A bearer token is irrelevant to the IP extractor.
+        let response = call(
+            limited(state),
+            Request::builder()
+                .header("Authorization", "Bearer abc")
+                .extension(peer("10.1.2.3")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.received()[0][1], generate_bucket_key("10.1.2.3"));
+    }
+
+    fn routed<S>(state: AppState<S>) -> Router
+    where
+        S: BucketStore,
+    {
+        Router::new()
+            .route("/search", get(|| async { "results" }))
+            .route("/export", get(|| async { "export" }))
+            .route("/", get(|| async { "home" }))
+            .route_layer(middleware::from_fn_with_state(
+                state,
+                rate_limiter_middleware::<S>,
+            ))
+    }
+
+    fn per_route_state(conn: ScriptedConnection) -> AppState<RedisStore<ScriptedConnection>> {
+        AppState::new(RedisStore::new(conn), BucketConfig::default())
+            .with_route(
+                "/search",
+                BucketConfig {
+                    max_tokens: 100,
+                    ..BucketConfig::default()
+                },
+            )
+            .with_route(
+                "/export",
+                BucketConfig {
+                    max_tokens: 1,
+                    ..BucketConfig::default()
+                },
+            )
+    }
+
+    async fn get_path(app: Router, path: &str) -> Response<Body> {
+        app.oneshot(
+            Request::builder()
+                .uri(path)
+                .header("Authorization", "Bearer abc")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_routes_exhaust_independently() {
+        let key = generate_bucket_key("abc");
+
+        // The only /export token is spent...
+        let conn = allow_script(None);
+        let response = get_path(routed(per_route_state(conn.clone())), "/export").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.received()[0][1], format!("{key}:/export"));
+        assert_eq!(conn.written().tokens, 0);
+
+        // ...so the next /export request is denied...
+        let exhausted = conn.written();
+        let conn = deny_script(&exhausted);
+        let response = get_path(routed(per_route_state(conn)), "/export").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // ...while /search draws from its own, larger bucket.
+        let conn = allow_script(None);
+        let response = get_path(routed(per_route_state(conn.clone())), "/search").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.received()[0][1], format!("{key}:/search"));
+        assert_eq!(conn.written().tokens, 99);
+    }
+
+    #[tokio::test]
+    async fn test_unconfigured_route_uses_default_bucket() {
+        let conn = allow_script(None);
+
+        let response = get_path(routed(per_route_state(conn.clone())), "/").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.received()[0][1], generate_bucket_key("abc"));
+        assert_eq!(conn.written().tokens, 9);
+    }
+
+    async fn send_costing(conn: ScriptedConnection, cost: u32) -> Response<Body> {
+        let state = AppState::new(RedisStore::new(conn), BucketConfig::default());
+        call(
+            limited(state),
+            Request::builder()
+                .header("Authorization", "Bearer abc")
+                .extension(RequestCost(cost)),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_weighted_cost_drains_bucket_in_two_calls() {
+        let conn = allow_script(None);
+        assert_eq!(send_costing(conn.clone(), 5).await.status(), StatusCode::OK);
+        let bucket = conn.written();
+        assert_eq!(bucket.tokens, 5);
+
+        let conn = allow_script(Some(&bucket));
+        assert_eq!(send_costing(conn.clone(), 5).await.status(), StatusCode::OK);
+        let bucket = conn.written();
+        assert_eq!(bucket.tokens, 0);
+
+        let conn = deny_script(&bucket);
+        assert_eq!(
+            send_costing(conn, 1).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cost_above_balance_is_denied() {
+        let bucket = TokenPersistence {
+            tokens: 4,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = deny_script(&bucket);
+
+        assert_eq!(
+            send_costing(conn.clone(), 5).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert!(conn.received().iter().all(|command| command[0] != "SET"));
+    }
+
+    #[tokio::test]
+    async fn test_cost_above_capacity_is_rejected_without_redis() {
+        let conn = ScriptedConnection::new(vec![]);
+
+        assert_eq!(
+            send_costing(conn.clone(), 11).await.status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert!(conn.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_zero_cost_skips_redis() {
+        let conn = ScriptedConnection::new(vec![]);
+
+        assert_eq!(send_costing(conn.clone(), 0).await.status(), StatusCode::OK);
+        assert!(conn.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_responses_have_no_rate_limit_headers() {
+        let response = send_with(
+            limited(AppState::new(
+                RedisStore::new(ScriptedConnection::new(vec![])),
+                BucketConfig::default(),
+            )),
+            "Authorization",
+            "Basic abc",
+        )
+        .await;
+
+        assert!(!response.headers().contains_key("X-RateLimit-Remaining"));
+        assert!(!response.headers().contains_key("RateLimit-Remaining"));
+    }
+
+    #[tokio::test]
+    async fn test_draft_headers_on_allowed_response() {
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn), BucketConfig::default())
+            .with_header_style(HeaderStyle::Draft);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "RateLimit-Limit"), 10);
+        assert_eq!(header_i64(&response, "RateLimit-Remaining"), 9);
+        assert!((3599..=3600).contains(&header_i64(&response, "RateLimit-Reset")));
+        assert_eq!(response.headers()["RateLimit-Policy"], "10;w=36000");
+        assert!(!response.headers().contains_key("X-RateLimit-Limit"));
+    }
+
+    #[tokio::test]
+    async fn test_both_header_styles_on_denied_response() {
+        let conn = deny_script(&TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now() - chrono::Duration::minutes(20),
+            penalty: None,
+        });
+        let state = AppState::new(RedisStore::new(conn), BucketConfig::default())
+            .with_header_style(HeaderStyle::Both);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+        assert_eq!(header_i64(&response, "RateLimit-Remaining"), 0);
+        assert_eq!(header_i64(&response, "RateLimit-Reset"), 40 * 60);
+        assert_eq!(header_i64(&response, "Retry-After"), 40 * 60);
+        assert_eq!(response.headers()["RateLimit-Policy"], "10;w=36000");
+    }
+
+    #[tokio::test]
+    async fn test_legacy_headers_on_denied_response_by_default() {
+        let conn = deny_script(&TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        });
+
+        let response = send(
+            limited(AppState::new(
+                RedisStore::new(conn),
+                BucketConfig::default(),
+            )),
+            "abc",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+        assert!(!response.headers().contains_key("RateLimit-Policy"));
+    }
+
+    async fn retry_after(bucket: TokenPersistence, config: BucketConfig, cost: u32) -> i64 {
+        let conn = deny_script(&bucket);
+        let response = call(
+            limited(AppState::new(RedisStore::new(conn), config)),
+            Request::builder()
+                .header("Authorization", "Bearer abc")
+                .extension(RequestCost(cost)),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        header_i64(&response, "Retry-After")
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_counts_partially_elapsed_interval() {
+        let bucket = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now() - chrono::Duration::minutes(25),
+            penalty: None,
+        };
+
+        assert_eq!(
+            retry_after(bucket, BucketConfig::default(), 1).await,
+            35 * 60
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_waits_for_enough_tokens_for_the_cost() {
+        let config = BucketConfig {
+            refill_interval: Duration::from_secs(10 * 60),
+            ..BucketConfig::default()
+        };
+        // Two tokens were refilled and five minutes of the third interval
+        // have passed; a cost of four needs two more intervals.
+        let bucket = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now() - chrono::Duration::minutes(25),
+            penalty: None,
+        };
+
+        assert_eq!(retry_after(bucket, config, 4).await, 15 * 60);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_with_multi_token_refills() {
+        let config = BucketConfig {
+            refill_rate: 3,
+            ..BucketConfig::default()
+        };
+        let bucket = TokenPersistence {
+            tokens: 1,
+            last_updated: Utc::now() - chrono::Duration::minutes(50),
+            penalty: None,
+        };
+
+        // One refill of three tokens covers the missing four minus one.
+        assert_eq!(retry_after(bucket, config, 4).await, 10 * 60);
+    }
+
+    #[tokio::test]
+    async fn test_custom_rejection_body() {
+        let conn = deny_script(&TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now() - chrono::Duration::minutes(58),
+            penalty: None,
+        });
+        let state = AppState::new(RedisStore::new(conn), BucketConfig::default()).with_rejection(
+            |decision| {
+                let body = serde_json::json!({
+                    "error": "rate_limited",
+                    "limit": decision.limit,
+                    "remaining": decision.remaining,
+                    "retry_after": decision.retry_after.unwrap().as_millis().div_ceil(1000) as u64,
+                });
+                Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap()
+            },
+        );
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(header_i64(&response, "Retry-After"), 120);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "error": "rate_limited",
+                "limit": 10,
+                "remaining": 0,
+                "retry_after": 120,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_default_rejection_is_empty() {
+        let conn = deny_script(&TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        });
+
+        let response = send(
+            limited(AppState::new(
+                RedisStore::new(conn),
+                BucketConfig::default(),
+            )),
+            "abc",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    async fn problem(response: Response<Body>) -> ProblemDetails {
+        assert_eq!(response.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_problem_details_unauthorized() {
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(vec![])),
+            BucketConfig::default(),
+        )
+        .with_problem_details(true);
+
+        let response = send_with(limited(state), "Authorization", "Basic abc").await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let problem = problem(response).await;
+        assert_eq!(problem.status, 401);
+        assert_eq!(problem.title, "Unauthorized");
+        assert_eq!(problem.problem_type, "urn:leaky-bucket:unauthorized");
+        assert_eq!(problem.retry_after, None);
+    }
+
+    #[tokio::test]
+    async fn test_problem_details_rate_limited() {
+        let conn = deny_script(&TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now() - chrono::Duration::minutes(58),
+            penalty: None,
+        });
+        let state = AppState::new(RedisStore::new(conn), BucketConfig::default())
+            .with_problem_details(true);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "Retry-After"), 120);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+        let problem = problem(response).await;
+        assert_eq!(problem.status, 429);
+        assert_eq!(problem.title, "Too Many Requests");
+        assert_eq!(problem.problem_type, "urn:leaky-bucket:rate-limited");
+        assert_eq!(problem.retry_after, Some(120));
+    }
+
+    #[tokio::test]
+    async fn test_problem_details_cost_above_capacity() {
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(vec![])),
+            BucketConfig::default(),
+        )
+        .with_problem_details(true);
+
+        let response = call(
+            limited(state),
+            Request::builder()
+                .header("Authorization", "Bearer abc")
+                .extension(RequestCost(11)),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let problem = problem(response).await;
+        assert_eq!(problem.status, 413);
+        assert_eq!(
+            problem.detail,
+            "The request costs 11 tokens but the bucket only holds 10."
+        );
+    }
+
+    #[test]
+    fn test_problem_details_serialization() {
+        let json = serde_json::to_value(ProblemDetails::unauthorized()).unwrap();
+
+        assert_eq!(json["type"], "urn:leaky-bucket:unauthorized");
+        assert_eq!(json["status"], 401);
+        assert!(json.get("retry_after").is_none());
+    }
+
+    fn refused() -> RedisError {
+        std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "connection refused").into()
+    }
+
+    #[tokio::test]
+    async fn test_redis_error_is_service_unavailable() {
+        let key = generate_bucket_key("abc");
+        let mock = MockRedisConnection::new(vec![
+            MockCmd::new(cmd("WATCH").arg(&key), Ok(Value::Okay)),
+            MockCmd::new(cmd("GET").arg(&key), Err::<Value, _>(refused())),
+        ]);
+
+        let response = send(
+            limited(AppState::new(
+                RedisStore::new(mock),
+                BucketConfig::default(),
+            )),
+            "abc",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(header_i64(&response, "Retry-After"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_redis_error_on_watch_is_service_unavailable() {
+        let mock = MockRedisConnection::new(vec![MockCmd::new(
+            cmd("WATCH").arg(generate_bucket_key("abc")),
+            Err::<Value, _>(refused()),
+        )]);
+        let state = AppState::new(RedisStore::new(mock), BucketConfig::default())
+            .with_problem_details(true);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let problem = problem(response).await;
+        assert_eq!(problem.status, 503);
+        assert_eq!(problem.problem_type, "urn:leaky-bucket:backend-unavailable");
+        assert_eq!(problem.retry_after, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_fail_open_forwards_on_redis_error() {
+        let key = generate_bucket_key("abc");
+        let mock = MockRedisConnection::new(vec![
+            MockCmd::new(cmd("WATCH").arg(&key), Ok(Value::Okay)),
+            MockCmd::new(cmd("GET").arg(&key), Err::<Value, _>(refused())),
+        ]);
+        let state = AppState::new(RedisStore::new(mock), BucketConfig::default())
+            .with_failure_policy(FailurePolicy::Open);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key("X-RateLimit-Remaining"));
+    }
+
+    #[tokio::test]
+    async fn test_fail_closed_is_default() {
+        let mock = MockRedisConnection::new(vec![MockCmd::new(
+            cmd("WATCH").arg(generate_bucket_key("abc")),
+            Err::<Value, _>(refused()),
+        )]);
+        let state = AppState::new(RedisStore::new(mock), BucketConfig::default());
+        assert_eq!(state.failure_policy, FailurePolicy::Closed);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(header_i64(&response, "Retry-After"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fail_open_still_denies_empty_bucket() {
+        let conn = deny_script(&TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        });
+        let state = AppState::new(RedisStore::new(conn), BucketConfig::default())
+            .with_failure_policy(FailurePolicy::Open);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_skips_redis_until_probe_succeeds() {
+        let conn = deny_script(&TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        });
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_circuit_breaker(CircuitBreakerConfig {
+                failure_threshold: 2,
+                window: Duration::from_secs(10),
+                cooldown: Duration::from_millis(50),
+            });
+        let svc = limited(state.clone());
+
+        conn.set_down(true);
+        for _ in 0..2 {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+        assert_eq!(state.breaker_state(), Some(BreakerState::Open));
+
+        // Open: answered without going near Redis.
+        let sent = conn.received().len();
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(conn.received().len(), sent);
+
+        conn.set_down(false);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(state.breaker_state(), Some(BreakerState::HalfOpen));
+
+        let response = send(svc, "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(state.breaker_state(), Some(BreakerState::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_no_breaker_by_default() {
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(vec![])),
+            BucketConfig::default(),
+        );
+
+        assert_eq!(state.breaker_state(), None);
+    }
+
+    #[tokio::test]
+    async fn test_local_fallback_limits_during_outage() {
+        let config = BucketConfig {
+            max_tokens: 3,
+            ..BucketConfig::default()
+        };
+        let drained = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            ("MULTI SET EXEC", committed()),
+            ("WATCH", Value::Okay),
+            ("GET", stored(&drained)),
+            ("UNWATCH", Value::Okay),
+        ]);
+        let state = AppState::new(RedisStore::new(conn.clone()), config).with_local_fallback(100);
+        let svc = limited(state.clone());
+
+        assert_eq!(send(svc.clone(), "abc").await.status(), StatusCode::OK);
+
+        conn.set_down(true);
+        let mut statuses = Vec::new();
+        for _ in 0..4 {
+            statuses.push(send(svc.clone(), "abc").await.status());
+        }
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+        assert_eq!(state.fallback.as_ref().unwrap().len(), 1);
+
+        conn.set_down(false);
+        let response = send(svc, "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(state.fallback.as_ref().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_local_fallback_while_breaker_open() {
+        let conn = ScriptedConnection::new(vec![]);
+        conn.set_down(true);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_circuit_breaker(CircuitBreakerConfig {
+                failure_threshold: 1,
+                ..CircuitBreakerConfig::default()
+            })
+            .with_local_fallback(100);
+        let svc = limited(state.clone());
+
+        for remaining in [9, 8] {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), remaining);
+        }
+        assert_eq!(state.breaker_state(), Some(BreakerState::Open));
+        assert_eq!(conn.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_consume_tokens_charges_the_identity_bucket() {
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        let decision = state.consume_tokens("abc", 3).await.unwrap();
+
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 7);
+        assert_eq!(conn.written().tokens, 7);
+        assert_eq!(conn.received()[0], ["WATCH", &generate_bucket_key("abc")]);
+    }
+
+    #[tokio::test]
+    async fn test_consume_tokens_denies_empty_bucket() {
+        let conn = deny_script(&TokenPersistence {
+            tokens: 1,
+            last_updated: Utc::now(),
+            penalty: None,
+        });
+        let state = AppState::new(RedisStore::new(conn), BucketConfig::default());
+
+        let decision = state.consume_tokens("abc", 2).await.unwrap();
+
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 1);
+        let retry_after = decision.retry_after.unwrap();
+        assert!(retry_after > Duration::from_secs(3590), "{retry_after:?}");
+    }
+
+    #[tokio::test]
+    async fn test_consume_tokens_returns_store_errors() {
+        let conn = ScriptedConnection::new(vec![]);
+        conn.set_down(true);
+        let state = AppState::new(RedisStore::new(conn), BucketConfig::default())
+            .with_failure_policy(FailurePolicy::Open)
+            .with_circuit_breaker(CircuitBreakerConfig {
+                failure_threshold: 1,
+                ..CircuitBreakerConfig::default()
+            });
+
+        let first = state.consume_tokens("abc", 1).await;
+        let second = state.consume_tokens("abc", 1).await;
+
+        assert!(
+            matches!(first, Err(ConsumeError::Store(StoreError::Redis(_)))),
+            "{first:?}"
+        );
+        assert!(
+            matches!(second, Err(ConsumeError::Store(StoreError::CircuitOpen))),
+            "{second:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_consume_tokens_uses_the_local_fallback() {
+        let conn = ScriptedConnection::new(vec![]);
+        conn.set_down(true);
+        let state =
+            AppState::new(RedisStore::new(conn), BucketConfig::default()).with_local_fallback(100);
+
+        let decision = state.consume_tokens("abc", 4).await.unwrap();
+
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 6);
+    }
+
+    #[tokio::test]
+    async fn test_consume_tokens_shares_the_request_bucket() {
+        let state = memory_state();
+
+        state.consume_tokens("abc", 9).await.unwrap();
+        let response = send(limited(state.clone()), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+        assert!(!state.consume_tokens("abc", 1).await.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    async fn test_check_tokens_only_reads() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let bucket = TokenPersistence {
+            tokens: 4,
+            last_updated: clock.now() - chrono::Duration::minutes(90),
+            penalty: None,
+        };
+        let conn =
+            ScriptedConnection::new(vec![("GET", stored(&bucket)), ("GET", stored(&bucket))]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_clock(clock.clone());
+
+        let first = state.check_tokens("abc").await.unwrap();
+        let second = state.check_tokens("abc").await.unwrap();
+
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 5);
+        assert_eq!(first.reset_at, clock.now() + chrono::Duration::minutes(30));
+        assert_eq!(second, first);
+        assert!(conn.received().iter().all(|command| command[0] == "GET"));
+    }
+
+    #[tokio::test]
+    async fn test_check_tokens_on_empty_bucket() {
+        let bucket = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(vec![("GET", stored(&bucket))])),
+            BucketConfig::default(),
+        );
+
+        let decision = state.check_tokens("abc").await.unwrap();
+
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert!(decision.retry_after.unwrap() > Duration::from_secs(3590));
+    }
+
+    #[tokio::test]
+    async fn test_check_tokens_on_missing_bucket() {
+        let state = AppState::new(
+            AsyncRedisStore::new(ScriptedConnection::new(vec![("GET", Value::Nil)])),
+            BucketConfig::default(),
+        );
+
+        let decision = state.check_tokens("abc").await.unwrap();
+
+        assert_eq!(decision.remaining, 10);
+        assert_eq!(decision.retry_after, None);
+    }
+
+    #[tokio::test]
+    async fn test_check_tokens_reads_hash_buckets() {
+        let conn = ScriptedConnection::new(vec![
+            ("TYPE", Value::SimpleString("hash".into())),
+            (
+                "HGETALL",
+                Value::Map(vec![
+                    (Value::BulkString(b"tokens".to_vec()), Value::Int(3)),
+                    (
+                        Value::BulkString(b"last_updated".to_vec()),
+                        Value::Int(Utc::now().timestamp_millis()),
+                    ),
+                ]),
+            ),
+        ]);
+        let state = AppState::new(
+            AsyncRedisStore::new(conn.clone()).with_format(StorageFormat::Hash),
+            BucketConfig::default(),
+        );
+
+        let decision = state.check_tokens("abc").await.unwrap();
+
+        assert_eq!(decision.remaining, 3);
+        assert_eq!(conn.received().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_decide_charges_what_the_middleware_would() {
+        let state = memory_state().with_allowlist(Allowlist::new().with_identity("friend"));
+        let parts =
+            |request: axum::http::request::Builder| request.body(()).unwrap().into_parts().0;
+        let bearer =
+            |token: &str| Request::get("/").header("Authorization", format!("Bearer {token}"));
+
+        let verdict = state.decide(&parts(bearer("abc"))).await.unwrap();
+
+        let Verdict::Charged(decision) = verdict else {
+            panic!("{verdict:?}");
+        };
+        assert_eq!(decision.remaining, 9);
+        let response = send(limited(state.clone()), "abc").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 8);
+        match state.decide(&parts(Request::get("/"))).await.unwrap() {
+            Verdict::Refused(response) => assert_eq!(response.status(), StatusCode::UNAUTHORIZED),
+            verdict => panic!("{verdict:?}"),
+        }
+        let verdict = state.decide(&parts(bearer("friend"))).await.unwrap();
+        assert!(matches!(verdict, Verdict::Free), "{verdict:?}");
+    }
+
+    #[tokio::test]
+    async fn test_consume_many_exact_fit_empties_the_bucket() {
+        let state = memory_state();
+
+        let grant = state.consume_many("abc", 10).await.unwrap();
+
+        assert_eq!((grant.granted, grant.available), (10, 0));
+        assert!(grant.decision.allowed);
+        let grant = state.consume_many("abc", 1).await.unwrap();
+        assert_eq!((grant.granted, grant.available), (0, 0));
+        assert!(grant.decision.retry_after.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_consume_many_takes_nothing_from_a_bucket_too_small() {
+        let state = memory_state();
+        state.consume_tokens("abc", 6).await.unwrap();
+
+        let grant = state.consume_many("abc", 5).await.unwrap();
+
+        assert_eq!((grant.granted, grant.available), (0, 4));
+        assert_eq!(state.check_tokens("abc").await.unwrap().remaining, 4);
+    }
+
+    #[tokio::test]
+    async fn test_consume_rejects_negative_counts() {
+        let state = memory_state();
+        state.consume_tokens("abc", 4).await.unwrap();
+
+        let error = state.consume_tokens("abc", -100).await.unwrap_err();
+        assert!(matches!(error, ConsumeError::Negative(-100)), "{error:?}");
+        assert_eq!(error.to_string(), "can't charge -100 tokens");
+        let error = state.consume_many("abc", -1).await.unwrap_err();
+        assert!(matches!(error, ConsumeError::Negative(-1)), "{error:?}");
+        let error = state.consume_up_to("abc", -5).await.unwrap_err();
+        assert!(matches!(error, ConsumeError::Negative(-5)), "{error:?}");
+
+        assert_eq!(state.check_tokens("abc").await.unwrap().remaining, 6);
+    }
+
+    #[tokio::test]
+    async fn test_consume_rejects_more_than_the_buckets_hold() {
+        let state = memory_state().with_windows([BucketConfig {
+            max_tokens: 5,
+            ..BucketConfig::default()
+        }]);
+
+        let error = state.consume_tokens("abc", i64::MAX).await.unwrap_err();
+        assert!(
+            matches!(
+                error,
+                ConsumeError::ExceedsCapacity {
+                    cost: i64::MAX,
+                    capacity: 5
+                }
+            ),
+            "{error:?}"
+        );
+        // The smallest bucket decides.
+        let error = state.consume_tokens("abc", 6).await.unwrap_err();
+        assert!(
+            matches!(error, ConsumeError::ExceedsCapacity { .. }),
+            "{error:?}"
+        );
+        assert!(state.consume_tokens("abc", 5).await.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    async fn test_consume_nothing_leaves_the_bucket_as_it_is() {
+        let state = memory_state();
+        state.consume_tokens("abc", 4).await.unwrap();
+
+        let grant = state.consume_many("abc", 0).await.unwrap();
+        assert_eq!((grant.granted, grant.available), (0, 6));
+        assert!(grant.decision.allowed);
+        let grant = state.consume_up_to("abc", 0).await.unwrap();
+        assert_eq!((grant.granted, grant.available), (0, 6));
+
+        assert_eq!(state.check_tokens("abc").await.unwrap().remaining, 6);
+    }
+
+    #[tokio::test]
+    async fn test_consume_up_to_takes_what_there_is() {
+        let state = memory_state();
+
+        let grant = state.consume_up_to("abc", 4).await.unwrap();
+        assert_eq!((grant.granted, grant.available), (4, 6));
+
+        let grant = state.consume_up_to("abc", 500).await.unwrap();
+        assert_eq!((grant.granted, grant.available), (6, 0));
+        assert!(grant.decision.allowed);
+
+        let grant = state.consume_up_to("abc", 500).await.unwrap();
+        assert_eq!((grant.granted, grant.available), (0, 0));
+        assert!(!grant.decision.allowed);
+    }
+
+    #[tokio::test]
+    async fn test_consume_many_rejects_more_than_the_buckets_hold() {
+        let state = memory_state();
+
+        let error = state.consume_many("abc", i64::MAX).await.unwrap_err();
+        assert!(
+            matches!(
+                error,
+                ConsumeError::ExceedsCapacity {
+                    cost: i64::MAX,
+                    capacity: 10
+                }
+            ),
+            "{error:?}"
+        );
+        assert_eq!(state.check_tokens("abc").await.unwrap().remaining, 10);
+    }
+
+    #[tokio::test]
+    async fn test_consume_up_to_takes_at_most_what_the_buckets_hold() {
+        let state = memory_state();
+        state.consume_tokens("abc", 3).await.unwrap();
+
+        let grant = state.consume_up_to("abc", i64::MAX).await.unwrap();
+        assert_eq!((grant.granted, grant.available), (7, 0));
+
+        let grant = state.consume_up_to("abc", i64::MAX).await.unwrap();
+        assert_eq!((grant.granted, grant.available), (0, 0));
+        assert!(!grant.decision.allowed);
+    }
+
+    #[tokio::test]
+    async fn test_consume_up_to_charges_redis_what_it_has() {
+        let bucket = TokenPersistence {
+            tokens: 3,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", stored(&bucket)),
+            ("UNWATCH", Value::Okay),
+            ("WATCH", Value::Okay),
+            ("GET", stored(&bucket)),
+            ("MULTI SET EXEC", committed()),
+        ]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        let grant = state.consume_up_to("abc", 500).await.unwrap();
+
+        assert_eq!((grant.granted, grant.available), (3, 0));
+        assert_eq!(conn.written().tokens, 0);
+    }
+
+    #[tokio::test]
+    async fn test_batch_cost_charges_per_item() {
+        let state =
+            memory_state().with_batch_cost(BatchCost::new(HeaderName::from_static("x-batch-size")));
+        let svc = limited(state);
+        let batch = |items: &str| {
+            Request::builder()
+                .header("Authorization", "Bearer abc")
+                .header("X-Batch-Size", items)
+        };
+
+        let response = call(svc.clone(), batch("7")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 3);
+
+        let response = call(svc.clone(), batch("5")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // How many of its items would have fit.
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 3);
+
+        let response = call(svc.clone(), batch("3")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_memory_check_tokens_doesnt_change_the_bucket() {
+        let state = memory_state();
+        state.consume_tokens("abc", 2).await.unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(state.check_tokens("abc").await.unwrap().remaining, 8);
+        }
+        assert_eq!(state.check_tokens("def").await.unwrap().remaining, 10);
+        assert_eq!(state.store.len(), 1);
+        assert_eq!(state.consume_tokens("abc", 1).await.unwrap().remaining, 7);
+    }
+
+    #[tokio::test]
+    async fn test_bucket_status_of_stored_bucket() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let bucket = TokenPersistence {
+            tokens: 7,
+            last_updated: clock.now() - chrono::Duration::minutes(20),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![("GET", stored(&bucket))]);
+        let state =
+            AppState::new(RedisStore::new(conn.clone()), BucketConfig::default()).with_clock(clock);
+
+        let status = state.get_bucket_status("abc").await.unwrap().unwrap();
+
+        assert_eq!(status.tokens, 7);
+        assert_eq!(status.last_updated, bucket.last_updated);
+        assert_eq!(status.time_to_full, Duration::from_secs(160 * 60));
+        assert_eq!(conn.received()[0], ["GET", &generate_bucket_key("abc")]);
+    }
+
+    #[tokio::test]
+    async fn test_bucket_status_of_missing_bucket() {
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(vec![("GET", Value::Nil)])),
+            BucketConfig::default(),
+        );
+
+        assert_eq!(state.get_bucket_status("abc").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_bucket_status_store_error() {
+        let conn = ScriptedConnection::new(vec![]);
+        conn.set_down(true);
+        let state = AppState::new(AsyncRedisStore::new(conn), BucketConfig::default());
+
+        let status = state.get_bucket_status("abc").await;
+
+        assert!(matches!(status, Err(StoreError::Redis(_))), "{status:?}");
+    }
+
+    #[tokio::test]
+    async fn test_denial_cache_answers_without_the_store() {
+        let bucket = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = deny_script(&bucket);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_denial_cache(100);
+        let svc = limited(state);
+
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let commands = conn.received().len();
+
+        for _ in 0..10 {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+            assert!(header_i64(&response, "Retry-After") > 0);
+        }
+        assert_eq!(conn.received().len(), commands);
+    }
+
+    #[tokio::test]
+    async fn test_denial_cache_asks_the_store_again_once_tokens_are_due() {
+        let clock = ManualClock::default();
+        let config = BucketConfig {
+            max_tokens: 1,
+            refill_interval: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        let state = AppState::new(MemoryStore::new(), config)
+            .with_clock(clock.clone())
+            .with_denial_cache(100);
+        let svc = limited(state.clone());
+        let status = |response: Response<Body>| response.status();
+
+        assert_eq!(status(send(svc.clone(), "abc").await), StatusCode::OK);
+        assert_eq!(
+            status(send(svc.clone(), "abc").await),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        assert!(state.reset_bucket("abc").await.unwrap());
+        assert_eq!(status(send(svc.clone(), "abc").await), StatusCode::OK);
+        assert_eq!(
+            status(send(svc.clone(), "abc").await),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        state.set_tokens("abc", 1).await.unwrap();
+        assert_eq!(status(send(svc.clone(), "abc").await), StatusCode::OK);
+        assert_eq!(
+            status(send(svc.clone(), "abc").await),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(
+            status(send(svc.clone(), "abc").await),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(status(send(svc.clone(), "abc").await), StatusCode::OK);
+    }
+
+    /// How many requests `identity` has in flight under `state`.
+    fn in_flight<S>(state: &AppState<S>, identity: &str) -> usize {
+        state
+            .in_flight
+            .as_ref()
+            .unwrap()
+            .count(&state.bucket_key(identity))
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_cap_rejects_concurrent_requests() {
+        let state = memory_state()
+            .with_max_in_flight(1)
+            .with_problem_details(true);
+        let release = Arc::new(tokio::sync::Notify::new());
+        let inner = tower::service_fn({
+            let release = Arc::clone(&release);
+            move |_req: Request<Body>| {
+                let release = Arc::clone(&release);
+                async move {
+                    release.notified().await;
+                    Ok::<_, Infallible>(Response::new(Body::from("slow")))
+                }
+            }
+        });
+        let svc = ServiceBuilder::new()
+            .layer(RateLimiterLayer::new(state.clone()))
+            .service(inner);
+
+        let first = tokio::spawn(send(svc.clone(), "abc"));
+        while in_flight(&state, "abc") == 0 {
+            tokio::task::yield_now().await;
+        }
+        let response = send(svc.clone(), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["X-RateLimit-Scope"], "concurrency");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let problem: ProblemDetails = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem.problem_type, "urn:leaky-bucket:too-many-in-flight");
+        // Turned away before its bucket was charged.
+        assert_eq!(state.check_tokens("abc").await.unwrap().remaining, 9);
+
+        release.notify_one();
+        let response = first.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Still in flight until its body is gone.
+        assert_eq!(in_flight(&state, "abc"), 1);
+        drop(response);
+        assert_eq!(in_flight(&state, "abc"), 0);
+    }
+
+    async fn panicking(_: Request<Body>) -> Result<Response<Body>, Infallible> {
+        panic!("the handler blew up")
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_request_is_released_when_the_handler_panics() {
+        let state = memory_state().with_max_in_flight(1);
+        let svc = ServiceBuilder::new()
+            .layer(RateLimiterLayer::new(state.clone()))
+            .service(tower::service_fn(panicking));
+
+        for _ in 0..3 {
+            let outcome = tokio::spawn(send(svc.clone(), "abc")).await;
+            // Let through to the handler each time, not turned away.
+            assert!(outcome.unwrap_err().is_panic());
+            assert_eq!(in_flight(&state, "abc"), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_request_is_released_when_it_is_dropped() {
+        let state = memory_state().with_max_in_flight(1);
+        let hung = tower::service_fn(|_req: Request<Body>| {
+            std::future::pending::<Result<Response<Body>, Infallible>>()
+        });
+        let svc = ServiceBuilder::new()
+            .layer(RateLimiterLayer::new(state.clone()))
+            .service(hung);
+
+        for _ in 0..3 {
+            let request = send(svc.clone(), "abc");
+            assert!(
+                tokio::time::timeout(Duration::from_millis(20), request)
+                    .await
+                    .is_err()
+            );
+            assert_eq!(in_flight(&state, "abc"), 0);
+        }
+        assert_eq!(state.check_tokens("abc").await.unwrap().remaining, 7);
+    }
+
+    #[tokio::test]
+    async fn test_reset_bucket_deletes_it() {
+        let conn = ScriptedConnection::new(vec![("DEL", Value::Int(1)), ("DEL", Value::Int(0))]);
+        let state = AppState::new(AsyncRedisStore::new(conn.clone()), BucketConfig::default());
+
+        assert!(state.reset_bucket("abc").await.unwrap());
+        assert!(!state.reset_bucket("abc").await.unwrap());
+        assert_eq!(conn.received()[0], ["DEL", &generate_bucket_key("abc")]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_reset_bucket_deletes_it() {
+        let conn = ScriptedConnection::new(vec![("DEL", Value::Int(0))]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        assert!(!state.reset_bucket("abc").await.unwrap());
+        assert_eq!(conn.received(), [["DEL", &generate_bucket_key("abc")]]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_reset_bucket_refills_it() {
+        let state = memory_state();
+        state.consume_tokens("abc", 10).await.unwrap();
+        assert_eq!(
+            state
+                .get_bucket_status("abc")
+                .await
+                .unwrap()
+                .unwrap()
+                .tokens,
+            0
+        );
+
+        assert!(state.reset_bucket("abc").await.unwrap());
+
+        assert_eq!(state.get_bucket_status("abc").await.unwrap(), None);
+        assert!(state.consume_tokens("abc", 10).await.unwrap().allowed);
+        assert!(!state.reset_bucket("def").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_allowlisted_identity_never_reaches_redis() {
+        let conn = ScriptedConnection::new(vec![]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_allowlist(Allowlist::new().with_identity("probe"))
+            .with_shared_blocklist(true);
+        let svc = limited(state);
+
+        for _ in 0..100 {
+            let response = send(svc.clone(), "probe").await;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["X-RateLimit-Bypass"], "true");
+            assert!(!response.headers().contains_key("X-RateLimit-Remaining"));
+        }
+        assert!(conn.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_prefix_matches_raw_identity() {
+        let state = memory_state().with_allowlist(Allowlist::new().with_prefix("internal-"));
+        let svc = limited(state.clone());
+
+        let internal = send(svc.clone(), "internal-billing").await;
+        let external = send(svc, "external").await;
+
+        assert_eq!(internal.headers()["X-RateLimit-Bypass"], "true");
+        assert!(!external.headers().contains_key("X-RateLimit-Bypass"));
+        assert_eq!(header_i64(&external, "X-RateLimit-Remaining"), 9);
+        assert_eq!(state.store.len(), 1);
+    }
+
+    fn preflight() -> axum::http::request::Builder {
+        Request::builder()
+            .method("OPTIONS")
+            .header(header::ORIGIN, "https://app.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+    }
+
+    #[tokio::test]
+    async fn test_preflight_reaches_the_handler_without_a_token() {
+        let state = memory_state();
+        let reached = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&reached);
+        let svc = ServiceBuilder::new()
+            .layer(RateLimiterLayer::new(state.clone()))
+            .service_fn(move |request: Request<Body>| {
+                seen.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(request.method(), "OPTIONS");
+                async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+            });
+
+        let response = call(svc, preflight()).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(reached.load(Ordering::SeqCst), 1);
+        assert!(!response.headers().contains_key("X-RateLimit-Remaining"));
+        assert!(state.store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_preflight_can_be_limited() {
+        let svc = limited(memory_state().with_exempt_preflight(false));
+
+        let response = call(svc, preflight()).await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_plain_options_request_is_limited() {
+        let svc = limited(memory_state());
+
+        let response = call(svc.clone(), Request::builder().method("OPTIONS")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = call(
+            svc,
+            Request::builder()
+                .method("GET")
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    fn probed(state: AppState<MemoryStore>) -> Router {
+        Router::new()
+            .route("/healthz", get(|| async { "ok" }))
+            .route("/api/thing", get(|| async { "thing" }))
+            .layer(RateLimiterLayer::new(state))
+    }
+
+    #[tokio::test]
+    async fn test_exempt_path_needs_no_identity() {
+        let state = memory_state().with_exempt_paths(ExemptPaths::new().with_path("/healthz"));
+        let app = probed(state.clone());
+
+        for path in ["/healthz", "/healthz/"] {
+            let response = call(app.clone(), Request::builder().uri(path)).await;
+            assert_ne!(response.status(), StatusCode::UNAUTHORIZED, "{path}");
+            assert!(!response.headers().contains_key("X-RateLimit-Remaining"));
+        }
+        let response = call(app.clone(), Request::builder().uri("/healthz")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = call(app.clone(), Request::builder().uri("/api/thing")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = call(
+            app,
+            Request::builder().uri("/api/thing").header("Bearer", "abc"),
+        )
+        .await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+        assert_eq!(state.store.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_exempt_pattern_matches_the_path_before_nesting() {
+        let state = memory_state().with_exempt_paths(ExemptPaths::new().with_path("/internal/*"));
+        let app = Router::new()
+            .nest("/internal", probed(state.clone()))
+            .merge(probed(state.clone()));
+
+        let response = call(app.clone(), Request::builder().uri("/internal/api/thing")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call(app, Request::builder().uri("/api/thing")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(state.store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_identity_is_forbidden_without_a_charge() {
+        let state = memory_state();
+        state.add_to_blocklist("abc").await.unwrap();
+        let svc = limited(state.clone());
+
+        let blocked = send(svc.clone(), "abc").await;
+        let other = send(svc, "def").await;
+
+        assert_eq!(blocked.status(), StatusCode::FORBIDDEN);
+        assert!(!blocked.headers().contains_key("X-RateLimit-Remaining"));
+        assert_eq!(other.status(), StatusCode::OK);
+        assert_eq!(state.store.len(), 1);
+        assert!(state.get_bucket_status("abc").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unblocked_identity_is_limited_again() {
+        let state = memory_state();
+        state.add_to_blocklist("abc").await.unwrap();
+
+        state.remove_from_blocklist("abc").await.unwrap();
+        let response = send(limited(state.clone()), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state.blocklist.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_response_is_configurable() {
+        let state = memory_state().with_problem_details(true);
+        state.blocklist.insert("abc");
+
+        let response = send(limited(state.clone()), "abc").await;
+        assert_eq!(
+            problem(response).await.problem_type,
+            "urn:leaky-bucket:blocked"
+        );
+
+        let state = state.with_blocked_response(|| {
+            Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("go away"))
+                .unwrap()
+        });
+        let response = send(limited(state), "abc").await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "go away");
+    }
+
+    #[tokio::test]
+    async fn test_shared_blocklist_is_checked_before_the_bucket() {
+        let conn = ScriptedConnection::new(vec![("SISMEMBER", Value::Int(1))]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_shared_blocklist(true);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            conn.received(),
+            [[
+                "SISMEMBER",
+                "bucket:{blocklist}",
+                &generate_bucket_key("abc")
+            ]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_blocklist_lets_others_through() {
+        let conn = ScriptedConnection::new(vec![
+            ("SISMEMBER", Value::Int(0)),
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            ("MULTI SET EXEC", committed()),
+        ]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_shared_blocklist(true);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.written().tokens, 9);
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_shared_blocklist_blocks_no_one() {
+        let conn = ScriptedConnection::new(vec![
+            ("SISMEMBER", Value::SimpleString("nonsense".into())),
+            ("EVALSHA", refilled(None)),
+        ]);
+        let state = AppState::new(AsyncRedisStore::new(conn), BucketConfig::default())
+            .with_shared_blocklist(true);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_updates_reach_the_shared_set() {
+        let conn = ScriptedConnection::new(vec![("SADD", Value::Int(1)), ("SREM", Value::Int(1))]);
+        let state = AppState::new(AsyncRedisStore::new(conn.clone()), BucketConfig::default())
+            .with_shared_blocklist(true);
+        let key = generate_bucket_key("abc");
+
+        state.add_to_blocklist("abc").await.unwrap();
+        assert!(state.blocklist.contains("abc"));
+        state.remove_from_blocklist("abc").await.unwrap();
+
+        assert!(state.blocklist.is_empty());
+        assert_eq!(
+            conn.received(),
+            [
+                ["SADD", "bucket:{blocklist}", &key],
+                ["SREM", "bucket:{blocklist}", &key]
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_blocklist_skips_the_shared_one() {
+        let conn = ScriptedConnection::new(vec![("SADD", Value::Int(1))]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_shared_blocklist(true);
+        state.add_to_blocklist("abc").await.unwrap();
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(conn.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_memory_shared_blocklist() {
+        let state = memory_state().with_shared_blocklist(true);
+        state
+            .store
+            .set_blocked(&generate_bucket_key("abc"), true)
+            .await
+            .unwrap();
+
+        let response = send(limited(state.clone()), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(state.blocklist.is_empty());
+    }
+
+    async fn admin(app: Router, method: &str, uri: &str, body: Option<&str>) -> Response<Body> {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+        app.oneshot(request.body(body).unwrap()).await.unwrap()
+    }
+
+    async fn bucket_body(response: Response<Body>) -> BucketBody {
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_gets_bucket() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = memory_state().with_clock(clock.clone());
+        state.consume_tokens("abc", 3).await.unwrap();
+        clock.advance(Duration::from_secs(20 * 60));
+
+        let response = admin(admin_router(state), "GET", "/buckets/abc", None).await;
+
+        assert_eq!(
+            bucket_body(response).await,
+            BucketBody {
+                tokens: 7,
+                last_updated: "2025-03-01T12:00:00Z".parse().unwrap(),
+                seconds_to_full: 160 * 60,
+                banned_until: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_missing_bucket_is_not_found() {
+        let app = admin_router(memory_state());
+
+        let get = admin(app.clone(), "GET", "/buckets/abc", None).await;
+        let delete = admin(app, "DELETE", "/buckets/abc", None).await;
+
+        assert_eq!(get.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            problem(get).await.problem_type,
+            "urn:leaky-bucket:bucket-not-found"
+        );
+        assert_eq!(delete.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_admin_deletes_bucket() {
+        let state = memory_state();
+        state.consume_tokens("abc", 10).await.unwrap();
+
+        let response = admin(admin_router(state.clone()), "DELETE", "/buckets/abc", None).await;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state.consume_tokens("abc", 10).await.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    async fn test_admin_sets_tokens() {
+        let state = memory_state();
+        let app = admin_router(state.clone());
+
+        let response = admin(app, "PUT", "/buckets/abc/tokens", Some(r#"{"tokens":2}"#)).await;
+
+        assert_eq!(bucket_body(response).await.tokens, 2);
+        let response = send(limited(state), "abc").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_rejects_tokens_beyond_capacity() {
+        let app = admin_router(memory_state());
+
+        for tokens in ["-1", "11"] {
+            let body = format!(r#"{{"tokens":{tokens}}}"#);
+            let response = admin(app.clone(), "PUT", "/buckets/abc/tokens", Some(&body)).await;
+
+            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(
+                problem(response).await.problem_type,
+                "urn:leaky-bucket:invalid-tokens"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_admin_swaps_the_config() {
+        let state = memory_state();
+        let app = admin_router(state.clone());
+
+        let response = admin(
+            app.clone(),
+            "PUT",
+            "/config",
+            Some(r#"{"max_tokens":2,"refill_interval_ms":60000}"#),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let expected = BucketConfig {
+            max_tokens: 2,
+            refill_interval: Duration::from_secs(60),
+            ..BucketConfig::default()
+        };
+        assert_eq!(*state.config(), expected);
+
+        let response = admin(app.clone(), "GET", "/config", None).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<BucketConfig>(&body).unwrap(),
+            expected
+        );
+
+        let response = admin(app, "PUT", "/config", Some(r#"{"max_tokens":0}"#)).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            problem(response).await.problem_type,
+            "urn:leaky-bucket:invalid-config"
+        );
+        assert_eq!(*state.config(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_admin_writes_tokens_to_redis() {
+        let conn = ScriptedConnection::new(vec![("MULTI SET EXEC", committed())]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = admin(
+            admin_router(state),
+            "PUT",
+            "/buckets/abc/tokens",
+            Some(r#"{"tokens":4}"#),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(conn.written().tokens, 4);
+        assert_eq!(conn.received()[1][1], generate_bucket_key("abc"));
+    }
+
+    #[tokio::test]
+    async fn test_admin_store_errors_are_bad_gateway() {
+        let conn = ScriptedConnection::new(vec![]);
+        conn.set_down(true);
+        let app = admin_router(AppState::new(
+            AsyncRedisStore::new(conn),
+            BucketConfig::default(),
+        ));
+
+        for (method, uri, body) in [
+            ("GET", "/buckets/abc", None),
+            ("DELETE", "/buckets/abc", None),
+            ("PUT", "/buckets/abc/tokens", Some(r#"{"tokens":4}"#)),
+        ] {
+            let response = admin(app.clone(), method, uri, body).await;
+
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY, "{method}");
+            assert_eq!(
+                problem(response).await.problem_type,
+                "urn:leaky-bucket:store-error"
+            );
+        }
+    }
+
+    /// The script's reply for a bucket that holds `bucket` once refilled, or a
+    /// full new one.
+    fn async_script(bucket: Option<&TokenPersistence>) -> ScriptedConnection {
+        ScriptedConnection::new(vec![("EVALSHA", refilled(bucket))])
+    }
+
+    fn refilled(bucket: Option<&TokenPersistence>) -> Value {
+        let (tokens, last_updated) =
+            bucket.map_or((10, Utc::now()), |b| (b.tokens, b.last_updated));
+        Value::Array(vec![
+            Value::Int(tokens),
+            Value::Int(last_updated.timestamp_millis()),
+            Value::Int(0),
+            Value::Int(0),
+            Value::Int(0),
+            Value::Int(0),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_async_allows_new_bucket() {
+        let conn = async_script(None);
+        let state = AppState::new(AsyncRedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0][3], generate_bucket_key("abc"));
+        assert_eq!(received[0][4..8], ["10", "1", "3600000", "1"]);
+    }
+
+    #[tokio::test]
+    async fn test_async_charges_refilled_bucket() {
+        let conn = async_script(Some(&TokenPersistence {
+            tokens: 5,
+            last_updated: Utc::now() - chrono::Duration::minutes(30),
+            penalty: None,
+        }));
+        let state = AppState::new(AsyncRedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 4);
+    }
+
+    #[tokio::test]
+    async fn test_async_denies_empty_bucket() {
+        let conn = async_script(Some(&TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now() - chrono::Duration::minutes(58),
+            penalty: None,
+        }));
+        let state = AppState::new(AsyncRedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "Retry-After"), 120);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_async_charge_routes_by_the_bucket_key() {
+        let conn = async_script(None);
+        let state = AppState::new(AsyncRedisStore::new(conn.clone()), BucketConfig::default());
+
+        send(limited(state), "abc").await;
+
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0][0], "EVALSHA");
+        // One key, so a cluster sends the script to the node owning it.
+        assert_eq!(received[0][2], "1");
+        assert_eq!(
+            get_slot(received[0][3].as_bytes()),
+            get_slot(generate_bucket_key("abc").as_bytes())
+        );
+    }
+
+    #[test]
+    fn test_route_buckets_share_the_identity_slot() {
+        let key = generate_bucket_key("abc");
+
+        assert!(key.starts_with("bucket:{") && key.ends_with('}'));
+        assert_eq!(
+            get_slot(format!("{key}:/expensive").as_bytes()),
+            get_slot(key.as_bytes())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prefixes_keep_states_apart() {
+        let staging = KeyPrefix::new("myapp:staging:bucket:").unwrap();
+        let prod = KeyPrefix::new("myapp:prod:bucket:").unwrap();
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            ("MULTI SET EXEC", committed()),
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            ("MULTI SET EXEC", committed()),
+        ]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = send(limited(state.clone().with_key_prefix(staging)), "abc").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(limited(state.with_key_prefix(prod)), "abc").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let hash = generate_bucket_key("abc").replacen("bucket:", "", 1);
+        let touched = conn
+            .received()
+            .into_iter()
+            .filter(|command| command[0] == "WATCH")
+            .map(|command| command[1].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            touched,
+            [
+                format!("myapp:staging:bucket:{hash}"),
+                format!("myapp:prod:bucket:{hash}")
+            ]
+        );
+
+        // Sharing a store, one state's exhausted bucket is none of the
+        // other's business.
+        let config = BucketConfig {
+            max_tokens: 1,
+            ..BucketConfig::default()
+        };
+        let staging = AppState::new(MemoryStore::new(), config)
+            .with_key_prefix(KeyPrefix::new("myapp:staging:bucket:").unwrap());
+        let prod = staging
+            .clone()
+            .with_key_prefix(KeyPrefix::new("myapp:prod:bucket:").unwrap());
+
+        assert!(staging.consume_tokens("abc", 1).await.unwrap().allowed);
+        assert!(!staging.consume_tokens("abc", 1).await.unwrap().allowed);
+        assert!(prod.consume_tokens("abc", 1).await.unwrap().allowed);
+        prod.reset_bucket("abc").await.unwrap();
+        assert!(!staging.consume_tokens("abc", 1).await.unwrap().allowed);
+    }
+
+    #[tokio::test]
+    async fn test_async_sends_script_source_on_noscript() {
+        let noscript = redis::parse_redis_value(b"-NOSCRIPT No matching script.\r\n").unwrap();
+        let conn = ScriptedConnection::new(vec![
+            ("EVALSHA", noscript),
+            ("EVAL", refilled(None)),
+            ("EVALSHA", refilled(None)),
+        ]);
+        let state = AppState::new(AsyncRedisStore::new(conn.clone()), BucketConfig::default());
+        let svc = limited(state);
+
+        assert_eq!(send(svc.clone(), "abc").await.status(), StatusCode::OK);
+        assert_eq!(send(svc, "abc").await.status(), StatusCode::OK);
+
+        let received = conn.received();
+        let names = received.iter().map(|c| c[0].as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["EVALSHA", "EVAL", "EVALSHA"]);
+        assert!(received[1][1].contains("redis.call('GET', b.key)"));
+        assert_eq!(received[1][2..], received[0][2..]);
+    }
+
+    #[tokio::test]
+    async fn test_async_redis_error_is_service_unavailable() {
+        let conn = ScriptedConnection::new(vec![]);
+        conn.set_down(true);
+        let state = AppState::new(AsyncRedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(header_i64(&response, "Retry-After"), 1);
+        assert_eq!(conn.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_async_script_error_is_service_unavailable() {
+        let failed =
+            redis::parse_redis_value(b"-ERR user_script:1: invalid last_updated\r\n").unwrap();
+        let conn = ScriptedConnection::new(vec![("EVALSHA", failed)]);
+        let state = AppState::new(AsyncRedisStore::new(conn), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_async_missing_token_skips_redis() {
+        let conn = ScriptedConnection::new(vec![]);
+        let state = AppState::new(AsyncRedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = call(limited(state), Request::builder()).await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(conn.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_async_local_fallback() {
+        let conn = ScriptedConnection::new(vec![]);
+        conn.set_down(true);
+        let state = AppState::new(AsyncRedisStore::new(conn), BucketConfig::default())
+            .with_local_fallback(10);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+    }
+
+    /// Hands out `connections` in order, one per reconnect.
+    fn reconnecting(
+        connections: Vec<ScriptedConnection>,
+    ) -> (ReconnectingConnection<ScriptedConnection>, Arc<AtomicUsize>) {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let connections = Arc::new(StdMutex::new(VecDeque::from(connections)));
+        let counter = Arc::clone(&connects);
+        let conn = ReconnectingConnection::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let next = connections.lock().unwrap().pop_front().ok_or_else(refused);
+            Box::pin(async move { next })
+        });
+        (conn, connects)
+    }
+
+    fn read_only() -> Value {
+        redis::parse_redis_value(b"-READONLY You can't write against a read only replica.\r\n")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_demoted_master_is_replaced_within_the_request() {
+        let demoted = ScriptedConnection::new(vec![("EVALSHA", read_only())]);
+        let promoted = async_script(None);
+        let (conn, connects) = reconnecting(vec![demoted, promoted.clone()]);
+        let state = AppState::new(AsyncRedisStore::new(conn), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+        assert_eq!(promoted.received().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_master_is_looked_up_again_on_the_next_request() {
+        let dead = ScriptedConnection::new(vec![]);
+        dead.set_down(true);
+        let (conn, connects) = reconnecting(vec![dead, async_script(None)]);
+        let state = AppState::new(AsyncRedisStore::new(conn), BucketConfig::default());
+        let svc = limited(state);
+
+        let first = send(svc.clone(), "abc").await;
+        let second = send(svc, "abc").await;
+
+        assert_eq!(first.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_broken_connection_is_replaced_within_the_request() {
+        let conn = async_script(None);
+        conn.break_pipe();
+        let (reconnecting, connects) = reconnecting(vec![conn.clone(), conn.clone()]);
+        let state = AppState::new(AsyncRedisStore::new(reconnecting), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+        assert_eq!(conn.received().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_broken_blocking_connection_is_replaced_on_the_next_request() {
+        let conn = allow_script(None);
+        conn.break_pipe();
+        let connects = Arc::new(AtomicUsize::new(0));
+        let reconnecting = BlockingReconnectingConnection::new({
+            let conn = conn.clone();
+            let connects = Arc::clone(&connects);
+            move || {
+                connects.fetch_add(1, Ordering::SeqCst);
+                Ok(conn.clone())
+            }
+        });
+        let state = AppState::new(RedisStore::new(reconnecting), BucketConfig::default());
+        let svc = limited(state);
+
+        let first = send(svc.clone(), "abc").await;
+        let second = send(svc, "abc").await;
+
+        assert_eq!(first.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_healthy_connection_is_kept() {
+        let conn = ScriptedConnection::new(vec![
+            ("EVALSHA", refilled(None)),
+            ("EVALSHA", refilled(None)),
+        ]);
+        let (conn, connects) = reconnecting(vec![conn]);
+        let state = AppState::new(AsyncRedisStore::new(conn), BucketConfig::default());
+        let svc = limited(state);
+
+        assert_eq!(send(svc.clone(), "abc").await.status(), StatusCode::OK);
+        assert_eq!(send(svc, "abc").await.status(), StatusCode::OK);
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pooled_requests_run_concurrently() {
+        let connections = (0..50)
+            .map(|_| async_script(None).with_delay(Duration::from_millis(100)))
+            .collect::<Vec<_>>();
+        let state = AppState::new(
+            AsyncRedisStore::from_pool(ConnectionPool::new(connections.clone())),
+            BucketConfig::default(),
+        );
+        let svc = limited(state);
+
+        let started = tokio::time::Instant::now();
+        let responses = futures_util::future::join_all((0..50).map(|i| {
+            let svc = svc.clone();
+            async move { send(svc, &format!("client-{i}")).await }
+        }))
+        .await;
+
+        assert!(responses.iter().all(|r| r.status() == StatusCode::OK));
+        // One round trip each; one connection would take 50 times as long.
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            started.elapsed()
+        );
+        assert!(connections.iter().all(|conn| conn.received().len() == 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connection_released_before_handler_runs() {
+        let conn = ScriptedConnection::new(
+            [allow_script(None), allow_script(None)]
+                .iter()
+                .flat_map(|script| script.replies.lock().unwrap().clone())
+                .collect(),
+        );
+        let slow = tower::service_fn(|_req: Request<Body>| async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        });
+        let svc = ServiceBuilder::new()
+            .layer(middleware::from_fn_with_state(
+                AppState::new(RedisStore::new(conn.clone()), BucketConfig::default()),
+                rate_limiter_middleware::<RedisStore<ScriptedConnection>>,
+            ))
+            .service(slow);
+
+        let started = tokio::time::Instant::now();
+        let (first, second) = tokio::join!(send(svc.clone(), "a"), send(svc, "b"));
+
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        // Both handlers sleep side by side; holding the connection through
+        // the first one would push the second to two seconds.
+        assert!(started.elapsed() < Duration::from_millis(1500));
+        assert_eq!(
+            conn.received().iter().filter(|c| c[0] == "WATCH").count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_redis_does_not_stall_runtime() {
+        let conn = allow_script(None).with_delay(Duration::from_millis(50));
+        let svc = limited(AppState::new(
+            RedisStore::new(conn).with_timeout(Duration::from_secs(1)),
+            BucketConfig::default(),
+        ));
+        let done = AtomicBool::new(false);
+
+        let request = async {
+            let response = send(svc, "abc").await;
+            done.store(true, Ordering::SeqCst);
+            response
+        };
+        let ticker = async {
+            let mut ticks = 0;
+            while !done.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                ticks += 1;
+            }
+            ticks
+        };
+        let (response, ticks) = tokio::join!(request, ticker);
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // Four round trips of 50ms; a blocked thread would tick only once.
+        assert!(ticks >= 10, "{ticks}");
+    }
+
+    #[tokio::test]
+    async fn test_hung_redis_fails_closed_within_the_timeout() {
+        let conn = allow_script(None).with_delay(Duration::from_millis(500));
+        let store = RedisStore::new(conn).with_timeout(Duration::from_millis(50));
+        let svc = limited(AppState::new(store, BucketConfig::default()));
+
+        let started = Instant::now();
+        let response = send(svc, "abc").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            started.elapsed() < Duration::from_millis(400),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hung_redis_fails_open_within_the_timeout() {
+        let conn = async_script(None).with_delay(Duration::from_secs(60));
+        let state = AppState::new(AsyncRedisStore::new(conn), BucketConfig::default())
+            .with_failure_policy(FailurePolicy::Open);
+
+        let started = tokio::time::Instant::now();
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(started.elapsed(), DEFAULT_REDIS_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeouts_trip_the_circuit_breaker() {
+        let conn = async_script(None).with_delay(Duration::from_secs(60));
+        let store = AsyncRedisStore::new(conn.clone());
+        let state = AppState::new(store, BucketConfig::default()).with_circuit_breaker(
+            CircuitBreakerConfig {
+                failure_threshold: 2,
+                window: Duration::from_secs(10),
+                cooldown: Duration::from_secs(10),
+            },
+        );
+        let svc = limited(state.clone());
+
+        for _ in 0..2 {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        assert_eq!(state.breaker_state(), Some(BreakerState::Open));
+        let error = state
+            .store
+            .take_token("abc", 1, &BucketConfig::default(), Utc::now())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "no answer from the store in 100ms");
+    }
+
+    #[tokio::test]
+    async fn test_shadow_mode_lets_denied_requests_through() {
+        let state = memory_state().with_mode(Mode::Shadow);
+        let svc = limited(state.clone());
+
+        for remaining in (0..10).rev() {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), remaining);
+            assert!(response.headers().get("X-RateLimit-Would-Block").is_none());
+        }
+        for _ in 0..2 {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["X-RateLimit-Would-Block"], "true");
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+            assert!(response.headers().get(header::RETRY_AFTER).is_none());
+        }
+        // The bucket really was drained.
+        assert!(!state.check_tokens("abc").await.unwrap().allowed);
+    }
+
+    fn echoing_remaining<S: BucketStore>(state: AppState<S>) -> Router {
+        Router::new()
+            .route(
+                "/",
+                get(|info: RateLimitInfo| async move { info.remaining.to_string() }),
+            )
+            .route(
+                "/optional",
+                get(|info: Option<RateLimitInfo>| async move { format!("{info:?}") }),
+            )
+            .layer(middleware::from_fn_with_state(
+                state,
+                rate_limiter_middleware::<S>,
+            ))
+    }
+
+    async fn body_text(response: Response<Body>) -> String {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handler_reads_rate_limit_info() {
+        let svc = echoing_remaining(memory_state());
+
+        for remaining in ["9", "8"] {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_text(response).await, remaining);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_info_describes_the_bucket() {
+        let clock = ManualClock::new(Utc::now());
+        let state = memory_state().with_clock(clock.clone());
+        let info = Arc::new(StdMutex::new(None));
+        let seen = Arc::clone(&info);
+        let svc = Router::new()
+            .route(
+                "/",
+                get(move |info: RateLimitInfo| async move {
+                    *seen.lock().unwrap() = Some(info);
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                state,
+                rate_limiter_middleware::<MemoryStore>,
+            ));
+
+        send(svc, "abc").await;
+
+        let info = info.lock().unwrap().take().unwrap();
+        assert_eq!(info.limit, 10);
+        assert_eq!(info.remaining, 9);
+        assert!(info.reset_at > clock.now());
+        assert_eq!(info.key_hash, generate_bucket_key("abc"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_info_is_missing_without_a_charge() {
+        let state = memory_state().with_allowlist(Allowlist::new().with_identity("abc"));
+        let svc = echoing_remaining(state);
+
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let response = call(
+            svc,
+            Request::builder().uri("/optional").header("Bearer", "abc"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_text(response).await, "None");
+    }
+
+    #[tokio::test]
+    async fn test_shadow_mode_still_charges_the_store() {
+        let bucket = TokenPersistence {
+            tokens: 1,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = allow_script(Some(&bucket));
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_mode(Mode::Shadow);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("X-RateLimit-Would-Block").is_none());
+        assert_eq!(conn.written().tokens, 0);
+
+        let conn = deny_script(&conn.written());
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_mode(Mode::Shadow);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-RateLimit-Would-Block"], "true");
+        assert_eq!(conn.received().len(), 3);
+    }
+
+    /// A recorded metric as `(name, sorted labels, value)`.
+    #[cfg(feature = "metrics")]
+    type Recorded = (String, Vec<(String, String)>, DebugValue);
+
+    /// Runs `flow` with a recorder of its own and returns what it recorded.
+    #[cfg(feature = "metrics")]
+    fn recorded(flow: impl std::future::Future<Output = ()>) -> Vec<Recorded> {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        metrics::with_local_recorder(&recorder, || runtime.block_on(flow));
+        snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let (_, key) = key.into_parts();
+                let mut labels = key
+                    .labels()
+                    .map(|label| (label.key().to_owned(), label.value().to_owned()))
+                    .collect::<Vec<_>>();
+                labels.sort();
+                (key.name().to_owned(), labels, value)
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "metrics")]
+    fn requests_total(recorded: &[Recorded], outcome: &str) -> u64 {
+        recorded
+            .iter()
+            .filter(|(name, labels, _)| {
+                name == "leaky_bucket_requests_total"
+                    && labels.contains(&("outcome".to_owned(), outcome.to_owned()))
+            })
+            .map(|(_, _, value)| match value {
+                DebugValue::Counter(count) => *count,
+                other => panic!("not a counter: {other:?}"),
+            })
+            .sum()
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics_count_outcomes() {
+        let recorded = recorded(async {
+            let allowed =
+                AppState::new(RedisStore::new(allow_script(None)), BucketConfig::default());
+            assert_eq!(send(limited(allowed), "abc").await.status(), StatusCode::OK);
+
+            let empty = TokenPersistence {
+                tokens: 0,
+                last_updated: Utc::now(),
+                penalty: None,
+            };
+            for _ in 0..2 {
+                let denied = AppState::new(
+                    RedisStore::new(deny_script(&empty)),
+                    BucketConfig::default(),
+                );
+                let response = send(limited(denied), "abc").await;
+                assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            }
+
+            let unauthorized = AppState::new(
+                RedisStore::new(ScriptedConnection::new(vec![])),
+                BucketConfig::default(),
+            );
+            let response = call(limited(unauthorized), Request::builder()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+            let failing = MockRedisConnection::new(vec![MockCmd::new(
+                cmd("WATCH").arg(generate_bucket_key("abc")),
+                Err::<Value, _>(refused()),
+            )]);
+            let failing = AppState::new(RedisStore::new(failing), BucketConfig::default());
+            let response = send(limited(failing), "abc").await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        });
+
+        assert_eq!(requests_total(&recorded, "allowed"), 1);
+        assert_eq!(requests_total(&recorded, "denied"), 2);
+        assert_eq!(requests_total(&recorded, "unauthorized"), 1);
+        assert_eq!(requests_total(&recorded, "error"), 1);
+
+        let histogram = |name: &str| {
+            recorded
+                .iter()
+                .filter(|(recorded, _, _)| recorded == name)
+                .flat_map(|(_, _, value)| match value {
+                    DebugValue::Histogram(values) => values.iter().map(|v| v.0).collect::<Vec<_>>(),
+                    other => panic!("not a histogram: {other:?}"),
+                })
+                .collect::<Vec<f64>>()
+        };
+        // Everything that got as far as the store, failures included.
+        assert_eq!(histogram("leaky_bucket_store_duration_seconds").len(), 4);
+        let mut remaining = histogram("leaky_bucket_remaining_tokens");
+        remaining.sort_by(f64::total_cmp);
+        assert_eq!(remaining, [0.0, 0.0, 9.0]);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics_route_label() {
+        let recorded = recorded(async {
+            let state = per_route_state(allow_script(None)).with_metrics_route_label(true);
+            get_path(routed(state), "/search").await;
+            let state = per_route_state(allow_script(None));
+            get_path(routed(state), "/export").await;
+        });
+
+        let labels = recorded
+            .iter()
+            .filter(|(name, _, _)| name == "leaky_bucket_requests_total")
+            .map(|(_, labels, _)| labels.clone())
+            .collect::<Vec<_>>();
+        let outcome = ("outcome".to_owned(), "allowed".to_owned());
+        assert_eq!(labels.len(), 2);
+        assert!(labels.contains(&vec![
+            outcome.clone(),
+            ("route".to_owned(), "/search".to_owned())
+        ]));
+        assert!(labels.contains(&vec![outcome]));
+        assert!(
+            recorded
+                .iter()
+                .flat_map(|(_, labels, _)| labels)
+                .all(|(_, value)| !value.contains("abc"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_counts_outcomes() {
+        let mut script = conflicting(2);
+        script.extend(allow_script(None).replies.lock().unwrap().clone());
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        script.extend(deny_script(&empty).replies.lock().unwrap().clone());
+        let state = AppState::new(
+            RedisStore::new(ScriptedConnection::new(script)).with_retry(no_backoff(3)),
+            BucketConfig::default(),
+        );
+        let svc = limited(state.clone());
+
+        assert_eq!(send(svc.clone(), "abc").await.status(), StatusCode::OK);
+        assert_eq!(
+            send(svc.clone(), "abc").await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        let response = call(svc, Request::builder()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = metrics_router(state)
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            response.headers()[header::CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .starts_with("text/plain; version=0.0.4")
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        let samples = body
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            samples,
+            [
+                r#"leaky_bucket_requests_total{outcome="allowed"} 1"#,
+                r#"leaky_bucket_requests_total{outcome="denied"} 1"#,
+                r#"leaky_bucket_requests_total{outcome="unauthorized"} 1"#,
+                r#"leaky_bucket_requests_total{outcome="error"} 0"#,
+                "leaky_bucket_transaction_retries_total 2",
+            ]
+        );
+        assert!(body.contains("# TYPE leaky_bucket_requests_total counter\n"));
+    }
+
+    fn routed_memory_state(tokens: i64) -> AppState<MemoryStore> {
+        let config = BucketConfig {
+            max_tokens: tokens,
+            ..BucketConfig::default()
+        };
+        memory_state()
+            .with_route("/search", config.clone())
+            .with_route("/export", config)
+    }
+
+    #[tokio::test]
+    async fn test_stats_are_broken_down_by_route() {
+        let state = routed_memory_state(1);
+        let app = routed(state.clone());
+        for path in ["/export", "/export", "/export", "/search"] {
+            get_path(app.clone(), path).await;
+        }
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap();
+
+        let stats = state.stats();
+
+        let counts = |allowed, denied, unauthorized| OutcomeCounts {
+            allowed,
+            denied,
+            unauthorized,
+            errors: 0,
+        };
+        assert_eq!(stats.totals, counts(2, 2, 1));
+        assert_eq!(stats.routes["/export"], counts(1, 2, 0));
+        assert_eq!(stats.routes["/search"], counts(1, 0, 0));
+        assert_eq!(stats.routes["/"], counts(0, 0, 1));
+        assert_eq!(
+            serde_json::to_value(&stats).unwrap()["routes"]["/export"],
+            serde_json::json!({ "allowed": 1, "denied": 2, "unauthorized": 0, "errors": 0 })
+        );
+        // Reading them leaves them be, unless asked.
+        assert_eq!(state.stats_and_reset(), stats);
+        assert_eq!(state.stats().totals, OutcomeCounts::default());
+        assert_eq!(state.stats.allowed(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_stats_stay_consistent_under_concurrent_requests() {
+        fn add(total: &mut OutcomeCounts, counts: &OutcomeCounts) {
+            total.allowed += counts.allowed;
+            total.denied += counts.denied;
+            total.unauthorized += counts.unauthorized;
+            total.errors += counts.errors;
+        }
+
+        let state = routed_memory_state(25);
+        let app = routed(state.clone());
+        let requests = (0..200)
+            .map(|n| {
+                let path = if n % 2 == 0 { "/search" } else { "/export" };
+                tokio::spawn(get_path(app.clone(), path))
+            })
+            .collect::<Vec<_>>();
+        // A scraper taking deltas all the while.
+        let scraper = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let mut taken = Vec::new();
+                for _ in 0..50 {
+                    taken.push(state.stats_and_reset());
+                    tokio::task::yield_now().await;
+                }
+                taken
+            })
+        };
+        for request in requests {
+            request.await.unwrap();
+        }
+        let mut taken = scraper.await.unwrap();
+        taken.push(state.stats_and_reset());
+
+        let mut totals = OutcomeCounts::default();
+        let mut routes = std::collections::HashMap::<String, OutcomeCounts>::new();
+        for snapshot in &taken {
+            add(&mut totals, &snapshot.totals);
+            for (route, counts) in &snapshot.routes {
+                add(routes.entry(route.clone()).or_default(), counts);
+            }
+        }
+        let expected = OutcomeCounts {
+            allowed: 25,
+            denied: 75,
+            ..OutcomeCounts::default()
+        };
+        assert_eq!(routes["/search"], expected);
+        assert_eq!(routes["/export"], expected);
+        assert_eq!(totals.allowed, 50);
+        assert_eq!(totals.denied, 150);
+    }
+
+    /// Collects what a `tracing` subscriber writes, without colours.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<StdMutex<Vec<u8>>>);
+
+    impl CapturedLogs {
+        fn subscriber(&self) -> impl tracing::Subscriber + use<> {
+            tracing_subscriber::fmt()
+                .with_writer(self.clone())
+                .with_ansi(false)
+                .finish()
+        }
+
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for CapturedLogs {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self {
+            self.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_denial_is_logged_with_span_fields() {
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(logs.subscriber());
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let state = AppState::new(
+            RedisStore::new(deny_script(&empty)),
+            BucketConfig::default(),
+        );
+
+        let response = send(limited(state), "secret-token").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let logs = logs.text();
+        let denial = logs
+            .lines()
+            .find(|line| line.contains("rate limit exceeded"))
+            .unwrap_or_else(|| panic!("no denial logged: {logs}"));
+        assert!(denial.contains(" WARN "), "{denial}");
+        let bucket = generate_bucket_key("secret-token");
+        assert!(
+            denial.contains(&format!("rate_limit{{bucket={bucket} cost=1 store_ms=")),
+            "{denial}"
+        );
+        assert!(denial.contains(" allowed=false remaining=0}"), "{denial}");
+        assert!(denial.contains("shadow=false"), "{denial}");
+        assert!(!logs.contains("secret-token"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_store_failure_is_logged_as_error() {
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(logs.subscriber());
+        let mock = MockRedisConnection::new(vec![MockCmd::new(
+            cmd("WATCH").arg(generate_bucket_key("secret-token")),
+            Err::<Value, _>(refused()),
+        )]);
+        let state = AppState::new(RedisStore::new(mock), BucketConfig::default());
+
+        let response = send(limited(state), "secret-token").await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let logs = logs.text();
+        let failure = logs
+            .lines()
+            .find(|line| line.contains("bucket store failed"))
+            .unwrap_or_else(|| panic!("no failure logged: {logs}"));
+        assert!(failure.contains("ERROR"), "{failure}");
+        assert!(failure.contains("rate_limit{bucket=bucket:{"), "{failure}");
+        assert!(!logs.contains("secret-token"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_allowed_requests_log_nothing() {
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(logs.subscriber());
+        let state = AppState::new(RedisStore::new(allow_script(None)), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(logs.text(), "");
+    }
+
+    /// Runs `flow` inside a `request` span, as tower-http's tracing would,
+    /// and returns the spans exported through `tracing-opentelemetry`.
+    #[cfg(feature = "otel")]
+    async fn exported_spans(flow: impl std::future::Future<Output = ()>) -> Vec<SpanData> {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        flow.instrument(tracing::info_span!("request")).await;
+
+        provider.force_flush().unwrap();
+        exporter.get_finished_spans().unwrap()
+    }
+
+    #[cfg(feature = "otel")]
+    fn attribute(span: &SpanData, key: &str) -> Option<opentelemetry::Value> {
+        span.attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.clone())
+    }
+
+    #[cfg(feature = "otel")]
+    #[tokio::test]
+    async fn test_denial_is_recorded_on_the_request_span() {
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let spans = exported_spans(async {
+            let state = AppState::new(
+                RedisStore::new(deny_script(&empty)),
+                BucketConfig::default(),
+            );
+            let response = send(limited(state), "abc").await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        })
+        .await;
+
+        let request = spans.iter().find(|span| span.name == "request").unwrap();
+        assert_eq!(
+            attribute(request, "ratelimit.decision"),
+            Some("denied".into())
+        );
+        assert_eq!(attribute(request, "ratelimit.remaining"), Some(0.into()));
+        assert_eq!(attribute(request, "ratelimit.limit"), Some(10.into()));
+        let Some(opentelemetry::Value::I64(retry_after)) =
+            attribute(request, "ratelimit.retry_after_ms")
+        else {
+            panic!("no retry_after_ms on {request:?}");
+        };
+        assert!(retry_after > 3_590_000, "{retry_after}");
+
+        let transaction = spans.iter().find(|span| span.name == "rate_limit").unwrap();
+        assert_eq!(transaction.parent_span_id, request.span_context.span_id());
+        assert!(attribute(transaction, "store_ms").is_some());
+        assert!(transaction.end_time <= request.end_time);
+    }
+
+    #[cfg(feature = "otel")]
+    #[tokio::test]
+    async fn test_allowed_request_has_no_retry_after_attribute() {
+        let spans = exported_spans(async {
+            let state = AppState::new(RedisStore::new(allow_script(None)), BucketConfig::default());
+            send(limited(state), "abc").await;
+        })
+        .await;
+
+        let request = spans.iter().find(|span| span.name == "request").unwrap();
+        assert_eq!(
+            attribute(request, "ratelimit.decision"),
+            Some("allowed".into())
+        );
+        assert_eq!(attribute(request, "ratelimit.remaining"), Some(9.into()));
+        assert_eq!(attribute(request, "ratelimit.retry_after_ms"), None);
+    }
+
+    /// Hooks that send every call down a channel, tagged with the method.
+    struct RecordingHooks(tokio::sync::mpsc::UnboundedSender<(&'static str, DecisionCtx)>);
+
+    impl RecordingHooks {
+        fn new() -> (
+            Self,
+            tokio::sync::mpsc::UnboundedReceiver<(&'static str, DecisionCtx)>,
+        ) {
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            (Self(tx), rx)
+        }
+    }
+
+    impl RateLimitHooks for RecordingHooks {
+        fn on_allowed<'a>(&'a self, ctx: &'a DecisionCtx) -> BoxFuture<'a, ()> {
+            Box::pin(async move { self.0.send(("allowed", ctx.clone())).unwrap() })
+        }
+
+        fn on_denied<'a>(&'a self, ctx: &'a DecisionCtx) -> BoxFuture<'a, ()> {
+            Box::pin(async move { self.0.send(("denied", ctx.clone())).unwrap() })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_hear_about_allowed_requests() {
+        let (hooks, mut calls) = RecordingHooks::new();
+        let state = AppState::new(RedisStore::new(allow_script(None)), BucketConfig::default())
+            .with_hooks(hooks);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            calls.recv().await.unwrap(),
+            (
+                "allowed",
+                DecisionCtx {
+                    bucket_key: generate_bucket_key("abc"),
+                    route: None,
+                    remaining: 9,
+                    retry_after: None,
+                    tier: None,
+                }
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hooks_hear_about_denied_requests() {
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let (hooks, mut calls) = RecordingHooks::new();
+        let state = AppState::new(
+            RedisStore::new(deny_script(&empty)),
+            BucketConfig::default(),
+        )
+        .with_hooks(hooks);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let (method, ctx) = calls.recv().await.unwrap();
+        assert_eq!(method, "denied");
+        assert_eq!(ctx.bucket_key, generate_bucket_key("abc"));
+        assert_eq!(ctx.remaining, 0);
+        assert!(ctx.retry_after.unwrap() > Duration::from_secs(3590));
+    }
+
+    #[tokio::test]
+    async fn test_hooks_get_the_matched_route() {
+        let (hooks, mut calls) = RecordingHooks::new();
+        let state = per_route_state(allow_script(None)).with_hooks(hooks);
+
+        let response = get_path(routed(state), "/search").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let (method, ctx) = calls.recv().await.unwrap();
+        assert_eq!(method, "allowed");
+        assert_eq!(ctx.route.as_deref(), Some("/search"));
+        assert_eq!(
+            ctx.bucket_key,
+            format!("{}:/search", generate_bucket_key("abc"))
+        );
+        assert_eq!(ctx.remaining, 99);
+    }
+
+    #[tokio::test]
+    async fn test_awaited_hooks_run_before_responding() {
+        let (hooks, mut calls) = RecordingHooks::new();
+        let state = memory_state()
+            .with_hooks(hooks)
+            .with_hook_dispatch(HookDispatch::Awaited);
+        let svc = limited(state);
+
+        for _ in 0..11 {
+            send(svc.clone(), "abc").await;
+        }
+
+        let methods = std::iter::from_fn(|| calls.try_recv().ok())
+            .map(|(method, _)| method)
+            .collect::<Vec<_>>();
+        assert_eq!(methods, [["allowed"; 10].as_slice(), &["denied"]].concat());
+    }
+
+    #[tokio::test]
+    async fn test_spawned_hooks_dont_hold_up_the_response() {
+        struct Stuck;
+        impl RateLimitHooks for Stuck {
+            fn on_allowed<'a>(&'a self, _: &'a DecisionCtx) -> BoxFuture<'a, ()> {
+                Box::pin(std::future::pending())
+            }
+        }
+        let state = memory_state().with_hooks(Stuck);
+
+        let response = tokio::time::timeout(Duration::from_secs(5), send(limited(state), "abc"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    fn stream_id() -> Value {
+        Value::BulkString(b"1740830400000-0".to_vec())
+    }
+
+    fn xadd(received: &[Vec<String>]) -> Vec<String> {
+        received
+            .iter()
+            .find(|command| command[0] == "XADD")
+            .expect("no XADD issued")
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn test_denial_is_appended_to_the_stream() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: clock.now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", stored(&empty)),
+            ("UNWATCH XADD", Value::Array(vec![Value::Okay, stream_id()])),
+        ]);
+        let store = RedisStore::new(conn.clone()).with_denial_log(DenialLog::default());
+        let state = AppState::new(store, BucketConfig::default()).with_clock(clock.clone());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let key = generate_bucket_key("abc");
+        let ts = clock.now().timestamp_millis().to_string();
+        assert_eq!(
+            xadd(&conn.received()),
+            [
+                "XADD",
+                "bucket:denials",
+                "MAXLEN",
+                "~",
+                "100000",
+                "*",
+                "key",
+                &key,
+                "route",
+                "",
+                "ts",
+                &ts,
+                "remaining",
+                "0",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_denial_log_is_configurable_and_names_the_route() {
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", stored(&empty)),
+            ("UNWATCH XADD", Value::Array(vec![Value::Okay, stream_id()])),
+        ]);
+        let state = per_route_state(conn.clone());
+        let store = RedisStore::new(conn.clone()).with_denial_log(DenialLog {
+            stream: "audit:throttled".to_string(),
+            max_len: 500,
+        });
+        let state = AppState {
+            store: Arc::new(store),
+            ..state
+        };
+
+        let response = get_path(routed(state), "/export").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let xadd = xadd(&conn.received());
+        assert_eq!(xadd[1..5], ["audit:throttled", "MAXLEN", "~", "500"]);
+        assert_eq!(
+            xadd[6..10],
+            ["key", &generate_bucket_key("abc"), "route", "/export"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_denial_is_logged_in_the_penalty_transaction() {
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", stored(&empty)),
+            (
+                "MULTI SET XADD EXEC",
+                Value::Array(vec![Value::Okay, stream_id()]),
+            ),
+        ]);
+        let store = RedisStore::new(conn.clone()).with_denial_log(DenialLog::default());
+        let state = AppState::new(store, penalized());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(conn.written().penalty.unwrap().violations, 1);
+        assert_eq!(xadd(&conn.received())[1], "bucket:denials");
+    }
+
+    #[tokio::test]
+    async fn test_allowed_requests_are_not_logged() {
+        let conn = allow_script(None);
+        let store = RedisStore::new(conn.clone()).with_denial_log(DenialLog::default());
+
+        let response = send(
+            limited(AppState::new(store, BucketConfig::default())),
+            "abc",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(conn.received().iter().all(|command| command[0] != "XADD"));
+    }
+
+    #[tokio::test]
+    async fn test_async_denial_is_appended_by_the_script() {
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![("EVALSHA", refilled(Some(&empty)))]);
+        let store = AsyncRedisStore::new(conn.clone()).with_denial_log(DenialLog::default());
+
+        let response = send(
+            limited(AppState::new(store, BucketConfig::default())),
+            "abc",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(
+            received[0][2..5],
+            ["2", &generate_bucket_key("abc"), "bucket:denials"]
+        );
+        assert_eq!(
+            received[0][received[0].len() - 3..],
+            ["100000", "0", "record"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_async_denial_in_another_slot_is_appended_after_the_script() {
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("EVALSHA", refilled(Some(&empty))),
+            ("XADD", stream_id()),
+        ]);
+        let store = AsyncRedisStore::new(conn.clone())
+            .with_denial_log(DenialLog::default())
+            .with_cluster(true);
+
+        let response = send(
+            limited(AppState::new(store, BucketConfig::default())),
+            "abc",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let xadd = xadd(&conn.received());
+        assert_eq!(
+            xadd[..8],
+            [
+                "XADD",
+                "bucket:denials",
+                "MAXLEN",
+                "~",
+                "100000",
+                "*",
+                "key",
+                &generate_bucket_key("abc"),
+            ]
+        );
+        assert_eq!(xadd[12..], ["remaining", "0"]);
+    }
+
+    #[tokio::test]
+    async fn test_shared_keys_go_under_the_key_prefix() {
+        let prefix = KeyPrefix::new("myapp:prod:bucket:").unwrap();
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("SISMEMBER", Value::Int(0)),
+            ("EVALSHA", refilled(Some(&empty))),
+            ("XADD", stream_id()),
+            (
+                "ZINCRBY EXPIRE",
+                Value::Array(vec![Value::Int(1), Value::Int(1)]),
+            ),
+        ]);
+        let store = AsyncRedisStore::new(conn.clone())
+            .with_key_prefix(&prefix)
+            .with_denial_log(DenialLog::new(&prefix))
+            .with_leaderboard(Leaderboard::new(&prefix, Duration::from_secs(60)))
+            .with_cluster(true);
+        let state = AppState::new(store, BucketConfig::default())
+            .with_key_prefix(prefix)
+            .with_shared_blocklist(true);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let received = conn.received();
+        let names = received
+            .iter()
+            .map(|command| command[0].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["SISMEMBER", "EVALSHA", "XADD", "ZINCRBY", "EXPIRE"]);
+        assert_eq!(received[0][1], "myapp:prod:bucket:{blocklist}");
+        assert!(received[1][3].starts_with("myapp:prod:bucket:{"));
+        assert_eq!(received[2][1], "myapp:prod:bucket:denials");
+        assert!(received[3][1].starts_with("myapp:prod:bucket:leaderboard:"));
+    }
+
+    #[tokio::test]
+    async fn test_async_denial_log_failure_still_denies() {
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![("EVALSHA", refilled(Some(&empty)))]);
+        let store = AsyncRedisStore::new(conn.clone())
+            .with_denial_log(DenialLog::default())
+            .with_cluster(true);
+
+        let response = send(
+            limited(AppState::new(store, BucketConfig::default())),
+            "abc",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(conn.received().len(), 2);
+    }
+
+    fn counted(received: &[Vec<String>]) -> Vec<Vec<String>> {
+        received
+            .iter()
+            .filter(|command| ["ZINCRBY", "EXPIRE"].contains(&command[0].as_str()))
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_charge_is_counted_on_the_leaderboard_in_the_transaction() {
+        let clock = ManualClock::new("2025-03-01T12:34:56Z".parse().unwrap());
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            (
+                "MULTI SET ZINCRBY EXPIRE EXEC",
+                Value::Array(vec![
+                    Value::Okay,
+                    Value::BulkString(b"1".to_vec()),
+                    Value::Int(1),
+                ]),
+            ),
+        ]);
+        let store = RedisStore::new(conn.clone()).with_leaderboard(Leaderboard::default());
+        let state = AppState::new(store, BucketConfig::default()).with_clock(clock);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // The hour 12:00 starts.
+        let set = "bucket:leaderboard:1740830400";
+        assert_eq!(
+            counted(&conn.received()),
+            [
+                vec!["ZINCRBY", set, "1", &generate_bucket_key("abc")],
+                vec!["EXPIRE", set, "7200"],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_denied_charge_is_counted_too() {
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", stored(&empty)),
+            (
+                "UNWATCH ZINCRBY EXPIRE",
+                Value::Array(vec![
+                    Value::Okay,
+                    Value::BulkString(b"11".to_vec()),
+                    Value::Int(1),
+                ]),
+            ),
+        ]);
+        let leaderboard = Leaderboard {
+            prefix: "top:".to_string(),
+            window: Duration::from_secs(60),
+        };
+        let store = RedisStore::new(conn.clone()).with_leaderboard(leaderboard);
+
+        let response = send(
+            limited(AppState::new(store, BucketConfig::default())),
+            "abc",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let counted = counted(&conn.received());
+        assert!(counted[0][1].starts_with("top:"), "{counted:?}");
+        assert_eq!(counted[1][2], "120");
+    }
+
+    #[tokio::test]
+    async fn test_async_charge_is_counted_by_the_script() {
+        let clock = ManualClock::new("2025-03-01T12:34:56Z".parse().unwrap());
+        let conn = ScriptedConnection::new(vec![("EVALSHA", refilled(None))]);
+        let store = AsyncRedisStore::new(conn.clone())
+            .with_denial_log(DenialLog::default())
+            .with_leaderboard(Leaderboard::default());
+        let state = AppState::new(store, BucketConfig::default()).with_clock(clock);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let received = conn.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(
+            received[0][2..6],
+            [
+                "3",
+                &generate_bucket_key("abc"),
+                "bucket:denials",
+                "bucket:leaderboard:1740830400"
+            ]
+        );
+        assert_eq!(
+            received[0][received[0].len() - 3..],
+            ["100000", "7200", "record"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_async_charge_in_another_slot_is_counted_after_the_script() {
+        let conn = ScriptedConnection::new(vec![
+            ("EVALSHA", refilled(None)),
+            (
+                "ZINCRBY EXPIRE",
+                Value::Array(vec![Value::BulkString(b"1".to_vec()), Value::Int(1)]),
+            ),
+        ]);
+        let store = AsyncRedisStore::new(conn.clone())
+            .with_leaderboard(Leaderboard::default())
+            .with_cluster(true);
+
+        let response = send(
+            limited(AppState::new(store, BucketConfig::default())),
+            "abc",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let counted = counted(&conn.received());
+        assert_eq!(counted[0][3], generate_bucket_key("abc"));
+        assert_eq!(counted.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_async_count_failure_still_allows() {
+        let conn = ScriptedConnection::new(vec![("EVALSHA", refilled(None))]);
+        let store = AsyncRedisStore::new(conn.clone())
+            .with_leaderboard(Leaderboard::default())
+            .with_cluster(true);
+
+        let response = send(
+            limited(AppState::new(store, BucketConfig::default())),
+            "abc",
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(counted(&conn.received()).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_top_consumers_are_read_from_the_current_window() {
+        let clock = ManualClock::new("2025-03-01T12:34:56Z".parse().unwrap());
+        let conn = ScriptedConnection::new(vec![(
+            "ZREVRANGE",
+            Value::Array(vec![
+                Value::BulkString(b"bucket:{a}".to_vec()),
+                Value::BulkString(b"42".to_vec()),
+                Value::BulkString(b"bucket:{b}".to_vec()),
+                Value::BulkString(b"7".to_vec()),
+            ]),
+        )]);
+        let store = AsyncRedisStore::new(conn.clone()).with_leaderboard(Leaderboard::default());
+        let state = AppState::new(store, BucketConfig::default()).with_clock(clock);
+
+        let response = admin(admin_router(state), "GET", "/top?n=2", None).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!([
+                { "key": "bucket:{a}", "count": 42 },
+                { "key": "bucket:{b}", "count": 7 },
+            ])
+        );
+        assert_eq!(
+            conn.received(),
+            [[
+                "ZREVRANGE",
+                "bucket:leaderboard:1740830400",
+                "0",
+                "1",
+                "WITHSCORES"
+            ]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_leaderboard_is_kept_unless_asked() {
+        let conn = allow_script(None);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = send(limited(state.clone()), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(counted(&conn.received()).is_empty());
+        let response = admin(admin_router(state), "GET", "/top", None).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    fn memory_state() -> AppState<MemoryStore> {
+        AppState::new(MemoryStore::new(), BucketConfig::default())
+    }
+
+    #[tokio::test]
+    async fn test_memory_drains_bucket() {
+        let svc = limited(memory_state());
+
+        for remaining in (0..10).rev() {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), remaining);
+        }
+
+        let response = send(svc, "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+        assert!(header_i64(&response, "Retry-After") > 3590);
+    }
+
+    #[tokio::test]
+    async fn test_memory_identities_have_separate_buckets() {
+        let state = memory_state();
+        let svc = limited(state.clone());
+
+        send(svc.clone(), "abc").await;
+        let response = send(svc, "def").await;
+
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+        assert_eq!(state.store.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_gcra_spaces_requests_at_the_sustained_rate() {
+        let config = BucketConfig {
+            algorithm: Algorithm::Gcra,
+            ..BucketConfig::burst(1, 10, Duration::from_secs(3600))
+        };
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = AppState::new(MemoryStore::new(), config).with_clock(clock.clone());
+        let svc = limited(state);
+
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "Retry-After"), 6 * 60);
+
+        clock.advance(Duration::from_secs(6 * 60));
+        let response = send(svc, "abc").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_leaky_bucket_queue_drains_at_a_constant_rate() {
+        let config = BucketConfig {
+            max_tokens: 3,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(20),
+            algorithm: Algorithm::LeakyBucket,
+            ..BucketConfig::default()
+        };
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = AppState::new(MemoryStore::new(), config).with_clock(clock.clone());
+        let svc = limited(state);
+
+        for remaining in [2, 1, 0] {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), remaining);
+        }
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "Retry-After"), 20);
+
+        // Three quarters of a request drained isn't room for one yet.
+        clock.advance(Duration::from_secs(15));
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "Retry-After"), 5);
+
+        clock.advance(Duration::from_secs(25));
+        for _ in 0..2 {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = send(svc, "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    fn one_every(interval: Duration) -> BucketConfig {
+        BucketConfig {
+            max_tokens: 1,
+            refill_rate: 1,
+            refill_interval: interval,
+            ..BucketConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_waits_for_an_imminent_token() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = AppState::new(MemoryStore::new(), one_every(Duration::from_secs(2)))
+            .with_max_wait(Duration::from_secs(5))
+            .with_clock(clock.clone());
+        let svc = limited(state);
+
+        send(svc.clone(), "abc").await;
+        let response = send(svc, "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+        assert_eq!(
+            clock.now(),
+            "2025-03-01T12:00:02Z"
+                .parse::<chrono::DateTime<Utc>>()
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_past_max_wait_is_denied_straight_away() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = AppState::new(MemoryStore::new(), one_every(Duration::from_secs(10)))
+            .with_max_wait(Duration::from_secs(5))
+            .with_clock(clock.clone());
+        let svc = limited(state);
+
+        send(svc.clone(), "abc").await;
+        let response = send(svc, "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "Retry-After"), 10);
+        assert_eq!(
+            clock.now(),
+            "2025-03-01T12:00:00Z"
+                .parse::<chrono::DateTime<Utc>>()
+                .unwrap()
+        );
+    }
+
+    /// A clock that lets another client spend the token a request is waiting
+    /// for, the first time it sleeps.
+    struct RacingClock {
+        clock: ManualClock,
+        store: Arc<MemoryStore>,
+        config: BucketConfig,
+        raced: AtomicBool,
+    }
+
+    impl Clock for RacingClock {
+        fn now(&self) -> chrono::DateTime<Utc> {
+            self.clock.now()
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            self.clock.advance(duration);
+            let racing = !self.raced.swap(true, Ordering::SeqCst);
+            let (store, config, now) = (Arc::clone(&self.store), self.config.clone(), self.now());
+            Box::pin(async move {
+                if racing {
+                    let key = generate_bucket_key("abc");
+                    store.take_token(&key, 1, &config, now).await.unwrap();
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_waiting_request_is_charged_again_after_losing_a_race() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let config = one_every(Duration::from_secs(2));
+        let state =
+            AppState::new(MemoryStore::new(), config.clone()).with_max_wait(Duration::from_secs(5));
+        let racing = RacingClock {
+            clock: clock.clone(),
+            store: Arc::clone(&state.store),
+            config,
+            raced: AtomicBool::new(false),
+        };
+        let svc = limited(state.with_clock(racing));
+
+        send(svc.clone(), "abc").await;
+        let response = send(svc.clone(), "abc").await;
+
+        // Beaten to the first token, it waits for the next one.
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            clock.now(),
+            "2025-03-01T12:00:04Z"
+                .parse::<chrono::DateTime<Utc>>()
+                .unwrap()
+        );
+
+        // Without anyone racing it, one wait is enough.
+        let response = send(svc, "abc").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            clock.now(),
+            "2025-03-01T12:00:06Z"
+                .parse::<chrono::DateTime<Utc>>()
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_overdraft_raises_the_largest_cost_let_through() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = AppState::new(MemoryStore::new(), overdrawn(2)).with_clock(clock.clone());
+        let svc = limited(state);
+        let costing = |cost: u32| {
+            call(
+                svc.clone(),
+                Request::builder()
+                    .header("Authorization", "Bearer abc")
+                    .extension(RequestCost(cost)),
+            )
+        };
+
+        assert_eq!(costing(6).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let response = costing(5).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+
+        let response = costing(1).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "Retry-After"), 3 * 60);
+        clock.advance(Duration::from_secs(3 * 60));
+        assert_eq!(costing(1).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_server_error_refunds_the_request() {
+        let state = memory_state().with_refunds(Refunds::default());
+
+        let response = send(
+            answering(state.clone(), StatusCode::INTERNAL_SERVER_ERROR),
+            "abc",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+
+        let response = send(limited(state), "abc").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+    }
+
+    #[tokio::test]
+    async fn test_responses_outside_the_refunds_are_charged() {
+        let state = memory_state().with_refunds(Refunds::default());
+        send(answering(state.clone(), StatusCode::NOT_FOUND), "abc").await;
+        send(limited(state.clone()), "abc").await;
+
+        let unrefunded = memory_state();
+        for _ in 0..2 {
+            let failing = answering(unrefunded.clone(), StatusCode::INTERNAL_SERVER_ERROR);
+            send(failing, "abc").await;
+        }
+
+        for state in [state, unrefunded] {
+            let response = send(limited(state), "abc").await;
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 7);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refund_covers_every_bucket_charged() {
+        let global = BucketConfig {
+            max_tokens: 100,
+            ..BucketConfig::default()
+        };
+        let state = memory_state()
+            .with_global_limit(global.clone())
+            .with_refunds(Refunds::default());
+        let now = state.clock.now();
+
+        send(answering(state.clone(), StatusCode::BAD_GATEWAY), "abc").await;
+
+        let global = state
+            .store
+            .status(GLOBAL_BUCKET_KEY, &global, now)
+            .await
+            .unwrap();
+        assert_eq!(global.unwrap().tokens, 100);
+        let config = BucketConfig::default();
+        let own = state
+            .store
+            .status(&generate_bucket_key("abc"), &config, now)
+            .await;
+        assert_eq!(own.unwrap().unwrap().tokens, 10);
+    }
+
+    #[tokio::test]
+    async fn test_failed_attempts_trip_their_own_bucket() {
+        let failures = BucketConfig {
+            max_tokens: 3,
+            ..BucketConfig::default()
+        };
+        let refunds = Refunds::new()
+            .with_status(StatusCode::UNAUTHORIZED)
+            .with_status(StatusCode::FORBIDDEN)
+            .with_failures(401..=403, failures);
+        let state = memory_state().with_refunds(refunds);
+        let rejecting = answering(state.clone(), StatusCode::UNAUTHORIZED);
+
+        for _ in 0..3 {
+            let response = send(rejecting.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = send(rejecting, "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["x-ratelimit-scope"], "failures");
+        assert_eq!(header_i64(&response, "Retry-After"), 3600);
+        // Even with the right credentials, until the attempts refill.
+        let response = send(limited(state.clone()), "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // None of it came out of the bucket itself, nor anyone else's attempts.
+        let config = BucketConfig::default();
+        let bucket = generate_bucket_key("abc");
+        let own = state
+            .store
+            .status(&bucket, &config, state.clock.now())
+            .await;
+        assert_eq!(own.unwrap().unwrap().tokens, 10);
+        let response = send(limited(state), "xyz").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+    }
+
+    #[tokio::test]
+    async fn test_refunded_server_errors_leave_the_failed_attempts_alone() {
+        let failures = BucketConfig {
+            max_tokens: 1,
+            ..BucketConfig::default()
+        };
+        let refunds = Refunds::default()
+            .with_statuses(401..=403)
+            .with_failures(401..=403, failures);
+        let state = memory_state().with_refunds(refunds);
+        let failing = answering(state.clone(), StatusCode::BAD_GATEWAY);
+
+        for _ in 0..5 {
+            let response = send(failing.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        }
+        let response = send(answering(state.clone(), StatusCode::UNAUTHORIZED), "abc").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = send(limited(state), "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["x-ratelimit-scope"], "failures");
+    }
+
+    #[tokio::test]
+    async fn test_successful_requests_leave_the_failed_attempts_alone() {
+        let failures = BucketConfig {
+            max_tokens: 1,
+            ..BucketConfig::default()
+        };
+        let refunds = Refunds::new()
+            .with_status(StatusCode::FORBIDDEN)
+            .with_failures(403..=403, failures);
+        let state = memory_state().with_refunds(refunds);
+
+        for _ in 0..5 {
+            let response = send(limited(state.clone()), "abc").await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = send(answering(state.clone(), StatusCode::FORBIDDEN), "abc").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 4);
+
+        let response = send(limited(state), "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_denied_request_is_not_refunded() {
+        let state = AppState::new(MemoryStore::new(), one_every(Duration::from_secs(60)))
+            .with_refunds(Refunds::new().with_status(StatusCode::OK));
+        let bucket = generate_bucket_key("abc");
+        state
+            .store
+            .set_tokens(&bucket, 0, &state.config(), state.clock.now())
+            .await
+            .unwrap();
+
+        let response = send(limited(state.clone()), "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let status = state
+            .store
+            .status(&bucket, &state.config(), state.clock.now())
+            .await;
+        assert_eq!(status.unwrap().unwrap().tokens, 0);
+    }
+
+    #[tokio::test]
+    async fn test_redis_refund_writes_the_bucket_back() {
+        let conn = allow_script(Some(&TokenPersistence {
+            tokens: 6,
+            last_updated: Utc::now(),
+            penalty: None,
+        }));
+        let store = RedisStore::new(conn.clone());
+        let config = BucketConfig::default();
+
+        store
+            .refund(&[("bucket:{abc}", &config)], 2, Utc::now())
+            .await
+            .unwrap();
+
+        assert_eq!(conn.written().tokens, 8);
+    }
+
+    #[tokio::test]
+    async fn test_redis_keeps_the_receipt_with_the_charge() {
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("EXISTS", Value::Int(0)),
+            ("GET", Value::Nil),
+            (
+                "MULTI SET SET EXEC",
+                Value::Array(vec![Value::Okay, Value::Okay]),
+            ),
+        ]);
+        let store = RedisStore::new(conn.clone());
+        let config = BucketConfig::default();
+        let buckets = [("bucket:{abc}", &config)];
+
+        let charged = store
+            .take_tokens_once(
+                &buckets,
+                1,
+                Utc::now(),
+                "bucket:{abc}:idem:a",
+                Duration::from_secs(90),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(charged.unwrap()[0].remaining, 9);
+        let received = conn.received();
+        assert_eq!(
+            received[0],
+            ["WATCH", "bucket:{abc}", "bucket:{abc}:idem:a"]
+        );
+        let receipt = received
+            .iter()
+            .find(|command| command[0] == "SET" && command[1] == "bucket:{abc}:idem:a");
+        assert_eq!(receipt.unwrap()[2..], ["1", "PX", "90000"]);
+    }
+
+    #[tokio::test]
+    async fn test_redis_charges_nothing_against_a_kept_receipt() {
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("EXISTS", Value::Int(1)),
+            ("UNWATCH", Value::Okay),
+        ]);
+        let store = RedisStore::new(conn.clone());
+        let config = BucketConfig::default();
+        let buckets = [("bucket:{abc}", &config)];
+
+        let charged = store
+            .take_tokens_once(
+                &buckets,
+                1,
+                Utc::now(),
+                "bucket:{abc}:idem:a",
+                Duration::from_secs(90),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(charged, None);
+    }
+
+    #[tokio::test]
+    async fn test_redis_refund_leaves_a_missing_bucket_alone() {
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            ("UNWATCH", Value::Okay),
+        ]);
+        let store = RedisStore::new(conn.clone());
+        let config = BucketConfig::default();
+
+        store
+            .refund(&[("bucket:{abc}", &config)], 2, Utc::now())
+            .await
+            .unwrap();
+
+        assert!(conn.received().iter().all(|command| command[0] != "SET"));
+    }
+
+    #[tokio::test]
+    async fn test_memory_refills_stored_bucket() {
+        let state = memory_state();
+        state.store.insert(
+            &generate_bucket_key("abc"),
+            TokenPersistence {
+                tokens: 2,
+                last_updated: Utc::now() - chrono::Duration::minutes(3 * 60 + 30),
+                penalty: None,
+            },
+        );
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 4);
+    }
+
+    #[tokio::test]
+    async fn test_body_size_sets_the_cost() {
+        let state = memory_state().with_body_cost(BodyCost::new(100 * 1024));
+        let svc = limited(state);
+        let uploading = |length: u64| {
+            Request::builder()
+                .header("Authorization", "Bearer abc")
+                .header(header::CONTENT_LENGTH, length)
+        };
+
+        let response = call(svc.clone(), uploading(250 * 1024)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 7);
+        let response = call(svc.clone(), uploading(0)).await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 6);
+
+        // Six left, so eight units are two hours off.
+        let response = call(svc.clone(), uploading(700 * 1024 + 1)).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 6);
+        assert_eq!(header_i64(&response, "Retry-After"), 7200);
+
+        // More than the bucket could ever hold.
+        let response = call(svc.clone(), uploading(u64::MAX)).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // A set cost wins out over the body's size.
+        let weighed = uploading(u64::MAX).extension(RequestCost(2));
+        let response = call(svc, weighed).await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 4);
+    }
+
+    #[tokio::test]
+    async fn test_body_cost_cap_and_missing_length() {
+        let body_cost = BodyCost::new(1024)
+            .with_max(3)
+            .with_missing(MissingLength::Cost(2));
+        let svc = limited(memory_state().with_body_cost(body_cost.clone()));
+
+        let huge = Request::builder()
+            .header("Authorization", "Bearer abc")
+            .header(header::CONTENT_LENGTH, u64::MAX);
+        let response = call(svc.clone(), huge).await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 7);
+        let response = send(svc, "abc").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 5);
+
+        let state = memory_state()
+            .with_body_cost(body_cost.with_missing(MissingLength::Reject))
+            .with_problem_details(true);
+        let response = send(limited(state.clone()), "abc").await;
+        assert_eq!(response.status(), StatusCode::LENGTH_REQUIRED);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+        let bucket = generate_bucket_key("abc");
+        let status = state
+            .store
+            .status(&bucket, &state.config(), state.clock.now())
+            .await;
+        assert_eq!(status.unwrap(), None);
+    }
+
+    fn retrying(key: &str) -> axum::http::request::Builder {
+        Request::builder()
+            .header("Authorization", "Bearer abc")
+            .header("Idempotency-Key", key)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_with_the_same_idempotency_key_are_charged_once() {
+        let state = memory_state().with_idempotency(Duration::from_secs(60));
+        let svc = limited(state);
+
+        for _ in 0..2 {
+            let response = call(svc.clone(), retrying("order-1")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+        }
+        let response = call(svc.clone(), retrying("order-2")).await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 8);
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 7);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        let response = call(svc, retrying("order-1")).await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 6);
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_keys_are_ignored_unless_enabled() {
+        let svc = limited(memory_state());
+
+        call(svc.clone(), retrying("order-1")).await;
+        let response = call(svc.clone(), retrying("order-1")).await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 8);
+
+        let svc = limited(memory_state().with_idempotency(Duration::from_secs(60)));
+        let too_long = "k".repeat(MAX_IDEMPOTENCY_KEY_LEN + 1);
+        call(svc.clone(), retrying(&too_long)).await;
+        let response = call(svc, retrying(&too_long)).await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 8);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_request_is_not_refunded() {
+        let state = memory_state()
+            .with_idempotency(Duration::from_secs(60))
+            .with_refunds(Refunds::new().with_status(StatusCode::ACCEPTED));
+        call(limited(state.clone()), retrying("order-1")).await;
+
+        let accepting = answering(state.clone(), StatusCode::ACCEPTED);
+        let response = call(accepting, retrying("order-1")).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let response = send(limited(state), "abc").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 8);
+    }
+
+    #[tokio::test]
+    async fn test_denied_request_can_be_retried_under_its_key() {
+        let clock = ManualClock::new(Utc::now());
+        let state = AppState::new(MemoryStore::new(), one_every(Duration::from_secs(60)))
+            .with_idempotency(Duration::from_secs(600))
+            .with_clock(clock.clone());
+        let svc = limited(state);
+
+        send(svc.clone(), "abc").await;
+        let response = call(svc.clone(), retrying("order-1")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        clock.advance(Duration::from_secs(60));
+        let response = call(svc.clone(), retrying("order-1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = call(svc, retrying("order-1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_memory_weighted_cost() {
+        let svc = limited(memory_state());
+        let costing = |cost| {
+            Request::builder()
+                .header("Authorization", "Bearer abc")
+                .extension(RequestCost(cost))
+        };
+
+        assert_eq!(call(svc.clone(), costing(6)).await.status(), StatusCode::OK);
+        assert_eq!(
+            call(svc.clone(), costing(5)).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(call(svc.clone(), costing(4)).await.status(), StatusCode::OK);
+        assert_eq!(
+            call(svc, costing(11)).await.status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_routes_exhaust_independently() {
+        let state = memory_state().with_route(
+            "/export",
+            BucketConfig {
+                max_tokens: 1,
+                ..BucketConfig::default()
+            },
+        );
+        let app = routed(state);
+
+        assert_eq!(
+            get_path(app.clone(), "/export").await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            get_path(app.clone(), "/export").await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        let response = get_path(app, "/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+    }
+
+    #[tokio::test]
+    async fn test_memory_missing_token_is_rejected() {
+        let state = memory_state();
+
+        let response = call(limited(state.clone()), Request::builder()).await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(state.store.is_empty());
+    }
+
+    fn scan_page(cursor: &str, keys: &[&str]) -> Value {
+        Value::Array(vec![
+            Value::BulkString(cursor.into()),
+            Value::Array(
+                keys.iter()
+                    .map(|key| Value::BulkString(key.as_bytes().to_vec()))
+                    .collect(),
+            ),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_walks_every_scan_page() {
+        let stale = TokenPersistence {
+            tokens: 3,
+            last_updated: Utc::now() - chrono::Duration::hours(20),
+            penalty: None,
+        };
+        let fresh = TokenPersistence {
+            tokens: 3,
+            last_updated: Utc::now() - chrono::Duration::hours(1),
+            penalty: None,
+        };
+        let mut conn = ScriptedConnection::new(vec![
+            (
+                "SCAN",
+                scan_page("17", &["bucket:a", "bucket:b", "bucket:c"]),
+            ),
+            (
+                "MGET",
+                Value::Array(vec![stored(&stale), stored(&fresh), Value::Nil]),
+            ),
+            ("EVAL", Value::Array(vec![Value::Int(1)])),
+            ("SCAN", scan_page("42", &[])),
+            (
+                "SCAN",
+                scan_page("0", &["bucket:d", "bucket:e", "bucket:f"]),
+            ),
+            (
+                "MGET",
+                Value::Array(vec![
+                    stored(&stale),
+                    Value::BulkString(b"not json".to_vec()),
+                    stored(&stale),
+                ]),
+            ),
+            // bucket:f was charged between the read and the delete.
+            (
+                "EVAL EVAL",
+                Value::Array(vec![Value::Int(1), Value::Int(0)]),
+            ),
+        ]);
+
+        let removed = cleanup_stale_buckets(
+            &mut conn,
+            "bucket:",
+            100,
+            BucketConfig::default().full_refill(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(removed, 2);
+        let received = conn.received();
+        let scans = received
+            .iter()
+            .filter(|c| c[0] == "SCAN")
+            .collect::<Vec<_>>();
+        assert_eq!(scans.len(), 3);
+        assert_eq!(scans[0][1..], ["0", "MATCH", "bucket:*", "COUNT", "100"]);
+        assert_eq!(scans[1][1], "17");
+        assert_eq!(scans[2][1], "42");
+        let deleted = received
+            .iter()
+            .filter(|c| c[0] == "EVAL")
+            .map(|c| c[3].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(deleted, ["bucket:a", "bucket:d", "bucket:f"]);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_without_stale_buckets_deletes_nothing() {
+        let fresh = TokenPersistence {
+            tokens: 3,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let mut conn = ScriptedConnection::new(vec![
+            ("SCAN", scan_page("0", &["bucket:a"])),
+            ("MGET", Value::Array(vec![stored(&fresh)])),
+        ]);
+
+        let removed = cleanup_stale_buckets(&mut conn, "bucket:", 100, Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        assert_eq!(removed, 0);
+        assert_eq!(conn.received().len(), 2);
+    }
+
+    fn penalized() -> BucketConfig {
+        BucketConfig {
+            max_tokens: 2,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(60),
+            penalty: Some(PenaltyConfig {
+                violations: 3,
+                window: Duration::from_secs(60),
+                ban: Duration::from_secs(10 * 60),
+                max_ban: Duration::from_secs(40 * 60),
+            }),
+            ..BucketConfig::default()
+        }
+    }
+
+    async fn pass_time(clock: &ManualClock, by: Duration) {
+        clock.advance(by);
+        tokio::time::advance(by).await;
+    }
+
+    /// Drains the bucket and keeps going until banned, returning the ban's
+    /// `Retry-After`.
+    async fn get_banned<S>(svc: S) -> i64
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone,
+    {
+        for _ in 0..2 {
+            assert_eq!(send(svc.clone(), "abc").await.status(), StatusCode::OK);
+        }
+        for _ in 0..2 {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(header_i64(&response, "Retry-After"), 60);
+        }
+        let response = send(svc, "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+        header_i64(&response, "Retry-After")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_repeated_violations_get_banned() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = AppState::new(MemoryStore::new(), penalized()).with_clock(clock.clone());
+        let svc = limited(state.clone());
+
+        assert_eq!(get_banned(svc.clone()).await, 10 * 60);
+        let status = state.get_bucket_status("abc").await.unwrap().unwrap();
+        assert_eq!(
+            status.banned_until,
+            Some(clock.now() + chrono::Duration::minutes(10))
+        );
+
+        // The bucket refills, but the ban holds and doesn't get any longer.
+        pass_time(&clock, Duration::from_secs(5 * 60)).await;
+        for _ in 0..5 {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(header_i64(&response, "Retry-After"), 5 * 60);
+        }
+
+        pass_time(&clock, Duration::from_secs(5 * 60)).await;
+        let response = send(svc, "abc").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bans_double_up_to_the_cap_then_expire() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = AppState::new(MemoryStore::new(), penalized()).with_clock(clock.clone());
+        let svc = limited(state.clone());
+
+        for ban in [10, 20, 40, 40] {
+            assert_eq!(get_banned(svc.clone()).await, ban * 60);
+            pass_time(&clock, Duration::from_secs(ban as u64 * 60)).await;
+        }
+
+        // Forgotten once the last ban has been over for as long as the
+        // longest one.
+        pass_time(&clock, Duration::from_secs(40 * 60 - 1)).await;
+        state.store.evict_expired();
+        assert_eq!(state.store.len(), 1);
+        pass_time(&clock, Duration::from_secs(1)).await;
+        state.store.evict_expired();
+        assert!(state.store.is_empty());
+        assert_eq!(get_banned(svc).await, 10 * 60);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_violations_outside_the_window_dont_add_up() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = AppState::new(MemoryStore::new(), penalized()).with_clock(clock.clone());
+        let svc = limited(state);
+
+        for _ in 0..2 {
+            send(svc.clone(), "abc").await;
+        }
+        for _ in 0..5 {
+            // Two denials a minute, one short of a ban each time.
+            for _ in 0..2 {
+                let response = send(svc.clone(), "abc").await;
+                assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+                assert!(header_i64(&response, "Retry-After") <= 60);
+            }
+            pass_time(&clock, Duration::from_secs(60)).await;
+            assert_eq!(send(svc.clone(), "abc").await.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_denials_without_penalties_write_nothing() {
+        let bucket = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = deny_script(&bucket);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(conn.received().iter().all(|command| command[0] != "SET"));
+    }
+
+    #[tokio::test]
+    async fn test_violation_is_stored_with_the_bucket() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let bucket = TokenPersistence {
+            tokens: 0,
+            last_updated: clock.now(),
+            penalty: Some(Penalty {
+                violations: 2,
+                since: clock.now() - chrono::Duration::seconds(30),
+                bans: 1,
+                banned_until: clock.now() - chrono::Duration::minutes(5),
+            }),
+        };
+        let conn = allow_script(Some(&bucket));
+        let state =
+            AppState::new(RedisStore::new(conn.clone()), penalized()).with_clock(clock.clone());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "Retry-After"), 20 * 60);
+        let written = conn.written();
+        assert_eq!(written.tokens, 0);
+        assert_eq!(written.last_updated, bucket.last_updated);
+        assert_eq!(
+            written.penalty,
+            Some(Penalty {
+                violations: 0,
+                since: bucket.penalty.as_ref().unwrap().since,
+                bans: 2,
+                banned_until: clock.now() + chrono::Duration::minutes(20),
+            })
+        );
+        let received = conn.received();
+        let set = received.iter().find(|c| c[0] == "SET").unwrap();
+        // Kept until the ban has been over for `max_ban`, well after the
+        // bucket is full again.
+        assert_eq!(set[3..], ["EX", "3600"]);
+    }
+
+    #[tokio::test]
+    async fn test_banned_client_writes_nothing() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let bucket = TokenPersistence {
+            tokens: 2,
+            last_updated: clock.now(),
+            penalty: Some(Penalty {
+                banned_until: clock.now() + chrono::Duration::seconds(90),
+                ..Penalty::default()
+            }),
+        };
+        let conn = deny_script(&bucket);
+        let state =
+            AppState::new(RedisStore::new(conn.clone()), penalized()).with_clock(clock.clone());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "Retry-After"), 90);
+        assert!(conn.received().iter().all(|command| command[0] != "SET"));
+    }
+
+    #[tokio::test]
+    async fn test_global_limit_is_shared_by_every_client() {
+        let global = BucketConfig {
+            max_tokens: 3,
+            ..BucketConfig::default()
+        };
+        let state = memory_state()
+            .with_clock(ManualClock::new(Utc::now()))
+            .with_global_limit(global);
+        let svc = limited(state.clone());
+
+        for (token, remaining) in [("a", 2), ("b", 1), ("c", 0)] {
+            let response = send(svc.clone(), token).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 3);
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), remaining);
+        }
+        let response = send(svc, "d").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 3);
+        assert_eq!(response.headers()["X-RateLimit-Scope"], "global");
+        assert!(header_i64(&response, "Retry-After") > 0);
+        // Turned away by the global bucket, so "d" isn't charged for it.
+        assert_eq!(state.check_tokens("d").await.unwrap().remaining, 10);
+        assert_eq!(state.check_tokens("a").await.unwrap().remaining, 9);
+    }
+
+    #[tokio::test]
+    async fn test_client_denial_leaves_global_bucket_alone() {
+        let config = BucketConfig {
+            max_tokens: 2,
+            ..BucketConfig::default()
+        };
+        let global = BucketConfig::default();
+        let clock = ManualClock::new(Utc::now());
+        let state = AppState::new(MemoryStore::new(), config)
+            .with_clock(clock.clone())
+            .with_global_limit(global.clone());
+        let svc = limited(state.clone());
+
+        for remaining in [1, 0] {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 2);
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), remaining);
+        }
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 2);
+
+        let left = state
+            .store
+            .peek(GLOBAL_BUCKET_KEY, &global, clock.now())
+            .await
+            .unwrap();
+        assert_eq!(left.remaining, 8);
+        // Other clients still have the rest of it.
+        let response = send(svc, "other").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_global_denial_writes_nothing_to_redis() {
+        let now = Utc::now();
+        let bucket = |tokens| TokenPersistence {
+            tokens,
+            last_updated: now,
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", stored(&bucket(5))),
+            ("GET", stored(&bucket(0))),
+            ("UNWATCH", Value::Okay),
+        ]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_clock(ManualClock::new(now))
+            .with_global_limit(BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 0);
+        let received = conn.received();
+        assert_eq!(
+            received[0],
+            ["WATCH", &generate_bucket_key("abc"), GLOBAL_BUCKET_KEY]
+        );
+        assert!(received.iter().all(|command| command[0] != "SET"));
+    }
+
+    /// The keys of a script run by [`AsyncRedisStore`].
+    fn script_keys(command: &[String]) -> &[String] {
+        let count: usize = command[2].parse().unwrap();
+        &command[3..3 + count]
+    }
+
+    #[test]
+    fn test_global_and_address_buckets_are_in_slots_of_their_own() {
+        let own = get_slot(generate_bucket_key("abc").as_bytes());
+        assert_ne!(get_slot(GLOBAL_BUCKET_KEY.as_bytes()), own);
+        assert_ne!(get_slot(generate_ip_bucket_key("10.1.2.3").as_bytes()), own);
+    }
+
+    #[tokio::test]
+    async fn test_cluster_charges_each_slot_with_a_script_of_its_own() {
+        let conn = ScriptedConnection::new(vec![
+            ("EVALSHA", refilled(None)),
+            ("EVALSHA", refilled(None)),
+        ]);
+        let store = AsyncRedisStore::new(conn.clone()).with_cluster(true);
+        let state = AppState::new(store, BucketConfig::default())
+            .with_global_limit(BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let received = conn.received();
+        assert_eq!(received.len(), 2);
+        // The global bucket first, so the identity's is charged last.
+        assert_eq!(script_keys(&received[0]), [GLOBAL_BUCKET_KEY]);
+        assert_eq!(script_keys(&received[1]), [generate_bucket_key("abc")]);
+    }
+
+    #[tokio::test]
+    async fn test_cluster_refunds_the_slots_that_allowed_a_denied_charge() {
+        let empty = TokenPersistence {
+            tokens: 0,
+            last_updated: Utc::now(),
+            penalty: None,
+        };
+        let conn = ScriptedConnection::new(vec![
+            ("EVALSHA", refilled(Some(&empty))),
+            ("EVALSHA", refilled(None)),
+            ("EVALSHA", refilled(None)),
+        ]);
+        let store = AsyncRedisStore::new(conn.clone()).with_cluster(true);
+        let state = AppState::new(store, BucketConfig::default())
+            .with_global_limit(BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["X-RateLimit-Scope"], "global");
+        let received = conn.received();
+        assert_eq!(received.len(), 3);
+        let key = generate_bucket_key("abc");
+        assert_eq!(script_keys(&received[1]), [key.as_str()]);
+        // The identity's charge undone with a negative cost.
+        assert_eq!(script_keys(&received[2]), [key.as_str()]);
+        assert_eq!(received[2][7], "-1");
+    }
+
+    #[tokio::test]
+    async fn test_daily_quota_resets_at_midnight() {
+        let clock = ManualClock::new("2025-03-01T23:00:00Z".parse().unwrap());
+        let state = memory_state()
+            .with_clock(clock.clone())
+            .with_quota(BucketConfig::daily(3));
+        let svc = limited(state.clone());
+
+        for remaining in [2, 1, 0] {
+            let response = send(svc.clone(), "a").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), remaining);
+            assert_eq!(response.headers()["X-RateLimit-Scope"], "quota");
+        }
+        clock.advance(Duration::from_secs(30 * 60));
+        let response = send(svc.clone(), "a").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["X-RateLimit-Scope"], "quota");
+        assert_eq!(header_i64(&response, "Retry-After"), 30 * 60);
+        let problem = problem(response).await;
+        assert_eq!(problem.problem_type, "urn:leaky-bucket:quota-exceeded");
+        assert_eq!(problem.retry_after, Some(30 * 60));
+        // Turned away by the quota, so the hourly bucket isn't charged.
+        assert_eq!(state.check_tokens("a").await.unwrap().remaining, 7);
+
+        clock.set("2025-03-02T00:00:00Z".parse().unwrap());
+        let response = send(svc, "a").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 2);
+        // The hour since the first request gave the bucket one back, too.
+        assert_eq!(state.check_tokens("a").await.unwrap().remaining, 7);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_leaves_the_quota_alone() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let config = BucketConfig {
+            max_tokens: 2,
+            ..BucketConfig::default()
+        };
+        let quota = BucketConfig {
+            algorithm: Algorithm::FixedWindow {
+                offset: Duration::from_secs(6 * 60 * 60),
+            },
+            ..BucketConfig::daily(5)
+        };
+        let state = AppState::new(MemoryStore::new(), config)
+            .with_clock(clock.clone())
+            .with_quota(quota.clone())
+            .with_problem_details(true);
+        let svc = limited(state.clone());
+
+        for _ in 0..2 {
+            assert_eq!(send(svc.clone(), "a").await.status(), StatusCode::OK);
+        }
+        let response = send(svc.clone(), "a").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["X-RateLimit-Scope"], "token");
+        assert_eq!(
+            problem(response).await.problem_type,
+            "urn:leaky-bucket:rate-limited"
+        );
+        let quota_left = state
+            .store
+            .peek(&quota_key(&generate_bucket_key("a")), &quota, clock.now())
+            .await
+            .unwrap();
+        assert_eq!(quota_left.remaining, 3);
+        assert_eq!(
+            quota_left.reset_at,
+            "2025-03-02T06:00:00Z"
+                .parse::<chrono::DateTime<Utc>>()
+                .unwrap()
+        );
+    }
+
+    fn tiered_state() -> AppState<MemoryStore> {
+        memory_state()
+            .with_tier(
+                "free",
+                BucketConfig {
+                    max_tokens: 10,
+                    ..BucketConfig::default()
+                },
+            )
+            .with_tier(
+                "paid",
+                BucketConfig {
+                    max_tokens: 1000,
+                    refill_rate: 1000,
+                    ..BucketConfig::default()
+                },
+            )
+    }
+
+    #[tokio::test]
+    async fn test_tier_picks_the_limit() {
+        let (hooks, mut calls) = RecordingHooks::new();
+        let state = tiered_state()
+            .with_hooks(hooks)
+            .with_hook_dispatch(HookDispatch::Awaited);
+        let tier_key = tier_key(&generate_bucket_key("a"));
+        state.store.set_tier(&tier_key, Some("paid")).await.unwrap();
+        let svc = limited(state.clone());
+
+        let response = send(svc.clone(), "a").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 1000);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 999);
+        assert_eq!(response.headers()["X-RateLimit-Tier"], "paid");
+        let (_, ctx) = calls.recv().await.unwrap();
+        assert_eq!(ctx.tier.as_deref(), Some("paid"));
+
+        // Billing moves the identity to another tier between requests.
+        state.store.set_tier(&tier_key, Some("free")).await.unwrap();
+        let response = send(svc, "a").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+        assert_eq!(response.headers()["X-RateLimit-Tier"], "free");
+        assert_eq!(calls.recv().await.unwrap().1.tier.as_deref(), Some("free"));
+    }
+
+    #[tokio::test]
+    async fn test_identity_without_a_tier_gets_the_default() {
+        let (hooks, mut calls) = RecordingHooks::new();
+        let state = tiered_state()
+            .with_hooks(hooks)
+            .with_hook_dispatch(HookDispatch::Awaited);
+
+        let response = send(limited(state), "a").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 9);
+        assert!(!response.headers().contains_key("X-RateLimit-Tier"));
+        assert_eq!(calls.recv().await.unwrap().1.tier, None);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_tier_gets_the_default() {
+        let state = tiered_state().with_route(
+            "/search",
+            BucketConfig {
+                max_tokens: 100,
+                ..BucketConfig::default()
+            },
+        );
+        let tier_key = tier_key(&generate_bucket_key("abc"));
+        state.store.set_tier(&tier_key, Some("gold")).await.unwrap();
+
+        let response = send(limited(state.clone()), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+        assert!(!response.headers().contains_key("X-RateLimit-Tier"));
+
+        // Routes with limits of their own don't go by the tier at all.
+        state.store.set_tier(&tier_key, Some("paid")).await.unwrap();
+        let response = get_path(routed(state), "/search").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 100);
+        assert!(!response.headers().contains_key("X-RateLimit-Tier"));
+    }
+
+    #[tokio::test]
+    async fn test_redis_reads_the_tier_with_the_watch() {
+        let conn = ScriptedConnection::new(vec![
+            (
+                "WATCH GET",
+                Value::Array(vec![Value::Okay, Value::BulkString(b"paid".to_vec())]),
+            ),
+            ("GET", Value::Nil),
+            ("MULTI SET EXEC", Value::Array(vec![Value::Okay])),
+        ]);
+        let store = RedisStore::new(conn.clone());
+        let config = BucketConfig::default();
+        let paid = BucketConfig {
+            max_tokens: 1000,
+            ..BucketConfig::default()
+        };
+        let tiers = std::collections::HashMap::from([("paid".to_string(), paid)]);
+        let buckets = [("bucket:{abc}", &config)];
+
+        let Tiered { tier, decisions } = store
+            .take_tokens_tiered(&buckets, "tier:{abc}", &tiers, 1, Utc::now())
+            .await
+            .unwrap();
+
+        assert_eq!(tier.as_deref(), Some("paid"));
+        assert_eq!(decisions[0].limit, 1000);
+        assert_eq!(decisions[0].remaining, 999);
+        let received = conn.received();
+        assert_eq!(received[0], ["WATCH", "bucket:{abc}"]);
+        assert_eq!(received[1], ["GET", "tier:{abc}"]);
+        assert_eq!(received[2], ["GET", "bucket:{abc}"]);
+    }
+
+    #[tokio::test]
+    async fn test_updated_config_applies_to_the_next_request() {
+        let state = memory_state();
+        let svc = limited(state.clone());
+        for remaining in [9, 8, 7] {
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), remaining);
+        }
+
+        // Through another clone of the state, as an admin endpoint would.
+        state.clone().update_config(BucketConfig {
+            max_tokens: 2,
+            ..BucketConfig::default()
+        });
+
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 2);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 1);
+        assert_eq!(send(svc.clone(), "abc").await.status(), StatusCode::OK);
+        let response = send(svc, "abc").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let error = state.consume_tokens("xyz", 3).await.unwrap_err();
+        assert!(
+            matches!(error, ConsumeError::ExceedsCapacity { capacity: 2, .. }),
+            "{error:?}"
+        );
+    }
+
+    fn generous() -> BucketConfig {
+        BucketConfig {
+            max_tokens: 100,
+            refill_rate: 10,
+            ..BucketConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_override_replaces_the_default_limit() {
+        let state = tiered_state().with_overrides(Duration::from_secs(30));
+        let tier_key = tier_key(&generate_bucket_key("a"));
+        state.store.set_tier(&tier_key, Some("paid")).await.unwrap();
+        state.set_override("a", &generous()).await.unwrap();
+        let svc = limited(state.clone());
+
+        let response = send(svc.clone(), "a").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 100);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 99);
+        assert!(!response.headers().contains_key("X-RateLimit-Tier"));
+        assert_eq!(state.consume_tokens("a", 9).await.unwrap().remaining, 90);
+
+        // Everyone else goes by the static config.
+        let response = send(svc.clone(), "b").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+
+        state.clear_override("a").await.unwrap();
+        let response = send(svc, "a").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 1000);
+        assert_eq!(response.headers()["X-RateLimit-Tier"], "paid");
+    }
+
+    #[tokio::test]
+    async fn test_override_is_cached_until_it_expires() {
+        let clock = ManualClock::new("2025-03-01T12:00:00Z".parse().unwrap());
+        let state = memory_state()
+            .with_clock(clock.clone())
+            .with_overrides(Duration::from_secs(30));
+        let svc = limited(state.clone());
+        let key = KeyPrefix::default().config(&generate_bucket_key("a"));
+
+        let response = send(svc.clone(), "a").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+
+        // Set by another instance, so this one only sees it once what it
+        // read has expired.
+        let store = &state.store;
+        store
+            .set_config_override(&key, Some(&generous()))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(29));
+        let response = send(svc.clone(), "a").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+        clock.advance(Duration::from_secs(1));
+        let response = send(svc.clone(), "a").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 100);
+
+        // Resetting the bucket reads it again.
+        store.set_config_override(&key, None).await.unwrap();
+        let response = send(svc.clone(), "a").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 100);
+        state.reset_bucket("a").await.unwrap();
+        let response = send(svc, "a").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_override_falls_back_to_the_default() {
+        let conn = ScriptedConnection::new(vec![
+            (
+                "GET",
+                Value::BulkString(br#"{"max_tokens":"lots"}"#.to_vec()),
+            ),
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            ("MULTI SET EXEC", Value::Array(vec![Value::Okay])),
+            ("GET", Value::BulkString(br#"{"max_tokens":100}"#.to_vec())),
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            ("MULTI SET EXEC", Value::Array(vec![Value::Okay])),
+        ]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_overrides(Duration::from_secs(30));
+        let svc = limited(state);
+
+        let response = send(svc.clone(), "abc").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+
+        // Unreadable overrides aren't cached.
+        let response = send(svc, "abc").await;
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 100);
+        let received = conn.received();
+        let key = KeyPrefix::default().config(&generate_bucket_key("abc"));
+        assert_eq!(received[0], ["GET", key.as_str()]);
+        assert!(key.starts_with("bucket:config:{"));
+    }
+
+    #[test]
+    fn test_config_json_format() {
+        let config: BucketConfig = serde_json::from_str(
+            r#"{"max_tokens":100,"refill_interval_ms":60000,"algorithm":{"fixed_window":{"offset_ms":1000}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            BucketConfig {
+                max_tokens: 100,
+                refill_interval: Duration::from_secs(60),
+                algorithm: Algorithm::FixedWindow {
+                    offset: Duration::from_secs(1)
+                },
+                ..BucketConfig::default()
+            }
+        );
+
+        let config = BucketConfig {
+            penalty: Some(PenaltyConfig::default()),
+            algorithm: Algorithm::Gcra,
+            ..BucketConfig::default()
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.contains(r#""algorithm":"gcra""#));
+        assert_eq!(serde_json::from_str::<BucketConfig>(&json).unwrap(), config);
+        assert!(serde_json::from_str::<BucketConfig>(r#"{"max_token":5}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_global_limit_bounds_request_cost() {
+        let global = BucketConfig {
+            max_tokens: 3,
+            ..BucketConfig::default()
+        };
+        let state = memory_state().with_global_limit(global);
+        let svc = ServiceBuilder::new()
+            .layer(axum::Extension(RequestCost(5)))
+            .service(limited(state));
+
+        let response = send(svc, "abc").await;
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    fn ip_limited(token: BucketConfig, ip: BucketConfig) -> AppState<MemoryStore> {
+        AppState::new(MemoryStore::new(), token)
+            .with_clock(ManualClock::new(Utc::now()))
+            .with_ip_limit(TrustedProxies::default(), ip)
+    }
+
+    fn from(token: &str, ip: &str) -> axum::http::request::Builder {
+        Request::builder()
+            .header("Bearer", token)
+            .extension(peer(ip))
+    }
+
+    #[tokio::test]
+    async fn test_ip_and_token_buckets_are_charged_together() {
+        let token = BucketConfig {
+            max_tokens: 5,
+            ..BucketConfig::default()
+        };
+        // Larger, and quicker to refill.
+        let ip = BucketConfig {
+            max_tokens: 20,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(60),
+            ..BucketConfig::default()
+        };
+        let cases = [
+            (3, 10, StatusCode::OK, "token", (2, 9)),
+            (0, 10, StatusCode::TOO_MANY_REQUESTS, "token", (0, 10)),
+            (3, 0, StatusCode::TOO_MANY_REQUESTS, "ip", (3, 0)),
+            // Both out, so the wait is the token bucket's, the longer one.
+            (0, 0, StatusCode::TOO_MANY_REQUESTS, "token", (0, 0)),
+        ];
+
+        for (token_left, ip_left, status, scope, left) in cases {
+            let state = ip_limited(token.clone(), ip.clone());
+            let now = state.clock.now();
+            let ip_key = generate_ip_bucket_key("10.1.2.3");
+            state.set_tokens("abc", token_left).await.unwrap();
+            state
+                .store
+                .set_tokens(&ip_key, ip_left, &ip, now)
+                .await
+                .unwrap();
+
+            let response = call(limited(state.clone()), from("abc", "10.1.2.3")).await;
+
+            let case = format!("{token_left} and {ip_left} left");
+            assert_eq!(response.status(), status, "{case}");
+            assert_eq!(response.headers()["X-RateLimit-Scope"], scope, "{case}");
+            let token_bucket = state.check_tokens("abc").await.unwrap();
+            let ip_bucket = state.store.peek(&ip_key, &ip, now).await.unwrap();
+            assert_eq!(
+                (token_bucket.remaining, ip_bucket.remaining),
+                left,
+                "{case}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ip_bucket_is_shared_by_every_token_from_it() {
+        let ip = BucketConfig {
+            max_tokens: 2,
+            ..BucketConfig::default()
+        };
+        let svc = limited(ip_limited(BucketConfig::default(), ip));
+
+        for token in ["a", "b"] {
+            let response = call(svc.clone(), from(token, "10.1.2.3")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = call(svc.clone(), from("c", "10.1.2.3")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["X-RateLimit-Scope"], "ip");
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 2);
+
+        let response = call(svc, from("c", "10.9.9.9")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_ip_limit_without_connect_info_is_rejected() {
+        let state = ip_limited(BucketConfig::default(), BucketConfig::default());
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_ip_and_token_buckets_share_a_redis_transaction() {
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            ("GET", Value::Nil),
+            (
+                "MULTI SET SET EXEC",
+                Value::Array(vec![Value::Okay, Value::Okay]),
+            ),
+        ]);
+        let state = AppState::new(RedisStore::new(conn.clone()), BucketConfig::default())
+            .with_ip_limit(TrustedProxies::default(), BucketConfig::default());
+
+        let response = call(limited(state), from("abc", "10.1.2.3")).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let token_key = generate_bucket_key("abc");
+        let ip_key = generate_ip_bucket_key("10.1.2.3");
+        let received = conn.received();
+        assert_eq!(received[0], ["WATCH", &token_key, &ip_key]);
+        assert_eq!(received[1], ["GET", &token_key]);
+        assert_eq!(received[2], ["GET", &ip_key]);
+        let sets = received.iter().filter(|c| c[0] == "SET");
+        let keys = sets.map(|c| c[1].as_str()).collect::<Vec<_>>();
+        assert_eq!(keys, [&token_key, &ip_key]);
+    }
+
+    fn per_minute(max_tokens: i64) -> BucketConfig {
+        BucketConfig {
+            max_tokens,
+            refill_rate: max_tokens,
+            refill_interval: Duration::from_secs(60),
+            ..BucketConfig::default()
+        }
+    }
+
+    fn per_hour(max_tokens: i64) -> BucketConfig {
+        BucketConfig {
+            max_tokens,
+            refill_rate: max_tokens,
+            refill_interval: Duration::from_secs(3600),
+            ..BucketConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_minute_window_caps_bursts_without_touching_the_hour() {
+        let clock = ManualClock::new(Utc::now());
+        let state = AppState::new(MemoryStore::new(), per_hour(10))
+            .with_clock(clock.clone())
+            .with_windows([per_minute(3)]);
+        let svc = limited(state.clone());
+
+        for _ in 0..2 {
+            for remaining in [2, 1, 0] {
+                let response = send(svc.clone(), "abc").await;
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 3);
+                assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), remaining);
+            }
+            let response = send(svc.clone(), "abc").await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 3);
+            assert_eq!(header_i64(&response, "Retry-After"), 60);
+            clock.advance(Duration::from_secs(60));
+        }
+
+        // Only the requests let through came out of the hour.
+        assert_eq!(state.check_tokens("abc").await.unwrap().remaining, 4);
+    }
+
+    #[tokio::test]
+    async fn test_hour_window_denial_leaves_the_minute_window_alone() {
+        let clock = ManualClock::new(Utc::now());
+        let state = AppState::new(MemoryStore::new(), per_hour(10))
+            .with_clock(clock.clone())
+            .with_windows([per_minute(3)]);
+        state.set_tokens("abc", 0).await.unwrap();
+
+        let response = send(limited(state.clone()), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 10);
+        assert_eq!(header_i64(&response, "Retry-After"), 3600);
+        let minute_key = format!("{}:60s", generate_bucket_key("abc"));
+        let minute = state
+            .store
+            .peek(&minute_key, &per_minute(3), clock.now())
+            .await
+            .unwrap();
+        assert_eq!(minute.remaining, 3);
+    }
+
+    #[tokio::test]
+    async fn test_windows_are_stored_next_to_the_bucket() {
+        let conn = ScriptedConnection::new(vec![
+            ("WATCH", Value::Okay),
+            ("GET", Value::Nil),
+            ("GET", Value::Nil),
+            (
+                "MULTI SET SET EXEC",
+                Value::Array(vec![Value::Okay, Value::Okay]),
+            ),
+        ]);
+        let state = AppState::new(RedisStore::new(conn.clone()), per_hour(10))
+            .with_windows([per_minute(3)]);
+
+        let response = send(limited(state), "abc").await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header_i64(&response, "X-RateLimit-Remaining"), 2);
+        let key = generate_bucket_key("abc");
+        let minute_key = format!("{key}:60s");
+        assert_eq!(conn.received()[0], ["WATCH", &key, &minute_key]);
+        // Both in the identity's hash slot.
+        assert_eq!(get_slot(minute_key.as_bytes()), get_slot(key.as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn test_windows_leave_route_limits_alone() {
+        let state = AppState::new(MemoryStore::new(), per_hour(10))
+            .with_clock(ManualClock::new(Utc::now()))
+            .with_windows([per_minute(1)])
+            .with_route("/expensive", per_hour(5));
+        let app = Router::new()
+            .route("/expensive", get(|| async { "ok" }))
+            .layer(RateLimiterLayer::new(state));
+
+        for _ in 0..2 {
+            let response = get_path(app.clone(), "/expensive").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(header_i64(&response, "X-RateLimit-Limit"), 5);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+
+use crate::{BucketConfig, later};
+
+/// How many lookups go by between sweeps of expired entries.
+const SWEEP_EVERY: u64 = 1024;
+
+/// The overrides read from the store, see
+/// [`AppState::with_overrides`](crate::AppState::with_overrides), each kept
+/// for `ttl` so the store is only asked once in a while per identity. That
+/// there's none is kept too.
+#[derive(Debug)]
+pub(crate) struct OverrideCache {
+    ttl: Duration,
+    entries: DashMap<String, Cached>,
+    lookups: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Cached {
+    config: Option<BucketConfig>,
+    expires_at: DateTime<Utc>,
+}
+
+impl OverrideCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: DashMap::new(),
+            lookups: AtomicU64::new(0),
+        }
+    }
+
+    /// The override at `key` as of `now`, or `None` if it has to be read
+    /// again.
+    pub(crate) fn get(&self, key: &str, now: DateTime<Utc>) -> Option<Option<BucketConfig>> {
+        if self.lookups.fetch_add(1, Ordering::Relaxed) % SWEEP_EVERY == SWEEP_EVERY - 1 {
+            self.entries.retain(|_, cached| cached.expires_at > now);
+        }
+        self.entries
+            .get(key)
+            .filter(|cached| cached.expires_at > now)
+            .map(|cached| cached.config.clone())
+    }
+
+    pub(crate) fn insert(&self, key: &str, config: Option<BucketConfig>, now: DateTime<Utc>) {
+        let expires_at = later(now, self.ttl);
+        self.entries
+            .insert(key.to_string(), Cached { config, expires_at });
+    }
+
+    pub(crate) fn invalidate(&self, key: &str) {
+        self.entries.remove(key);
+    }
+}
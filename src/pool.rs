@@ -0,0 +1,86 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// A fixed set of Redis connections, each used by one request at a time.
+///
+/// Checkouts start at the next connection round-robin and take the first one
+/// that is free; when all are busy they wait for that next connection.
+pub struct ConnectionPool<C> {
+    connections: Vec<Arc<Mutex<C>>>,
+    next: AtomicUsize,
+}
+
+impl<C> ConnectionPool<C> {
+    /// Panics if `connections` is empty.
+    pub fn new(connections: impl IntoIterator<Item = C>) -> Self {
+        let connections = connections
+            .into_iter()
+            .map(|conn| Arc::new(Mutex::new(conn)))
+            .collect::<Vec<_>>();
+        assert!(!connections.is_empty(), "connection pool can't be empty");
+
+        Self {
+            connections,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    pub async fn get(&self) -> OwnedMutexGuard<C> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % self.len();
+
+        for i in 0..self.len() {
+            let conn = &self.connections[(start + i) % self.len()];
+            if let Ok(guard) = Arc::clone(conn).try_lock_owned() {
+                return guard;
+            }
+        }
+        Arc::clone(&self.connections[start]).lock_owned().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionPool;
+
+    #[tokio::test]
+    async fn test_checks_out_free_connections_first() {
+        let pool = ConnectionPool::new([0, 1, 2]);
+
+        let a = pool.get().await;
+        let b = pool.get().await;
+        let c = pool.get().await;
+        let mut taken = vec![*a, *b, *c];
+        taken.sort();
+        assert_eq!(taken, [0, 1, 2]);
+
+        drop(b);
+        assert_eq!(*pool.get().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let pool = ConnectionPool::new([0, 1]);
+
+        assert_eq!(*pool.get().await, 0);
+        assert_eq!(*pool.get().await, 1);
+        assert_eq!(*pool.get().await, 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_empty_pool_panics() {
+        ConnectionPool::<()>::new([]);
+    }
+}
@@ -0,0 +1,229 @@
+use std::{error::Error, fmt, str::FromStr, sync::Arc};
+
+use serde_derive::Deserialize;
+
+/// Characters that would break the `SCAN MATCH {prefix}*` patterns buckets
+/// are found with, or move every bucket into one Redis Cluster slot.
+const RESERVED: &[char] = &['*', '?', '[', ']', '\\', '{', '}'];
+
+/// What every key the limiter writes starts with, `bucket:` unless set with
+/// [`AppState::with_key_prefix`](crate::AppState::with_key_prefix), so that
+/// environments or services sharing a Redis keep apart, e.g.
+/// `myapp:prod:bucket:`.
+///
+/// Tier keys, which are written by hand, are kept beside the buckets rather
+/// than under them: `myapp:prod:tier:{hash}` for `myapp:prod:bucket:`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyPrefix(Arc<str>);
+
+impl KeyPrefix {
+    /// `prefix`, unless it's empty or has characters with a meaning in Redis
+    /// glob patterns or hash tags, or whitespace.
+    pub fn new(prefix: impl Into<String>) -> Result<Self, InvalidKeyPrefix> {
+        let prefix = prefix.into();
+        if prefix.is_empty() {
+            return Err(InvalidKeyPrefix::Empty);
+        }
+        match prefix
+            .chars()
+            .find(|c| RESERVED.contains(c) || c.is_whitespace() || c.is_control())
+        {
+            Some(c) => Err(InvalidKeyPrefix::Reserved(c)),
+            None => Ok(Self(prefix.into())),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The bucket of the identity that hashes to `hash`, see [`KeyHasher`].
+    /// The hash is wrapped in a Redis Cluster hash tag, so every key derived
+    /// from it (per-route buckets included) lands in the same slot.
+    ///
+    /// [`KeyHasher`]: crate::KeyHasher
+    pub(crate) fn bucket(&self, hash: &str) -> String {
+        format!("{}{{{hash}}}", self.0)
+    }
+
+    /// The set of blocked bucket keys a Redis store shares between instances.
+    /// It's a hash tag of its own, so it stays in one slot of a cluster.
+    pub fn blocklist(&self) -> String {
+        format!("{}{{blocklist}}", self.0)
+    }
+
+    /// The stream of a [`DenialLog`](crate::DenialLog).
+    pub fn denials(&self) -> String {
+        format!("{}denials", self.0)
+    }
+
+    /// What the sorted sets of a [`Leaderboard`](crate::Leaderboard) start
+    /// with, before the start of their window.
+    pub fn leaderboard(&self) -> String {
+        format!("{}leaderboard:", self.0)
+    }
+}
+
+/// Keys only the middleware has use for.
+#[cfg(feature = "axum")]
+impl KeyPrefix {
+    /// The bucket of the client address that hashes to `hash`, see
+    /// [`AppState::with_ip_limit`](crate::AppState::with_ip_limit). It's kept
+    /// apart from identity buckets, so a token that happens to look like an
+    /// address never shares one.
+    pub(crate) fn ip_bucket(&self, hash: &str) -> String {
+        format!("{}ip:{{{hash}}}", self.0)
+    }
+
+    /// Where the bucket set up with
+    /// [`AppState::with_global_limit`](crate::AppState::with_global_limit) is
+    /// stored.
+    pub(crate) fn global(&self) -> String {
+        format!("{}__global__", self.0)
+    }
+
+    /// The tier of the identity whose bucket is at `bucket_key`, see
+    /// [`AppState::with_tier`](crate::AppState::with_tier).
+    pub(crate) fn tier(&self, bucket_key: &str) -> String {
+        let namespace = self.0.strip_suffix("bucket:").unwrap_or(&self.0);
+        format!("{namespace}tier:{}", self.hash_of(bucket_key))
+    }
+
+    /// The config override of the identity whose bucket is at `bucket_key`,
+    /// see [`AppState::with_overrides`](crate::AppState::with_overrides).
+    pub(crate) fn config(&self, bucket_key: &str) -> String {
+        format!("{}config:{}", self.0, self.hash_of(bucket_key))
+    }
+
+    fn hash_of<'a>(&self, bucket_key: &'a str) -> &'a str {
+        bucket_key.strip_prefix(&*self.0).unwrap_or(bucket_key)
+    }
+}
+
+impl Default for KeyPrefix {
+    fn default() -> Self {
+        Self("bucket:".into())
+    }
+}
+
+impl fmt::Display for KeyPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for KeyPrefix {
+    type Err = InvalidKeyPrefix;
+
+    fn from_str(prefix: &str) -> Result<Self, Self::Err> {
+        Self::new(prefix)
+    }
+}
+
+impl TryFrom<String> for KeyPrefix {
+    type Error = InvalidKeyPrefix;
+
+    fn try_from(prefix: String) -> Result<Self, Self::Error> {
+        Self::new(prefix)
+    }
+}
+
+/// Why a string can't be a [`KeyPrefix`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidKeyPrefix {
+    Empty,
+    Reserved(char),
+}
+
+impl fmt::Display for InvalidKeyPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("key prefix can't be empty"),
+            Self::Reserved(c) => write!(f, "key prefix can't contain {c:?}"),
+        }
+    }
+}
+
+impl Error for InvalidKeyPrefix {}
+
+#[cfg(test)]
+mod tests {
+    use super::{InvalidKeyPrefix, KeyPrefix};
+    #[cfg(feature = "axum")]
+    use crate::KeyHasher;
+
+    #[test]
+    fn test_shared_keys_start_with_the_prefix() {
+        let prefix = KeyPrefix::new("myapp:prod:bucket:").unwrap();
+
+        assert_eq!(prefix.blocklist(), "myapp:prod:bucket:{blocklist}");
+        assert_eq!(prefix.denials(), "myapp:prod:bucket:denials");
+        assert_eq!(prefix.leaderboard(), "myapp:prod:bucket:leaderboard:");
+        assert_eq!(KeyPrefix::default().denials(), "bucket:denials");
+    }
+
+    #[test]
+    #[cfg(feature = "axum")]
+    fn test_keys_start_with_the_prefix() {
+        let sha = KeyHasher::default().hash("abc");
+        let hash = format!("{{{sha}}}");
+        let prefix = KeyPrefix::new("myapp:prod:bucket:").unwrap();
+
+        let bucket = prefix.bucket(&sha);
+
+        assert_eq!(bucket, format!("myapp:prod:bucket:{hash}"));
+        assert_eq!(
+            prefix.ip_bucket(&sha),
+            format!("myapp:prod:bucket:ip:{hash}")
+        );
+        assert_eq!(prefix.global(), "myapp:prod:bucket:__global__");
+        assert_eq!(prefix.tier(&bucket), format!("myapp:prod:tier:{hash}"));
+        assert_eq!(
+            prefix.config(&bucket),
+            format!("myapp:prod:bucket:config:{hash}")
+        );
+
+        // Without a trailing `bucket:`, tiers go under the prefix too.
+        let prefix = KeyPrefix::new("staging:").unwrap();
+        assert_eq!(
+            prefix.tier(&prefix.bucket(&sha)),
+            format!("staging:tier:{hash}")
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "axum")]
+    fn test_default_keys_are_unchanged() {
+        let prefix = KeyPrefix::default();
+        let bucket = prefix.bucket(&KeyHasher::default().hash("abc"));
+
+        assert!(bucket.starts_with("bucket:{") && bucket.ends_with('}'));
+        assert_eq!(prefix.global(), crate::GLOBAL_BUCKET_KEY);
+        assert_eq!(prefix.tier(&bucket), bucket.replacen("bucket:", "tier:", 1));
+        assert_eq!(
+            prefix.config(&bucket),
+            bucket.replacen("bucket:", "bucket:config:", 1)
+        );
+    }
+
+    #[test]
+    fn test_rejects_prefixes_that_break_patterns() {
+        assert_eq!(KeyPrefix::new(""), Err(InvalidKeyPrefix::Empty));
+        for (prefix, c) in [
+            ("app:*:", '*'),
+            ("app?", '?'),
+            ("app[1]:", '['),
+            ("app\\:", '\\'),
+            ("{app}:", '{'),
+            ("my app:", ' '),
+            ("app:\n", '\n'),
+        ] {
+            assert_eq!(KeyPrefix::new(prefix), Err(InvalidKeyPrefix::Reserved(c)));
+        }
+        assert_eq!(
+            "app:*:".parse::<KeyPrefix>().unwrap_err().to_string(),
+            "key prefix can't contain '*'"
+        );
+    }
+}
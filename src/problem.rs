@@ -0,0 +1,172 @@
+use std::time::Duration;
+
+use axum::{
+    body::{Body, HttpBody},
+    http::{HeaderValue, StatusCode, header},
+    response::Response,
+};
+use chrono::SecondsFormat;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::RateLimitDecision;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// An RFC 7807 problem details object, as sent in `application/problem+json`
+/// error responses when [`AppState::with_problem_details`] is enabled.
+///
+/// [`AppState::with_problem_details`]: crate::AppState::with_problem_details
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    /// Seconds until the request may be retried.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl ProblemDetails {
+    pub fn new(problem_type: &str, status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            problem_type: problem_type.to_string(),
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            detail: detail.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::new(
+            "urn:leaky-bucket:unauthorized",
+            StatusCode::UNAUTHORIZED,
+            "The request carries no usable identity to rate limit it by.",
+        )
+    }
+
+    pub fn blocked() -> Self {
+        Self::new(
+            "urn:leaky-bucket:blocked",
+            StatusCode::FORBIDDEN,
+            "This identity has been blocked.",
+        )
+    }
+
+    pub fn rate_limited(decision: &RateLimitDecision) -> Self {
+        Self {
+            retry_after: decision
+                .retry_after
+                .map(|d| d.as_millis().div_ceil(1000) as u64),
+            ..Self::new(
+                "urn:leaky-bucket:rate-limited",
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "{} of {} tokens left, which is not enough for this request.",
+                    decision.remaining, decision.limit
+                ),
+            )
+        }
+    }
+
+    /// Like [`rate_limited`](Self::rate_limited), for a request the
+    /// identity's quota has no room left for until it resets.
+    pub fn quota_exceeded(decision: &RateLimitDecision) -> Self {
+        Self {
+            retry_after: decision
+                .retry_after
+                .map(|d| d.as_millis().div_ceil(1000) as u64),
+            ..Self::new(
+                "urn:leaky-bucket:quota-exceeded",
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "The quota of {} is used up until {}.",
+                    decision.limit,
+                    decision.reset_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+                ),
+            )
+        }
+    }
+
+    /// For a request made while the identity already has as many in
+    /// flight as it may.
+    pub fn too_many_in_flight(max: usize) -> Self {
+        Self::new(
+            "urn:leaky-bucket:too-many-in-flight",
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "{max} requests are in flight for this identity already, the most it may have at once."
+            ),
+        )
+    }
+
+    pub fn cost_exceeds_capacity(cost: i64, limit: i64) -> Self {
+        Self::new(
+            "urn:leaky-bucket:cost-exceeds-capacity",
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("The request costs {cost} tokens but the bucket only holds {limit}."),
+        )
+    }
+
+    pub fn length_required() -> Self {
+        Self::new(
+            "urn:leaky-bucket:length-required",
+            StatusCode::LENGTH_REQUIRED,
+            "The request is charged by its size, so it needs a Content-Length.",
+        )
+    }
+
+    pub fn backend_unavailable(retry_after: Duration) -> Self {
+        Self {
+            retry_after: Some(retry_after.as_secs()),
+            ..Self::new(
+                "urn:leaky-bucket:backend-unavailable",
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The rate limit store could not be reached.",
+            )
+        }
+    }
+
+    pub fn into_response(self) -> Response {
+        Response::builder()
+            .status(self.status)
+            .header(header::CONTENT_TYPE, PROBLEM_JSON)
+            .body(Body::from(serde_json::to_vec(&self).unwrap()))
+            .unwrap()
+    }
+}
+
+/// Rejection builder answering 429 with a problem details body.
+pub fn problem_rejection(decision: &RateLimitDecision) -> Response {
+    ProblemDetails::rate_limited(decision).into_response()
+}
+
+/// The default for [`AppState::with_quota_rejection`], answering 429 with a
+/// `quota_exceeded` problem details body whether or not
+/// [`AppState::with_problem_details`] is on, so clients can tell it from a
+/// rate limit.
+///
+/// [`AppState::with_quota_rejection`]: crate::AppState::with_quota_rejection
+/// [`AppState::with_problem_details`]: crate::AppState::with_problem_details
+pub fn quota_rejection(decision: &RateLimitDecision) -> Response {
+    ProblemDetails::quota_exceeded(decision).into_response()
+}
+
+/// Gives an empty-bodied 401 from a key extractor a problem details body,
+/// keeping its headers. Responses that already have a body are left alone.
+pub(crate) fn fill_unauthorized(response: Response) -> Response {
+    if response.status() != StatusCode::UNAUTHORIZED
+        || response.body().size_hint().exact() != Some(0)
+    {
+        return response;
+    }
+
+    let (mut parts, _) = response.into_parts();
+    let problem = ProblemDetails::unauthorized().into_response();
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    Response::from_parts(parts, problem.into_body())
+}
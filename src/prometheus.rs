@@ -0,0 +1,69 @@
+use std::fmt::Write;
+
+use axum::{
+    Router,
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+
+use crate::{AppState, BucketStore, telemetry::Outcome};
+
+/// The Prometheus text format, version 0.0.4.
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// A `GET /metrics` route answering the middleware's [`Stats`](crate::Stats)
+/// in the Prometheus text format, for deployments without a `metrics`
+/// recorder of their own:
+///
+/// - `leaky_bucket_requests_total{outcome}`, with the outcomes `allowed`,
+///   `denied`, `unauthorized` and `error`, the latter counting store
+///   failures.
+/// - `leaky_bucket_transaction_retries_total`, transactions retried after
+///   losing to a concurrent write.
+///
+/// Pass it a clone of the state the middleware runs with, and mount it
+/// outside the rate limited routes.
+pub fn metrics_router<S: BucketStore>(state: AppState<S>) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics::<S>))
+        .with_state(state)
+}
+
+async fn metrics<S: BucketStore>(State(state): State<AppState<S>>) -> Response {
+    let stats = &state.stats;
+    let mut body = String::new();
+
+    body.push_str(
+        "# HELP leaky_bucket_requests_total Requests seen by the rate limiter, by outcome.\n\
+         # TYPE leaky_bucket_requests_total counter\n",
+    );
+    for (outcome, count) in [
+        (Outcome::Allowed, stats.allowed()),
+        (Outcome::Denied, stats.denied()),
+        (Outcome::Unauthorized, stats.unauthorized()),
+        (Outcome::Error, stats.errors()),
+    ] {
+        let outcome = outcome.as_str();
+        writeln!(
+            body,
+            "leaky_bucket_requests_total{{outcome=\"{outcome}\"}} {count}"
+        )
+        .unwrap();
+    }
+
+    body.push_str(
+        "# HELP leaky_bucket_transaction_retries_total Bucket transactions retried after \
+         losing to a concurrent write.\n\
+         # TYPE leaky_bucket_transaction_retries_total counter\n",
+    );
+    writeln!(
+        body,
+        "leaky_bucket_transaction_retries_total {}",
+        state.store.conflicts()
+    )
+    .unwrap();
+
+    ([(header::CONTENT_TYPE, TEXT_FORMAT)], body).into_response()
+}
@@ -0,0 +1,206 @@
+//! Fixed windows, selected with [`Algorithm::FixedWindow`], for quotas such
+//! as so many requests a day.
+//!
+//! A window is `refill_interval` long, and they're counted from the Unix
+//! epoch moved along by the offset, so daily windows start at midnight UTC
+//! unless told otherwise. Each holds `max_tokens`, all of which come back at
+//! once when the next one starts. The bucket is stored like a token bucket,
+//! with `last_updated` holding the start of its window.
+//!
+//! [`Algorithm::FixedWindow`]: crate::Algorithm::FixedWindow
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    Algorithm, BucketConfig, BucketStatus, RateLimitDecision, TokenPersistence, later, timestamp,
+};
+
+impl TokenPersistence {
+    /// A window holding `tokens` as of `now`.
+    pub(crate) fn from_window(config: &BucketConfig, tokens: i64, now: DateTime<Utc>) -> Self {
+        Self {
+            tokens,
+            last_updated: window_start(config, now),
+            penalty: None,
+        }
+    }
+
+    /// The tokens left as of `now` and the start of the window they're in.
+    /// A window from our future is left as it is.
+    fn windowed(&self, config: &BucketConfig, now: DateTime<Utc>) -> (i64, DateTime<Utc>) {
+        let start = window_start(config, now);
+        if self.last_updated < start {
+            (config.max_tokens, start)
+        } else {
+            (self.tokens, self.last_updated)
+        }
+    }
+
+    pub(crate) fn window_take(
+        &self,
+        config: &BucketConfig,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> (RateLimitDecision, Option<TokenPersistence>) {
+        let (tokens, start) = self.windowed(config, now);
+        let reset_at = later(start, config.refill_interval);
+        let decision = RateLimitDecision {
+            allowed: true,
+            limit: config.max_tokens,
+            remaining: tokens.max(0),
+            reset_at,
+            retry_after: None,
+        };
+
+        if tokens < cost {
+            let decision = RateLimitDecision {
+                allowed: false,
+                retry_after: Some((reset_at - now).to_std().unwrap_or_default()),
+                ..decision
+            };
+            return (decision, None);
+        }
+        let updated = TokenPersistence {
+            tokens: tokens - cost,
+            last_updated: start,
+            penalty: self.penalty.clone(),
+        };
+        let decision = RateLimitDecision {
+            remaining: tokens - cost,
+            ..decision
+        };
+        (decision, Some(updated))
+    }
+
+    /// Puts `cost` back into the window, if it's still the current one.
+    pub(crate) fn window_refund(
+        &self,
+        config: &BucketConfig,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let (tokens, start) = self.windowed(config, now);
+        TokenPersistence {
+            tokens: tokens.saturating_add(cost).min(config.max_tokens),
+            last_updated: start,
+            penalty: self.penalty.clone(),
+        }
+    }
+
+    pub(crate) fn window_status(&self, config: &BucketConfig, now: DateTime<Utc>) -> BucketStatus {
+        let (tokens, start) = self.windowed(config, now);
+        BucketStatus {
+            tokens,
+            last_updated: start,
+            time_to_full: self.window_time_to_full(config, now),
+            banned_until: self.banned(config, now).map(|decision| decision.reset_at),
+        }
+    }
+
+    /// How long until the next window, unless nothing's been used of this one.
+    pub(crate) fn window_time_to_full(
+        &self,
+        config: &BucketConfig,
+        now: DateTime<Utc>,
+    ) -> Duration {
+        let (tokens, start) = self.windowed(config, now);
+        if tokens >= config.max_tokens {
+            return Duration::ZERO;
+        }
+        (later(start, config.refill_interval) - now)
+            .to_std()
+            .unwrap_or_default()
+    }
+}
+
+/// When the window `now` falls in started.
+fn window_start(config: &BucketConfig, now: DateTime<Utc>) -> DateTime<Utc> {
+    let Algorithm::FixedWindow { offset } = config.algorithm else {
+        return now;
+    };
+    let period = i64::try_from(config.refill_interval.as_millis())
+        .unwrap_or(i64::MAX)
+        .max(1);
+    let offset = i64::try_from(offset.as_millis()).unwrap_or(i64::MAX) % period;
+    let now_ms = now.timestamp_millis();
+    let start = (now_ms - offset).div_euclid(period) * period + offset;
+    timestamp::from_millis(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+
+    use crate::{Algorithm, BucketConfig, TokenPersistence};
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        rfc3339.parse().unwrap()
+    }
+
+    #[test]
+    fn test_windows_start_at_midnight_or_the_offset() {
+        let daily = BucketConfig::daily(100);
+        let now = at("2025-03-01T17:30:00Z");
+        assert_eq!(
+            TokenPersistence::new(&daily, now).last_updated,
+            at("2025-03-01T00:00:00Z")
+        );
+
+        let shifted = BucketConfig {
+            algorithm: Algorithm::FixedWindow {
+                offset: Duration::from_secs(18 * 60 * 60),
+            },
+            ..daily
+        };
+        let bucket = TokenPersistence::new(&shifted, now);
+        assert_eq!(bucket.last_updated, at("2025-02-28T18:00:00Z"));
+        let (decision, _) = bucket.charge(&shifted, 1, now);
+        assert_eq!(decision.reset_at, at("2025-03-01T18:00:00Z"));
+    }
+
+    #[test]
+    fn test_quota_comes_back_all_at_once() {
+        let config = BucketConfig::daily(3);
+        let now = at("2025-03-01T23:00:00Z");
+
+        let (decision, updated) = TokenPersistence::new(&config, now).charge(&config, 3, now);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        let spent = updated.unwrap();
+        assert_eq!(
+            spent.time_to_full(&config, now),
+            Duration::from_secs(60 * 60)
+        );
+
+        let (denied, none) = spent.charge(&config, 1, at("2025-03-01T23:59:59Z"));
+        assert!(!denied.allowed);
+        assert!(none.is_none());
+        assert_eq!(denied.retry_after, Some(Duration::from_secs(1)));
+        assert_eq!(denied.reset_at, at("2025-03-02T00:00:00Z"));
+
+        let (decision, updated) = spent.charge(&config, 1, at("2025-03-02T00:00:00Z"));
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 2);
+        assert_eq!(updated.unwrap().last_updated, at("2025-03-02T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_refund_stays_in_its_window() {
+        let config = BucketConfig::daily(3);
+        let bucket = TokenPersistence {
+            tokens: 1,
+            last_updated: at("2025-03-01T00:00:00Z"),
+            penalty: None,
+        };
+
+        let refunded = bucket.refund(&config, 5, at("2025-03-01T12:00:00Z"));
+        assert_eq!(refunded.tokens, 3);
+        let next_day = bucket.refund(&config, 1, at("2025-03-02T12:00:00Z"));
+        assert_eq!(next_day.tokens, 3);
+        assert_eq!(next_day.last_updated, at("2025-03-02T00:00:00Z"));
+    }
+}
@@ -0,0 +1,233 @@
+use std::{sync::Arc, time::Duration};
+
+use redis::{
+    AsyncConnectionConfig, Client, Cmd, Connection, ErrorKind, Pipeline, RedisError, RedisFuture,
+    RedisResult, Value,
+    aio::{ConnectionLike, MultiplexedConnection},
+    sentinel::SentinelClient,
+};
+use tokio::sync::Mutex;
+
+use crate::BoxFuture;
+
+type Connect<C> = Arc<dyn Fn() -> BoxFuture<'static, RedisResult<C>> + Send + Sync>;
+
+/// An async connection that is thrown away and opened again once it stops
+/// being useful: when it breaks, or when the node it talks to has been demoted
+/// to a replica and answers writes with `READONLY`.
+///
+/// The new connection is opened lazily by the next command, so a restarted
+/// Redis is only felt by the requests that were in flight, and with
+/// [`ReconnectingConnection::sentinel`] the master is looked up again after a
+/// failover instead of every request failing until a restart.
+pub struct ReconnectingConnection<C> {
+    connect: Connect<C>,
+    conn: Option<C>,
+}
+
+impl<C> ReconnectingConnection<C> {
+    pub fn new<F>(connect: F) -> Self
+    where
+        F: Fn() -> BoxFuture<'static, RedisResult<C>> + Send + Sync + 'static,
+    {
+        Self {
+            connect: Arc::new(connect),
+            conn: None,
+        }
+    }
+}
+
+impl ReconnectingConnection<MultiplexedConnection> {
+    /// Connects to the server `client` is for now, and again whenever the
+    /// connection breaks, with `config`'s timeouts.
+    pub async fn open(client: Client, config: AsyncConnectionConfig) -> RedisResult<Self> {
+        let mut conn = Self::new(move || {
+            let client = client.clone();
+            let config = config.clone();
+            Box::pin(async move {
+                client
+                    .get_multiplexed_async_connection_with_config(&config)
+                    .await
+            })
+        });
+        conn.connection().await?;
+        Ok(conn)
+    }
+
+    /// Connects to whichever node the sentinels currently report as master.
+    pub fn sentinel(client: Arc<Mutex<SentinelClient>>) -> Self {
+        Self::new(move || {
+            let client = Arc::clone(&client);
+            Box::pin(async move { client.lock().await.get_async_connection().await })
+        })
+    }
+}
+
+impl<C: ConnectionLike + Send> ReconnectingConnection<C> {
+    async fn connection(&mut self) -> RedisResult<&mut C> {
+        let conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => (self.connect)().await?,
+        };
+        Ok(self.conn.insert(conn))
+    }
+
+    fn check<T>(
+        &mut self,
+        result: RedisResult<T>,
+        read_only: impl Fn(&T) -> bool,
+    ) -> RedisResult<T> {
+        if lost(&result, read_only) {
+            self.conn = None;
+        }
+        result
+    }
+}
+
+/// Whether the connection that answered with `result` should be replaced.
+fn lost<T>(result: &RedisResult<T>, read_only: impl Fn(&T) -> bool) -> bool {
+    match result {
+        Ok(value) => read_only(value),
+        Err(e) => e.kind() == ErrorKind::ReadOnly || e.is_unrecoverable_error(),
+    }
+}
+
+/// Server errors come back as values; a demoted master refuses writes with
+/// `READONLY`.
+fn is_read_only(value: &Value) -> bool {
+    match value {
+        Value::ServerError(e) => e.code() == "READONLY",
+        Value::Array(values) => values.iter().any(is_read_only),
+        _ => false,
+    }
+}
+
+impl<C: ConnectionLike + Send> ConnectionLike for ReconnectingConnection<C> {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            let result = self.connection().await?.req_packed_command(cmd).await;
+            self.check(result, is_read_only)
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            let result = self
+                .connection()
+                .await?
+                .req_packed_commands(cmd, offset, count)
+                .await;
+            self.check(result, |values| values.iter().any(is_read_only))
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.as_ref().map_or(0, ConnectionLike::get_db)
+    }
+}
+
+/// [`ReconnectingConnection`] for blocking connections, as
+/// [`RedisStore`](crate::RedisStore) takes.
+///
+/// The request that finds the connection broken still fails; the one after
+/// it gets a new connection.
+pub struct BlockingReconnectingConnection<C> {
+    connect: Arc<dyn Fn() -> RedisResult<C> + Send + Sync>,
+    conn: Option<C>,
+}
+
+impl<C> BlockingReconnectingConnection<C> {
+    pub fn new<F>(connect: F) -> Self
+    where
+        F: Fn() -> RedisResult<C> + Send + Sync + 'static,
+    {
+        Self {
+            connect: Arc::new(connect),
+            conn: None,
+        }
+    }
+}
+
+impl BlockingReconnectingConnection<Connection> {
+    /// Connects to the server `client` is for now, and again whenever the
+    /// connection breaks, giving up on connecting, reading and writing after
+    /// `timeout`.
+    pub fn open(client: Client, timeout: Duration) -> RedisResult<Self> {
+        let mut conn = Self::new(move || {
+            let conn = client.get_connection_with_timeout(timeout)?;
+            conn.set_read_timeout(Some(timeout))?;
+            conn.set_write_timeout(Some(timeout))?;
+            Ok(conn)
+        });
+        conn.connection()?;
+        Ok(conn)
+    }
+}
+
+impl<C: redis::ConnectionLike> BlockingReconnectingConnection<C> {
+    fn connection(&mut self) -> RedisResult<&mut C> {
+        let conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => (self.connect)()?,
+        };
+        Ok(self.conn.insert(conn))
+    }
+
+    fn check<T>(
+        &mut self,
+        result: RedisResult<T>,
+        read_only: impl Fn(&T) -> bool,
+    ) -> RedisResult<T> {
+        if lost(&result, read_only) {
+            self.conn = None;
+        }
+        result
+    }
+}
+
+impl<C: redis::ConnectionLike> redis::ConnectionLike for BlockingReconnectingConnection<C> {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        let result = self.connection()?.req_packed_command(cmd);
+        self.check(result, is_read_only)
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        let result = self.connection()?.req_packed_commands(cmd, offset, count);
+        self.check(result, |values| values.iter().any(is_read_only))
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.as_ref().map_or(0, redis::ConnectionLike::get_db)
+    }
+
+    fn check_connection(&mut self) -> bool {
+        let alive = self
+            .connection()
+            .is_ok_and(redis::ConnectionLike::check_connection);
+        if !alive {
+            self.conn = None;
+        }
+        alive
+    }
+
+    /// Open as long as a new connection can be opened in its place.
+    fn is_open(&self) -> bool {
+        true
+    }
+}
+
+/// Whether a charge that failed with `e` is worth retrying on a fresh
+/// connection: the node went away or stopped being the master mid-charge.
+pub(crate) fn lost_master(e: &RedisError) -> bool {
+    e.kind() == ErrorKind::ReadOnly || e.is_connection_dropped()
+}
@@ -0,0 +1,171 @@
+//! The token bucket's refill, selected with [`Algorithm::TokenBucket`]:
+//! `refill_rate` tokens come back every whole `refill_interval`, up to
+//! `max_tokens`.
+//!
+//! [`Algorithm::TokenBucket`]: crate::Algorithm::TokenBucket
+
+use chrono::{DateTime, Utc};
+
+use crate::BucketConfig;
+
+/// The tokens in a bucket holding `tokens` as of `last_updated` once it's
+/// refilled up to `now`, and the time they're counted from.
+pub(crate) fn refill(
+    tokens: i64,
+    last_updated: DateTime<Utc>,
+    now: DateTime<Utc>,
+    config: &BucketConfig,
+) -> (i64, DateTime<Utc>) {
+    let elapsed_ms = now
+        .signed_duration_since(last_updated)
+        .num_milliseconds()
+        .max(0);
+    let interval_ms = config.refill_interval.as_millis().max(1) as i64;
+    let intervals = elapsed_ms / interval_ms;
+
+    let refilled = tokens.saturating_add(intervals.saturating_mul(config.refill_rate));
+
+    // Only the time that was turned into tokens is used up, so a partial
+    // interval carries over to the next request. Time spent at capacity
+    // can't be banked.
+    if refilled >= config.max_tokens {
+        (config.max_tokens, now.max(last_updated))
+    } else {
+        (
+            refilled,
+            last_updated + chrono::Duration::milliseconds(intervals * interval_ms),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{DateTime, Utc};
+    use proptest::prelude::*;
+
+    use super::refill;
+    use crate::{BucketConfig, TokenPersistence};
+
+    fn at(ms: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(1_740_830_400_000 + ms).unwrap()
+    }
+
+    fn configs() -> impl Strategy<Value = BucketConfig> {
+        (1..=1_000i64, 1..=100i64, 1..=10_000_000u64).prop_map(
+            |(max_tokens, refill_rate, interval_ms)| BucketConfig {
+                max_tokens,
+                refill_rate,
+                refill_interval: Duration::from_millis(interval_ms),
+                ..BucketConfig::default()
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn test_never_more_than_max(
+            config in configs(),
+            tokens in -1_000..=2_000i64,
+            last_updated in -1_000_000..=0i64,
+            now in -1_000_000..=1_000_000_000i64,
+        ) {
+            let (refilled, _) = refill(tokens, at(last_updated), at(now), &config);
+
+            prop_assert!(refilled <= config.max_tokens);
+        }
+
+        #[test]
+        fn test_never_takes_tokens_away(
+            config in configs(),
+            tokens in -1_000..=1_000i64,
+            now in -1_000_000..=1_000_000_000i64,
+        ) {
+            let (refilled, last_updated) = refill(tokens, at(0), at(now), &config);
+
+            prop_assert!(refilled >= tokens.min(config.max_tokens));
+            if tokens >= 0 {
+                prop_assert!(refilled >= 0);
+            }
+            // Nor moves it back in time.
+            prop_assert!(last_updated >= at(0));
+        }
+
+        #[test]
+        fn test_later_is_never_fewer(
+            config in configs(),
+            tokens in 0..=1_000i64,
+            earlier in -1_000_000..=1_000_000_000i64,
+            by in 0..=1_000_000_000i64,
+        ) {
+            let (sooner, _) = refill(tokens, at(0), at(earlier), &config);
+            let (later, _) = refill(tokens, at(0), at(earlier + by), &config);
+
+            prop_assert!(later >= sooner);
+        }
+
+        #[test]
+        fn test_refilling_in_steps_is_refilling_at_once(
+            config in configs(),
+            tokens in 0..=1_000i64,
+            first in -1_000_000..=1_000_000_000i64,
+            then in 0..=1_000_000_000i64,
+        ) {
+            let (midway, midway_updated) = refill(tokens, at(0), at(first), &config);
+            let stepped = refill(midway, midway_updated, at(first + then), &config);
+
+            prop_assert_eq!(stepped, refill(tokens, at(0), at(first + then), &config));
+        }
+
+        #[test]
+        fn test_never_lets_through_more_than_refilled(
+            config in configs(),
+            requests in prop::collection::vec((0..=30_000_000i64, 1..=1_000i64), 1..100),
+        ) {
+            let mut bucket = TokenPersistence::new(&config, at(0));
+            let mut now = 0;
+            let mut consumed = 0;
+            for (wait, cost) in requests {
+                now += wait;
+                let (decision, updated) = bucket.take(&config, cost, at(now));
+                prop_assert!(decision.remaining >= 0);
+                if let Some(updated) = updated {
+                    if decision.allowed {
+                        consumed += cost;
+                    }
+                    bucket = updated;
+                }
+                prop_assert!(bucket.tokens >= 0);
+            }
+
+            let interval_ms = config.refill_interval.as_millis() as i64;
+            let refilled = config.refill_rate * (now / interval_ms);
+            prop_assert!(consumed <= config.max_tokens + refilled);
+        }
+
+        #[test]
+        fn test_negative_costs_never_overfill(
+            config in configs(),
+            taken in 0..=1_000i64,
+            cost in -10_000..=0i64,
+        ) {
+            let bucket = TokenPersistence::new(&config, at(0));
+            let (_, taken) = bucket.take(&config, taken.min(config.max_tokens), at(0));
+
+            let (_, updated) = taken.unwrap().take(&config, cost, at(0));
+
+            prop_assert!(updated.unwrap().tokens <= config.max_tokens);
+        }
+
+        #[test]
+        fn test_costs_that_never_fit_are_denied(config in configs()) {
+            let bucket = TokenPersistence::new(&config, at(0));
+
+            let (decision, _) = bucket.take(&config, i64::MAX, at(0));
+
+            prop_assert!(!decision.allowed);
+            prop_assert!(decision.retry_after.unwrap() > Duration::ZERO);
+        }
+    }
+}
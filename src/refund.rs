@@ -0,0 +1,115 @@
+use std::ops::RangeInclusive;
+
+use axum::http::StatusCode;
+
+use crate::BucketConfig;
+
+/// Which responses from the limited service give the client back what the
+/// request cost, so that failures of the service's own making don't count
+/// against anyone's quota.
+///
+/// The default refunds server errors, 500 through 599.
+#[derive(Clone, Debug)]
+pub struct Refunds {
+    statuses: Vec<RangeInclusive<u16>>,
+    failures: Option<(RangeInclusive<u16>, BucketConfig)>,
+}
+
+impl Default for Refunds {
+    fn default() -> Self {
+        Self::new().with_statuses(500..=599)
+    }
+}
+
+impl Refunds {
+    /// Refunds nothing until given statuses.
+    pub fn new() -> Self {
+        Self {
+            statuses: Vec::new(),
+            failures: None,
+        }
+    }
+
+    /// Refunds responses with a status in `statuses`, e.g. `502..=504`.
+    pub fn with_statuses(mut self, statuses: RangeInclusive<u16>) -> Self {
+        self.statuses.push(statuses);
+        self
+    }
+
+    pub fn with_status(self, status: StatusCode) -> Self {
+        self.with_statuses(status.as_u16()..=status.as_u16())
+    }
+
+    /// Counts each response with a status in `statuses` as a failed attempt,
+    /// one token from a bucket of the identity's own under `config`. Once
+    /// that's empty, the identity is turned away before reaching the service
+    /// until it refills. Other refunded responses don't count.
+    ///
+    /// Refunding and counting 401 through 403 with a strict `config` stops
+    /// credentials being guessed under someone's key without locking them out
+    /// of their own bucket in the process.
+    pub fn with_failures(mut self, statuses: RangeInclusive<u16>, config: BucketConfig) -> Self {
+        self.failures = Some((statuses, config));
+        self
+    }
+
+    pub(crate) fn failures(&self) -> Option<&BucketConfig> {
+        self.failures.as_ref().map(|(_, config)| config)
+    }
+
+    /// Whether `status` counts as a failed attempt.
+    pub(crate) fn failed(&self, status: StatusCode) -> bool {
+        self.failures
+            .as_ref()
+            .is_some_and(|(statuses, _)| statuses.contains(&status.as_u16()))
+    }
+
+    pub fn matches(&self, status: StatusCode) -> bool {
+        self.statuses
+            .iter()
+            .any(|statuses| statuses.contains(&status.as_u16()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::Refunds;
+    use crate::BucketConfig;
+
+    #[test]
+    fn test_default_refunds_server_errors() {
+        let refunds = Refunds::default();
+
+        assert!(refunds.matches(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(refunds.matches(StatusCode::GATEWAY_TIMEOUT));
+        assert!(!refunds.matches(StatusCode::OK));
+        assert!(!refunds.matches(StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    #[test]
+    fn test_statuses_add_up() {
+        let refunds = Refunds::new()
+            .with_status(StatusCode::BAD_GATEWAY)
+            .with_statuses(400..=404);
+
+        assert!(refunds.matches(StatusCode::BAD_GATEWAY));
+        assert!(refunds.matches(StatusCode::UNAUTHORIZED));
+        assert!(!refunds.matches(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!Refunds::new().matches(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[test]
+    fn test_failures_have_their_own_statuses() {
+        let refunds = Refunds::default()
+            .with_statuses(401..=403)
+            .with_failures(401..=403, BucketConfig::default());
+
+        assert!(refunds.failed(StatusCode::UNAUTHORIZED));
+        assert!(refunds.failed(StatusCode::FORBIDDEN));
+        assert!(!refunds.failed(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(refunds.matches(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!Refunds::default().failed(StatusCode::UNAUTHORIZED));
+    }
+}
@@ -0,0 +1,188 @@
+use axum::{Extension, Router};
+
+use crate::{AppState, BucketConfig, BucketStore, RateLimiterLayer};
+
+/// The bucket config a router was limited with, put in the request
+/// extensions for the middleware to charge against instead of the state's.
+#[derive(Clone, Debug)]
+pub(crate) struct RouteLimit {
+    pub(crate) config: BucketConfig,
+    /// Shared by every route limited under the same name.
+    pub(crate) group: Option<String>,
+}
+
+/// Limits the routes of a [`Router`] without wiring the middleware up by
+/// hand:
+///
+#[cfg_attr(feature = "memory", doc = "```")]
+#[cfg_attr(not(feature = "memory"), doc = "```ignore")]
+/// use axum::{Router, routing::get};
+/// use leaky_bucket::{AppState, BucketConfig, MemoryStore, RateLimitedRouterExt};
+///
+/// let state = AppState::new(MemoryStore::new(), BucketConfig::default());
+/// let search = Router::<()>::new()
+///     .route("/search", get(|| async { "results" }))
+///     .route("/suggest", get(|| async { "suggestions" }))
+///     .rate_limit_group(state, "search", BucketConfig::default());
+/// ```
+///
+/// Like [`Router::route_layer`], both only cover the routes added before
+/// them, so routes can be limited in groups and merged into one app. Those
+/// routes shouldn't sit behind another rate limiting layer as well, or each
+/// request is charged twice.
+pub trait RateLimitedRouterExt {
+    /// Limits each route with `config`, in a bucket per identity and route.
+    fn rate_limited<S: BucketStore>(self, state: AppState<S>, config: BucketConfig) -> Self;
+
+    /// Limits the routes with `config` in one bucket per identity that all of
+    /// them draw from, and so does every other route limited under `name`.
+    fn rate_limit_group<S: BucketStore>(
+        self,
+        state: AppState<S>,
+        name: impl Into<String>,
+        config: BucketConfig,
+    ) -> Self;
+}
+
+impl<T: Clone + Send + Sync + 'static> RateLimitedRouterExt for Router<T> {
+    fn rate_limited<S: BucketStore>(self, state: AppState<S>, config: BucketConfig) -> Self {
+        limit(
+            self,
+            state,
+            RouteLimit {
+                config,
+                group: None,
+            },
+        )
+    }
+
+    fn rate_limit_group<S: BucketStore>(
+        self,
+        state: AppState<S>,
+        name: impl Into<String>,
+        config: BucketConfig,
+    ) -> Self {
+        limit(
+            self,
+            state,
+            RouteLimit {
+                config,
+                group: Some(name.into()),
+            },
+        )
+    }
+}
+
+fn limit<T, S>(router: Router<T>, state: AppState<S>, limit: RouteLimit) -> Router<T>
+where
+    T: Clone + Send + Sync + 'static,
+    S: BucketStore,
+{
+    // The last layer added runs first, so the config is in place by the time
+    // the limiter looks for it.
+    router
+        .route_layer(RateLimiterLayer::new(state))
+        .route_layer(Extension(limit))
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use axum::{
+        Router,
+        body::Body,
+        http::{Request, StatusCode},
+        routing::get,
+    };
+    use chrono::Utc;
+    use tower::ServiceExt;
+
+    use crate::{AppState, BucketConfig, MemoryStore, testing::ManualClock};
+
+    use super::RateLimitedRouterExt;
+
+    fn state() -> AppState<MemoryStore> {
+        AppState::new(MemoryStore::new(), BucketConfig::default())
+            .with_clock(ManualClock::new(Utc::now()))
+    }
+
+    fn holding(max_tokens: i64) -> BucketConfig {
+        BucketConfig {
+            max_tokens,
+            ..BucketConfig::default()
+        }
+    }
+
+    fn routes(paths: &[&'static str]) -> Router {
+        paths.iter().fold(Router::new(), |router, path| {
+            router.route(path, get(|| async { "ok" }))
+        })
+    }
+
+    async fn status(app: &Router, path: &str) -> StatusCode {
+        let request = Request::get(path)
+            .header("Bearer", "abc")
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_grouped_routes_share_a_bucket() {
+        let app = routes(&["/search", "/suggest"]).rate_limit_group(state(), "search", holding(2));
+
+        assert_eq!(status(&app, "/search").await, StatusCode::OK);
+        assert_eq!(status(&app, "/suggest").await, StatusCode::OK);
+        assert_eq!(status(&app, "/search").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            status(&app, "/suggest").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_groups_are_independent() {
+        let state = state();
+        let app = routes(&["/search"])
+            .rate_limit_group(state.clone(), "search", holding(1))
+            .merge(routes(&["/export"]).rate_limit_group(state.clone(), "export", holding(1)))
+            .merge(routes(&["/suggest"]).rate_limit_group(state, "search", holding(1)));
+
+        assert_eq!(status(&app, "/search").await, StatusCode::OK);
+        assert_eq!(
+            status(&app, "/suggest").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(status(&app, "/export").await, StatusCode::OK);
+        assert_eq!(status(&app, "/export").await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_routes_get_a_bucket_each() {
+        let state = state();
+        let app = routes(&["/search", "/suggest"])
+            .rate_limited(state.clone(), holding(1))
+            .merge(routes(&["/"]).rate_limited(state.clone(), holding(3)));
+
+        assert_eq!(status(&app, "/search").await, StatusCode::OK);
+        assert_eq!(status(&app, "/search").await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status(&app, "/suggest").await, StatusCode::OK);
+        for _ in 0..3 {
+            assert_eq!(status(&app, "/").await, StatusCode::OK);
+        }
+        assert_eq!(status(&app, "/").await, StatusCode::TOO_MANY_REQUESTS);
+        // None of it came out of the state's own bucket.
+        assert_eq!(state.check_tokens("abc").await.unwrap().remaining, 10);
+    }
+
+    #[tokio::test]
+    async fn test_routes_added_afterwards_are_not_limited() {
+        let app = routes(&["/search"])
+            .rate_limit_group(state(), "search", holding(1))
+            .route("/health", get(|| async { "ok" }));
+
+        assert_eq!(status(&app, "/search").await, StatusCode::OK);
+        for _ in 0..3 {
+            assert_eq!(status(&app, "/health").await, StatusCode::OK);
+        }
+    }
+}
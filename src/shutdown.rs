@@ -0,0 +1,171 @@
+use std::{future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::Router;
+use tokio::{net::TcpListener, sync::Notify};
+
+/// Resolves once the process is asked to stop, by SIGTERM as Kubernetes
+/// does or by Ctrl-C, for `axum::serve(..).with_graceful_shutdown` or
+/// [`serve_with_shutdown`].
+pub async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %e, "couldn't listen for ctrl-c");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "couldn't listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => {}
+        () = terminate => {}
+    }
+}
+
+/// Serves `app` on `listener` until `signal` resolves, then stops accepting
+/// connections and waits for the requests in flight to finish, for up to
+/// `deadline`.
+///
+/// A request cut off mid-charge may leave its bucket charged for a response
+/// that was never sent, so `deadline` should leave the handlers time to
+/// finish, within whatever grace period the process is given before it's
+/// killed. Requests still running after it are given up on, and dropped
+/// along with the runtime as the process exits.
+pub async fn serve_with_shutdown<F>(
+    listener: TcpListener,
+    app: Router,
+    signal: F,
+    deadline: Duration,
+) -> io::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let draining = Arc::new(Notify::new());
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown({
+        let draining = Arc::clone(&draining);
+        async move {
+            signal.await;
+            tracing::info!(?deadline, "shutting down, draining requests in flight");
+            draining.notify_one();
+        }
+    });
+
+    tokio::select! {
+        served = server => served,
+        () = async {
+            draining.notified().await;
+            tokio::time::sleep(deadline).await;
+        } => {
+            tracing::warn!(?deadline, "requests still in flight after the deadline, giving up on them");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{Router, routing::get};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+        time::Instant,
+    };
+
+    use super::serve_with_shutdown;
+
+    fn app() -> Router {
+        Router::new().route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                "done"
+            }),
+        )
+    }
+
+    /// Starts serving `app` on an ephemeral port, returning the address, the
+    /// trigger for the shutdown and the server's task.
+    async fn start(
+        app: Router,
+        deadline: Duration,
+    ) -> (
+        std::net::SocketAddr,
+        oneshot::Sender<()>,
+        tokio::task::JoinHandle<std::io::Result<()>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let signal = async move {
+            let _ = stopped.await;
+        };
+        let server = tokio::spawn(serve_with_shutdown(listener, app, signal, deadline));
+        (addr, stop, server)
+    }
+
+    /// Sends `GET path` and returns the whole response, or what there was of
+    /// it when the connection closed.
+    async fn fetch(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_requests_finish_after_the_signal() {
+        let (addr, stop, server) = start(app(), Duration::from_secs(5)).await;
+
+        let request = tokio::spawn(fetch(addr, "/slow"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop.send(()).unwrap();
+
+        let response = request.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("done"), "{response}");
+        server.await.unwrap().unwrap();
+        // No longer accepting.
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_gives_up_after_the_deadline() {
+        let app = Router::new().route("/hung", get(std::future::pending::<&str>));
+        let (addr, stop, server) = start(app, Duration::from_millis(100)).await;
+
+        tokio::spawn(fetch(addr, "/hung"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let started = Instant::now();
+        stop.send(()).unwrap();
+
+        server.await.unwrap().unwrap();
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+}
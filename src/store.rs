@@ -0,0 +1,321 @@
+use std::{collections::HashMap, error::Error, fmt, time::Duration};
+
+use chrono::{DateTime, Utc};
+
+use serde_derive::Deserialize;
+
+use crate::{BoxFuture, BucketConfig, BucketStatus, RateLimitDecision};
+
+mod cached;
+#[cfg(feature = "memory")]
+mod memory;
+#[cfg(feature = "redis")]
+mod redis;
+mod sharded;
+
+pub use cached::{CachedStore, DEFAULT_FLUSH_INTERVAL, DEFAULT_MAX_DRIFT};
+#[cfg(feature = "memory")]
+pub use memory::MemoryStore;
+#[cfg(any(feature = "testing", all(test, feature = "redis")))]
+pub(crate) use redis::write_into;
+#[cfg(feature = "redis")]
+pub use redis::{
+    AsyncRedisStore, DEFAULT_REDIS_TIMEOUT, DenialLog, Leaderboard, RedisStore, TransactionRetry,
+};
+pub use sharded::ShardedStore;
+
+/// Where bucket state lives.
+///
+/// Implementations own the read-refill-write cycle for a key and must make it
+/// atomic with respect to other requests charging the same bucket. The refill
+/// math itself is shared; see the stores in this crate.
+///
+/// The stores in this crate implement every method. Those with a default
+/// return [`StoreError::Unsupported`] unless said otherwise, so a store only
+/// has to implement the ones it's used for.
+pub trait BucketStore: Send + Sync + 'static {
+    /// Charges `cost` tokens to the bucket at `key` as of `now`, creating it
+    /// full if it doesn't exist yet.
+    fn take_token<'a>(
+        &'a self,
+        key: &'a str,
+        cost: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>>;
+
+    /// Charges `cost` tokens to every bucket in `buckets`, each under its own
+    /// config, in one atomic step: to all of them if they all allow it, and
+    /// to none of them otherwise. Returns each bucket's decision, in order.
+    ///
+    /// Left as it is, it only handles a single bucket.
+    fn take_tokens<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<RateLimitDecision>, StoreError>> {
+        Box::pin(async move {
+            match buckets {
+                [(key, config)] => Ok(vec![self.take_token(key, cost, config, now).await?]),
+                _ => Err(StoreError::Unsupported("charge several buckets at once")),
+            }
+        })
+    }
+
+    /// [`take_tokens`](Self::take_tokens), but only once per `receipt` for as
+    /// long as it's kept: the charge stores it for `ttl`, in the same atomic
+    /// step. While it's stored, nothing is charged again and `None` is
+    /// returned. A denied charge leaves no receipt.
+    fn take_tokens_once<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+        receipt: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<Option<Vec<RateLimitDecision>>, StoreError>> {
+        let _ = (buckets, cost, now, receipt, ttl);
+        Box::pin(async { Err(StoreError::Unsupported("deduplicate charges")) })
+    }
+
+    /// [`take_tokens`](Self::take_tokens), with the first bucket charged
+    /// under its identity's tier: the tier stored at `tier_key` picks its
+    /// config out of `tiers`, and a missing or unknown one leaves the
+    /// bucket's own.
+    ///
+    /// [`RedisStore`] reads the tier in the same round trip as it watches the
+    /// buckets. Left as it is, it reads it with [`tier`](Self::tier) first,
+    /// which costs the other stores in this crate a round trip at most.
+    fn take_tokens_tiered<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        tier_key: &'a str,
+        tiers: &'a HashMap<String, BucketConfig>,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Tiered, StoreError>> {
+        Box::pin(async move {
+            let tier = self.tier(tier_key).await?;
+            let buckets = tiered(buckets, tier.as_deref(), tiers);
+            let decisions = self.take_tokens(&buckets, cost, now).await?;
+            Ok(Tiered { tier, decisions })
+        })
+    }
+
+    /// The tier stored at `key` by whatever bills the identity, such as
+    /// `free` or `paid`, or `None` if there's none.
+    fn tier<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, StoreError>> {
+        let _ = key;
+        Box::pin(async { Err(StoreError::Unsupported("keep tiers")) })
+    }
+
+    /// Stores `tier` at `key`, or with `None` removes it.
+    fn set_tier<'a>(
+        &'a self,
+        key: &'a str,
+        tier: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let _ = (key, tier);
+        Box::pin(async { Err(StoreError::Unsupported("keep tiers")) })
+    }
+
+    /// The config stored at `key` to use for an identity instead of the
+    /// default one, or `None` if there's none. See
+    /// [`AppState::set_override`](crate::AppState::set_override).
+    fn config_override<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<BucketConfig>, StoreError>> {
+        let _ = key;
+        Box::pin(async { Err(StoreError::Unsupported("keep overrides")) })
+    }
+
+    /// Stores `config` at `key`, or with `None` removes it.
+    fn set_config_override<'a>(
+        &'a self,
+        key: &'a str,
+        config: Option<&'a BucketConfig>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let _ = (key, config);
+        Box::pin(async { Err(StoreError::Unsupported("keep overrides")) })
+    }
+
+    /// Puts `cost` tokens back into every bucket in `buckets` as of `now`,
+    /// for a request that was charged but shouldn't have been. A bucket never
+    /// ends up more than full, however much it refilled in between, and one
+    /// that's no longer stored is left that way, as it's full already.
+    fn refund<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let _ = (buckets, cost, now);
+        Box::pin(async { Err(StoreError::Unsupported("refund charges")) })
+    }
+
+    /// The decision a one-token charge to `key` would get as of `now`, without
+    /// taking the token or writing anything back, not even the refill.
+    /// `remaining` counts the tokens in the bucket before that charge.
+    fn peek<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>>;
+
+    /// The bucket at `key` as stored, or `None` if there is none, which is
+    /// the same as a full one.
+    fn status<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Option<BucketStatus>, StoreError>>;
+
+    /// Deletes the bucket at `key`, so it starts over full. Returns whether
+    /// there was one.
+    fn reset<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>>;
+
+    /// Stores a bucket at `key` holding `tokens` as of `now`, replacing
+    /// whatever was there.
+    fn set_tokens<'a>(
+        &'a self,
+        key: &'a str,
+        tokens: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    /// Whether the bucket key `key` is in the store's blocklist, which all
+    /// instances sharing the store see.
+    fn is_blocked<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>>;
+
+    fn set_blocked<'a>(
+        &'a self,
+        key: &'a str,
+        blocked: bool,
+    ) -> BoxFuture<'a, Result<(), StoreError>>;
+
+    /// The `n` bucket keys charged most in the current window of the store's
+    /// [`Leaderboard`], most first, with how many charges each had. The
+    /// Redis stores in this crate only keep one once given it.
+    fn top_consumers(
+        &self,
+        n: usize,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<Vec<(String, u64)>, StoreError>> {
+        let _ = (n, now);
+        Box::pin(async { Err(StoreError::Unsupported("rank consumers")) })
+    }
+
+    /// How many transactions have been retried after losing to a concurrent
+    /// write so far, for stores that can lose at all.
+    fn conflicts(&self) -> u64 {
+        0
+    }
+
+    /// Whether the store can be reached, e.g. for readiness checks. Left as
+    /// it is, it always can, as stores kept in process can.
+    fn ping(&self) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// What [`BucketStore::take_tokens_tiered`] charged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tiered {
+    /// The tier read, whether or not it was known.
+    pub tier: Option<String>,
+    pub decisions: Vec<RateLimitDecision>,
+}
+
+/// `buckets` with the first one's config swapped for `tier`'s, if `tiers` has
+/// it.
+pub(crate) fn tiered<'a>(
+    buckets: &[(&'a str, &'a BucketConfig)],
+    tier: Option<&str>,
+    tiers: &'a HashMap<String, BucketConfig>,
+) -> Vec<(&'a str, &'a BucketConfig)> {
+    let mut buckets = buckets.to_vec();
+    if let (Some(first), Some(config)) = (buckets.first_mut(), tier.and_then(|t| tiers.get(t))) {
+        first.1 = config;
+    }
+    buckets
+}
+
+/// How the Redis stores lay out a bucket under its key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageFormat {
+    /// The bucket serialized as a JSON string, or as MessagePack with the
+    /// `msgpack` feature.
+    #[default]
+    Json,
+    /// A hash with `tokens` and `last_updated` (epoch milliseconds) fields,
+    /// which Redis tooling can read and update in place. Buckets still stored
+    /// as JSON are read as such and rewritten as hashes on their next charge,
+    /// so switching over doesn't need a flush.
+    Hash,
+}
+
+/// The store couldn't be asked. Never a sign of the client being over its
+/// limit. Which variants there are depends on the features, so it's
+/// non-exhaustive.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StoreError {
+    #[cfg(feature = "redis")]
+    Redis(::redis::RedisError),
+    /// Every attempt at the transaction lost to a concurrent write.
+    Contended {
+        attempts: u32,
+    },
+    /// The circuit breaker is open, so the store wasn't asked.
+    CircuitOpen,
+    /// The store was given up on after this long without an answer.
+    Timeout(Duration),
+    /// The store can't do what it was asked, such as refund charges.
+    Unsupported(&'static str),
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "redis")]
+            Self::Redis(e) => write!(f, "redis error: {e}"),
+            Self::Contended { attempts } => {
+                write!(f, "bucket still contended after {attempts} attempts")
+            }
+            Self::CircuitOpen => f.write_str("circuit breaker is open"),
+            Self::Timeout(timeout) => {
+                write!(f, "no answer from the store in {}ms", timeout.as_millis())
+            }
+            Self::Unsupported(what) => write!(f, "bucket store can't {what}"),
+            Self::Other(e) => write!(f, "bucket store error: {e}"),
+        }
+    }
+}
+
+impl Error for StoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(feature = "redis")]
+            Self::Redis(e) => Some(e),
+            Self::Contended { .. }
+            | Self::CircuitOpen
+            | Self::Timeout(_)
+            | Self::Unsupported(_) => None,
+            Self::Other(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+impl From<::redis::RedisError> for StoreError {
+    fn from(e: ::redis::RedisError) -> Self {
+        Self::Redis(e)
+    }
+}
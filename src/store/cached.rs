@@ -0,0 +1,688 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+
+use super::{BucketStore, StoreError, Tiered};
+use crate::{BoxFuture, BucketConfig, BucketStatus, RateLimitDecision, TokenPersistence};
+
+/// Tokens a bucket may be charged locally between syncs, unless set with
+/// [`CachedStore::with_max_drift`].
+pub const DEFAULT_MAX_DRIFT: i64 = 10;
+
+/// How long a cached bucket is charged locally before it's synced again,
+/// unless set with [`CachedStore::with_flush_interval`].
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Cached {
+    /// The bucket as the store last had it, less what was charged here since.
+    bucket: TokenPersistence,
+    config: BucketConfig,
+    /// Tokens charged here that the store hasn't been charged yet.
+    pending: i64,
+    synced_at: DateTime<Utc>,
+    /// When it was last charged, in charges, for evicting the least recently
+    /// used one.
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    buckets: HashMap<String, Cached>,
+    charges: u64,
+}
+
+/// What a bucket owes the store: its key, config and the tokens charged
+/// locally.
+type Debt = (String, BucketConfig, i64);
+
+/// Keeps the buckets charged most recently in the process, in front of
+/// `inner`, for keys charged so often that a round trip to Redis for every
+/// request is what limits throughput.
+///
+/// A bucket read from the store is charged locally, up to `max_drift` tokens
+/// or for `flush_interval`, whichever runs out first. The next charge after
+/// that pays what was taken to the store and reads the bucket again. So does
+/// any charge the cached bucket would deny, so nobody is turned away on what
+/// may be a stale view of it.
+///
+/// The price is overshoot. Instances don't see each other's local charges, so
+/// with `n` of them sharing a store a bucket lets through up to
+/// `n * max_drift` tokens more than it holds. Debts are paid as far as the
+/// bucket has tokens for them, and the rest is written off. A `max_drift` of
+/// 0 charges every request to the store, as if there were no cache.
+///
+/// At most `capacity` buckets are kept, and the least recently charged one is
+/// evicted, paying what it owes, to make room. Buckets under a
+/// [`PenaltyConfig`](crate::PenaltyConfig) are never cached, since paying a
+/// debt the bucket can't cover would count as a violation. Idempotent and
+/// tiered charges, refunds and reads pay what the buckets owe and go straight
+/// to `inner`. Call [`flush`](Self::flush) before shutting down, so nothing
+/// charged here is lost.
+pub struct CachedStore<S> {
+    inner: S,
+    capacity: usize,
+    max_drift: i64,
+    flush_interval: chrono::Duration,
+    entries: Mutex<Entries>,
+}
+
+impl<S: BucketStore> CachedStore<S> {
+    /// Panics if `capacity` is 0.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        assert!(capacity > 0, "cache needs room for a bucket");
+        Self {
+            inner,
+            capacity,
+            max_drift: DEFAULT_MAX_DRIFT,
+            flush_interval: chrono::Duration::from_std(DEFAULT_FLUSH_INTERVAL)
+                .expect("flush interval is in range"),
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Lets a bucket be charged up to `max_drift` tokens locally before
+    /// they're charged to the store.
+    pub fn with_max_drift(mut self, max_drift: i64) -> Self {
+        self.max_drift = max_drift.max(0);
+        self
+    }
+
+    /// Syncs a bucket with the store at least every `interval`, however few
+    /// tokens it was charged.
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = chrono::Duration::from_std(interval).unwrap_or(chrono::Duration::MAX);
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Charges the store whatever every cached bucket owes it as of `now`,
+    /// keeping the buckets cached. Call it on an interval to bound how stale
+    /// the store can be, and before shutting down.
+    pub async fn flush(&self, now: DateTime<Utc>) -> Result<(), StoreError> {
+        let debts = self
+            .lock()
+            .buckets
+            .iter_mut()
+            .filter(|(_, cached)| cached.pending > 0)
+            .map(|(key, cached)| {
+                let debt = (key.clone(), cached.config.clone(), cached.pending);
+                cached.pending = 0;
+                debt
+            })
+            .collect();
+        self.pay(debts, now).await
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The decisions for charging `cost` to `buckets` locally, if they're
+    /// all cached, fresh, within their drift, and allow it.
+    fn charge_locally(
+        &self,
+        buckets: &[(&str, &BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> Option<Vec<RateLimitDecision>> {
+        let mut entries = self.lock();
+        let cached = buckets
+            .iter()
+            .map(|(key, config)| {
+                entries.buckets.get(*key).filter(|cached| {
+                    cached.config == **config
+                        && cached.pending.saturating_add(cost) <= self.max_drift
+                        && now < cached.synced_at + self.flush_interval
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let charged = TokenPersistence::charge_all(
+            cached
+                .iter()
+                .zip(buckets)
+                .map(|(cached, (_, config))| (&cached.bucket, *config)),
+            cost,
+            now,
+        );
+        if !charged.iter().all(|(decision, _)| decision.allowed) {
+            return None;
+        }
+
+        entries.charges += 1;
+        let used = entries.charges;
+        let mut decisions = Vec::with_capacity(charged.len());
+        for ((key, _), (decision, updated)) in buckets.iter().zip(charged) {
+            if let Some(cached) = entries.buckets.get_mut(*key) {
+                if let Some(updated) = updated {
+                    cached.bucket = updated;
+                }
+                cached.pending += cost;
+                cached.used = used;
+            }
+            decisions.push(decision);
+        }
+        Some(decisions)
+    }
+
+    /// Takes `keys` out of the cache and pays what they owe, so `inner` has
+    /// every charge to them before it's asked about them.
+    async fn settle<'k>(
+        &self,
+        keys: impl IntoIterator<Item = &'k str>,
+        now: DateTime<Utc>,
+    ) -> Result<(), StoreError> {
+        let debts = {
+            let mut entries = self.lock();
+            keys.into_iter()
+                .filter_map(|key| entries.buckets.remove_entry(key))
+                .filter(|(_, cached)| cached.pending > 0)
+                .map(|(key, cached)| (key, cached.config, cached.pending))
+                .collect()
+        };
+        self.pay(debts, now).await
+    }
+
+    /// Charges `inner` each debt, or as much of it as the bucket still has if
+    /// it can't cover it all. What it can't is overshoot, written off.
+    async fn pay(&self, debts: Vec<Debt>, now: DateTime<Utc>) -> Result<(), StoreError> {
+        for (key, config, pending) in debts {
+            let decision = self.inner.take_token(&key, pending, &config, now).await?;
+            if !decision.allowed && decision.remaining > 0 {
+                self.inner
+                    .take_token(&key, decision.remaining, &config, now)
+                    .await?;
+            }
+            if !decision.allowed {
+                tracing::debug!(key, pending, "bucket overshot across instances");
+            }
+        }
+        Ok(())
+    }
+
+    /// Caches `buckets` as `inner` just left them, evicting the least
+    /// recently charged ones to make room.
+    async fn cache(
+        &self,
+        buckets: &[(&str, &BucketConfig)],
+        decisions: &[RateLimitDecision],
+        now: DateTime<Utc>,
+    ) {
+        if self.max_drift == 0 {
+            return;
+        }
+        let evicted = {
+            let mut entries = self.lock();
+            entries.charges += 1;
+            let used = entries.charges;
+            let mut evicted = Vec::new();
+            for ((key, config), decision) in buckets.iter().zip(decisions) {
+                if config.penalty.is_some() {
+                    continue;
+                }
+                // Charged here by another request while this one was at the
+                // store.
+                let pending = entries.buckets.get(*key).map_or(0, |cached| cached.pending);
+                if pending == 0 && entries.buckets.len() >= self.capacity {
+                    evicted.extend(evict(&mut entries.buckets));
+                }
+                let cached = Cached {
+                    bucket: TokenPersistence::holding(config, decision.remaining - pending, now),
+                    config: (*config).clone(),
+                    pending,
+                    synced_at: now,
+                    used,
+                };
+                entries.buckets.insert(key.to_string(), cached);
+            }
+            evicted
+        };
+        // The charge went through whether or not this does.
+        if let Err(e) = self.pay(evicted, now).await {
+            tracing::warn!(error = %e, "couldn't pay what evicted buckets owed");
+        }
+    }
+}
+
+/// Removes the least recently charged bucket, returning its debt if it has
+/// one.
+fn evict(buckets: &mut HashMap<String, Cached>) -> Option<Debt> {
+    let key = buckets
+        .iter()
+        .min_by_key(|(_, cached)| cached.used)
+        .map(|(key, _)| key.clone())?;
+    let cached = buckets.remove(&key)?;
+    (cached.pending > 0).then_some((key, cached.config, cached.pending))
+}
+
+impl<S: BucketStore> BucketStore for CachedStore<S> {
+    fn take_token<'a>(
+        &'a self,
+        key: &'a str,
+        cost: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+        Box::pin(async move {
+            let mut decisions = self.take_tokens(&[(key, config)], cost, now).await?;
+            Ok(decisions.remove(0))
+        })
+    }
+
+    fn take_tokens<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<RateLimitDecision>, StoreError>> {
+        Box::pin(async move {
+            if let Some(decisions) = self.charge_locally(buckets, cost, now) {
+                return Ok(decisions);
+            }
+            self.settle(buckets.iter().map(|(key, _)| *key), now)
+                .await?;
+            let decisions = self.inner.take_tokens(buckets, cost, now).await?;
+            self.cache(buckets, &decisions, now).await;
+            Ok(decisions)
+        })
+    }
+
+    fn take_tokens_once<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+        receipt: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<Option<Vec<RateLimitDecision>>, StoreError>> {
+        Box::pin(async move {
+            self.settle(buckets.iter().map(|(key, _)| *key), now)
+                .await?;
+            self.inner
+                .take_tokens_once(buckets, cost, now, receipt, ttl)
+                .await
+        })
+    }
+
+    fn take_tokens_tiered<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        tier_key: &'a str,
+        tiers: &'a HashMap<String, BucketConfig>,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Tiered, StoreError>> {
+        Box::pin(async move {
+            self.settle(buckets.iter().map(|(key, _)| *key), now)
+                .await?;
+            self.inner
+                .take_tokens_tiered(buckets, tier_key, tiers, cost, now)
+                .await
+        })
+    }
+
+    fn tier<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, StoreError>> {
+        self.inner.tier(key)
+    }
+
+    fn set_tier<'a>(
+        &'a self,
+        key: &'a str,
+        tier: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.set_tier(key, tier)
+    }
+
+    fn config_override<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<BucketConfig>, StoreError>> {
+        self.inner.config_override(key)
+    }
+
+    fn set_config_override<'a>(
+        &'a self,
+        key: &'a str,
+        config: Option<&'a BucketConfig>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.set_config_override(key, config)
+    }
+
+    fn refund<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            self.settle(buckets.iter().map(|(key, _)| *key), now)
+                .await?;
+            self.inner.refund(buckets, cost, now).await
+        })
+    }
+
+    fn peek<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+        Box::pin(async move {
+            self.settle([key], now).await?;
+            self.inner.peek(key, config, now).await
+        })
+    }
+
+    fn status<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Option<BucketStatus>, StoreError>> {
+        Box::pin(async move {
+            self.settle([key], now).await?;
+            self.inner.status(key, config, now).await
+        })
+    }
+
+    /// Drops the cached bucket, with whatever it owed, along with the stored
+    /// one.
+    fn reset<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+        self.lock().buckets.remove(key);
+        self.inner.reset(key)
+    }
+
+    fn set_tokens<'a>(
+        &'a self,
+        key: &'a str,
+        tokens: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.lock().buckets.remove(key);
+        self.inner.set_tokens(key, tokens, config, now)
+    }
+
+    fn is_blocked<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+        self.inner.is_blocked(key)
+    }
+
+    fn set_blocked<'a>(
+        &'a self,
+        key: &'a str,
+        blocked: bool,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.inner.set_blocked(key, blocked)
+    }
+
+    fn top_consumers(
+        &self,
+        n: usize,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<Vec<(String, u64)>, StoreError>> {
+        self.inner.top_consumers(n, now)
+    }
+
+    fn conflicts(&self) -> u64 {
+        self.inner.conflicts()
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), StoreError>> {
+        self.inner.ping()
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use chrono::{DateTime, Utc};
+
+    use super::CachedStore;
+    use crate::{
+        BoxFuture, BucketConfig, BucketStatus, BucketStore, MemoryStore, RateLimitDecision,
+        StoreError,
+    };
+
+    /// One [`MemoryStore`] shared by several caches, standing in for the
+    /// Redis that instances share, counting the charges that reach it.
+    #[derive(Clone, Default)]
+    struct Shared {
+        store: Arc<MemoryStore>,
+        charges: Arc<AtomicUsize>,
+    }
+
+    impl BucketStore for Shared {
+        fn take_token<'a>(
+            &'a self,
+            key: &'a str,
+            cost: i64,
+            config: &'a BucketConfig,
+            now: DateTime<Utc>,
+        ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+            self.charges.fetch_add(1, Ordering::SeqCst);
+            self.store.take_token(key, cost, config, now)
+        }
+
+        fn take_tokens<'a>(
+            &'a self,
+            buckets: &'a [(&'a str, &'a BucketConfig)],
+            cost: i64,
+            now: DateTime<Utc>,
+        ) -> BoxFuture<'a, Result<Vec<RateLimitDecision>, StoreError>> {
+            self.charges.fetch_add(1, Ordering::SeqCst);
+            self.store.take_tokens(buckets, cost, now)
+        }
+
+        fn peek<'a>(
+            &'a self,
+            key: &'a str,
+            config: &'a BucketConfig,
+            now: DateTime<Utc>,
+        ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+            self.store.peek(key, config, now)
+        }
+
+        fn status<'a>(
+            &'a self,
+            key: &'a str,
+            config: &'a BucketConfig,
+            now: DateTime<Utc>,
+        ) -> BoxFuture<'a, Result<Option<BucketStatus>, StoreError>> {
+            self.store.status(key, config, now)
+        }
+
+        fn reset<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+            self.store.reset(key)
+        }
+
+        fn set_tokens<'a>(
+            &'a self,
+            key: &'a str,
+            tokens: i64,
+            config: &'a BucketConfig,
+            now: DateTime<Utc>,
+        ) -> BoxFuture<'a, Result<(), StoreError>> {
+            self.store.set_tokens(key, tokens, config, now)
+        }
+
+        fn is_blocked<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+            self.store.is_blocked(key)
+        }
+
+        fn set_blocked<'a>(
+            &'a self,
+            key: &'a str,
+            blocked: bool,
+        ) -> BoxFuture<'a, Result<(), StoreError>> {
+            self.store.set_blocked(key, blocked)
+        }
+    }
+
+    /// 100 tokens that don't come back during a test.
+    fn config() -> BucketConfig {
+        BucketConfig {
+            max_tokens: 100,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(3600),
+            ..BucketConfig::default()
+        }
+    }
+
+    fn cached(shared: &Shared, max_drift: i64) -> CachedStore<Shared> {
+        CachedStore::new(shared.clone(), 16)
+            .with_max_drift(max_drift)
+            .with_flush_interval(Duration::from_secs(60))
+    }
+
+    async fn tokens(shared: &Shared, key: &str, now: DateTime<Utc>) -> i64 {
+        shared
+            .store
+            .peek(key, &config(), now)
+            .await
+            .unwrap()
+            .remaining
+    }
+
+    #[tokio::test]
+    async fn test_charges_locally_between_syncs() {
+        let shared = Shared::default();
+        let store = cached(&shared, 10);
+        let (config, now) = (config(), Utc::now());
+
+        for n in 1..=25 {
+            let decision = store.take_token("a", 1, &config, now).await.unwrap();
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, 100 - n);
+        }
+
+        // One read and one payment every 11 charges.
+        assert!(shared.charges.load(Ordering::SeqCst) <= 6);
+        assert!(tokens(&shared, "a", now).await > 75);
+        store.flush(now).await.unwrap();
+        assert_eq!(tokens(&shared, "a", now).await, 75);
+    }
+
+    #[tokio::test]
+    async fn test_overshoot_is_bounded_by_max_drift_per_instance() {
+        for max_drift in [0, 1, 5, 10, 30] {
+            let shared = Shared::default();
+            let instances = [cached(&shared, max_drift), cached(&shared, max_drift)];
+            let (config, now) = (config(), Utc::now());
+
+            let mut allowed = 0;
+            let mut denied_in_a_row = 0;
+            for n in 0.. {
+                let instance = &instances[n % 2];
+                if instance
+                    .take_token("a", 1, &config, now)
+                    .await
+                    .unwrap()
+                    .allowed
+                {
+                    allowed += 1;
+                    denied_in_a_row = 0;
+                } else {
+                    denied_in_a_row += 1;
+                }
+                if denied_in_a_row == 4 {
+                    break;
+                }
+            }
+
+            assert!(allowed >= 100, "{max_drift}: only {allowed} allowed");
+            assert!(
+                allowed <= 100 + 2 * max_drift,
+                "{max_drift}: {allowed} allowed"
+            );
+            for instance in &instances {
+                instance.flush(now).await.unwrap();
+            }
+            assert_eq!(tokens(&shared, "a", now).await, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_denials_are_verified_with_the_store() {
+        let shared = Shared::default();
+        let store = cached(&shared, 10);
+        let (config, now) = (config(), Utc::now());
+        shared.store.set_tokens("a", 1, &config, now).await.unwrap();
+        assert!(
+            store
+                .take_token("a", 1, &config, now)
+                .await
+                .unwrap()
+                .allowed
+        );
+
+        // Another instance refunds it, say, behind this one's back.
+        shared
+            .store
+            .set_tokens("a", 50, &config, now)
+            .await
+            .unwrap();
+
+        let decision = store.take_token("a", 1, &config, now).await.unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 49);
+    }
+
+    #[tokio::test]
+    async fn test_stale_buckets_are_synced() {
+        let shared = Shared::default();
+        let store = cached(&shared, 10).with_flush_interval(Duration::from_millis(100));
+        let (config, now) = (config(), Utc::now());
+        store.take_token("a", 1, &config, now).await.unwrap();
+        store.take_token("a", 1, &config, now).await.unwrap();
+        assert_eq!(tokens(&shared, "a", now).await, 99);
+
+        let later = now + chrono::Duration::milliseconds(100);
+        store.take_token("a", 1, &config, later).await.unwrap();
+
+        assert_eq!(tokens(&shared, "a", later).await, 97);
+    }
+
+    #[tokio::test]
+    async fn test_evicted_buckets_pay_what_they_owe() {
+        let shared = Shared::default();
+        let store = CachedStore::new(shared.clone(), 1).with_max_drift(10);
+        let (config, now) = (config(), Utc::now());
+        for _ in 0..3 {
+            store.take_token("a", 1, &config, now).await.unwrap();
+        }
+        assert_eq!(tokens(&shared, "a", now).await, 99);
+
+        store.take_token("b", 1, &config, now).await.unwrap();
+
+        assert_eq!(tokens(&shared, "a", now).await, 97);
+        assert_eq!(tokens(&shared, "b", now).await, 99);
+    }
+
+    #[tokio::test]
+    async fn test_reads_see_local_charges() {
+        let shared = Shared::default();
+        let store = cached(&shared, 10);
+        let (config, now) = (config(), Utc::now());
+        for _ in 0..5 {
+            store.take_token("a", 1, &config, now).await.unwrap();
+        }
+
+        let status = store.status("a", &config, now).await.unwrap().unwrap();
+
+        assert_eq!(status.tokens, 95);
+        assert_eq!(store.peek("a", &config, now).await.unwrap().remaining, 95);
+    }
+}
@@ -0,0 +1,536 @@
+use std::{
+    sync::{
+        Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+use crate::{
+    Blocklist, BoxFuture, BucketConfig, BucketStatus, RateLimitDecision, TokenPersistence,
+};
+
+use super::{BucketStore, StoreError};
+
+/// Charges between sweeps for expired buckets.
+const SWEEP_EVERY: u64 = 1024;
+
+/// Locks charges are serialized by, each covering the keys hashing to it.
+const STRIPES: usize = 64;
+
+struct Entry {
+    bucket: TokenPersistence,
+    expires_at: Instant,
+}
+
+/// Buckets kept inside the process, for single-instance services and tests.
+///
+/// Charges lock the keys they touch, and only charges to keys that happen to
+/// share a lock contend. Every charge pushes a bucket's expiry out to [`BucketConfig::full_refill`]
+/// from now; by then it would be full again anyway, so dropping it changes
+/// nothing for the client. Expired buckets are swept every so many charges.
+pub struct MemoryStore {
+    buckets: DashMap<String, Entry>,
+    /// Held around reading a bucket and writing it back. The map only ever
+    /// has one entry locked at a time, so a charge spanning several buckets
+    /// can't deadlock on keys sharing a shard.
+    stripes: Box<[Mutex<()>]>,
+    /// When each receipt from [`BucketStore::take_tokens_once`] expires.
+    receipts: DashMap<String, Instant>,
+    tiers: DashMap<String, String>,
+    overrides: DashMap<String, BucketConfig>,
+    charges: AtomicU64,
+    blocklist: Blocklist,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            buckets: DashMap::new(),
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+            receipts: DashMap::new(),
+            tiers: DashMap::new(),
+            overrides: DashMap::new(),
+            charges: AtomicU64::new(0),
+            blocklist: Blocklist::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Drops expired buckets right away instead of waiting for the next
+    /// sweep.
+    pub fn evict_expired(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|_, entry| entry.expires_at > now);
+        self.receipts.retain(|_, expires_at| *expires_at > now);
+    }
+
+    /// Locks the stripes of `keys`, always in the same order.
+    fn lock<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes = keys
+            .into_iter()
+            .map(|key| self.buckets.hash_usize(&key) % STRIPES)
+            .collect::<Vec<_>>();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|stripe| {
+                self.stripes[stripe]
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+            })
+            .collect()
+    }
+
+    /// The bucket at `key` as of `instant`, or a full one if it has expired.
+    fn current(
+        &self,
+        key: &str,
+        config: &BucketConfig,
+        now: DateTime<Utc>,
+        instant: Instant,
+    ) -> TokenPersistence {
+        match self.buckets.get(key) {
+            Some(entry) if entry.expires_at > instant => entry.bucket.clone(),
+            _ => TokenPersistence::new(config, now),
+        }
+    }
+
+    /// Charges the buckets unless `receipt` is still kept, in which case it
+    /// returns `None`, and keeps it if they're charged.
+    fn charge(
+        &self,
+        buckets: &[(&str, &BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+        receipt: Option<(&str, Duration)>,
+    ) -> Option<Vec<RateLimitDecision>> {
+        let instant = Instant::now();
+        let receipt_key = receipt.map(|(key, _)| key);
+        let _locked = self.lock(buckets.iter().map(|(key, _)| *key).chain(receipt_key));
+        let kept = receipt_key
+            .and_then(|key| self.receipts.get(key))
+            .is_some_and(|expires_at| *expires_at > instant);
+        if kept {
+            return None;
+        }
+
+        let stored = buckets
+            .iter()
+            .map(|(key, config)| self.current(key, config, now, instant))
+            .collect::<Vec<_>>();
+        let configs = buckets.iter().map(|(_, config)| *config);
+        let charged = TokenPersistence::charge_all(stored.iter().zip(configs), cost, now);
+        let allowed = charged.iter().all(|(decision, _)| decision.allowed);
+
+        let mut decisions = Vec::with_capacity(buckets.len());
+        for (((key, config), bucket), (decision, updated)) in
+            buckets.iter().zip(stored).zip(charged)
+        {
+            let bucket = updated.unwrap_or(bucket);
+            let expires_in = bucket.expires_in(config, now);
+            let expires_at = instant + config.full_refill().max(expires_in);
+            self.buckets
+                .insert(key.to_string(), Entry { bucket, expires_at });
+            decisions.push(decision);
+        }
+        if let Some((key, ttl)) = receipt.filter(|_| allowed) {
+            self.receipts.insert(key.to_string(), instant + ttl);
+        }
+        Some(decisions)
+    }
+
+    /// Sweeps for expired buckets every so many charges.
+    fn count_charge(&self) {
+        if self.charges.fetch_add(1, Ordering::Relaxed) % SWEEP_EVERY == SWEEP_EVERY - 1 {
+            self.evict_expired();
+        }
+    }
+
+    #[cfg(all(test, feature = "axum", feature = "redis"))]
+    pub(crate) fn insert(&self, key: &str, bucket: TokenPersistence) {
+        let expires_at = Instant::now() + BucketConfig::default().full_refill();
+        self.buckets
+            .insert(key.to_string(), Entry { bucket, expires_at });
+    }
+}
+
+impl BucketStore for MemoryStore {
+    fn take_token<'a>(
+        &'a self,
+        key: &'a str,
+        cost: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+        Box::pin(async move {
+            let mut decisions = self.take_tokens(&[(key, config)], cost, now).await?;
+            Ok(decisions.remove(0))
+        })
+    }
+
+    fn take_tokens<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<RateLimitDecision>, StoreError>> {
+        Box::pin(async move {
+            // Without a receipt, there's always a charge.
+            let decisions = self.charge(buckets, cost, now, None).unwrap_or_default();
+            self.count_charge();
+            Ok(decisions)
+        })
+    }
+
+    fn take_tokens_once<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+        receipt: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<Option<Vec<RateLimitDecision>>, StoreError>> {
+        Box::pin(async move {
+            let decisions = self.charge(buckets, cost, now, Some((receipt, ttl)));
+            self.count_charge();
+            Ok(decisions)
+        })
+    }
+
+    fn refund<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let instant = Instant::now();
+            let _locked = self.lock(buckets.iter().map(|(key, _)| *key));
+            for (key, config) in buckets {
+                // Only ever fuller, so it can expire when it would have.
+                let stored = self.buckets.get_mut(*key);
+                if let Some(mut entry) = stored.filter(|entry| entry.expires_at > instant) {
+                    entry.bucket = entry.bucket.refund(config, cost, now);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn peek<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+        Box::pin(async move {
+            let decision = match self.buckets.get(key) {
+                Some(entry) if entry.expires_at > Instant::now() => entry.bucket.peek(config, now),
+                _ => TokenPersistence::new(config, now).peek(config, now),
+            };
+            Ok(decision)
+        })
+    }
+
+    fn status<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Option<BucketStatus>, StoreError>> {
+        Box::pin(async move {
+            let status = self
+                .buckets
+                .get(key)
+                .filter(|entry| entry.expires_at > Instant::now())
+                .map(|entry| entry.bucket.status(config, now));
+            Ok(status)
+        })
+    }
+
+    fn reset<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+        Box::pin(async move {
+            let _locked = self.lock([key]);
+            let removed = self
+                .buckets
+                .remove(key)
+                .is_some_and(|(_, entry)| entry.expires_at > Instant::now());
+            Ok(removed)
+        })
+    }
+
+    fn set_tokens<'a>(
+        &'a self,
+        key: &'a str,
+        tokens: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let bucket = TokenPersistence::holding(config, tokens, now);
+            let expires_at = Instant::now() + config.full_refill();
+            let _locked = self.lock([key]);
+            self.buckets
+                .insert(key.to_string(), Entry { bucket, expires_at });
+            Ok(())
+        })
+    }
+
+    fn tier<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, StoreError>> {
+        Box::pin(async move { Ok(self.tiers.get(key).map(|tier| tier.clone())) })
+    }
+
+    fn set_tier<'a>(
+        &'a self,
+        key: &'a str,
+        tier: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            match tier {
+                Some(tier) => self.tiers.insert(key.to_string(), tier.to_string()),
+                None => self.tiers.remove(key).map(|(_, tier)| tier),
+            };
+            Ok(())
+        })
+    }
+
+    fn config_override<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<BucketConfig>, StoreError>> {
+        Box::pin(async move { Ok(self.overrides.get(key).map(|config| config.clone())) })
+    }
+
+    fn set_config_override<'a>(
+        &'a self,
+        key: &'a str,
+        config: Option<&'a BucketConfig>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            match config {
+                Some(config) => self.overrides.insert(key.to_string(), config.clone()),
+                None => self.overrides.remove(key).map(|(_, config)| config),
+            };
+            Ok(())
+        })
+    }
+
+    fn is_blocked<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+        Box::pin(async move { Ok(self.blocklist.contains_key(key)) })
+    }
+
+    fn set_blocked<'a>(
+        &'a self,
+        key: &'a str,
+        blocked: bool,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            if blocked {
+                self.blocklist.insert_key(key.to_string());
+            } else {
+                self.blocklist.remove_key(key);
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use chrono::Utc;
+
+    use crate::{BucketConfig, BucketStore};
+
+    use super::MemoryStore;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_charges_on_one_key_are_not_lost() {
+        let store = Arc::new(MemoryStore::new());
+        let config = BucketConfig {
+            max_tokens: 100,
+            ..BucketConfig::default()
+        };
+
+        let tasks = (0..250)
+            .map(|_| {
+                let store = Arc::clone(&store);
+                let config = config.clone();
+                tokio::spawn(async move {
+                    store
+                        .take_token("hot", 1, &config, Utc::now())
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut allowed = 0;
+        for task in tasks {
+            if task.await.unwrap().allowed {
+                allowed += 1;
+            }
+        }
+
+        assert_eq!(allowed, 100);
+        let last = store
+            .take_token("hot", 1, &config, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(last.remaining, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_buckets_expire_once_fully_refilled() {
+        let store = MemoryStore::new();
+        let config = BucketConfig {
+            max_tokens: 4,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(60),
+            ..BucketConfig::default()
+        };
+        store
+            .take_token("idle", 4, &config, Utc::now())
+            .await
+            .unwrap();
+
+        tokio::time::advance(Duration::from_secs(3 * 60)).await;
+        store
+            .take_token("busy", 4, &config, Utc::now())
+            .await
+            .unwrap();
+        store.evict_expired();
+        assert_eq!(store.len(), 2);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        store.evict_expired();
+        assert_eq!(store.len(), 1);
+        assert!(store.buckets.contains_key("busy"));
+
+        let decision = store
+            .take_token("idle", 1, &config, Utc::now())
+            .await
+            .unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_receipts_charge_once_until_they_expire() {
+        let store = MemoryStore::new();
+        let config = BucketConfig::default();
+        let buckets = [("key", &config)];
+        let ttl = Duration::from_secs(60);
+        let charge = || store.take_tokens_once(&buckets, 3, Utc::now(), "key:idem:a", ttl);
+
+        let first = charge().await.unwrap().unwrap();
+        assert_eq!(first[0].remaining, 7);
+        assert_eq!(charge().await.unwrap(), None);
+        let other = store.take_tokens_once(&buckets, 3, Utc::now(), "key:idem:b", ttl);
+        assert_eq!(other.await.unwrap().unwrap()[0].remaining, 4);
+
+        tokio::time::advance(ttl).await;
+        store.evict_expired();
+        assert!(store.receipts.is_empty());
+        let again = charge().await.unwrap().unwrap();
+        assert_eq!(again[0].remaining, 1);
+    }
+
+    #[tokio::test]
+    async fn test_denied_charge_leaves_no_receipt() {
+        let store = MemoryStore::new();
+        let config = BucketConfig::default();
+        let buckets = [("key", &config)];
+        let ttl = Duration::from_secs(60);
+
+        let denied = store.take_tokens_once(&buckets, 11, Utc::now(), "key:idem:a", ttl);
+        assert!(!denied.await.unwrap().unwrap()[0].allowed);
+        let allowed = store.take_tokens_once(&buckets, 1, Utc::now(), "key:idem:a", ttl);
+        assert!(allowed.await.unwrap().unwrap()[0].allowed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_charging_refreshes_the_expiry() {
+        let store = MemoryStore::new();
+        let config = BucketConfig {
+            max_tokens: 2,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(60),
+            ..BucketConfig::default()
+        };
+        store
+            .take_token("key", 1, &config, Utc::now())
+            .await
+            .unwrap();
+
+        tokio::time::advance(Duration::from_secs(90)).await;
+        store
+            .take_token("key", 1, &config, Utc::now())
+            .await
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(90)).await;
+        store.evict_expired();
+
+        assert_eq!(store.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_bucket_is_fresh_before_the_sweep() {
+        let store = MemoryStore::new();
+        let config = BucketConfig {
+            max_tokens: 2,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(60),
+            ..BucketConfig::default()
+        };
+        store
+            .take_token("key", 2, &config, Utc::now())
+            .await
+            .unwrap();
+
+        tokio::time::advance(Duration::from_secs(2 * 60)).await;
+        let decision = store
+            .take_token("key", 2, &config, Utc::now())
+            .await
+            .unwrap();
+
+        assert!(decision.allowed);
+    }
+
+    #[tokio::test]
+    async fn test_sweep_runs_while_charging() {
+        let store = MemoryStore::new();
+        let config = BucketConfig {
+            max_tokens: 0,
+            ..BucketConfig::default()
+        };
+
+        for i in 0..super::SWEEP_EVERY {
+            store
+                .take_token(&i.to_string(), 0, &config, Utc::now())
+                .await
+                .unwrap();
+        }
+
+        assert!(store.is_empty());
+    }
+}
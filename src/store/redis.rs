@@ -0,0 +1,2937 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use redis::{
+    ConnectionLike, ErrorKind, FromRedisValue, RedisError, RedisResult, Script, ToRedisArgs, aio,
+    cluster_routing::get_slot,
+};
+
+use crate::{
+    Algorithm, BlockingReconnectingConnection, BoxFuture, BucketConfig, BucketStatus,
+    ConnectionPool, KeyPrefix, Penalty, RateLimitDecision, TokenPersistence, encoding,
+    reconnect::lost_master, timestamp::from_millis,
+};
+
+use super::{
+    BucketStore, StorageFormat, StoreError, Tiered,
+    sharded::{Group, allowed, grouped, ungrouped},
+    tiered,
+};
+
+/// How long [`RedisStore`] and [`AsyncRedisStore`] wait for Redis before
+/// giving up, unless told otherwise with `with_timeout`.
+pub const DEFAULT_REDIS_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The bucket in the reply to a `GET` of `key`, `None` if there isn't one.
+///
+/// Anything but a string or nil, such as the reply to a transaction, is an
+/// error rather than a missing bucket, which would be charged as a full one.
+fn from_get(key: &str, reply: &redis::Value) -> RedisResult<Option<TokenPersistence>> {
+    let bytes = match reply {
+        redis::Value::BulkString(bytes) => bytes,
+        redis::Value::Nil => return Ok(None),
+        reply => {
+            return Err(RedisError::from((
+                ErrorKind::TypeError,
+                "unexpected reply for bucket state",
+                format!("{reply:?}"),
+            )));
+        }
+    };
+    let bucket = encoding::decode(bytes).map_err(|e| {
+        RedisError::from((ErrorKind::TypeError, "invalid bucket state", e.to_string()))
+    });
+    Ok(readable(key, bucket).flatten())
+}
+
+impl ToRedisArgs for TokenPersistence {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + redis::RedisWrite,
+    {
+        out.write_arg(&encoding::encode(self))
+    }
+}
+
+/// Bounds on [`RedisStore`]'s optimistic transaction, which starts over
+/// whenever another request writes the bucket between its `WATCH` and `EXEC`.
+#[derive(Clone, Debug)]
+pub struct TransactionRetry {
+    /// Attempts after the first before giving up. Giving up is a store
+    /// failure, handled like Redis being down.
+    pub max_retries: u32,
+    /// Upper bound of the random wait before the first retry. It doubles with
+    /// every retry after that.
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for TransactionRetry {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            base_backoff: Duration::from_millis(2),
+            max_backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+impl TransactionRetry {
+    /// Full jitter: anywhere between nothing and the capped exponential
+    /// backoff, so contending requests spread out instead of colliding again.
+    fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .base_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Denied requests appended to a Redis stream, for a durable record of who
+/// got throttled and when. Stores keep none unless given one.
+///
+/// Each denial is an entry with the fields `key` (the hashed bucket key,
+/// without the route), `route` (empty for the default bucket), `ts` (epoch
+/// milliseconds) and `remaining`. The stream is trimmed to about `max_len`
+/// entries as it's appended to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DenialLog {
+    pub stream: String,
+    pub max_len: usize,
+}
+
+impl Default for DenialLog {
+    fn default() -> Self {
+        Self::new(&KeyPrefix::default())
+    }
+}
+
+impl DenialLog {
+    /// A log in the stream under `prefix`, see [`KeyPrefix::denials`].
+    pub fn new(prefix: &KeyPrefix) -> Self {
+        Self {
+            stream: prefix.denials(),
+            max_len: 100_000,
+        }
+    }
+
+    fn entry(&self, key: &str, decision: &RateLimitDecision, now: DateTime<Utc>) -> redis::Cmd {
+        let (bucket, route) = split_route(key);
+        let mut xadd = redis::cmd("XADD");
+        xadd.arg(&self.stream)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.max_len)
+            .arg("*")
+            .arg("key")
+            .arg(bucket)
+            .arg("route")
+            .arg(route)
+            .arg("ts")
+            .arg(now.timestamp_millis())
+            .arg("remaining")
+            .arg(decision.remaining);
+        xadd
+    }
+}
+
+/// Per-route buckets are the default one's key, then `:` and the route.
+fn split_route(key: &str) -> (&str, &str) {
+    match key.find("}:") {
+        Some(end) => (&key[..=end], &key[end + 2..]),
+        None => (key, ""),
+    }
+}
+
+/// Requests counted per identity in a Redis sorted set for each window, for
+/// finding out who's making the most of them, see
+/// [`BucketStore::top_consumers`]. Stores keep none unless given one, so the
+/// extra write is only made when it's wanted.
+///
+/// Each charge adds one to its identity's bucket key (without the route) in
+/// the set of the window it falls in, `{prefix}{start}` with `start` the
+/// window's start in epoch seconds, e.g. `bucket:leaderboard:1767225600`.
+/// Each set expires a window after its own ends, so the one before is kept
+/// for a while too.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Leaderboard {
+    pub prefix: String,
+    pub window: Duration,
+}
+
+impl Default for Leaderboard {
+    fn default() -> Self {
+        Self::new(&KeyPrefix::default(), Duration::from_secs(60 * 60))
+    }
+}
+
+impl Leaderboard {
+    /// A leaderboard of `window`s in the sets under `prefix`, see
+    /// [`KeyPrefix::leaderboard`].
+    pub fn new(prefix: &KeyPrefix, window: Duration) -> Self {
+        Self {
+            prefix: prefix.leaderboard(),
+            window,
+        }
+    }
+
+    /// The sorted set of the window `now` falls in.
+    pub fn key(&self, now: DateTime<Utc>) -> String {
+        let window = self.window_secs() as i64;
+        let start = now.timestamp().div_euclid(window) * window;
+        format!("{}{start}", self.prefix)
+    }
+
+    fn window_secs(&self) -> u64 {
+        expiry_secs(self.window)
+    }
+
+    /// Counts a charge to `key` as of `now`.
+    fn count(&self, key: &str, now: DateTime<Utc>) -> [redis::Cmd; 2] {
+        let set = self.key(now);
+        let (bucket, _) = split_route(key);
+        let mut zincrby = redis::cmd("ZINCRBY");
+        zincrby.arg(&set).arg(1).arg(bucket);
+        let mut expire = redis::cmd("EXPIRE");
+        expire.arg(&set).arg(self.window_secs() * 2);
+        [zincrby, expire]
+    }
+
+    /// The `n` bucket keys counted most as of `now`, with their counts.
+    fn top(&self, n: usize, now: DateTime<Utc>) -> redis::Cmd {
+        let mut zrevrange = redis::cmd("ZREVRANGE");
+        zrevrange
+            .arg(self.key(now))
+            .arg(0)
+            .arg(n as i64 - 1)
+            .arg("WITHSCORES");
+        zrevrange
+    }
+}
+
+/// What's written along with a charge besides the buckets.
+#[derive(Clone, Debug, Default)]
+struct Records {
+    denials: Option<DenialLog>,
+    leaderboard: Option<Leaderboard>,
+}
+
+/// The records `take_token.lua` writes along with a charge.
+#[derive(Clone, Copy, Debug, Default)]
+struct Recording<'a> {
+    denials: Option<&'a DenialLog>,
+    leaderboard: Option<&'a Leaderboard>,
+}
+
+impl Records {
+    fn all(&self) -> Recording<'_> {
+        Recording {
+            denials: self.denials.as_ref(),
+            leaderboard: self.leaderboard.as_ref(),
+        }
+    }
+}
+
+impl<'a> Recording<'a> {
+    /// The records written along with charging `buckets` as of `now`, and
+    /// the ones left to write after it: on a cluster, those that aren't in
+    /// the slot of the first bucket.
+    fn split(
+        self,
+        buckets: &[(&str, &BucketConfig)],
+        now: DateTime<Utc>,
+        cluster: bool,
+    ) -> (Recording<'a>, Recording<'a>) {
+        let first = buckets.first().map(|(key, _)| slot(key));
+        let along = |key: &str| !cluster || first == Some(slot(key));
+        let mut recording = Recording::default();
+        let mut after = Recording::default();
+        if let Some(log) = self.denials {
+            match along(&log.stream) {
+                true => recording.denials = Some(log),
+                false => after.denials = Some(log),
+            }
+        }
+        if let Some(leaderboard) = self.leaderboard {
+            match along(&leaderboard.key(now)) {
+                true => recording.leaderboard = Some(leaderboard),
+                false => after.leaderboard = Some(leaderboard),
+            }
+        }
+        (recording, after)
+    }
+}
+
+/// Buckets stored in Redis, through blocking connections.
+///
+/// Each transaction runs on tokio's blocking pool with a connection checked
+/// out of the pool, so up to `pool.len()` requests talk to Redis at once.
+pub struct RedisStore<C> {
+    pool: ConnectionPool<C>,
+    format: StorageFormat,
+    retry: TransactionRetry,
+    conflicts: Arc<AtomicU64>,
+    records: Records,
+    timeout: Duration,
+    blocklist: String,
+}
+
+impl<C> RedisStore<C> {
+    pub fn new(conn: C) -> Self {
+        Self::from_pool(ConnectionPool::new([conn]))
+    }
+
+    pub fn from_pool(pool: ConnectionPool<C>) -> Self {
+        Self {
+            pool,
+            format: StorageFormat::default(),
+            retry: TransactionRetry::default(),
+            conflicts: Arc::default(),
+            records: Records::default(),
+            timeout: DEFAULT_REDIS_TIMEOUT,
+            blocklist: KeyPrefix::default().blocklist(),
+        }
+    }
+
+    pub fn with_format(mut self, format: StorageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: TransactionRetry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Keeps the blocklist under `prefix`, as given to
+    /// [`AppState::with_key_prefix`](crate::AppState::with_key_prefix) for the
+    /// buckets. A [`DenialLog`] or [`Leaderboard`] takes its own with `new`.
+    pub fn with_key_prefix(mut self, prefix: &KeyPrefix) -> Self {
+        self.blocklist = prefix.blocklist();
+        self
+    }
+
+    /// Appends denials to `log`, in the same round trip that ends the
+    /// transaction.
+    pub fn with_denial_log(mut self, log: DenialLog) -> Self {
+        self.records.denials = Some(log);
+        self
+    }
+
+    /// Counts every charge on `leaderboard`, in the same round trip that
+    /// ends the transaction.
+    pub fn with_leaderboard(mut self, leaderboard: Leaderboard) -> Self {
+        self.records.leaderboard = Some(leaderboard);
+        self
+    }
+
+    /// Gives up on any operation, waiting for a connection and every
+    /// retry included, after `timeout` rather than [`DEFAULT_REDIS_TIMEOUT`].
+    /// Giving up is a store failure, handled like Redis being down.
+    ///
+    /// The blocking call itself goes on, with its connection out of the pool,
+    /// until the connection's own timeouts end it; see
+    /// [`connect`](RedisStore::connect).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many transactions have been aborted by a concurrent write so far.
+    pub fn conflicts(&self) -> u64 {
+        self.conflicts.load(Ordering::Relaxed)
+    }
+}
+
+impl RedisStore<BlockingReconnectingConnection<redis::Connection>> {
+    /// A store with `connections` connections to `client`, which give up on
+    /// connecting, reading and writing after `timeout`, as does the store,
+    /// and are opened again whenever they break.
+    pub fn connect(
+        client: &redis::Client,
+        connections: usize,
+        timeout: Duration,
+    ) -> RedisResult<Self> {
+        let pool = (0..connections)
+            .map(|_| BlockingReconnectingConnection::open(client.clone(), timeout))
+            .collect::<RedisResult<Vec<_>>>()?;
+        Ok(Self::from_pool(ConnectionPool::new(pool)).with_timeout(timeout))
+    }
+}
+
+impl<C> BucketStore for RedisStore<C>
+where
+    C: ConnectionLike + Send + Sync + 'static,
+{
+    fn take_token<'a>(
+        &'a self,
+        key: &'a str,
+        cost: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+        Box::pin(async move {
+            let mut decisions = self.take_tokens(&[(key, config)], cost, now).await?;
+            Ok(decisions.remove(0))
+        })
+    }
+
+    fn take_tokens<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<RateLimitDecision>, StoreError>> {
+        let format = self.format;
+        let records = self.records.clone();
+        Box::pin(async move {
+            let decisions = self
+                .transaction(buckets, move |con, buckets| {
+                    watch(con, buckets, None, None)?;
+                    charge(con, buckets, cost, format, &records, None, now)
+                })
+                .await?;
+            // Without a receipt, there's always a charge.
+            Ok(decisions.unwrap_or_default())
+        })
+    }
+
+    fn take_tokens_tiered<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        tier_key: &'a str,
+        tiers: &'a HashMap<String, BucketConfig>,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Tiered, StoreError>> {
+        let format = self.format;
+        let records = self.records.clone();
+        let tier_key = tier_key.to_string();
+        let tiers = tiers.clone();
+        Box::pin(self.transaction(buckets, move |con, buckets| {
+            let tier = watch(con, buckets, None, Some(&tier_key))?;
+            let buckets = tiered(buckets, tier.as_deref(), &tiers);
+            let charged = charge(con, &buckets, cost, format, &records, None, now)?;
+            // Without a receipt, there's always a charge.
+            Ok(charged.map(|decisions| Tiered {
+                tier,
+                decisions: decisions.unwrap_or_default(),
+            }))
+        }))
+    }
+
+    fn take_tokens_once<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+        receipt: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<Option<Vec<RateLimitDecision>>, StoreError>> {
+        let format = self.format;
+        let records = self.records.clone();
+        let receipt = receipt.to_string();
+        Box::pin(self.transaction(buckets, move |con, buckets| {
+            watch(con, buckets, Some(&receipt), None)?;
+            let receipt = Some((receipt.as_str(), ttl));
+            charge(con, buckets, cost, format, &records, receipt, now)
+        }))
+    }
+
+    fn refund<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let format = self.format;
+        Box::pin(self.transaction(buckets, move |con, buckets| {
+            refund(con, buckets, cost, format, now)
+        }))
+    }
+
+    fn peek<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+        Box::pin(async move {
+            let bucket = self
+                .stored(key)
+                .await?
+                .unwrap_or_else(|| TokenPersistence::new(config, now));
+            Ok(bucket.peek(config, now))
+        })
+    }
+
+    fn status<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Option<BucketStatus>, StoreError>> {
+        Box::pin(async move {
+            let stored = self.stored(key).await?;
+            Ok(stored.map(|bucket| bucket.status(config, now)))
+        })
+    }
+
+    fn reset<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+        let key = key.to_string();
+        Box::pin(self.blocking(move |con| redis::cmd("DEL").arg(key).query(con)))
+    }
+
+    fn set_tokens<'a>(
+        &'a self,
+        key: &'a str,
+        tokens: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let bucket = TokenPersistence::holding(config, tokens, now);
+        let pipe = write(key, &bucket, config, self.format, now);
+        Box::pin(self.blocking(move |con| pipe.exec(con)))
+    }
+
+    fn tier<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, StoreError>> {
+        let get = redis::cmd("GET").arg(key).clone();
+        Box::pin(self.blocking(move |con| get.query(con)))
+    }
+
+    fn set_tier<'a>(
+        &'a self,
+        key: &'a str,
+        tier: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let update = set_tier(key, tier);
+        Box::pin(self.blocking(move |con| update.exec(con)))
+    }
+
+    fn config_override<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<BucketConfig>, StoreError>> {
+        let get = redis::cmd("GET").arg(key).clone();
+        Box::pin(async move {
+            let json = self.blocking(move |con| get.query(con)).await?;
+            parse_override(json)
+        })
+    }
+
+    fn set_config_override<'a>(
+        &'a self,
+        key: &'a str,
+        config: Option<&'a BucketConfig>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let update = set_config_override(key, config);
+        Box::pin(self.blocking(move |con| update.exec(con)))
+    }
+
+    fn is_blocked<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+        let sismember = redis::cmd("SISMEMBER")
+            .arg(&self.blocklist)
+            .arg(key)
+            .clone();
+        Box::pin(self.blocking(move |con| sismember.query(con)))
+    }
+
+    fn set_blocked<'a>(
+        &'a self,
+        key: &'a str,
+        blocked: bool,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        let update = set_blocked(&self.blocklist, key, blocked);
+        Box::pin(self.blocking(move |con| update.exec(con)))
+    }
+
+    fn top_consumers(
+        &self,
+        n: usize,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<Vec<(String, u64)>, StoreError>> {
+        let top = self
+            .records
+            .leaderboard
+            .as_ref()
+            .map(|leaderboard| leaderboard.top(n, now));
+        Box::pin(async move {
+            match top {
+                None => Err(StoreError::Unsupported(
+                    "rank consumers without a leaderboard",
+                )),
+                Some(_) if n == 0 => Ok(Vec::new()),
+                Some(top) => self.blocking(move |con| top.query(con)).await,
+            }
+        })
+    }
+
+    fn conflicts(&self) -> u64 {
+        RedisStore::conflicts(self)
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(self.blocking(|con| redis::cmd("PING").exec(con)))
+    }
+}
+
+impl<C> RedisStore<C>
+where
+    C: ConnectionLike + Send + Sync + 'static,
+{
+    async fn stored(&self, key: &str) -> Result<Option<TokenPersistence>, StoreError> {
+        let key = key.to_string();
+        let format = self.format;
+        self.blocking(move |con| read(con, &key, format)).await
+    }
+
+    /// Runs `attempt` over `buckets` on tokio's blocking pool until it isn't
+    /// beaten to them by a concurrent write, backing off between attempts.
+    async fn transaction<T, F>(
+        &self,
+        buckets: &[(&str, &BucketConfig)],
+        attempt: F,
+    ) -> Result<T, StoreError>
+    where
+        F: Fn(&mut C, &[(&str, &BucketConfig)]) -> RedisResult<Option<T>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let buckets = buckets
+            .iter()
+            .map(|(key, config)| (key.to_string(), (*config).clone()))
+            .collect::<Vec<_>>();
+        let retry = self.retry.clone();
+        let conflicts = Arc::clone(&self.conflicts);
+
+        bounded(self.timeout, async move {
+            let mut conn = self.pool.get().await;
+            tokio::task::spawn_blocking(move || {
+                let buckets = buckets
+                    .iter()
+                    .map(|(key, config)| (key.as_str(), config))
+                    .collect::<Vec<_>>();
+                for n in 0..=retry.max_retries {
+                    if n > 0 {
+                        std::thread::sleep(retry.backoff(n - 1));
+                    }
+                    if let Some(done) = attempt(&mut *conn, &buckets)? {
+                        return Ok(done);
+                    }
+                    conflicts.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(StoreError::Contended {
+                    attempts: retry.max_retries + 1,
+                })
+            })
+            .await
+            .map_err(|e| StoreError::Other(Box::new(e)))?
+        })
+        .await
+    }
+
+    /// Runs `f` against a pooled connection on tokio's blocking pool.
+    async fn blocking<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&mut C) -> RedisResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        bounded(self.timeout, async move {
+            let mut conn = self.pool.get().await;
+            let result = tokio::task::spawn_blocking(move || f(&mut *conn))
+                .await
+                .map_err(|e| StoreError::Other(Box::new(e)))?;
+            Ok(result?)
+        })
+        .await
+    }
+}
+
+/// `operation`'s result, unless it takes longer than `timeout`.
+async fn bounded<T>(
+    timeout: Duration,
+    operation: impl Future<Output = Result<T, StoreError>>,
+) -> Result<T, StoreError> {
+    tokio::time::timeout(timeout, operation)
+        .await
+        .unwrap_or(Err(StoreError::Timeout(timeout)))
+}
+
+/// Watches every bucket, and `receipt` if there is one, for the transaction
+/// charging them. The tier at `tier_key` is read in the same round trip.
+fn watch<C: ConnectionLike>(
+    con: &mut C,
+    buckets: &[(&str, &BucketConfig)],
+    receipt: Option<&str>,
+    tier_key: Option<&str>,
+) -> RedisResult<Option<String>> {
+    let mut watch = redis::cmd("WATCH");
+    for (key, _) in buckets {
+        watch.arg(*key);
+    }
+    if let Some(key) = receipt {
+        watch.arg(key);
+    }
+    let Some(tier_key) = tier_key else {
+        watch.exec(con)?;
+        return Ok(None);
+    };
+    let (tier,) = redis::pipe()
+        .add_command(watch)
+        .ignore()
+        .cmd("GET")
+        .arg(tier_key)
+        .query(con)?;
+    Ok(tier)
+}
+
+/// One optimistic WATCH/MULTI attempt over every bucket, once they're
+/// watched. `None` means another writer got to one of them first and nothing
+/// was written. `receipt` is kept along with the charge, and the charge is
+/// `Some(None)` if it's already there.
+fn charge<C: ConnectionLike>(
+    con: &mut C,
+    buckets: &[(&str, &BucketConfig)],
+    cost: i64,
+    format: StorageFormat,
+    records: &Records,
+    receipt: Option<(&str, Duration)>,
+    now: DateTime<Utc>,
+) -> RedisResult<Option<Option<Vec<RateLimitDecision>>>> {
+    if let Some((key, _)) = receipt {
+        let kept: bool = redis::cmd("EXISTS").arg(key).query(con)?;
+        if kept {
+            redis::cmd("UNWATCH").exec(con)?;
+            return Ok(Some(None));
+        }
+    }
+
+    let mut stored = Vec::with_capacity(buckets.len());
+    for (key, config) in buckets {
+        stored.push(read(con, key, format)?.unwrap_or_else(|| TokenPersistence::new(config, now)));
+    }
+    let configs = buckets.iter().map(|(_, config)| *config);
+    let charged = TokenPersistence::charge_all(stored.iter().zip(configs), cost, now);
+
+    let mut transaction = redis::pipe();
+    transaction.atomic();
+    let mut recorded = Vec::new();
+    for ((key, config), (decision, updated)) in buckets.iter().zip(&charged) {
+        if let Some(updated) = updated {
+            write_into(&mut transaction, key, updated, config, format, now);
+        }
+        if let Some(log) = records.denials.as_ref().filter(|_| !decision.allowed) {
+            recorded.push(log.entry(key, decision, now));
+        }
+    }
+    if let Some((leaderboard, (key, _))) = records.leaderboard.as_ref().zip(buckets.first()) {
+        recorded.extend(leaderboard.count(key, now));
+    }
+    let allowed = charged.iter().all(|(decision, _)| decision.allowed);
+    let decisions = charged.into_iter().map(|(decision, _)| decision).collect();
+    if let Some((key, ttl)) = receipt.filter(|_| allowed) {
+        let ttl_ms = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX).max(1);
+        transaction
+            .cmd("SET")
+            .arg(key)
+            .arg(1)
+            .arg("PX")
+            .arg(ttl_ms)
+            .ignore();
+    }
+
+    if transaction.cmd_iter().next().is_none() {
+        if recorded.is_empty() {
+            redis::cmd("UNWATCH").exec(con)?;
+        } else {
+            let mut pipe = redis::pipe();
+            pipe.cmd("UNWATCH").ignore();
+            for record in recorded {
+                pipe.add_command(record).ignore();
+            }
+            pipe.exec(con)?;
+        }
+        return Ok(Some(Some(decisions)));
+    }
+
+    for record in recorded {
+        transaction.add_command(record).ignore();
+    }
+    let committed: Option<()> = transaction.query(con)?;
+    Ok(committed.map(|()| Some(decisions)))
+}
+
+/// One optimistic WATCH/MULTI attempt at refunding every bucket that's
+/// still stored. `None` means another writer got to one of them first.
+fn refund<C: ConnectionLike>(
+    con: &mut C,
+    buckets: &[(&str, &BucketConfig)],
+    cost: i64,
+    format: StorageFormat,
+    now: DateTime<Utc>,
+) -> RedisResult<Option<()>> {
+    let mut watch = redis::cmd("WATCH");
+    for (key, _) in buckets {
+        watch.arg(*key);
+    }
+    watch.exec(con)?;
+
+    let mut transaction = redis::pipe();
+    transaction.atomic();
+    for (key, config) in buckets {
+        if let Some(stored) = read(con, key, format)? {
+            let refunded = stored.refund(config, cost, now);
+            write_into(&mut transaction, key, &refunded, config, format, now);
+        }
+    }
+    if transaction.cmd_iter().next().is_none() {
+        redis::cmd("UNWATCH").exec(con)?;
+        return Ok(Some(()));
+    }
+    transaction.query(con)
+}
+
+fn set_tier(key: &str, tier: Option<&str>) -> redis::Cmd {
+    match tier {
+        Some(tier) => redis::cmd("SET").arg(key).arg(tier).clone(),
+        None => redis::cmd("DEL").arg(key).clone(),
+    }
+}
+
+/// Overrides are stored as JSON, for whoever sets them by hand to read and
+/// write.
+fn set_config_override(key: &str, config: Option<&BucketConfig>) -> redis::Cmd {
+    match config {
+        Some(config) => redis::cmd("SET")
+            .arg(key)
+            .arg(serde_json::to_string(config).unwrap())
+            .clone(),
+        None => redis::cmd("DEL").arg(key).clone(),
+    }
+}
+
+fn parse_override(json: Option<String>) -> Result<Option<BucketConfig>, StoreError> {
+    json.map(|json| serde_json::from_str(&json))
+        .transpose()
+        .map_err(|e| StoreError::Other(e.into()))
+}
+
+/// Adds `key` to or removes it from the set of blocked bucket keys at
+/// `blocklist`, shared by every instance using the store.
+fn set_blocked(blocklist: &str, key: &str, blocked: bool) -> redis::Cmd {
+    let mut cmd = redis::cmd(if blocked { "SADD" } else { "SREM" });
+    cmd.arg(blocklist).arg(key);
+    cmd
+}
+
+/// A transaction storing `bucket` at `key`, set to expire once it's full.
+fn write(
+    key: &str,
+    bucket: &TokenPersistence,
+    config: &BucketConfig,
+    format: StorageFormat,
+    now: DateTime<Utc>,
+) -> redis::Pipeline {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    write_into(&mut pipe, key, bucket, config, format, now);
+    pipe
+}
+
+/// Adds the commands [`write`] is made of to `pipe`.
+pub(crate) fn write_into(
+    pipe: &mut redis::Pipeline,
+    key: &str,
+    bucket: &TokenPersistence,
+    config: &BucketConfig,
+    format: StorageFormat,
+    now: DateTime<Utc>,
+) {
+    let ttl = expiry_secs(bucket.expires_in(config, now));
+    if config.algorithm == Algorithm::Gcra {
+        // Whatever the format, a GCRA bucket is just its arrival time.
+        pipe.cmd("SET")
+            .arg(key)
+            .arg(bucket.last_updated.timestamp_millis())
+            .arg("EX")
+            .arg(ttl)
+            .ignore();
+        return;
+    }
+    match format {
+        StorageFormat::Json => pipe
+            .cmd("SET")
+            .arg(key)
+            .arg(bucket)
+            .arg("EX")
+            .arg(ttl)
+            .ignore(),
+        // DEL first, in case the key still holds the bucket as JSON.
+        StorageFormat::Hash => {
+            let hset = pipe
+                .del(key)
+                .ignore()
+                .cmd("HSET")
+                .arg(key)
+                .arg("tokens")
+                .arg(bucket.tokens)
+                .arg("last_updated")
+                .arg(bucket.last_updated.timestamp_millis());
+            if let Some(penalty) = &bucket.penalty {
+                hset.arg("violations")
+                    .arg(penalty.violations)
+                    .arg("violations_since")
+                    .arg(penalty.since.timestamp_millis())
+                    .arg("bans")
+                    .arg(penalty.bans)
+                    .arg("banned_until")
+                    .arg(penalty.banned_until.timestamp_millis());
+            }
+            hset.ignore().cmd("EXPIRE").arg(key).arg(ttl).ignore()
+        }
+    };
+}
+
+/// Reads the bucket at `key`, accepting JSON left over from before a switch
+/// to [`StorageFormat::Hash`].
+fn read<C: ConnectionLike>(
+    con: &mut C,
+    key: &str,
+    format: StorageFormat,
+) -> RedisResult<Option<TokenPersistence>> {
+    if format == StorageFormat::Hash {
+        let kind: String = redis::cmd("TYPE").arg(key).query(con)?;
+        match kind.as_str() {
+            "hash" => {
+                let fields: redis::Value = redis::cmd("HGETALL").arg(key).query(con)?;
+                let bucket = HashMap::from_redis_value(&fields).and_then(|f| from_fields(&f));
+                return Ok(readable(key, bucket));
+            }
+            "string" => {}
+            _ => return Ok(None),
+        }
+    }
+
+    let stored: redis::Value = redis::cmd("GET").arg(key).query(con)?;
+    from_get(key, &stored)
+}
+
+/// A bucket that can't be parsed is started over rather than failing every
+/// request charged against it until it expires.
+fn readable<T>(key: &str, bucket: RedisResult<T>) -> Option<T> {
+    bucket
+        .inspect_err(
+            |e| tracing::warn!(bucket = key, error = %e, "bucket is unreadable, starting it over"),
+        )
+        .ok()
+}
+
+fn from_fields(fields: &HashMap<String, i64>) -> RedisResult<TokenPersistence> {
+    let field = |name: &str| {
+        fields.get(name).copied().ok_or_else(|| {
+            RedisError::from((
+                ErrorKind::TypeError,
+                "bucket hash is missing a field",
+                name.to_string(),
+            ))
+        })
+    };
+    // The penalty fields are written all together, or not at all.
+    let penalty = if fields.contains_key("banned_until") {
+        Some(Penalty {
+            violations: u32::try_from(field("violations")?).unwrap_or_default(),
+            since: from_millis(field("violations_since")?),
+            bans: u32::try_from(field("bans")?).unwrap_or_default(),
+            banned_until: from_millis(field("banned_until")?),
+        })
+    } else {
+        None
+    };
+    Ok(TokenPersistence {
+        tokens: field("tokens")?,
+        last_updated: from_millis(field("last_updated")?),
+        penalty,
+    })
+}
+
+/// Buckets stored in Redis, through async connections such as
+/// [`redis::aio::MultiplexedConnection`] or
+/// [`redis::cluster_async::ClusterConnection`].
+///
+/// Each charge is a single `EVALSHA` of a script that refills and charges the
+/// bucket server-side, so it is atomic without `WATCH` and never retries on
+/// contention. The script's only key is the bucket, so on a cluster it runs on
+/// the node that owns it. Buckets charged together, such as a client's and the
+/// global one, go to the same script. On a cluster, see
+/// [`with_cluster`](Self::with_cluster), that takes one script per slot
+/// instead, refunding the others if one denies the charge, as
+/// [`ShardedStore`](crate::ShardedStore) does across shards.
+///
+/// Denials and leaderboard counts are written by the same script, so they
+/// cost no round trip of their own and are never lost apart from the charge.
+/// On a cluster, see [`with_cluster`](Self::with_cluster), that's only
+/// possible for a stream or set in the slot of the buckets; the others are
+/// written with a call of their own after the script.
+pub struct AsyncRedisStore<C> {
+    pool: ConnectionPool<C>,
+    format: StorageFormat,
+    records: Records,
+    cluster: bool,
+    timeout: Duration,
+    blocklist: String,
+}
+
+impl<C> AsyncRedisStore<C> {
+    pub fn new(conn: C) -> Self {
+        Self::from_pool(ConnectionPool::new([conn]))
+    }
+
+    pub fn from_pool(pool: ConnectionPool<C>) -> Self {
+        Self {
+            pool,
+            format: StorageFormat::default(),
+            records: Records::default(),
+            cluster: false,
+            timeout: DEFAULT_REDIS_TIMEOUT,
+            blocklist: KeyPrefix::default().blocklist(),
+        }
+    }
+
+    pub fn with_format(mut self, format: StorageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Gives up on any operation, waiting for a connection included, after
+    /// `timeout` rather than [`DEFAULT_REDIS_TIMEOUT`]. Giving up is a store
+    /// failure, handled like Redis being down.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keeps the blocklist under `prefix`, as given to
+    /// [`AppState::with_key_prefix`](crate::AppState::with_key_prefix) for the
+    /// buckets. A [`DenialLog`] or [`Leaderboard`] takes its own with `new`.
+    pub fn with_key_prefix(mut self, prefix: &KeyPrefix) -> Self {
+        self.blocklist = prefix.blocklist();
+        self
+    }
+
+    /// Appends denials to `log`, in the script that charges the buckets.
+    /// On a cluster, an append that has to follow the script fails the
+    /// request no more than a failed count does: it's only logged.
+    pub fn with_denial_log(mut self, log: DenialLog) -> Self {
+        self.records.denials = Some(log);
+        self
+    }
+
+    /// Counts every charge on `leaderboard`, in the script that charges the
+    /// buckets, like the denial log.
+    pub fn with_leaderboard(mut self, leaderboard: Leaderboard) -> Self {
+        self.records.leaderboard = Some(leaderboard);
+        self
+    }
+
+    /// Whether the connections are to a Redis Cluster, where a script can
+    /// only touch keys in one slot, so buckets in several, such as the
+    /// global one or a client address's beside an identity's, are charged a
+    /// slot at a time. Off by default, for a single server.
+    pub fn with_cluster(mut self, enabled: bool) -> Self {
+        self.cluster = enabled;
+        self
+    }
+}
+
+impl<C> BucketStore for AsyncRedisStore<C>
+where
+    C: aio::ConnectionLike + Send + Sync + 'static,
+{
+    fn take_token<'a>(
+        &'a self,
+        key: &'a str,
+        cost: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+        Box::pin(async move {
+            let mut decisions = self.take_tokens(&[(key, config)], cost, now).await?;
+            Ok(decisions.remove(0))
+        })
+    }
+
+    fn take_tokens<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<RateLimitDecision>, StoreError>> {
+        Box::pin(async move {
+            let decisions = self.charge(buckets, cost, now, None).await?;
+            // Without a receipt, there's always a charge.
+            Ok(decisions.unwrap_or_default())
+        })
+    }
+
+    fn take_tokens_once<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+        receipt: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<Option<Vec<RateLimitDecision>>, StoreError>> {
+        Box::pin(self.charge(buckets, cost, now, Some((receipt, ttl))))
+    }
+
+    fn refund<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            if !self.cluster {
+                return self.refund_slot(buckets, cost, now).await;
+            }
+            for group in grouped(buckets, slot) {
+                self.refund_slot(&group.buckets, cost, now).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn peek<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+        Box::pin(async move {
+            let bucket = self
+                .stored(key)
+                .await?
+                .unwrap_or_else(|| TokenPersistence::new(config, now));
+            Ok(bucket.peek(config, now))
+        })
+    }
+
+    fn status<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Option<BucketStatus>, StoreError>> {
+        Box::pin(async move {
+            let stored = self.stored(key).await?;
+            Ok(stored.map(|bucket| bucket.status(config, now)))
+        })
+    }
+
+    fn reset<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+        Box::pin(async move {
+            let del = redis::cmd("DEL").arg(key).clone();
+            self.with_failover(&del, |conn, del| Box::pin(del.query_async(conn)))
+                .await
+        })
+    }
+
+    fn set_tokens<'a>(
+        &'a self,
+        key: &'a str,
+        tokens: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let bucket = TokenPersistence::holding(config, tokens, now);
+            let pipe = write(key, &bucket, config, self.format, now);
+            self.with_failover(&pipe, |conn, pipe| Box::pin(pipe.exec_async(conn)))
+                .await
+        })
+    }
+
+    fn tier<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, StoreError>> {
+        Box::pin(async move {
+            let get = redis::cmd("GET").arg(key).clone();
+            self.with_failover(&get, |conn, get| Box::pin(get.query_async(conn)))
+                .await
+        })
+    }
+
+    fn set_tier<'a>(
+        &'a self,
+        key: &'a str,
+        tier: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let update = set_tier(key, tier);
+            self.with_failover(&update, |conn, update| Box::pin(update.exec_async(conn)))
+                .await
+        })
+    }
+
+    fn config_override<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<BucketConfig>, StoreError>> {
+        Box::pin(async move {
+            let get = redis::cmd("GET").arg(key).clone();
+            let json = self
+                .with_failover(&get, |conn, get| Box::pin(get.query_async(conn)))
+                .await?;
+            parse_override(json)
+        })
+    }
+
+    fn set_config_override<'a>(
+        &'a self,
+        key: &'a str,
+        config: Option<&'a BucketConfig>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let update = set_config_override(key, config);
+            self.with_failover(&update, |conn, update| Box::pin(update.exec_async(conn)))
+                .await
+        })
+    }
+
+    fn is_blocked<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+        Box::pin(async move {
+            let sismember = redis::cmd("SISMEMBER")
+                .arg(&self.blocklist)
+                .arg(key)
+                .clone();
+            self.with_failover(&sismember, |conn, sismember| {
+                Box::pin(sismember.query_async(conn))
+            })
+            .await
+        })
+    }
+
+    fn set_blocked<'a>(
+        &'a self,
+        key: &'a str,
+        blocked: bool,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            let update = set_blocked(&self.blocklist, key, blocked);
+            self.with_failover(&update, |conn, update| Box::pin(update.exec_async(conn)))
+                .await
+        })
+    }
+
+    fn top_consumers(
+        &self,
+        n: usize,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<Vec<(String, u64)>, StoreError>> {
+        Box::pin(async move {
+            let Some(leaderboard) = self.records.leaderboard.as_ref() else {
+                return Err(StoreError::Unsupported(
+                    "rank consumers without a leaderboard",
+                ));
+            };
+            if n == 0 {
+                return Ok(Vec::new());
+            }
+            let top = leaderboard.top(n, now);
+            self.with_failover(&top, |conn, top| Box::pin(top.query_async(conn)))
+                .await
+        })
+    }
+
+    fn ping(&self) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(async move {
+            let ping = redis::cmd("PING");
+            self.with_failover(&ping, |conn, ping| Box::pin(ping.exec_async(conn)))
+                .await
+        })
+    }
+}
+
+impl<C> AsyncRedisStore<C>
+where
+    C: aio::ConnectionLike + Send + Sync + 'static,
+{
+    /// Charges the buckets, keeping `receipt` along with the charge, and
+    /// records the denials and the charge. `None` if the receipt was already
+    /// kept.
+    ///
+    /// On a cluster, the slots other than the first bucket's are charged
+    /// first, then that one, and every slot that allowed the charge is
+    /// refunded unless they all did.
+    async fn charge(
+        &self,
+        buckets: &[(&str, &BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+        receipt: Option<(&str, Duration)>,
+    ) -> Result<Option<Vec<RateLimitDecision>>, StoreError> {
+        let records = self.records.all();
+        if !self.cluster {
+            return self.charge_slot(buckets, cost, now, receipt, records).await;
+        }
+        let groups = grouped(buckets, slot);
+        let Some((first, rest)) = groups.split_first() else {
+            return Ok(Some(Vec::new()));
+        };
+        if rest.is_empty() {
+            return self.charge_slot(buckets, cost, now, receipt, records).await;
+        }
+
+        // The charge is counted once, with the first bucket's slot.
+        let denials = Recording {
+            leaderboard: None,
+            ..records
+        };
+        let mut charged = Vec::with_capacity(groups.len());
+        for group in rest {
+            match self
+                .charge_slot(&group.buckets, cost, now, None, denials)
+                .await
+            {
+                Ok(decisions) => charged.push(decisions.unwrap_or_default()),
+                Err(e) => {
+                    self.refund_allowed(&rest[..charged.len()], &charged, cost, now)
+                        .await;
+                    return Err(e);
+                }
+            }
+        }
+        // A denied charge leaves no receipt, so there's none to keep unless
+        // the others allowed it.
+        let receipt = receipt.filter(|_| charged.iter().all(|decisions| allowed(decisions)));
+        let decisions = match self
+            .charge_slot(&first.buckets, cost, now, receipt, records)
+            .await
+        {
+            Ok(Some(decisions)) => decisions,
+            result => {
+                self.refund_allowed(rest, &charged, cost, now).await;
+                return result;
+            }
+        };
+        charged.insert(0, decisions);
+        if !charged.iter().all(|decisions| allowed(decisions)) {
+            self.refund_allowed(&groups, &charged, cost, now).await;
+        }
+        Ok(Some(ungrouped(&groups, charged)))
+    }
+
+    /// Gives back what the groups that allowed their charge took.
+    async fn refund_allowed(
+        &self,
+        groups: &[Group<'_>],
+        decisions: &[Vec<RateLimitDecision>],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) {
+        for (group, decisions) in groups.iter().zip(decisions) {
+            if !allowed(decisions) {
+                continue;
+            }
+            if let Err(e) = self.refund_slot(&group.buckets, cost, now).await {
+                tracing::warn!(error = %e, slot = group.place, "couldn't undo a charge");
+            }
+        }
+    }
+
+    /// Charges buckets in one slot with the script, as [`charge`](Self::charge)
+    /// does, recording what `records` asks for.
+    async fn charge_slot(
+        &self,
+        buckets: &[(&str, &BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+        receipt: Option<(&str, Duration)>,
+        records: Recording<'_>,
+    ) -> Result<Option<Vec<RateLimitDecision>>, StoreError> {
+        let format = self.format;
+        let (recording, after) = records.split(buckets, now, self.cluster);
+        let args = (buckets, receipt, recording);
+        let decisions = self
+            .with_failover(&args, |conn, &(buckets, receipt, recording)| {
+                Box::pin(charge_async(
+                    conn, buckets, cost, format, receipt, recording, now,
+                ))
+            })
+            .await?;
+        if after.denials.is_none() && after.leaderboard.is_none() {
+            return Ok(decisions);
+        }
+        // The charge stands whatever happens to the log, so a slow pool or
+        // append only loses the entries, all within the one timeout.
+        let recorded = bounded(self.timeout, async {
+            let mut conn = self.pool.get().await;
+            if let Some((log, decisions)) = after.denials.zip(decisions.as_ref()) {
+                for ((key, _), decision) in buckets.iter().zip(decisions) {
+                    if decision.allowed {
+                        continue;
+                    }
+                    let xadd = log.entry(key, decision, now);
+                    if let Err(e) = xadd.exec_async(&mut *conn).await {
+                        tracing::error!(error = %e, stream = log.stream, "couldn't log a denial");
+                    }
+                }
+            }
+            let counted = after
+                .leaderboard
+                .zip(buckets.first())
+                .filter(|_| decisions.is_some());
+            if let Some((leaderboard, (key, _))) = counted {
+                let mut pipe = redis::pipe();
+                for command in leaderboard.count(key, now) {
+                    pipe.add_command(command).ignore();
+                }
+                if let Err(e) = pipe.exec_async(&mut *conn).await {
+                    tracing::error!(error = %e, "couldn't count a charge on the leaderboard");
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = recorded.await {
+            tracing::error!(error = %e, "couldn't record a charge");
+        }
+        Ok(decisions)
+    }
+
+    /// Refunds buckets in one slot with the script.
+    async fn refund_slot(
+        &self,
+        buckets: &[(&str, &BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> Result<(), StoreError> {
+        let format = self.format;
+        // A negative cost makes the script refund.
+        let refund = (buckets, -cost, format, now);
+        self.with_failover(&refund, |conn, &(buckets, cost, format, now)| {
+            Box::pin(take_token(
+                conn,
+                buckets,
+                cost,
+                format,
+                None,
+                Recording::default(),
+                now,
+            ))
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn stored(&self, key: &str) -> Result<Option<TokenPersistence>, StoreError> {
+        self.with_failover(key, |conn, key| {
+            Box::pin(read_async(conn, key, self.format))
+        })
+        .await
+    }
+
+    /// Runs `op` with `args` on a connection from the pool, giving up on it
+    /// after the timeout. If it failed because the connection lost its
+    /// master, it's run once more, so with a connection that can reconnect a
+    /// failover costs a round trip rather than a failed request.
+    async fn with_failover<A, T>(
+        &self,
+        args: &A,
+        op: impl for<'c> Fn(&'c mut C, &'c A) -> BoxFuture<'c, RedisResult<T>>,
+    ) -> Result<T, StoreError>
+    where
+        A: Sync + ?Sized,
+    {
+        bounded(self.timeout, async {
+            let mut conn = self.pool.get().await;
+            match op(&mut *conn, args).await {
+                Err(e) if lost_master(&e) => Ok(op(&mut *conn, args).await?),
+                result => Ok(result?),
+            }
+        })
+        .await
+    }
+}
+
+/// The Redis Cluster slot of `key`.
+fn slot(key: &str) -> usize {
+    usize::from(get_slot(key.as_bytes()))
+}
+
+/// [`read`] for async connections. Reading doesn't need the script, so a
+/// peek leaves the bucket as it is.
+async fn read_async<C: aio::ConnectionLike>(
+    con: &mut C,
+    key: &str,
+    format: StorageFormat,
+) -> RedisResult<Option<TokenPersistence>> {
+    if format == StorageFormat::Hash {
+        let kind: String = redis::cmd("TYPE").arg(key).query_async(con).await?;
+        match kind.as_str() {
+            "hash" => {
+                let fields: redis::Value = redis::cmd("HGETALL").arg(key).query_async(con).await?;
+                let bucket = HashMap::from_redis_value(&fields).and_then(|f| from_fields(&f));
+                return Ok(readable(key, bucket));
+            }
+            "string" => {}
+            _ => return Ok(None),
+        }
+    }
+
+    let stored: redis::Value = redis::cmd("GET").arg(key).query_async(con).await?;
+    from_get(key, &stored)
+}
+
+/// `EX` takes whole seconds and rejects zero; rounding up keeps the key until
+/// the bucket is full.
+fn expiry_secs(ttl: Duration) -> u64 {
+    (ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0)).max(1)
+}
+
+static TAKE_TOKEN: LazyLock<Script> = LazyLock::new(|| Script::new(include_str!("take_token.lua")));
+
+/// Charges the buckets with the script, or not at all if `receipt` is
+/// already kept.
+async fn charge_async<C>(
+    con: &mut C,
+    buckets: &[(&str, &BucketConfig)],
+    cost: i64,
+    format: StorageFormat,
+    receipt: Option<(&str, Duration)>,
+    recording: Recording<'_>,
+    now: DateTime<Utc>,
+) -> RedisResult<Option<Vec<RateLimitDecision>>>
+where
+    C: aio::ConnectionLike,
+{
+    let refilled = take_token(con, buckets, cost, format, receipt, recording, now).await?;
+    let Some(refilled) = refilled else {
+        return Ok(None);
+    };
+    // The buckets are already refilled up to `now`, so charging them again
+    // only derives the decisions the script made.
+    let charged = TokenPersistence::charge_all(
+        refilled
+            .iter()
+            .zip(buckets.iter().map(|(_, config)| *config)),
+        cost,
+        now,
+    );
+    Ok(Some(
+        charged.into_iter().map(|(decision, _)| decision).collect(),
+    ))
+}
+
+/// Runs the script by hash, sending its source only when the server doesn't
+/// have it cached yet, writing `recording` along with the charge. Returns
+/// the buckets as refilled before the charge, or `None` if `receipt` was
+/// already kept; a negative `cost` refunds them instead.
+async fn take_token<C>(
+    con: &mut C,
+    buckets: &[(&str, &BucketConfig)],
+    cost: i64,
+    format: StorageFormat,
+    receipt: Option<(&str, Duration)>,
+    recording: Recording<'_>,
+    now: DateTime<Utc>,
+) -> RedisResult<Option<Vec<TokenPersistence>>>
+where
+    C: aio::ConnectionLike,
+{
+    let leaderboard = recording
+        .leaderboard
+        .map(|leaderboard| (leaderboard.key(now), leaderboard.window_secs() * 2));
+    // Whole milliseconds, the resolution the script works in.
+    let now = now.timestamp_millis();
+    let millis = |duration: Duration| i64::try_from(duration.as_millis()).unwrap_or(i64::MAX);
+    let penalty = |config: &BucketConfig| {
+        config.penalty.as_ref().map_or((0, 0, 0, 0), |penalty| {
+            (
+                penalty.violations.max(1),
+                millis(penalty.window),
+                millis(penalty.ban),
+                millis(penalty.max_ban),
+            )
+        })
+    };
+    let interval = |config: &BucketConfig| config.refill_interval.as_millis().max(1) as i64;
+
+    let mut args = Vec::new();
+    for (i, (_, config)) in buckets.iter().enumerate() {
+        let (violations, window, ban, max_ban) = penalty(config);
+        let config_args = (config.max_tokens, config.refill_rate, interval(config));
+        let algorithm = match config.algorithm {
+            Algorithm::TokenBucket => "token_bucket",
+            Algorithm::Gcra => "gcra",
+            Algorithm::LeakyBucket => "leaky_bucket",
+            Algorithm::FixedWindow { .. } => "fixed_window",
+        };
+        let offset = match config.algorithm {
+            Algorithm::FixedWindow { offset } => millis(offset),
+            _ => 0,
+        };
+        if i == 0 {
+            let format = match format {
+                StorageFormat::Json if cfg!(feature = "msgpack") => "msgpack",
+                StorageFormat::Json => "json",
+                StorageFormat::Hash => "hash",
+            };
+            redis::ToRedisArgs::write_redis_args(&(config_args, cost, now, format), &mut args);
+            redis::ToRedisArgs::write_redis_args(
+                &(
+                    violations,
+                    window,
+                    ban,
+                    max_ban,
+                    algorithm,
+                    config.overdraft,
+                    offset,
+                ),
+                &mut args,
+            );
+        } else {
+            redis::ToRedisArgs::write_redis_args(
+                &(config_args, violations, window, ban, max_ban),
+                &mut args,
+            );
+            redis::ToRedisArgs::write_redis_args(&(algorithm, config.overdraft, offset), &mut args);
+        }
+    }
+    let mut keys = buckets.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+    if let Some((key, ttl)) = receipt {
+        keys.push(key);
+        redis::ToRedisArgs::write_redis_args(&millis(ttl).max(1), &mut args);
+    }
+    if recording.denials.is_some() || leaderboard.is_some() {
+        let mut max_len = 0;
+        if let Some(log) = recording.denials {
+            keys.push(&log.stream);
+            max_len = log.max_len.max(1);
+        }
+        let mut ttl = 0;
+        if let Some((set, set_ttl)) = &leaderboard {
+            keys.push(set);
+            ttl = *set_ttl;
+        }
+        redis::ToRedisArgs::write_redis_args(&(max_len, ttl, "record"), &mut args);
+    }
+
+    let refilled: Vec<i64> = match redis::cmd("EVALSHA")
+        .arg(TAKE_TOKEN.get_hash())
+        .arg(keys.len())
+        .arg(&keys)
+        .arg(&args)
+        .query_async(con)
+        .await
+    {
+        Err(e) if e.kind() == ErrorKind::NoScriptError => {
+            redis::cmd("EVAL")
+                .arg(include_str!("take_token.lua"))
+                .arg(keys.len())
+                .arg(&keys)
+                .arg(&args)
+                .query_async(con)
+                .await?
+        }
+        result => result?,
+    };
+    if receipt.is_some() && refilled.is_empty() {
+        return Ok(None);
+    }
+
+    let (refilled, rest) = refilled.as_chunks::<6>();
+    if !rest.is_empty() {
+        return Err((ErrorKind::TypeError, "script replied with a partial bucket").into());
+    }
+    let refilled = refilled
+        .iter()
+        .map(
+            |[tokens, last_updated, violations, since, bans, banned_until]| TokenPersistence {
+                tokens: *tokens,
+                last_updated: from_millis(*last_updated),
+                penalty: (*banned_until > 0 || *violations > 0).then(|| Penalty {
+                    violations: *violations as u32,
+                    since: from_millis(*since),
+                    bans: *bans as u32,
+                    banned_until: from_millis(*banned_until),
+                }),
+            },
+        )
+        .collect::<Vec<_>>();
+    if refilled.len() != buckets.len() {
+        return Err((
+            ErrorKind::TypeError,
+            "script replied for the wrong number of buckets",
+        )
+            .into());
+    }
+    Ok(Some(refilled))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::Arc, time::Duration};
+
+    use chrono::{DateTime, Utc};
+    use mlua::{Lua, LuaSerdeExt, Variadic};
+    use redis::{ErrorKind, RedisFuture, Value, aio};
+
+    use crate::{
+        Algorithm, BucketConfig, DenialLog, Penalty, PenaltyConfig, TokenPersistence, later,
+        timestamp::from_millis,
+    };
+
+    use super::{AsyncRedisStore, TransactionRetry, from_get};
+
+    const SCRIPT: &str = include_str!("take_token.lua");
+
+    #[derive(Clone, Debug, Default)]
+    struct Key {
+        value: Option<Vec<u8>>,
+        hash: Option<HashMap<String, String>>,
+        /// Seconds, as last set with `SET ... EX` or `EXPIRE`.
+        ex: Option<i64>,
+        /// The fields of each entry appended with `XADD`.
+        entries: Vec<HashMap<String, String>>,
+    }
+
+    /// Runs the script with `stored` in the bucket key, against just enough of
+    /// Redis' scripting environment for it. Returns the reply and what's left
+    /// in the key.
+    fn run(stored: Option<String>, args: [i64; 5]) -> ((i64, i64), Key) {
+        let key = Key {
+            value: stored.map(String::into_bytes),
+            ..Key::default()
+        };
+        run_as(key, args, "json")
+    }
+
+    fn run_as(key: Key, args: [i64; 5], format: &str) -> ((i64, i64), Key) {
+        let (reply, key) = run_penalized(key, args, format, None);
+        ((reply[0], reply[1]), key)
+    }
+
+    /// Returns the whole reply, including the penalty fields.
+    fn run_penalized(
+        key: Key,
+        args: [i64; 5],
+        format: &str,
+        penalty: Option<&PenaltyConfig>,
+    ) -> (Vec<i64>, Key) {
+        let (reply, mut left) = run_script(vec![key], penalized_argv(args, format, penalty));
+        (reply, left.remove(0))
+    }
+
+    fn penalized_argv(
+        args: [i64; 5],
+        format: &str,
+        penalty: Option<&PenaltyConfig>,
+    ) -> Vec<String> {
+        let mut argv: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        argv.push(format.to_string());
+        argv.extend(penalty_args(penalty).iter().map(|arg| arg.to_string()));
+        argv.extend(["token_bucket", "0", "0"].map(String::from));
+        argv
+    }
+
+    /// Runs the script recording on a denial log and a leaderboard, which are
+    /// returned after what's left in `keys`.
+    fn run_recorded(mut keys: Vec<Key>, mut argv: Vec<String>) -> (Vec<Key>, Key, Key) {
+        keys.extend([Key::default(), Key::default()]);
+        argv.extend(["100", "7200", "record"].map(String::from));
+        let (_, mut left) = run_script(keys, argv);
+        let leaderboard = left.pop().unwrap();
+        let denials = left.pop().unwrap();
+        (left, denials, leaderboard)
+    }
+
+    /// The `remaining` of each denial recorded by the script.
+    fn recorded(denials: &Key) -> Vec<(String, i64)> {
+        let entries = denials.entries.iter();
+        let entries = entries.map(|entry| (entry["key"].clone(), entry["remaining"].parse()));
+        entries
+            .map(|(key, remaining)| (key, remaining.unwrap()))
+            .collect()
+    }
+
+    fn penalty_args(penalty: Option<&PenaltyConfig>) -> [i64; 4] {
+        penalty.map_or([0; 4], |penalty| {
+            [
+                i64::from(penalty.violations.max(1)),
+                penalty.window.as_millis() as i64,
+                penalty.ban.as_millis() as i64,
+                penalty.max_ban.as_millis() as i64,
+            ]
+        })
+    }
+
+    /// Runs the script with `keys` stored as `bucket0`, `bucket1` and so on.
+    /// Returns the reply and what's left in each key.
+    fn run_script(keys: Vec<Key>, argv: Vec<String>) -> (Vec<i64>, Vec<Key>) {
+        let lua = Lua::new();
+        let names = (0..keys.len())
+            .map(|i| format!("bucket{i}"))
+            .collect::<Vec<_>>();
+        let stored = Rc::new(RefCell::new(
+            names.iter().cloned().zip(keys).collect::<HashMap<_, _>>(),
+        ));
+
+        let redis = lua.create_table().unwrap();
+        let keys = Rc::clone(&stored);
+        let call = lua
+            .create_function(move |lua, raw: Variadic<mlua::LuaString>| {
+                let raw = raw
+                    .iter()
+                    .map(|arg| arg.as_bytes().to_vec())
+                    .collect::<Vec<_>>();
+                let args = raw
+                    .iter()
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect::<Vec<_>>();
+                let mut keys = keys.borrow_mut();
+                let data = keys
+                    .get_mut(&args[1])
+                    .ok_or_else(|| mlua::Error::runtime(format!("undeclared key {}", args[1])))?;
+                match args[0].as_str() {
+                    "TYPE" => {
+                        let kind = match (&data.value, &data.hash) {
+                            (Some(_), _) => "string",
+                            (_, Some(_)) => "hash",
+                            _ => "none",
+                        };
+                        let status = lua.create_table()?;
+                        status.set("ok", kind)?;
+                        Ok(mlua::Value::Table(status))
+                    }
+                    "EXISTS" => {
+                        let exists = data.value.is_some() || data.hash.is_some();
+                        Ok(mlua::Value::Integer(i64::from(exists)))
+                    }
+                    "GET" => match data.value.as_deref() {
+                        Some(value) => Ok(mlua::Value::String(lua.create_string(value)?)),
+                        None => Ok(mlua::Value::Boolean(false)),
+                    },
+                    "HMGET" => {
+                        let hash = data.hash.clone().unwrap_or_default();
+                        let fields = args[2..].iter().map(|field| hash.get(field).cloned());
+                        let fields = lua.create_sequence_from(fields.map(|field| {
+                            field.map_or(mlua::Value::Boolean(false), |field| {
+                                mlua::Value::String(lua.create_string(field).unwrap())
+                            })
+                        }))?;
+                        Ok(mlua::Value::Table(fields))
+                    }
+                    "SET" => {
+                        let ex = args
+                            .get(3)
+                            .filter(|option| *option == "EX")
+                            .and_then(|_| args[4].parse().ok());
+                        *data = Key {
+                            value: Some(raw[2].clone()),
+                            hash: None,
+                            ex,
+                            entries: Vec::new(),
+                        };
+                        Ok(mlua::Value::Nil)
+                    }
+                    "DEL" => {
+                        *data = Key::default();
+                        Ok(mlua::Value::Nil)
+                    }
+                    "HSET" => {
+                        let hash = data.hash.get_or_insert_default();
+                        for pair in args[2..].chunks(2) {
+                            hash.insert(pair[0].clone(), pair[1].clone());
+                        }
+                        Ok(mlua::Value::Nil)
+                    }
+                    "EXPIRE" => {
+                        data.ex = args[2].parse().ok();
+                        Ok(mlua::Value::Nil)
+                    }
+                    "XADD" => {
+                        let id = args.iter().position(|arg| arg == "*").unwrap();
+                        let fields = args[id + 1..].chunks(2);
+                        let fields = fields.map(|pair| (pair[0].clone(), pair[1].clone()));
+                        data.entries.push(fields.collect());
+                        Ok(mlua::Value::Nil)
+                    }
+                    "ZINCRBY" => {
+                        let score = data.hash.get_or_insert_default().entry(args[3].clone());
+                        let score = score.or_insert_with(|| "0".to_string());
+                        *score = (score.parse::<i64>().unwrap() + args[2].parse::<i64>().unwrap())
+                            .to_string();
+                        Ok(mlua::Value::Nil)
+                    }
+                    command => Err(mlua::Error::runtime(format!("unexpected {command}"))),
+                }
+            })
+            .unwrap();
+        redis.set("call", call).unwrap();
+        redis.set("LOG_WARNING", 3).unwrap();
+        let log = lua
+            .create_function(|_, (_level, _message): (i64, String)| Ok(()))
+            .unwrap();
+        redis.set("log", log).unwrap();
+        lua.globals().set("redis", redis).unwrap();
+
+        let cjson = lua.create_table().unwrap();
+        let decode = lua
+            .create_function(|lua, json: String| {
+                let value: serde_json::Value =
+                    serde_json::from_str(&json).map_err(mlua::Error::external)?;
+                lua.to_value(&value)
+            })
+            .unwrap();
+        let encode = lua
+            .create_function(|lua, value: mlua::Value| {
+                Ok(integral(lua.from_value(value)?).to_string())
+            })
+            .unwrap();
+        cjson.set("decode", decode).unwrap();
+        cjson.set("encode", encode).unwrap();
+        lua.globals().set("cjson", cjson).unwrap();
+
+        #[cfg(feature = "msgpack")]
+        {
+            let cmsgpack = lua.create_table().unwrap();
+            let unpack = lua
+                .create_function(|lua, bytes: mlua::LuaString| {
+                    let value: serde_json::Value =
+                        rmp_serde::from_slice(&bytes.as_bytes()).map_err(mlua::Error::external)?;
+                    lua.to_value(&value)
+                })
+                .unwrap();
+            let pack = lua
+                .create_function(|lua, value: mlua::Value| {
+                    let value = integral(lua.from_value(value)?);
+                    let bytes = rmp_serde::to_vec_named(&value).map_err(mlua::Error::external)?;
+                    lua.create_string(bytes)
+                })
+                .unwrap();
+            cmsgpack.set("unpack", unpack).unwrap();
+            cmsgpack.set("pack", pack).unwrap();
+            lua.globals().set("cmsgpack", cmsgpack).unwrap();
+        }
+
+        lua.globals().set("KEYS", names.clone()).unwrap();
+        lua.globals().set("ARGV", argv).unwrap();
+
+        let reply: Vec<i64> = lua.load(SCRIPT).eval().unwrap();
+        let mut stored = stored.borrow_mut();
+        let left = names.iter().map(|name| stored.remove(name).unwrap());
+        (reply, left.collect())
+    }
+
+    /// Like cjson and cmsgpack, writes integral numbers without a fraction.
+    fn integral(mut value: serde_json::Value) -> serde_json::Value {
+        for field in value.as_object_mut().unwrap().values_mut() {
+            if field.is_object() {
+                *field = integral(field.take());
+            } else if let Some(n) = field.as_f64().filter(|n| n.fract() == 0.0) {
+                *field = (n as i64).into();
+            }
+        }
+        value
+    }
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        rfc3339.parse().unwrap()
+    }
+
+    fn args(config: &BucketConfig, cost: i64, now: DateTime<Utc>) -> [i64; 5] {
+        [
+            config.max_tokens,
+            config.refill_rate,
+            config.refill_interval.as_millis() as i64,
+            cost,
+            now.timestamp_millis(),
+        ]
+    }
+
+    #[test]
+    fn test_script_matches_charge() {
+        let configs = [
+            BucketConfig::default(),
+            BucketConfig {
+                max_tokens: 5,
+                refill_rate: 2,
+                refill_interval: Duration::from_secs(60),
+                ..BucketConfig::default()
+            },
+        ];
+        let nows = [
+            at("2025-03-01T12:00:00Z"),
+            at("2024-03-01T00:30:00.250Z"),
+            at("2000-01-01T00:00:00.001Z"),
+        ];
+        let ages_ms = [
+            -7_200_000,
+            -90_500,
+            -1,
+            0,
+            1,
+            59_999,
+            60_000,
+            90_500,
+            3_600_000,
+            12_600_000,
+            108_000_000,
+        ];
+
+        for config in &configs {
+            for now in nows {
+                for tokens in [0, 1, 2, 4, config.max_tokens] {
+                    for age in ages_ms {
+                        for cost in [1, 2, 3, config.max_tokens + 1] {
+                            let bucket = TokenPersistence {
+                                tokens,
+                                last_updated: now - chrono::Duration::milliseconds(age),
+                                penalty: None,
+                            };
+                            let stored = serde_json::to_string(&bucket).unwrap();
+                            let (expected, updated) = bucket.charge(config, cost, now);
+
+                            let ((tokens, last_updated), left) =
+                                run(Some(stored.clone()), args(config, cost, now));
+                            let refilled = TokenPersistence {
+                                tokens,
+                                last_updated: DateTime::from_timestamp_millis(last_updated)
+                                    .unwrap(),
+                                penalty: None,
+                            };
+                            let (decision, _) = refilled.charge(config, cost, now);
+
+                            let case = format!("{stored} cost {cost} at {now}");
+                            assert_eq!(decision.allowed, expected.allowed, "{case}");
+                            assert_eq!(decision.remaining, expected.remaining, "{case}");
+                            assert_eq!(decision.reset_at, expected.reset_at, "{case}");
+                            assert_eq!(decision.retry_after, expected.retry_after, "{case}");
+
+                            let ex = left.ex;
+                            let left: TokenPersistence =
+                                serde_json::from_slice(&left.value.unwrap()).unwrap();
+                            let expected_ex = updated.as_ref().map(|updated| {
+                                super::expiry_secs(updated.time_to_full(config, now)) as i64
+                            });
+                            let expected = updated.unwrap_or(bucket);
+                            assert_eq!(left.tokens, expected.tokens, "{case}");
+                            assert_eq!(left.last_updated, expected.last_updated, "{case}");
+                            assert_eq!(ex, expected_ex, "{case}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// What the script left in the key, in either format.
+    fn left_bucket(left: &Key) -> TokenPersistence {
+        match (&left.value, &left.hash) {
+            (Some(value), _) => crate::encoding::decode(value).unwrap().unwrap(),
+            (_, Some(hash)) => {
+                let fields = hash
+                    .iter()
+                    .map(|(field, value)| (field.clone(), value.parse().unwrap()))
+                    .collect();
+                super::from_fields(&fields).unwrap()
+            }
+            _ => panic!("nothing stored"),
+        }
+    }
+
+    /// `bucket` as the blocking store would have written it in `format`.
+    fn stored_as(bucket: &TokenPersistence, format: &str) -> Key {
+        if format != "hash" {
+            return Key {
+                value: Some(serde_json::to_vec(bucket).unwrap()),
+                ..Key::default()
+            };
+        }
+        let mut hash = HashMap::from([
+            ("tokens".to_string(), bucket.tokens.to_string()),
+            (
+                "last_updated".to_string(),
+                bucket.last_updated.timestamp_millis().to_string(),
+            ),
+        ]);
+        if let Some(penalty) = &bucket.penalty {
+            hash.extend([
+                ("violations".to_string(), penalty.violations.to_string()),
+                (
+                    "violations_since".to_string(),
+                    penalty.since.timestamp_millis().to_string(),
+                ),
+                ("bans".to_string(), penalty.bans.to_string()),
+                (
+                    "banned_until".to_string(),
+                    penalty.banned_until.timestamp_millis().to_string(),
+                ),
+            ]);
+        }
+        Key {
+            hash: Some(hash),
+            ..Key::default()
+        }
+    }
+
+    #[test]
+    fn test_script_matches_charge_with_penalties() {
+        let penalty = PenaltyConfig {
+            violations: 3,
+            window: Duration::from_secs(60),
+            ban: Duration::from_secs(10 * 60),
+            max_ban: Duration::from_secs(40 * 60),
+        };
+        let config = BucketConfig {
+            max_tokens: 2,
+            refill_rate: 1,
+            refill_interval: Duration::from_secs(60),
+            penalty: Some(penalty.clone()),
+            ..BucketConfig::default()
+        };
+        let now = at("2025-03-01T12:00:00.250Z");
+        let ago = |secs: i64| now - chrono::Duration::seconds(secs);
+        let record = |violations, since, bans, banned_until| Penalty {
+            violations,
+            since,
+            bans,
+            banned_until,
+        };
+        let penalties = [
+            None,
+            Some(record(1, ago(10), 0, DateTime::UNIX_EPOCH)),
+            Some(record(2, ago(10), 0, DateTime::UNIX_EPOCH)),
+            Some(record(2, ago(70), 1, ago(600))),
+            Some(record(0, ago(900), 2, ago(-300))),
+            Some(record(2, ago(30), 3, ago(1))),
+        ];
+
+        for format in ["json", "hash"] {
+            for tokens in [0, 1, 2] {
+                for age in [0, 30, 90] {
+                    for penalty in &penalties {
+                        for cost in [1, 3] {
+                            let bucket = TokenPersistence {
+                                tokens,
+                                last_updated: ago(age),
+                                penalty: penalty.clone(),
+                            };
+                            let (expected, updated) = bucket.charge(&config, cost, now);
+
+                            let stored = stored_as(&bucket, format);
+                            let (reply, left) = run_penalized(
+                                stored.clone(),
+                                args(&config, cost, now),
+                                format,
+                                config.penalty.as_ref(),
+                            );
+                            let refilled = TokenPersistence {
+                                tokens: reply[0],
+                                last_updated: DateTime::from_timestamp_millis(reply[1]).unwrap(),
+                                penalty: (reply[5] > 0 || reply[2] > 0).then(|| Penalty {
+                                    violations: reply[2] as u32,
+                                    since: DateTime::from_timestamp_millis(reply[3]).unwrap(),
+                                    bans: reply[4] as u32,
+                                    banned_until: DateTime::from_timestamp_millis(reply[5])
+                                        .unwrap(),
+                                }),
+                            };
+                            let (decision, _) = refilled.charge(&config, cost, now);
+
+                            let case = format!("{bucket:?} cost {cost} as {format}");
+                            assert_eq!(decision, expected, "{case}");
+
+                            let argv = penalized_argv(
+                                args(&config, cost, now),
+                                format,
+                                config.penalty.as_ref(),
+                            );
+                            let (_, denials, leaderboard) =
+                                run_recorded(vec![stored.clone()], argv);
+                            let denied = (!expected.allowed)
+                                .then(|| ("bucket0".to_string(), expected.remaining));
+                            assert_eq!(recorded(&denials), Vec::from_iter(denied), "{case}");
+                            let counted = leaderboard.hash.unwrap();
+                            assert_eq!(counted["bucket0"], "1", "{case}");
+                            assert_eq!(leaderboard.ex, Some(7200), "{case}");
+
+                            let Some(updated) = updated else {
+                                assert_eq!(left.value, stored.value, "{case}");
+                                assert_eq!(left.hash, stored.hash, "{case}");
+                                continue;
+                            };
+                            let written = left_bucket(&left);
+                            assert_eq!(written.tokens, updated.tokens, "{case}");
+                            assert_eq!(written.last_updated, updated.last_updated, "{case}");
+                            assert_eq!(written.penalty, updated.penalty, "{case}");
+                            let expected_ex = super::expiry_secs(updated.expires_in(&config, now));
+                            assert_eq!(left.ex, Some(expected_ex as i64), "{case}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_script_matches_overdraft() {
+        let config = BucketConfig {
+            max_tokens: 3,
+            refill_rate: 2,
+            refill_interval: Duration::from_secs(60),
+            overdraft: 5,
+            ..BucketConfig::default()
+        };
+        let now = at("2025-03-01T12:00:00.250Z");
+
+        for tokens in [-5, -2, 0, 1, 3] {
+            for age in [0, 59_999, 60_000, 150_000, 600_000] {
+                for cost in [1, 3, 6, 8] {
+                    let bucket = TokenPersistence {
+                        tokens,
+                        last_updated: now - chrono::Duration::milliseconds(age),
+                        penalty: None,
+                    };
+                    let (expected, updated) = bucket.charge(&config, cost, now);
+
+                    let mut argv = args(&config, cost, now).map(|arg| arg.to_string()).to_vec();
+                    argv.extend(
+                        ["json", "0", "0", "0", "0", "token_bucket", "5", "0"].map(String::from),
+                    );
+                    let key = stored_as(&bucket, "json");
+                    let (reply, left) = run_script(vec![key.clone()], argv);
+
+                    let case = format!("{bucket:?} cost {cost}");
+                    let refilled = TokenPersistence {
+                        tokens: reply[0],
+                        last_updated: from_millis(reply[1]),
+                        penalty: None,
+                    };
+                    assert_eq!(refilled.charge(&config, cost, now).0, expected, "{case}");
+                    match updated {
+                        Some(updated) => {
+                            let written = left_bucket(&left[0]);
+                            assert_eq!(written.tokens, updated.tokens, "{case}");
+                            assert_eq!(written.last_updated, updated.last_updated, "{case}");
+                            let expected_ex = super::expiry_secs(updated.expires_in(&config, now));
+                            assert_eq!(left[0].ex, Some(expected_ex as i64), "{case}");
+                        }
+                        None => assert_eq!(left[0].value, key.value, "{case}"),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_script_matches_refund() {
+        let now = at("2025-03-01T12:00:00.250Z");
+        for (algorithm, name) in [
+            (Algorithm::TokenBucket, "token_bucket"),
+            (Algorithm::LeakyBucket, "leaky_bucket"),
+        ] {
+            let config = BucketConfig {
+                max_tokens: 5,
+                refill_rate: 2,
+                refill_interval: Duration::from_secs(60),
+                algorithm,
+                ..BucketConfig::default()
+            };
+            let argv = |cost: i64| {
+                let mut argv = args(&config, -cost, now)
+                    .map(|arg| arg.to_string())
+                    .to_vec();
+                argv.extend(["json", "0", "0", "0", "0", name, "0", "0"].map(String::from));
+                argv
+            };
+
+            for tokens in [0, 2, 5] {
+                for age in [0, 59_999, 60_000, 150_000] {
+                    for cost in [1, 3, 8] {
+                        let bucket = TokenPersistence {
+                            tokens,
+                            last_updated: now - chrono::Duration::milliseconds(age),
+                            penalty: None,
+                        };
+                        let expected = bucket.refund(&config, cost, now);
+
+                        let (_, left) = run_script(vec![stored_as(&bucket, "json")], argv(cost));
+
+                        let case = format!("{name} {bucket:?} cost {cost}");
+                        let written = left_bucket(&left[0]);
+                        assert_eq!(written.tokens, expected.tokens, "{case}");
+                        assert_eq!(written.last_updated, expected.last_updated, "{case}");
+                    }
+                }
+            }
+
+            let (_, left) = run_script(vec![Key::default()], argv(1));
+            assert_eq!(left[0].value, None, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_script_matches_fixed_window() {
+        let config = BucketConfig {
+            algorithm: Algorithm::FixedWindow {
+                offset: Duration::from_secs(18 * 60 * 60),
+            },
+            ..BucketConfig::daily(3)
+        };
+        let now = at("2025-03-01T17:59:59.250Z");
+        let argv = |cost: i64| {
+            let mut argv = args(&config, cost, now).map(|arg| arg.to_string()).to_vec();
+            argv.extend(
+                ["json", "0", "0", "0", "0", "fixed_window", "0", "64800000"].map(String::from),
+            );
+            argv
+        };
+
+        for tokens in [0, 1, 3] {
+            for age in [0, 1_000, 86_399_000, 86_400_000] {
+                for cost in [1, 2, -1] {
+                    let bucket = TokenPersistence::from_window(
+                        &config,
+                        tokens,
+                        now - chrono::Duration::milliseconds(age),
+                    );
+                    let (reply, left) = run_script(vec![stored_as(&bucket, "json")], argv(cost));
+
+                    let case = format!("{bucket:?} cost {cost}");
+                    let refilled = TokenPersistence {
+                        tokens: reply[0],
+                        last_updated: from_millis(reply[1]),
+                        penalty: None,
+                    };
+                    let expected = if cost < 0 {
+                        Some(bucket.refund(&config, -cost, now))
+                    } else {
+                        let (expected, updated) = bucket.charge(&config, cost, now);
+                        assert_eq!(refilled.charge(&config, cost, now).0, expected, "{case}");
+                        updated
+                    };
+                    if let Some(expected) = expected {
+                        let written = left_bucket(&left[0]);
+                        assert_eq!(written.tokens, expected.tokens, "{case}");
+                        assert_eq!(written.last_updated, expected.last_updated, "{case}");
+                        let expected_ex = super::expiry_secs(
+                            (later(expected.last_updated, config.refill_interval) - now)
+                                .to_std()
+                                .unwrap(),
+                        );
+                        assert_eq!(left[0].ex, Some(expected_ex as i64), "{case}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_script_matches_gcra_refund() {
+        let config = BucketConfig {
+            algorithm: Algorithm::Gcra,
+            ..BucketConfig::burst(3, 10, Duration::from_secs(60))
+        };
+        let now = at("2025-03-01T12:00:00.250Z");
+        let mut argv = args(&config, -2, now).map(|arg| arg.to_string()).to_vec();
+        argv.extend(["json", "0", "0", "0", "0", "gcra", "0", "0"].map(String::from));
+
+        for ahead_ms in [0, 5_999, 12_000, 18_000] {
+            let stored = TokenPersistence::from_tat(now + chrono::Duration::milliseconds(ahead_ms));
+            let expected = stored.refund(&config, 2, now);
+            let key = Key {
+                value: Some(
+                    stored
+                        .last_updated
+                        .timestamp_millis()
+                        .to_string()
+                        .into_bytes(),
+                ),
+                ..Key::default()
+            };
+
+            let (_, left) = run_script(vec![key], argv.clone());
+
+            let left_at = String::from_utf8_lossy(left[0].value.as_deref().unwrap()).parse::<i64>();
+            assert_eq!(
+                left_at.unwrap(),
+                expected.last_updated.timestamp_millis(),
+                "{ahead_ms}ms ahead"
+            );
+        }
+
+        let (_, left) = run_script(vec![Key::default()], argv);
+        assert_eq!(left[0].value, None);
+    }
+
+    #[test]
+    fn test_script_charges_once_per_receipt() {
+        let config = BucketConfig::default();
+        let now = at("2025-03-01T12:00:00Z");
+        let argv = |cost: i64| {
+            let mut argv = args(&config, cost, now).map(|arg| arg.to_string()).to_vec();
+            argv.extend(
+                [
+                    "json",
+                    "0",
+                    "0",
+                    "0",
+                    "0",
+                    "token_bucket",
+                    "0",
+                    "0",
+                    "60000",
+                ]
+                .map(String::from),
+            );
+            argv
+        };
+
+        let (reply, left) = run_script(vec![Key::default(), Key::default()], argv(4));
+        assert_eq!(reply[..2], [10, now.timestamp_millis()]);
+        assert_eq!(left_bucket(&left[0]).tokens, 6);
+        assert_eq!(left[1].value.as_deref(), Some(&b"1"[..]));
+
+        let (reply, replayed) = run_script(left.clone(), argv(4));
+        assert!(reply.is_empty());
+        assert_eq!(replayed[0].value, left[0].value);
+
+        // A denied charge leaves no receipt to be replayed.
+        let (reply, left) = run_script(vec![Key::default(), Key::default()], argv(11));
+        assert_eq!(reply.len(), 6);
+        assert_eq!(left[0].value, None);
+        assert_eq!(left[1].value, None);
+    }
+
+    #[test]
+    fn test_script_matches_leaky_drain() {
+        let penalty = PenaltyConfig {
+            violations: 2,
+            window: Duration::from_secs(60),
+            ban: Duration::from_secs(10 * 60),
+            max_ban: Duration::from_secs(40 * 60),
+        };
+        let plain = BucketConfig {
+            max_tokens: 5,
+            refill_rate: 3,
+            refill_interval: Duration::from_secs(1),
+            algorithm: Algorithm::LeakyBucket,
+            ..BucketConfig::default()
+        };
+        let penalized = BucketConfig {
+            penalty: Some(penalty),
+            ..plain.clone()
+        };
+        let now = at("2025-03-01T12:00:00.250Z");
+        let ago = |ms: i64| now - chrono::Duration::milliseconds(ms);
+
+        for config in [&plain, &penalized] {
+            for format in ["json", "hash"] {
+                for level in [None, Some(0), Some(2), Some(5), Some(7)] {
+                    for age in [-500, 0, 333, 334, 1_000, 1_999, 60_000] {
+                        for cost in [1, 3, 6] {
+                            let bucket = level.map(|level| TokenPersistence {
+                                tokens: level,
+                                last_updated: ago(age),
+                                penalty: None,
+                            });
+                            let fresh = TokenPersistence::new(config, now);
+                            let (expected, updated) =
+                                bucket.as_ref().unwrap_or(&fresh).charge(config, cost, now);
+
+                            let mut argv =
+                                args(config, cost, now).map(|arg| arg.to_string()).to_vec();
+                            argv.push(format.to_string());
+                            argv.extend(
+                                penalty_args(config.penalty.as_ref()).map(|arg| arg.to_string()),
+                            );
+                            argv.extend(["leaky_bucket", "0", "0"].map(String::from));
+                            let key = bucket
+                                .as_ref()
+                                .map_or_else(Key::default, |bucket| stored_as(bucket, format));
+                            let (reply, left) = run_script(vec![key.clone()], argv.clone());
+
+                            let case = format!("{bucket:?} cost {cost} as {format}, {config:?}");
+                            let drained = TokenPersistence {
+                                tokens: reply[0],
+                                last_updated: from_millis(reply[1]),
+                                penalty: None,
+                            };
+                            assert_eq!(drained.charge(config, cost, now).0, expected, "{case}");
+
+                            let (_, denials, _) = run_recorded(vec![key.clone()], argv);
+                            let denied = (!expected.allowed)
+                                .then(|| ("bucket0".to_string(), expected.remaining));
+                            assert_eq!(recorded(&denials), Vec::from_iter(denied), "{case}");
+
+                            let Some(updated) = updated else {
+                                assert_eq!(left[0].value, key.value, "{case}");
+                                assert_eq!(left[0].hash, key.hash, "{case}");
+                                continue;
+                            };
+                            let written = left_bucket(&left[0]);
+                            assert_eq!(written.tokens, updated.tokens, "{case}");
+                            assert_eq!(written.last_updated, updated.last_updated, "{case}");
+                            assert_eq!(written.penalty, updated.penalty, "{case}");
+                            let expected_ex = super::expiry_secs(updated.expires_in(config, now));
+                            assert_eq!(left[0].ex, Some(expected_ex as i64), "{case}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_script_creates_missing_bucket_full() {
+        let config = BucketConfig::default();
+        let now = at("2025-03-01T12:00:00.123Z");
+
+        let (reply, left) = run(None, args(&config, 3, now));
+
+        assert_eq!(reply, (10, now.timestamp_millis()));
+        assert_eq!(left.ex, Some(3 * 60 * 60));
+        let left: TokenPersistence = serde_json::from_slice(&left.value.unwrap()).unwrap();
+        assert_eq!(left.tokens, 7);
+        assert_eq!(left.last_updated, now);
+    }
+
+    #[test]
+    fn test_script_leaves_denied_bucket_alone() {
+        let config = BucketConfig::default();
+        let stored = r#"{"tokens":0,"last_updated":"2025-03-01T11:30:00.123456789Z"}"#;
+
+        let (reply, left) = run(
+            Some(stored.to_string()),
+            args(&config, 1, at("2025-03-01T12:00:00Z")),
+        );
+
+        assert_eq!(reply.0, 0);
+        assert_eq!(left.value.as_deref(), Some(stored.as_bytes()));
+        assert_eq!(left.ex, None);
+    }
+
+    #[test]
+    fn test_script_treats_newer_versions_as_missing() {
+        let config = BucketConfig::default();
+        let now = at("2025-03-01T12:00:00Z");
+        let stored = r#"{"version":2,"remaining":0,"since":1740830400000}"#;
+
+        let (reply, left) = run(Some(stored.to_string()), args(&config, 1, now));
+
+        assert_eq!(reply, (10, now.timestamp_millis()));
+        let written: serde_json::Value = serde_json::from_slice(&left.value.unwrap()).unwrap();
+        assert_eq!(
+            written,
+            serde_json::json!({
+                "version": 1,
+                "tokens": 9,
+                "last_updated": now.timestamp_millis(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_script_starts_unreadable_buckets_over() {
+        let config = BucketConfig::default();
+        let now = at("2025-03-01T12:00:00Z");
+
+        for stored in [
+            r#"{"tokens":0,"last_upd"#,
+            "\u{0}garbage",
+            r#"{"tokens":"none","last_updated":1740830400000}"#,
+            r#"{"tokens":1.5,"last_updated":1740830400000}"#,
+            r#"{"tokens":0,"last_updated":"yesterday"}"#,
+            r#"[0,1740830400000]"#,
+        ] {
+            let (reply, left) = run(Some(stored.to_string()), args(&config, 1, now));
+
+            assert_eq!(reply, (10, now.timestamp_millis()), "{stored}");
+            let left: TokenPersistence = serde_json::from_slice(&left.value.unwrap()).unwrap();
+            assert_eq!(left.tokens, 9, "{stored}");
+        }
+    }
+
+    #[test]
+    fn test_script_reads_offsets() {
+        let config = BucketConfig::default();
+        let stored = r#"{"tokens":0,"last_updated":"2025-03-01T06:00:00-03:00"}"#;
+
+        let ((tokens, last_updated), _) = run(
+            Some(stored.to_string()),
+            args(&config, 1, at("2025-03-01T10:30:00Z")),
+        );
+
+        assert_eq!(tokens, 1);
+        assert_eq!(last_updated, at("2025-03-01T10:00:00Z").timestamp_millis());
+    }
+
+    #[test]
+    fn test_script_round_trips_hash_buckets() {
+        let config = BucketConfig::default();
+        let now = at("2025-03-01T12:00:00.123Z");
+
+        let (_, left) = run_as(Key::default(), args(&config, 3, now), "hash");
+        let hash = left.hash.clone().unwrap();
+        assert_eq!(hash["tokens"], "7");
+        assert_eq!(hash["last_updated"], now.timestamp_millis().to_string());
+        assert_eq!(left.ex, Some(3 * 60 * 60));
+
+        let later = now + chrono::Duration::minutes(90);
+        let (reply, left) = run_as(left, args(&config, 1, later), "hash");
+        assert_eq!(
+            reply,
+            (8, (now + chrono::Duration::hours(1)).timestamp_millis())
+        );
+        assert_eq!(left.hash.unwrap()["tokens"], "7");
+    }
+
+    #[test]
+    fn test_script_migrates_json_buckets_to_hashes() {
+        let config = BucketConfig::default();
+        let stored = Key {
+            value: Some(br#"{"tokens":4,"last_updated":"2025-03-01T11:00:00Z"}"#.to_vec()),
+            ..Key::default()
+        };
+
+        let (reply, left) = run_as(stored, args(&config, 1, at("2025-03-01T12:00:00Z")), "hash");
+
+        assert_eq!(reply, (5, at("2025-03-01T12:00:00Z").timestamp_millis()));
+        assert_eq!(left.value, None);
+        let hash = left.hash.unwrap();
+        assert_eq!(hash["tokens"], "4");
+        assert_eq!(
+            hash["last_updated"],
+            at("2025-03-01T12:00:00Z").timestamp_millis().to_string()
+        );
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_script_rewrites_json_buckets_as_msgpack() {
+        let config = BucketConfig::default();
+        let now = at("2025-03-01T12:00:00Z");
+        let stored = Key {
+            value: Some(br#"{"tokens":4,"last_updated":"2025-03-01T11:00:00Z"}"#.to_vec()),
+            ..Key::default()
+        };
+
+        let (_, left) = run_as(stored, args(&config, 1, now), "msgpack");
+        let written = left.value.unwrap();
+        assert_eq!(written[0], crate::encoding::MSGPACK);
+        let bucket = crate::encoding::decode(&written).unwrap().unwrap();
+        assert_eq!(bucket.tokens, 4);
+        assert_eq!(bucket.last_updated, now);
+
+        // And read back by the script itself.
+        let stored = Key {
+            value: Some(written),
+            ..Key::default()
+        };
+        let (reply, _) = run_as(stored, args(&config, 1, now), "msgpack");
+        assert_eq!(reply, (4, now.timestamp_millis()));
+    }
+
+    #[test]
+    fn test_reads_bucket_out_of_get_reply() {
+        let stored = br#"{"version":1,"tokens":7,"last_updated":1740830400000}"#.to_vec();
+
+        let bucket = from_get("abc", &Value::BulkString(stored)).unwrap();
+
+        assert_eq!(bucket.unwrap().tokens, 7);
+        assert!(from_get("abc", &Value::Nil).unwrap().is_none());
+        // Started over rather than failing every request.
+        let garbage = Value::BulkString(b"garbage".to_vec());
+        assert!(from_get("abc", &garbage).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unexpected_replies_are_type_errors() {
+        let stored = br#"{"version":1,"tokens":7,"last_updated":1740830400000}"#.to_vec();
+        for reply in [
+            Value::Int(1),
+            Value::Okay,
+            Value::SimpleString("QUEUED".to_string()),
+            // Transaction replies, committed or not, aren't empty buckets.
+            Value::Array(vec![]),
+            Value::Array(vec![Value::BulkString(stored), Value::Okay]),
+        ] {
+            let e = from_get("abc", &reply).err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::TypeError, "{reply:?}");
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_cap() {
+        let retry = TransactionRetry {
+            max_retries: 10,
+            base_backoff: Duration::from_millis(2),
+            max_backoff: Duration::from_millis(50),
+        };
+
+        for _ in 0..100 {
+            assert!(retry.backoff(0) <= Duration::from_millis(2));
+            assert!(retry.backoff(3) <= Duration::from_millis(16));
+            assert!(retry.backoff(9) <= Duration::from_millis(50));
+        }
+        let spread = (0..100).map(|_| retry.backoff(9)).collect::<Vec<_>>();
+        assert!(spread.iter().any(|wait| *wait != spread[0]));
+    }
+
+    #[test]
+    fn test_script_charges_every_bucket_or_none() {
+        let client = BucketConfig {
+            max_tokens: 5,
+            ..BucketConfig::default()
+        };
+        let global = BucketConfig {
+            max_tokens: 10,
+            refill_rate: 2,
+            refill_interval: Duration::from_secs(60),
+            ..BucketConfig::default()
+        };
+        let now = at("2025-03-01T12:00:00Z");
+        let stored = |tokens| Key {
+            value: Some(
+                serde_json::to_vec(&TokenPersistence {
+                    tokens,
+                    last_updated: now,
+                    penalty: None,
+                })
+                .unwrap(),
+            ),
+            ..Key::default()
+        };
+        let argv = |cost: i64| {
+            let mut argv = args(&client, cost, now).map(|arg| arg.to_string()).to_vec();
+            argv.push("json".to_string());
+            argv.extend(["0", "0", "0", "0", "token_bucket", "0", "0"].map(String::from));
+            let interval = global.refill_interval.as_millis() as i64;
+            argv.extend(
+                [global.max_tokens, global.refill_rate, interval].map(|arg| arg.to_string()),
+            );
+            argv.extend(["0", "0", "0", "0", "token_bucket", "0", "0"].map(String::from));
+            argv
+        };
+
+        // The global bucket is out, so the client's keeps its tokens.
+        let (reply, left) = run_script(vec![stored(3), stored(0)], argv(1));
+        assert_eq!(reply[..2], [3, now.timestamp_millis()]);
+        assert_eq!(reply[6..8], [0, now.timestamp_millis()]);
+        assert_eq!(left_bucket(&left[0]).tokens, 3);
+        assert_eq!(left_bucket(&left[1]).tokens, 0);
+
+        // Both have enough, so both are charged.
+        let (_, left) = run_script(vec![stored(3), stored(4)], argv(2));
+        assert_eq!(left_bucket(&left[0]).tokens, 1);
+        assert_eq!(left_bucket(&left[1]).tokens, 2);
+
+        // A bucket never charged before is full.
+        let (reply, left) = run_script(vec![Key::default(), Key::default()], argv(1));
+        assert_eq!(reply.len(), 12);
+        assert_eq!(left_bucket(&left[0]).tokens, 4);
+        assert_eq!(left_bucket(&left[1]).tokens, 9);
+    }
+
+    #[test]
+    fn test_script_records_what_the_charge_did() {
+        let config = BucketConfig {
+            max_tokens: 5,
+            ..BucketConfig::default()
+        };
+        let now = at("2025-03-01T12:00:00Z");
+        let stored = |tokens| Key {
+            value: Some(
+                serde_json::to_vec(&TokenPersistence {
+                    tokens,
+                    last_updated: now,
+                    penalty: None,
+                })
+                .unwrap(),
+            ),
+            ..Key::default()
+        };
+        let argv = |cost: i64, record: &[&str]| {
+            let mut argv = args(&config, cost, now).map(|arg| arg.to_string()).to_vec();
+            argv.push("json".to_string());
+            argv.extend(["0", "0", "0", "0", "token_bucket", "0", "0"].map(String::from));
+            let interval = config.refill_interval.as_millis() as i64;
+            argv.extend(
+                [config.max_tokens, config.refill_rate, interval].map(|arg| arg.to_string()),
+            );
+            argv.extend(["0", "0", "0", "0", "token_bucket", "0", "0"].map(String::from));
+            argv.extend(record.iter().map(|arg| arg.to_string()));
+            argv
+        };
+
+        // Only the bucket that's out is logged, and the charge is counted on
+        // the first one.
+        let (left, denials, leaderboard) = run_recorded(vec![stored(3), stored(0)], argv(1, &[]));
+        assert_eq!(left_bucket(&left[0]).tokens, 3);
+        assert_eq!(recorded(&denials), [("bucket1".to_string(), 0)]);
+        assert_eq!(denials.entries[0]["ts"], now.timestamp_millis().to_string());
+        assert_eq!(denials.entries[0]["route"], "");
+        assert_eq!(leaderboard.hash.unwrap()["bucket0"], "1");
+
+        // A charge that goes through is counted but not logged.
+        let (_, denials, leaderboard) = run_recorded(vec![stored(3), stored(4)], argv(1, &[]));
+        assert!(denials.entries.is_empty());
+        assert_eq!(leaderboard.hash.unwrap()["bucket0"], "1");
+
+        // Just a leaderboard, after the buckets.
+        let keys = vec![stored(3), stored(0), Key::default()];
+        let (_, left) = run_script(keys, argv(1, &["0", "7200", "record"]));
+        assert_eq!(left[2].hash.as_ref().unwrap()["bucket0"], "1");
+
+        // Just a denial log.
+        let keys = vec![stored(3), stored(0), Key::default()];
+        let (_, left) = run_script(keys, argv(1, &["100", "0", "record"]));
+        assert_eq!(recorded(&left[2]), [("bucket1".to_string(), 0)]);
+        assert_eq!(left[2].ex, None);
+
+        // A refund records nothing.
+        let (_, denials, leaderboard) = run_recorded(vec![stored(0), stored(0)], argv(-1, &[]));
+        assert!(denials.entries.is_empty());
+        assert_eq!(leaderboard.hash, None);
+    }
+
+    #[test]
+    fn test_script_matches_gcra() {
+        let config = BucketConfig {
+            algorithm: Algorithm::Gcra,
+            ..BucketConfig::burst(3, 10, Duration::from_secs(60))
+        };
+        let now = at("2025-03-01T12:00:00.250Z");
+        let argv = |cost: i64| {
+            let mut argv = args(&config, cost, now).map(|arg| arg.to_string()).to_vec();
+            argv.extend(["json", "0", "0", "0", "0", "gcra", "0", "0"].map(String::from));
+            argv
+        };
+
+        for ahead_ms in [-60_000, -1, 0, 1, 5_999, 6_000, 12_000, 17_999, 18_000] {
+            for cost in [1, 2, 3] {
+                let stored =
+                    TokenPersistence::from_tat(now + chrono::Duration::milliseconds(ahead_ms));
+                let (expected, updated) = stored.charge(&config, cost, now);
+                let key = Key {
+                    value: Some(
+                        stored
+                            .last_updated
+                            .timestamp_millis()
+                            .to_string()
+                            .into_bytes(),
+                    ),
+                    ..Key::default()
+                };
+
+                let (reply, left) = run_script(vec![key.clone()], argv(cost));
+
+                let case = format!("{ahead_ms}ms ahead, cost {cost}");
+                let (_, denials, _) = run_recorded(vec![key], argv(cost));
+                let denied =
+                    (!expected.allowed).then(|| ("bucket0".to_string(), expected.remaining));
+                assert_eq!(recorded(&denials), Vec::from_iter(denied), "{case}");
+                let replied = TokenPersistence::from_tat(from_millis(reply[1]));
+                let (decision, _) = replied.charge(&config, cost, now);
+                assert_eq!(decision.allowed, expected.allowed, "{case}");
+                assert_eq!(decision.remaining, expected.remaining, "{case}");
+                assert_eq!(decision.retry_after, expected.retry_after, "{case}");
+
+                let left_at = left[0]
+                    .value
+                    .as_deref()
+                    .map(|value| String::from_utf8_lossy(value).parse::<i64>().unwrap());
+                let kept = updated.as_ref().unwrap_or(&stored);
+                assert_eq!(
+                    left_at,
+                    Some(kept.last_updated.timestamp_millis()),
+                    "{case}"
+                );
+                let expected_ex = updated
+                    .as_ref()
+                    .map(|updated| super::expiry_secs(updated.expires_in(&config, now)) as i64);
+                assert_eq!(left[0].ex, expected_ex, "{case}");
+            }
+        }
+    }
+
+    /// Answers every command with `reply` after `delay`.
+    #[derive(Clone)]
+    struct Slow {
+        reply: Value,
+        delay: Duration,
+    }
+
+    impl aio::ConnectionLike for Slow {
+        fn req_packed_command<'a>(&'a mut self, _: &'a redis::Cmd) -> RedisFuture<'a, Value> {
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                Ok(self.reply.clone())
+            })
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _: &'a redis::Pipeline,
+            _: usize,
+            count: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                Ok(vec![self.reply.clone(); count])
+            })
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_recording_waits_no_longer_than_the_timeout() {
+        let now = Utc::now();
+        let empty = Value::Array(vec![
+            Value::Int(0),
+            Value::Int(now.timestamp_millis()),
+            Value::Int(0),
+            Value::Int(0),
+            Value::Int(0),
+            Value::Int(0),
+        ]);
+        let conn = Slow {
+            reply: empty,
+            delay: Duration::from_millis(50),
+        };
+        let store = Arc::new(
+            AsyncRedisStore::new(conn)
+                .with_denial_log(DenialLog::default())
+                .with_cluster(true)
+                .with_timeout(Duration::from_millis(200)),
+        );
+
+        let charging = tokio::spawn({
+            let store = Arc::clone(&store);
+            async move {
+                let config = BucketConfig::default();
+                store.charge(&[("bucket", &config)], 1, now, None).await
+            }
+        });
+        // Queue for the connection while the script has it, and keep it from
+        // the denial's append.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let _held = store.pool.get().await;
+
+        let charged = tokio::time::timeout(Duration::from_secs(5), charging)
+            .await
+            .expect("the append outlived the timeout")
+            .unwrap();
+        // The charge stands without it.
+        assert!(!charged.unwrap().unwrap()[0].allowed);
+    }
+}
@@ -0,0 +1,640 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use super::{BucketStore, StoreError, Tiered};
+use crate::{BoxFuture, BucketConfig, BucketStatus, RateLimitDecision};
+
+/// Spreads buckets over several stores, such as one [`AsyncRedisStore`] per
+/// Redis instance, for more charges a second than one instance can take.
+///
+/// A key goes to the shard its hash picks, by jump consistent hashing, so
+/// it's the same one after a restart and adding a shard at the end only
+/// moves the keys that now belong to it. The hash is the one in the key's
+/// hash tag, so an identity's bucket and everything derived from it (route
+/// buckets, quota, tier, override) share a shard.
+///
+/// A charge whose buckets are on several shards, such as with a global or
+/// per-address limit, is made on each in turn. If one of them denies it, the
+/// others are refunded, which is all-or-nothing in the end but not at every
+/// instant. An idempotent charge is made on the shard of its first bucket
+/// last, so its receipt is only kept once the others allowed it.
+///
+/// [`AsyncRedisStore`]: crate::AsyncRedisStore
+pub struct ShardedStore<S> {
+    shards: Vec<S>,
+}
+
+/// How the buckets on the shard of the first one are charged, see
+/// [`ShardedStore::charge_across`].
+#[derive(Clone, Copy)]
+enum Head<'a> {
+    Plain,
+    Once {
+        receipt: &'a str,
+        ttl: Duration,
+    },
+    Tiered {
+        tier_key: &'a str,
+        tiers: &'a HashMap<String, BucketConfig>,
+    },
+}
+
+/// The buckets of a charge that are in one place, such as a shard or a
+/// cluster slot, with where each one was in the charge.
+pub(super) struct Group<'a> {
+    pub(super) place: usize,
+    pub(super) positions: Vec<usize>,
+    pub(super) buckets: Vec<(&'a str, &'a BucketConfig)>,
+}
+
+/// `buckets` by the place `place` puts each in, the first bucket's first.
+pub(super) fn grouped<'a>(
+    buckets: &[(&'a str, &'a BucketConfig)],
+    place: impl Fn(&str) -> usize,
+) -> Vec<Group<'a>> {
+    let mut groups: Vec<Group<'a>> = Vec::new();
+    for (position, bucket) in buckets.iter().enumerate() {
+        let at = place(bucket.0);
+        match groups.iter_mut().find(|group| group.place == at) {
+            Some(group) => {
+                group.positions.push(position);
+                group.buckets.push(*bucket);
+            }
+            None => groups.push(Group {
+                place: at,
+                positions: vec![position],
+                buckets: vec![*bucket],
+            }),
+        }
+    }
+    groups
+}
+
+/// Puts the decisions of each group back in the order of the charge.
+pub(super) fn ungrouped(
+    groups: &[Group<'_>],
+    charged: Vec<Vec<RateLimitDecision>>,
+) -> Vec<RateLimitDecision> {
+    let len = groups.iter().map(|group| group.positions.len()).sum();
+    let mut decisions = vec![None; len];
+    for (group, charged) in groups.iter().zip(charged) {
+        for (position, decision) in group.positions.iter().zip(charged) {
+            decisions[*position] = Some(decision);
+        }
+    }
+    decisions.into_iter().flatten().collect()
+}
+
+impl<S> ShardedStore<S> {
+    /// Panics if `shards` is empty. Their order decides which keys each one
+    /// gets, so it must stay the same across restarts, with new shards added
+    /// at the end.
+    pub fn new(shards: impl IntoIterator<Item = S>) -> Self {
+        let shards = shards.into_iter().collect::<Vec<_>>();
+        assert!(!shards.is_empty(), "sharded store needs a shard");
+        Self { shards }
+    }
+
+    pub fn shards(&self) -> &[S] {
+        &self.shards
+    }
+
+    /// The shard that has the key `key`.
+    pub fn shard(&self, key: &str) -> &S {
+        &self.shards[self.shard_index(key)]
+    }
+
+    pub(crate) fn shard_index(&self, key: &str) -> usize {
+        jump(position(key), self.shards.len())
+    }
+
+    /// `buckets` by shard, the first bucket's first.
+    fn split<'a>(&self, buckets: &[(&'a str, &'a BucketConfig)]) -> Vec<Group<'a>> {
+        grouped(buckets, |key| self.shard_index(key))
+    }
+}
+
+impl<S: BucketStore> ShardedStore<S> {
+    /// Charges every group in `groups`, refunding the ones already charged if
+    /// a shard fails.
+    async fn charge_groups(
+        &self,
+        groups: &[Group<'_>],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Vec<RateLimitDecision>>, StoreError> {
+        let mut charged = Vec::with_capacity(groups.len());
+        for group in groups {
+            match self.shards[group.place]
+                .take_tokens(&group.buckets, cost, now)
+                .await
+            {
+                Ok(decisions) => charged.push(decisions),
+                Err(e) => {
+                    self.refund_allowed(&groups[..charged.len()], &charged, cost, now)
+                        .await;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(charged)
+    }
+
+    /// Gives back what the groups that allowed their charge took.
+    async fn refund_allowed(
+        &self,
+        groups: &[Group<'_>],
+        decisions: &[Vec<RateLimitDecision>],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) {
+        for (group, decisions) in groups.iter().zip(decisions) {
+            if !allowed(decisions) {
+                continue;
+            }
+            let refunded = self.shards[group.place]
+                .refund(&group.buckets, cost, now)
+                .await;
+            if let Err(e) = refunded {
+                tracing::warn!(error = %e, shard = group.place, "couldn't undo a charge");
+            }
+        }
+    }
+
+    /// Charges the groups after the first, then the first one as `head`
+    /// says, and undoes them all unless every one allowed it. Returns the
+    /// tier read, if any, and the decisions in the order of `buckets`, or
+    /// `None` for a charge that was already made.
+    async fn charge_across(
+        &self,
+        buckets: &[(&str, &BucketConfig)],
+        head: Head<'_>,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> Result<(Option<String>, Option<Vec<RateLimitDecision>>), StoreError> {
+        let groups = self.split(buckets);
+        let Some((first, rest)) = groups.split_first() else {
+            return Ok((None, Some(Vec::new())));
+        };
+        let mut charged = self.charge_groups(rest, cost, now).await?;
+        let others_allowed = charged.iter().all(|decisions| allowed(decisions));
+
+        let (tier, decisions) = match self
+            .charge_head(first, head, others_allowed, cost, now)
+            .await
+        {
+            Ok((tier, Some(decisions))) => (tier, decisions),
+            Ok((tier, None)) => {
+                self.refund_allowed(rest, &charged, cost, now).await;
+                return Ok((tier, None));
+            }
+            Err(e) => {
+                self.refund_allowed(rest, &charged, cost, now).await;
+                return Err(e);
+            }
+        };
+        charged.insert(0, decisions);
+        if !charged.iter().all(|decisions| allowed(decisions)) {
+            self.refund_allowed(&groups, &charged, cost, now).await;
+        }
+
+        Ok((tier, Some(ungrouped(&groups, charged))))
+    }
+
+    /// Charges the group with the first bucket, once the others are.
+    async fn charge_head(
+        &self,
+        group: &Group<'_>,
+        head: Head<'_>,
+        others_allowed: bool,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> Result<(Option<String>, Option<Vec<RateLimitDecision>>), StoreError> {
+        let shard = &self.shards[group.place];
+        match head {
+            // A denied charge leaves no receipt, so there's none to keep
+            // unless the others allowed it.
+            Head::Once { receipt, ttl } if others_allowed => Ok((
+                None,
+                shard
+                    .take_tokens_once(&group.buckets, cost, now, receipt, ttl)
+                    .await?,
+            )),
+            Head::Plain | Head::Once { .. } => Ok((
+                None,
+                Some(shard.take_tokens(&group.buckets, cost, now).await?),
+            )),
+            Head::Tiered { tier_key, tiers } if self.shard_index(tier_key) == group.place => {
+                let Tiered { tier, decisions } = shard
+                    .take_tokens_tiered(&group.buckets, tier_key, tiers, cost, now)
+                    .await?;
+                Ok((tier, Some(decisions)))
+            }
+            Head::Tiered { tier_key, tiers } => {
+                let tier = self.shard(tier_key).tier(tier_key).await?;
+                let buckets = super::tiered(&group.buckets, tier.as_deref(), tiers);
+                let decisions = shard.take_tokens(&buckets, cost, now).await?;
+                Ok((tier, Some(decisions)))
+            }
+        }
+    }
+
+    /// The shard all of `buckets` are on, if they are.
+    fn only_shard(&self, buckets: &[(&str, &BucketConfig)]) -> Option<usize> {
+        let (first, rest) = buckets.split_first()?;
+        let shard = self.shard_index(first.0);
+        rest.iter()
+            .all(|(key, _)| self.shard_index(key) == shard)
+            .then_some(shard)
+    }
+}
+
+impl<S: BucketStore> BucketStore for ShardedStore<S> {
+    fn take_token<'a>(
+        &'a self,
+        key: &'a str,
+        cost: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+        self.shard(key).take_token(key, cost, config, now)
+    }
+
+    fn take_tokens<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<RateLimitDecision>, StoreError>> {
+        if let Some(shard) = self.only_shard(buckets) {
+            return self.shards[shard].take_tokens(buckets, cost, now);
+        }
+        Box::pin(async move {
+            let (_, decisions) = self.charge_across(buckets, Head::Plain, cost, now).await?;
+            Ok(decisions.unwrap_or_default())
+        })
+    }
+
+    fn take_tokens_once<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+        receipt: &'a str,
+        ttl: Duration,
+    ) -> BoxFuture<'a, Result<Option<Vec<RateLimitDecision>>, StoreError>> {
+        if let Some(shard) = self.only_shard(buckets) {
+            return self.shards[shard].take_tokens_once(buckets, cost, now, receipt, ttl);
+        }
+        Box::pin(async move {
+            let head = Head::Once { receipt, ttl };
+            let (_, decisions) = self.charge_across(buckets, head, cost, now).await?;
+            Ok(decisions)
+        })
+    }
+
+    fn take_tokens_tiered<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        tier_key: &'a str,
+        tiers: &'a HashMap<String, BucketConfig>,
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Tiered, StoreError>> {
+        match self.only_shard(buckets) {
+            // The tier is normally kept with the identity's bucket.
+            Some(shard) if self.shard_index(tier_key) == shard => {
+                self.shards[shard].take_tokens_tiered(buckets, tier_key, tiers, cost, now)
+            }
+            _ => Box::pin(async move {
+                let head = Head::Tiered { tier_key, tiers };
+                let (tier, decisions) = self.charge_across(buckets, head, cost, now).await?;
+                Ok(Tiered {
+                    tier,
+                    decisions: decisions.unwrap_or_default(),
+                })
+            }),
+        }
+    }
+
+    fn tier<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<String>, StoreError>> {
+        self.shard(key).tier(key)
+    }
+
+    fn set_tier<'a>(
+        &'a self,
+        key: &'a str,
+        tier: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.shard(key).set_tier(key, tier)
+    }
+
+    fn config_override<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<Option<BucketConfig>, StoreError>> {
+        self.shard(key).config_override(key)
+    }
+
+    fn set_config_override<'a>(
+        &'a self,
+        key: &'a str,
+        config: Option<&'a BucketConfig>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.shard(key).set_config_override(key, config)
+    }
+
+    fn refund<'a>(
+        &'a self,
+        buckets: &'a [(&'a str, &'a BucketConfig)],
+        cost: i64,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        Box::pin(async move {
+            // As much as can be, even with a shard down.
+            let mut result = Ok(());
+            for group in self.split(buckets) {
+                let refunded = self.shards[group.place]
+                    .refund(&group.buckets, cost, now)
+                    .await;
+                result = result.and(refunded);
+            }
+            result
+        })
+    }
+
+    fn peek<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<RateLimitDecision, StoreError>> {
+        self.shard(key).peek(key, config, now)
+    }
+
+    fn status<'a>(
+        &'a self,
+        key: &'a str,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Option<BucketStatus>, StoreError>> {
+        self.shard(key).status(key, config, now)
+    }
+
+    fn reset<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+        self.shard(key).reset(key)
+    }
+
+    fn set_tokens<'a>(
+        &'a self,
+        key: &'a str,
+        tokens: i64,
+        config: &'a BucketConfig,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.shard(key).set_tokens(key, tokens, config, now)
+    }
+
+    /// Asks the shard of the bucket, which is the one that keeps it in its
+    /// blocklist.
+    fn is_blocked<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<bool, StoreError>> {
+        self.shard(key).is_blocked(key)
+    }
+
+    fn set_blocked<'a>(
+        &'a self,
+        key: &'a str,
+        blocked: bool,
+    ) -> BoxFuture<'a, Result<(), StoreError>> {
+        self.shard(key).set_blocked(key, blocked)
+    }
+
+    fn conflicts(&self) -> u64 {
+        self.shards.iter().map(BucketStore::conflicts).sum()
+    }
+
+    /// Pings every shard, as a request could need any of them.
+    fn ping(&self) -> BoxFuture<'_, Result<(), StoreError>> {
+        Box::pin(async move {
+            for shard in &self.shards {
+                shard.ping().await?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Each shard counts the keys it has, so the top of all of them is
+    /// among the tops of each.
+    fn top_consumers(
+        &self,
+        n: usize,
+        now: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<Vec<(String, u64)>, StoreError>> {
+        Box::pin(async move {
+            let mut top = Vec::new();
+            for shard in &self.shards {
+                top.extend(shard.top_consumers(n, now).await?);
+            }
+            top.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+            top.truncate(n);
+            Ok(top)
+        })
+    }
+}
+
+pub(super) fn allowed(decisions: &[RateLimitDecision]) -> bool {
+    decisions.iter().all(|decision| decision.allowed)
+}
+
+/// Where `key` falls among the shards: the hash in its hash tag, as Redis
+/// Cluster finds it, or the SHA-256 of the tag if it isn't hex.
+fn position(key: &str) -> u64 {
+    let tag = key
+        .split_once('{')
+        .and_then(|(_, rest)| rest.split_once('}'))
+        .map(|(tag, _)| tag)
+        .filter(|tag| !tag.is_empty())
+        .unwrap_or(key);
+    match tag.get(..16).map(|hex| u64::from_str_radix(hex, 16)) {
+        Some(Ok(position)) => position,
+        _ => {
+            let digest = Sha256::digest(tag.as_bytes());
+            u64::from_be_bytes(digest[..8].try_into().unwrap())
+        }
+    }
+}
+
+/// Lamping and Veach's jump consistent hash of `key` over `shards`.
+fn jump(mut key: u64, shards: usize) -> usize {
+    let (mut shard, mut next) = (-1i64, 0i64);
+    while next < shards as i64 {
+        shard = next;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        next = ((shard + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    shard as usize
+}
+
+#[cfg(all(test, feature = "axum", feature = "memory", feature = "redis"))]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::Utc;
+    use redis::{Value, cmd};
+    use redis_test::{MockCmd, MockRedisConnection};
+
+    use super::{ShardedStore, jump};
+    use crate::{AppState, AsyncRedisStore, BucketConfig, BucketStore, Leaderboard, MemoryStore};
+
+    /// An identity for each shard of `store`, by how its bucket key is
+    /// hashed.
+    fn identities<S>(state: &AppState<ShardedStore<S>>) -> Vec<String> {
+        let mut found = HashMap::new();
+        for n in 0.. {
+            let identity = format!("client-{n}");
+            found
+                .entry(state.store.shard_index(&state.bucket_key(&identity)))
+                .or_insert(identity);
+            if found.len() == state.store.shards().len() {
+                break;
+            }
+        }
+        (0..found.len())
+            .map(|shard| found[&shard].clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_shards_stay_put() {
+        // The reference implementation's, so keys keep their shards from one
+        // version to the next.
+        assert_eq!(jump(1, 1), 0);
+        assert_eq!(jump(42, 57), 43);
+        assert_eq!(jump(0xDEAD10CC, 1), 0);
+        assert_eq!(jump(0xDEAD10CC, 666), 361);
+        assert_eq!(jump(256, 1024), 520);
+
+        // A fourth shard only takes keys from the other three.
+        let three = ShardedStore::new([(); 3]);
+        let four = ShardedStore::new([(); 4]);
+        let state = AppState::new(MemoryStore::new(), BucketConfig::default());
+        let mut moved = 0;
+        for n in 0..1000 {
+            let key = state.bucket_key(&n.to_string());
+            let (before, after) = (three.shard_index(&key), four.shard_index(&key));
+            if before != after {
+                assert_eq!(after, 3);
+                moved += 1;
+            }
+            // Keys derived from a bucket's stay with it.
+            assert_eq!(three.shard_index(&format!("{key}:/search")), before);
+            assert_eq!(three.shard_index(&format!("{key}:quota")), before);
+        }
+        assert!((150..350).contains(&moved), "{moved}");
+    }
+
+    #[tokio::test]
+    async fn test_keys_only_reach_their_shard() {
+        let probe = AppState::new(ShardedStore::new([(); 3]), BucketConfig::default());
+        let identities = identities(&probe);
+
+        let mocks = identities
+            .iter()
+            .map(|identity| {
+                let key = probe.bucket_key(identity);
+                MockRedisConnection::new(vec![
+                    MockCmd::new(cmd("DEL").arg(&key), Ok(Value::Int(1))),
+                    MockCmd::new(
+                        cmd("SISMEMBER").arg("bucket:{blocklist}").arg(&key),
+                        Ok(Value::Int(0)),
+                    ),
+                ])
+            })
+            .collect::<Vec<_>>();
+        let store = ShardedStore::new(mocks.into_iter().map(AsyncRedisStore::new));
+        let state = AppState::new(store, BucketConfig::default()).with_shared_blocklist(true);
+
+        // Each mock fails any command it doesn't expect, in the order it
+        // expects them.
+        for identity in &identities {
+            assert!(state.reset_bucket(identity).await.unwrap());
+        }
+        for identity in identities.iter().rev() {
+            let key = state.bucket_key(identity);
+            assert!(!state.store.is_blocked(&key).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_charges_across_shards_are_all_or_nothing() {
+        let store = ShardedStore::new([MemoryStore::new(), MemoryStore::new()]);
+        let config = BucketConfig::default();
+        let state = AppState::new(store, config.clone()).with_global_limit(BucketConfig {
+            max_tokens: 1,
+            ..BucketConfig::default()
+        });
+        let global = state.key_prefix.global();
+        let global_shard = state.store.shard_index(&global);
+        let elsewhere = identities(&state)
+            .into_iter()
+            .filter(|identity| state.store.shard_index(&state.bucket_key(identity)) != global_shard)
+            .collect::<Vec<_>>();
+        let [identity] = elsewhere.as_slice() else {
+            panic!("{elsewhere:?}");
+        };
+        let key = state.bucket_key(identity);
+        let now = Utc::now();
+
+        assert!(state.consume_tokens(identity, 1).await.unwrap().allowed);
+        let charged = state.store.status(&key, &config, now).await.unwrap();
+        assert_eq!(charged.unwrap().tokens, 9);
+
+        // The global bucket is out, so the identity's own is given back the
+        // token it was charged on the other shard.
+        assert!(!state.consume_tokens(identity, 1).await.unwrap().allowed);
+        let status = state.store.status(&key, &config, now).await.unwrap();
+        assert_eq!(status.unwrap().tokens, 9);
+        assert!(
+            state.store.shards()[global_shard]
+                .status(&global, &config, now)
+                .await
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_top_consumers_are_merged_across_shards() {
+        let now = Utc::now();
+        let leaderboard = Leaderboard::default();
+        let top = |entries: &[(&str, &str)]| {
+            let zrevrange = cmd("ZREVRANGE")
+                .arg(leaderboard.key(now))
+                .arg(0)
+                .arg(1)
+                .arg("WITHSCORES")
+                .clone();
+            let entries = entries
+                .iter()
+                .flat_map(|(key, count)| [*key, *count])
+                .map(|arg| Value::BulkString(arg.as_bytes().to_vec()))
+                .collect();
+            MockRedisConnection::new(vec![MockCmd::new(zrevrange, Ok(Value::Array(entries)))])
+        };
+        let shards = [
+            top(&[("a", "5"), ("b", "3")]),
+            top(&[("c", "9"), ("d", "3")]),
+        ];
+        let store = ShardedStore::new(
+            shards
+                .into_iter()
+                .map(|conn| AsyncRedisStore::new(conn).with_leaderboard(leaderboard.clone())),
+        );
+
+        let top = store.top_consumers(2, now).await.unwrap();
+
+        assert_eq!(top, [("c".to_string(), 9), ("a".to_string(), 5)]);
+    }
+}
@@ -0,0 +1,505 @@
+-- Refills the buckets at KEYS and takes ARGV[4] tokens out of each of them
+-- if they all have enough, in one step. Mirrors `TokenPersistence::charge_all`.
+-- A negative cost puts that many back instead, like
+-- `TokenPersistence::refund`, into each bucket that's still stored.
+--
+-- ARGV: max_tokens, refill_rate, refill_interval_ms, cost, now_ms, format,
+-- the `PenaltyConfig`: violations, window_ms, ban_ms, max_ban_ms, with zero
+-- violations for none, the algorithm, "token_bucket", "gcra", "leaky_bucket"
+-- or "fixed_window", the overdraft and the fixed window's offset_ms. Every
+-- key after the first adds its own max_tokens, refill_rate,
+-- refill_interval_ms, violations, window_ms, ban_ms, max_ban_ms, algorithm,
+-- overdraft and offset_ms.
+-- A charge made only once per idempotency receipt, like
+-- `BucketStore::take_tokens_once`, has the receipt's key last in KEYS and its
+-- TTL in milliseconds last in ARGV. It's kept if the charge goes through, and
+-- while it is, the script charges nothing and returns an empty reply.
+-- What the charge did can be recorded in the same step, like the blocking
+-- store does in its transaction: KEYS then end with the `DenialLog` stream
+-- and the `Leaderboard` set of the window, whichever there are, and ARGV with
+-- the stream's max_len and the set's TTL in seconds, 0 for the ones left
+-- out, and "record". Every bucket that denies the charge is appended to the
+-- stream, and the first bucket, without its route, counted on the set.
+-- Returns each refilled bucket before the charge: {tokens, last_updated_ms,
+-- violations, violations_since_ms, bans, banned_until_ms}, with zeros for a
+-- client that has never been penalized, one after the other. A GCRA bucket
+-- is returned as no tokens and its arrival time, and a leaky bucket as its
+-- drained level in place of tokens.
+--
+-- With format "json" buckets are stored as the same versioned JSON
+-- `encoding::encode` writes, with `last_updated` in epoch milliseconds;
+-- unversioned buckets with RFC 3339 strings are still read, and buckets of a
+-- newer version are treated as missing. "msgpack" stores the same fields
+-- as MessagePack behind a \1 byte, as the `msgpack` feature does. With "hash"
+-- they are a hash of `tokens` and `last_updated`, plus `violations`,
+-- `violations_since`, `bans` and `banned_until` once penalized. Any of these
+-- is read whatever the format, and rewritten in it. GCRA buckets are stored
+-- as their arrival time in epoch milliseconds, whatever the format. Leaky
+-- buckets are stored like token buckets, with the level in `tokens`, and
+-- fixed windows with the start of their window in `last_updated`.
+
+local cost = tonumber(ARGV[4])
+local now_ms = tonumber(ARGV[5])
+local format = ARGV[6]
+local refund = cost < 0
+
+local key_count, arg_count = #KEYS, #ARGV
+local record
+if ARGV[arg_count] == 'record' then
+    record = { max_len = tonumber(ARGV[arg_count - 2]), ttl = tonumber(ARGV[arg_count - 1]) }
+    arg_count = arg_count - 3
+    if record.ttl > 0 then
+        record.leaderboard = KEYS[key_count]
+        key_count = key_count - 1
+    end
+    if record.max_len > 0 then
+        record.stream = KEYS[key_count]
+        key_count = key_count - 1
+    end
+end
+
+local receipt
+if arg_count == 13 + (key_count - 2) * 10 + 1 then
+    receipt = { key = KEYS[key_count], ttl_ms = tonumber(ARGV[arg_count]) }
+    if redis.call('EXISTS', receipt.key) == 1 then
+        return {}
+    end
+end
+local bucket_count = key_count
+if receipt then
+    bucket_count = bucket_count - 1
+end
+
+-- The config of the bucket at KEYS[i].
+local function config(i)
+    local at = { 1, 2, 3, 7, 8, 9, 10, 11, 12, 13 }
+    if i > 1 then
+        local base = 13 + (i - 2) * 10
+        at = {}
+        for n = 1, 10 do
+            at[n] = base + n
+        end
+    end
+    local overdraft = math.max(tonumber(ARGV[at[9]]), 0)
+    if ARGV[at[8]] == 'fixed_window' then
+        -- A window is never overdrawn.
+        overdraft = 0
+    end
+    return {
+        key = KEYS[i],
+        max_tokens = tonumber(ARGV[at[1]]),
+        refill_rate = tonumber(ARGV[at[2]]),
+        interval_ms = tonumber(ARGV[at[3]]),
+        max_violations = tonumber(ARGV[at[4]]),
+        window_ms = tonumber(ARGV[at[5]]),
+        ban_ms = tonumber(ARGV[at[6]]),
+        max_ban_ms = tonumber(ARGV[at[7]]),
+        gcra = ARGV[at[8]] == 'gcra',
+        leaky = ARGV[at[8]] == 'leaky_bucket',
+        fixed = ARGV[at[8]] == 'fixed_window',
+        offset_ms = tonumber(ARGV[at[10]]),
+        -- Like `TokenPersistence::take`, the balance the charge needs: under
+        -- an overdraft it may take the bucket that far below zero, but only
+        -- from a positive balance.
+        needed = math.max(cost - overdraft, math.min(cost, 1)),
+    }
+end
+
+local function days_from_civil(y, m, d)
+    if m <= 2 then
+        y = y - 1
+    end
+    local era = math.floor(y / 400)
+    local yoe = y - era * 400
+    local doy = math.floor((153 * ((m + 9) % 12) + 2) / 5) + d - 1
+    local doe = yoe * 365 + math.floor(yoe / 4) - math.floor(yoe / 100) + doy
+    return era * 146097 + doe - 719468
+end
+
+local function parse_millis(timestamp)
+    local y, mo, d, h, mi, s, rest =
+        string.match(timestamp, '^(%d+)-(%d+)-(%d+)T(%d+):(%d+):(%d+)(.*)$')
+    if not y then
+        error('invalid last_updated: ' .. timestamp)
+    end
+
+    local ms = 0
+    local fraction = string.match(rest, '^%.(%d+)')
+    if fraction then
+        ms = tonumber(string.sub(fraction .. '00', 1, 3))
+    end
+
+    local offset = 0
+    local sign, oh, om = string.match(rest, '([+-])(%d%d):(%d%d)$')
+    if sign then
+        offset = (tonumber(oh) * 60 + tonumber(om)) * 60000
+        if sign == '-' then
+            offset = -offset
+        end
+    end
+
+    local days = days_from_civil(tonumber(y), tonumber(mo), tonumber(d))
+    local seconds = ((days * 24 + tonumber(h)) * 60 + tonumber(mi)) * 60 + tonumber(s)
+    return seconds * 1000 + ms - offset
+end
+
+-- Integer division that truncates towards zero, like Rust's.
+local function div(a, b)
+    local q = a / b
+    if q < 0 then
+        return math.ceil(q)
+    end
+    return math.floor(q)
+end
+
+local function is_integer(n)
+    return type(n) == 'number' and math.floor(n) == n
+end
+
+-- Clamped like `timestamp::from_millis`, to 1970 through year 9999.
+local function clamp_millis(ms)
+    return math.min(math.max(ms, 0), 253402300799999)
+end
+
+local function read_penalty(penalty)
+    if penalty == nil then
+        return nil
+    end
+    for _, field in ipairs({ 'violations', 'since', 'bans', 'banned_until' }) do
+        if not is_integer(penalty[field]) then
+            error('unexpected penalty fields')
+        end
+    end
+    return {
+        violations = penalty.violations,
+        since = clamp_millis(penalty.since),
+        bans = penalty.bans,
+        banned_until = clamp_millis(penalty.banned_until),
+    }
+end
+
+-- Mirrors `encoding::decode`: nothing for a bucket of a newer version, and an
+-- error for one that doesn't parse.
+local function decode(stored)
+    local bucket
+    if string.byte(stored, 1) == 1 then
+        bucket = cmsgpack.unpack(string.sub(stored, 2))
+    else
+        bucket = cjson.decode(stored)
+    end
+    if (bucket.version or 0) > 1 then
+        return nil
+    end
+
+    local at = bucket.last_updated
+    if type(at) == 'string' then
+        at = parse_millis(at)
+    end
+    if not is_integer(bucket.tokens) or type(at) ~= 'number' then
+        error('unexpected bucket fields')
+    end
+    return bucket.tokens, at, read_penalty(bucket.penalty)
+end
+
+-- Like `TokenPersistence::gcra_take`, works out whether bucket `b` lets the
+-- request through, and when it would have its whole burst back after it.
+local function load_gcra(b)
+    local tat
+    if redis.call('TYPE', b.key)['ok'] == 'string' then
+        -- Anything but a number is a bucket of the other kind, started over.
+        tat = tonumber(redis.call('GET', b.key))
+    end
+    b.stored = tat ~= nil
+    b.tat = math.max(clamp_millis(tat or now_ms), now_ms)
+
+    local rate = math.max(b.refill_rate, 1)
+    local interval = div(math.max(b.interval_ms, 1) + rate - 1, rate)
+    b.charged = b.tat + cost * interval
+    b.fits = b.charged - interval * b.max_tokens <= now_ms
+    -- Like `gcra::decision`, how many requests it could send right now.
+    b.remaining = math.min(math.max(div(now_ms + interval * b.max_tokens - b.tat, interval), 0),
+        math.max(b.max_tokens, 0))
+    b.tokens = 0
+    b.last_updated = b.tat
+    return b
+end
+
+-- How long leaky bucket `b` takes to drain `units`, rounded up to the
+-- millisecond.
+local function drain_ms(b, units)
+    local rate = math.max(b.refill_rate, 1)
+    return div(units * math.max(b.interval_ms, 1) + rate - 1, rate)
+end
+
+-- Like `TokenPersistence::drained`, lets leaky bucket `b` drain up to now.
+-- Its `tokens` are the room left in the queue.
+local function drain(b)
+    local level = math.max(b.stored_tokens, 0)
+    local elapsed = math.max(now_ms - b.stored_at, 0)
+    if elapsed >= drain_ms(b, level) then
+        b.level = 0
+        b.last_updated = math.max(now_ms, b.stored_at)
+    else
+        local drained = div(elapsed * math.max(b.refill_rate, 1), math.max(b.interval_ms, 1))
+        b.level = level - drained
+        b.last_updated = b.stored_at + drain_ms(b, drained)
+    end
+    b.tokens = b.max_tokens - b.level
+end
+
+-- Like `quota::window_start`, when the window of fixed window `b` that now
+-- falls in started.
+local function window_start(b)
+    local period = math.max(b.interval_ms, 1)
+    local offset = b.offset_ms % period
+    return math.floor((now_ms - offset) / period) * period + offset
+end
+
+-- Reads the bucket at KEYS[i] and refills it up to now.
+local function load(i)
+    local b = config(i)
+    if b.gcra then
+        return load_gcra(b)
+    end
+    b.tokens = b.max_tokens
+    b.last_updated = now_ms
+
+    local kind = redis.call('TYPE', b.key)['ok']
+    if kind == 'hash' then
+        local fields = redis.call('HMGET', b.key, 'tokens', 'last_updated',
+            'violations', 'violations_since', 'bans', 'banned_until')
+        b.stored_tokens = tonumber(fields[1])
+        b.stored_at = tonumber(fields[2])
+        if not b.stored_at then
+            b.stored_tokens = nil
+        end
+        if fields[6] then
+            b.penalty = read_penalty({
+                violations = tonumber(fields[3]),
+                since = tonumber(fields[4]),
+                bans = tonumber(fields[5]),
+                banned_until = tonumber(fields[6]),
+            })
+        end
+    elseif kind == 'string' then
+        -- A bucket that can't be parsed is started over, like the blocking
+        -- store does, rather than failing every request until it expires.
+        local ok, decoded, at, read = pcall(decode, redis.call('GET', b.key))
+        if ok then
+            b.stored_tokens, b.stored_at, b.penalty = decoded, at, read
+        else
+            redis.log(redis.LOG_WARNING,
+                'bucket ' .. b.key .. ' is unreadable, starting it over: ' .. tostring(decoded))
+        end
+    end
+
+    if b.fixed then
+        -- Like `TokenPersistence::windowed`, full again once its window is
+        -- over.
+        local start = window_start(b)
+        b.last_updated = start
+        if b.stored_tokens then
+            b.stored_at = clamp_millis(b.stored_at)
+            if b.stored_at >= start then
+                b.tokens, b.last_updated = b.stored_tokens, b.stored_at
+            end
+        end
+    elseif b.leaky then
+        b.level = 0
+        if b.stored_tokens then
+            b.stored_at = clamp_millis(b.stored_at)
+            drain(b)
+        end
+    elseif b.stored_tokens then
+        b.stored_at = clamp_millis(b.stored_at)
+        -- Like `TokenPersistence::charge`, a bucket from our future neither
+        -- loses tokens nor moves back in time.
+        local intervals = div(math.max(now_ms - b.stored_at, 0), b.interval_ms)
+        local refilled = b.stored_tokens + intervals * b.refill_rate
+
+        if refilled < b.max_tokens then
+            b.tokens = refilled
+            b.last_updated = b.stored_at + intervals * b.interval_ms
+        else
+            b.last_updated = math.max(now_ms, b.stored_at)
+        end
+    end
+    return b
+end
+
+-- Stores bucket `b` holding `held` tokens as of `at`, or for a leaky bucket
+-- `held` room in the queue. Once it's full again, and the client's record no
+-- longer counts, it's no different from a missing key, so it expires then,
+-- rounded up to whole seconds.
+local function write(b, held, at, penalized)
+    local rate = math.max(b.refill_rate, 1)
+    local expires_at = at + math.ceil(math.max(b.max_tokens - held, 0) / rate) * b.interval_ms
+    if b.leaky then
+        held = b.max_tokens - held
+        expires_at = at + drain_ms(b, math.max(held, 0))
+    elseif b.fixed then
+        expires_at = at + b.interval_ms
+    end
+    if penalized then
+        expires_at = math.max(expires_at, penalized.banned_until, penalized.since + b.window_ms)
+        if penalized.bans > 0 then
+            expires_at = math.max(expires_at, penalized.banned_until + b.max_ban_ms)
+        end
+    end
+    local ttl = math.max(math.ceil((expires_at - now_ms) / 1000), 1)
+
+    if format == 'hash' then
+        redis.call('DEL', b.key)
+        -- Formatted explicitly: Lua turns numbers into strings with %.14g.
+        local fields = { 'tokens', string.format('%d', held), 'last_updated', string.format('%d', at) }
+        if penalized then
+            table.insert(fields, 'violations')
+            table.insert(fields, string.format('%d', penalized.violations))
+            table.insert(fields, 'violations_since')
+            table.insert(fields, string.format('%d', penalized.since))
+            table.insert(fields, 'bans')
+            table.insert(fields, string.format('%d', penalized.bans))
+            table.insert(fields, 'banned_until')
+            table.insert(fields, string.format('%d', penalized.banned_until))
+        end
+        redis.call('HSET', b.key, unpack(fields))
+        redis.call('EXPIRE', b.key, ttl)
+    else
+        local bucket = { version = 1, tokens = held, last_updated = at, penalty = penalized }
+        local encoded
+        if format == 'msgpack' then
+            encoded = '\1' .. cmsgpack.pack(bucket)
+        else
+            encoded = cjson.encode(bucket)
+        end
+        redis.call('SET', b.key, encoded, 'EX', ttl)
+    end
+end
+
+-- Puts the refund back into bucket `b` if it's still stored, never past
+-- full.
+local function give_back(b)
+    if b.gcra and b.stored then
+        local tat = math.max(b.charged, now_ms)
+        local ttl = math.max(math.ceil((tat - now_ms) / 1000), 1)
+        redis.call('SET', b.key, string.format('%d', tat), 'EX', ttl)
+    elseif not b.gcra and b.stored_tokens then
+        local tokens, at = b.tokens - cost, b.last_updated
+        if tokens >= b.max_tokens and not b.leaky and not b.fixed then
+            -- Refilled to the brim, like any bucket that's full.
+            tokens, at = b.max_tokens, math.max(now_ms, at)
+        end
+        write(b, math.min(tokens, b.max_tokens), at, b.penalty)
+    end
+end
+
+local buckets = {}
+local allowed = true
+for i = 1, bucket_count do
+    local b = load(i)
+    if refund then
+        -- Never denied.
+    elseif b.gcra then
+        b.denied = not b.fits
+    else
+        b.banned = b.max_violations > 0 and b.penalty and now_ms < b.penalty.banned_until
+        b.denied = b.banned or b.tokens < b.needed
+        -- Like the decision `TokenPersistence::charge` makes.
+        if b.banned then
+            b.remaining = 0
+        else
+            b.remaining = math.max(b.tokens, 0)
+        end
+    end
+    allowed = allowed and not b.denied
+    buckets[i] = b
+end
+
+local reply = {}
+for _, b in ipairs(buckets) do
+    if refund then
+        give_back(b)
+    elseif b.gcra then
+        -- Kept until the arrival time, when the client has its burst back.
+        if allowed then
+            local ttl = math.max(math.ceil((b.charged - now_ms) / 1000), 1)
+            redis.call('SET', b.key, string.format('%d', b.charged), 'EX', ttl)
+        end
+    elseif b.banned then
+        -- Nothing changes until the ban is over.
+    elseif b.tokens >= b.needed then
+        -- Charged only if every other bucket has enough too.
+        if allowed then
+            write(b, b.tokens - cost, b.last_updated, b.penalty)
+        end
+    elseif b.max_violations > 0 then
+        -- Like `TokenPersistence::violate`, counts the denial and stores the
+        -- bucket as it was.
+        local counted = { violations = 0, since = 0, bans = 0, banned_until = 0 }
+        for field, value in pairs(b.penalty or {}) do
+            counted[field] = value
+        end
+        if counted.violations == 0 or now_ms - counted.since >= b.window_ms then
+            counted.violations = 0
+            counted.since = now_ms
+        end
+        counted.violations = counted.violations + 1
+        if counted.violations >= b.max_violations then
+            counted.violations = 0
+            counted.banned_until = now_ms + math.min(b.ban_ms * 2 ^ counted.bans, b.max_ban_ms)
+            counted.bans = counted.bans + 1
+        end
+        if now_ms < counted.banned_until then
+            -- Banned by this very denial.
+            b.remaining = 0
+        end
+        local held = b.stored_tokens or b.max_tokens
+        if b.leaky then
+            held = b.max_tokens - (b.stored_tokens or 0)
+        end
+        write(b, held, b.stored_at or now_ms, counted)
+    end
+
+    local before = b.penalty or { violations = 0, since = 0, bans = 0, banned_until = 0 }
+    local tokens = b.tokens
+    if b.leaky then
+        tokens = b.level
+    end
+    for _, value in ipairs({
+        tokens, b.last_updated,
+        before.violations, before.since, before.bans, before.banned_until,
+    }) do
+        table.insert(reply, value)
+    end
+end
+if receipt and allowed then
+    redis.call('SET', receipt.key, '1', 'PX', receipt.ttl_ms)
+end
+
+-- A bucket key without its route, which follows the hash tag, and the route.
+local function split_route(key)
+    local close = string.find(key, '}:', 1, true)
+    if close then
+        return string.sub(key, 1, close), string.sub(key, close + 2)
+    end
+    return key, ''
+end
+
+if record and not refund then
+    if record.stream then
+        for _, b in ipairs(buckets) do
+            if b.denied then
+                local bucket, route = split_route(b.key)
+                redis.call('XADD', record.stream, 'MAXLEN', '~', record.max_len, '*',
+                    'key', bucket, 'route', route, 'ts', string.format('%d', now_ms),
+                    'remaining', string.format('%d', b.remaining))
+            end
+        end
+    end
+    if record.leaderboard then
+        local bucket = split_route(buckets[1].key)
+        redis.call('ZINCRBY', record.leaderboard, 1, bucket)
+        redis.call('EXPIRE', record.leaderboard, record.ttl)
+    end
+end
+return reply
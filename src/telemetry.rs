@@ -0,0 +1,239 @@
+//! What the middleware reports: always to the [`Stats`] in its state, by
+//! matched route too, and through the `metrics` facade with the `metrics`
+//! feature.
+//!
+//! - `leaky_bucket_requests_total{outcome}`: every request, by [`Outcome`].
+//! - `leaky_bucket_store_duration_seconds`: how long charging the store took,
+//!   failures included.
+//! - `leaky_bucket_remaining_tokens`: tokens left at each decision.
+//!
+//! All of them carry a `route` label with the matched route when
+//! [`AppState::with_metrics_route_label`](crate::AppState::with_metrics_route_label)
+//! is on. Identities never end up in labels.
+//!
+//! With the `otel` feature the decision is also set as OpenTelemetry
+//! attributes on the span the middleware runs in, normally the request's:
+//! `ratelimit.decision` (`allowed` or `denied`), `ratelimit.remaining`,
+//! `ratelimit.limit` and, for denials, `ratelimit.retry_after_ms`. The
+//! `rate_limit` span around the store transaction carries its latency as
+//! `store_ms`.
+
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::RateLimitDecision;
+
+/// Running totals of what the middleware decided, for
+/// [`metrics_router`](crate::metrics_router) to expose, or anything else by
+/// way of [`snapshot`](Self::snapshot). Shared by every clone of the
+/// [`AppState`](crate::AppState) it belongs to.
+#[derive(Debug, Default)]
+pub struct Stats {
+    totals: Counts,
+    /// By matched route, for requests that had one.
+    routes: DashMap<String, Counts>,
+}
+
+/// What [`Stats`] counted, as of when it was read.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub totals: OutcomeCounts,
+    /// By matched route, as registered with the router, such as
+    /// `/users/{id}`. Requests without one are only in the totals.
+    pub routes: BTreeMap<String, OutcomeCounts>,
+}
+
+/// Requests by how the middleware decided them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutcomeCounts {
+    pub allowed: u64,
+    pub denied: u64,
+    pub unauthorized: u64,
+    /// Requests the store failed for.
+    pub errors: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    unauthorized: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Counts {
+    fn add(&self, outcome: Outcome) {
+        let count = match outcome {
+            Outcome::Allowed => &self.allowed,
+            Outcome::Denied => &self.denied,
+            Outcome::Unauthorized => &self.unauthorized,
+            Outcome::Error => &self.errors,
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counts, set back to zero if `reset`. Each request is counted in
+    /// exactly one of the reads that reset them.
+    fn read(&self, reset: bool) -> OutcomeCounts {
+        let read = |count: &AtomicU64| match reset {
+            true => count.swap(0, Ordering::Relaxed),
+            false => count.load(Ordering::Relaxed),
+        };
+        OutcomeCounts {
+            allowed: read(&self.allowed),
+            denied: read(&self.denied),
+            unauthorized: read(&self.unauthorized),
+            errors: read(&self.errors),
+        }
+    }
+}
+
+impl Stats {
+    pub fn allowed(&self) -> u64 {
+        self.totals.allowed.load(Ordering::Relaxed)
+    }
+
+    pub fn denied(&self) -> u64 {
+        self.totals.denied.load(Ordering::Relaxed)
+    }
+
+    pub fn unauthorized(&self) -> u64 {
+        self.totals.unauthorized.load(Ordering::Relaxed)
+    }
+
+    /// Requests the store failed for.
+    pub fn errors(&self) -> u64 {
+        self.totals.errors.load(Ordering::Relaxed)
+    }
+
+    /// Everything counted so far.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.read(false)
+    }
+
+    /// Everything counted since the last time this was called, or since the
+    /// start, counting from zero again. The counters
+    /// [`metrics_router`](crate::metrics_router) exposes start over too.
+    pub fn snapshot_and_reset(&self) -> StatsSnapshot {
+        self.read(true)
+    }
+
+    fn read(&self, reset: bool) -> StatsSnapshot {
+        StatsSnapshot {
+            totals: self.totals.read(reset),
+            routes: self
+                .routes
+                .iter()
+                .map(|counts| (counts.key().clone(), counts.read(reset)))
+                .collect(),
+        }
+    }
+
+    fn record(&self, outcome: Outcome, route: Option<&str>) {
+        if let Some(route) = route {
+            match self.routes.get(route) {
+                Some(counts) => counts.add(outcome),
+                None => self
+                    .routes
+                    .entry(route.to_owned())
+                    .or_default()
+                    .add(outcome),
+            }
+        }
+        self.totals.add(outcome);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    /// Let through, including allowlisted identities and free requests.
+    Allowed,
+    /// Over the limit, blocked, or costing more than the bucket holds. Also
+    /// requests let through in shadow mode that would have been denied.
+    Denied,
+    /// No usable identity.
+    Unauthorized,
+    /// The store failed, whatever the failure policy made of it.
+    Error,
+}
+
+impl Outcome {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Allowed => "allowed",
+            Self::Denied => "denied",
+            Self::Unauthorized => "unauthorized",
+            Self::Error => "error",
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn labels(route: Option<&str>) -> Vec<metrics::Label> {
+    route
+        .map(|route| metrics::Label::new("route", route.to_owned()))
+        .into_iter()
+        .collect()
+}
+
+/// Counts `outcome` in `stats` under the `matched` route, and as a metric
+/// labelled with `route`.
+pub(crate) fn record_outcome(
+    stats: &Stats,
+    outcome: Outcome,
+    matched: Option<&str>,
+    route: Option<&str>,
+) {
+    stats.record(outcome, matched);
+
+    #[cfg(feature = "metrics")]
+    {
+        let mut labels = labels(route);
+        labels.push(metrics::Label::new("outcome", outcome.as_str()));
+        metrics::counter!("leaky_bucket_requests_total", labels).increment(1);
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (outcome, route);
+}
+
+pub(crate) fn record_store_duration(elapsed: Duration, route: Option<&str>) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!("leaky_bucket_store_duration_seconds", labels(route)).record(elapsed);
+    #[cfg(not(feature = "metrics"))]
+    let _ = (elapsed, route);
+}
+
+pub(crate) fn record_remaining(remaining: i64, route: Option<&str>) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!("leaky_bucket_remaining_tokens", labels(route)).record(remaining as f64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = (remaining, route);
+}
+
+pub(crate) fn record_decision(decision: &RateLimitDecision) {
+    #[cfg(feature = "otel")]
+    {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let span = tracing::Span::current();
+        let outcome = if decision.allowed {
+            "allowed"
+        } else {
+            "denied"
+        };
+        span.set_attribute("ratelimit.decision", outcome);
+        span.set_attribute("ratelimit.remaining", decision.remaining);
+        span.set_attribute("ratelimit.limit", decision.limit);
+        if let Some(retry_after) = decision.retry_after {
+            span.set_attribute("ratelimit.retry_after_ms", retry_after.as_millis() as i64);
+        }
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = decision;
+}
@@ -0,0 +1,190 @@
+//! Helpers for testing code that uses the rate limiter.
+//!
+//! Besides a [`ManualClock`], with the `testing` feature there are the
+//! commands a `RedisStore` sends Redis to charge a request, ready for a
+//! `MockRedisConnection`, so tests don't have to spell out the transaction
+//! and keep it up to date. See `mock_allow_sequence`.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+#[cfg(any(feature = "testing", all(test, feature = "redis")))]
+use redis::Value;
+#[cfg(any(feature = "testing", all(test, feature = "redis")))]
+use redis_test::MockCmd;
+
+use crate::{BoxFuture, Clock, KeyHasher, KeyPrefix};
+#[cfg(any(feature = "testing", all(test, feature = "redis")))]
+use crate::{BucketConfig, StorageFormat, TokenPersistence, store::write_into};
+
+/// A [`Clock`] that only moves when told to.
+///
+/// Clones share the same time, so keep one to advance after handing another
+/// to [`AppState::with_clock`](crate::AppState::with_clock). Sleeping on it
+/// moves it on by that much and returns straight away.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Default for ManualClock {
+    /// Starts at the current time.
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.advance(duration);
+        Box::pin(std::future::ready(()))
+    }
+}
+
+/// The key of `identity`'s bucket under the default key prefix and hasher.
+pub fn bucket_key(identity: &str) -> String {
+    KeyPrefix::default().bucket(&KeyHasher::default().hash(identity))
+}
+
+/// The commands a [`RedisStore`](crate::RedisStore) sends to read the bucket
+/// at `key`, holding `tokens` as of `last_updated`, with Redis's replies.
+#[cfg(any(feature = "testing", all(test, feature = "redis")))]
+pub fn mock_bucket(key: &str, tokens: i64, last_updated: DateTime<Utc>) -> Vec<MockCmd> {
+    let bucket = TokenPersistence {
+        tokens,
+        last_updated,
+        penalty: None,
+    };
+    read(key, Some(&bucket))
+}
+
+/// The commands a [`RedisStore`](crate::RedisStore) sends, and Redis's
+/// replies, to let a request through as of `now` by charging one token to the
+/// bucket at `key`, which holds `stored` as `(tokens, last_updated)` or
+/// doesn't exist yet.
+///
+/// ```
+/// use leaky_bucket::{
+///     BucketConfig, BucketStore, Clock, RedisStore,
+///     testing::{ManualClock, bucket_key, mock_allow_sequence},
+/// };
+/// use redis_test::MockRedisConnection;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let clock = ManualClock::default();
+/// let config = BucketConfig::default();
+/// let key = bucket_key("127.0.0.1");
+/// let commands = mock_allow_sequence(&key, &config, Some((3, clock.now())), clock.now());
+/// let store = RedisStore::new(MockRedisConnection::new(commands));
+///
+/// let decision = store.take_token(&key, 1, &config, clock.now()).await.unwrap();
+/// assert_eq!(decision.remaining, 2);
+/// # }
+/// ```
+///
+/// # Panics
+///
+/// If the request would be denied.
+#[cfg(any(feature = "testing", all(test, feature = "redis")))]
+pub fn mock_allow_sequence(
+    key: &str,
+    config: &BucketConfig,
+    stored: Option<(i64, DateTime<Utc>)>,
+    now: DateTime<Utc>,
+) -> Vec<MockCmd> {
+    let (allowed, commands) = sequence(key, config, stored, now);
+    assert!(allowed, "a request to {key} would be denied");
+    commands
+}
+
+/// Like [`mock_allow_sequence`], for a request that's denied because the
+/// bucket at `key` holds too few tokens.
+///
+/// # Panics
+///
+/// If the request would be let through.
+#[cfg(any(feature = "testing", all(test, feature = "redis")))]
+pub fn mock_deny_sequence(
+    key: &str,
+    config: &BucketConfig,
+    tokens: i64,
+    last_updated: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Vec<MockCmd> {
+    let (allowed, commands) = sequence(key, config, Some((tokens, last_updated)), now);
+    assert!(!allowed, "a request to {key} would be let through");
+    commands
+}
+
+#[cfg(any(feature = "testing", all(test, feature = "redis")))]
+fn read(key: &str, bucket: Option<&TokenPersistence>) -> Vec<MockCmd> {
+    let stored = bucket.map_or(Value::Nil, |bucket| {
+        Value::BulkString(crate::encoding::encode(bucket))
+    });
+    vec![
+        MockCmd::new(redis::cmd("WATCH").arg(key), Ok(Value::Okay)),
+        MockCmd::new(redis::cmd("GET").arg(key), Ok(stored)),
+    ]
+}
+
+/// Whether charging one token is allowed, and the commands that go with it.
+#[cfg(any(feature = "testing", all(test, feature = "redis")))]
+fn sequence(
+    key: &str,
+    config: &BucketConfig,
+    stored: Option<(i64, DateTime<Utc>)>,
+    now: DateTime<Utc>,
+) -> (bool, Vec<MockCmd>) {
+    let stored = stored.map(|(tokens, last_updated)| TokenPersistence {
+        tokens,
+        last_updated,
+        penalty: None,
+    });
+    let mut commands = read(key, stored.as_ref());
+    let bucket = stored.unwrap_or_else(|| TokenPersistence::new(config, now));
+    let (decision, updated) = bucket.charge(config, 1, now);
+    match updated {
+        Some(updated) => {
+            let mut transaction = redis::pipe();
+            transaction.atomic();
+            write_into(
+                &mut transaction,
+                key,
+                &updated,
+                config,
+                StorageFormat::Json,
+                now,
+            );
+            commands.push(MockCmd::new(
+                transaction,
+                Ok(Value::Array(vec![Value::Okay])),
+            ));
+        }
+        None => commands.push(MockCmd::new(redis::cmd("UNWATCH"), Ok(Value::Okay))),
+    }
+    (decision.allowed, commands)
+}
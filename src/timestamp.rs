@@ -0,0 +1,125 @@
+//! Serde format for `TokenPersistence::last_updated`: epoch milliseconds.
+//!
+//! Buckets written as RFC 3339 strings by earlier versions are still read.
+//! Anything outside 1970 to the end of year 9999 is clamped into that range,
+//! so a corrupt bucket can't make the refill math overflow.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::{
+    Deserializer, Serializer,
+    de::{self, Visitor},
+};
+
+/// `9999-12-31T23:59:59.999Z`.
+const MAX_MILLIS: i64 = 253_402_300_799_999;
+
+pub(crate) fn serialize<S>(at: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_i64(at.timestamp_millis())
+}
+
+pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(TimestampVisitor)
+}
+
+pub(crate) fn from_millis(millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(millis.clamp(0, MAX_MILLIS)).unwrap_or_default()
+}
+
+struct TimestampVisitor;
+
+impl Visitor<'_> for TimestampVisitor {
+    type Value = DateTime<Utc>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("epoch milliseconds or an RFC 3339 timestamp")
+    }
+
+    fn visit_i64<E: de::Error>(self, millis: i64) -> Result<Self::Value, E> {
+        Ok(from_millis(millis))
+    }
+
+    fn visit_u64<E: de::Error>(self, millis: u64) -> Result<Self::Value, E> {
+        Ok(from_millis(i64::try_from(millis).unwrap_or(i64::MAX)))
+    }
+
+    fn visit_f64<E: de::Error>(self, millis: f64) -> Result<Self::Value, E> {
+        // `as` saturates, and turns NaN into 0.
+        Ok(from_millis(millis as i64))
+    }
+
+    fn visit_str<E: de::Error>(self, rfc3339: &str) -> Result<Self::Value, E> {
+        let at = DateTime::parse_from_rfc3339(rfc3339).map_err(E::custom)?;
+        Ok(from_millis(at.timestamp_millis()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Stamped {
+        #[serde(with = "super")]
+        at: DateTime<Utc>,
+    }
+
+    fn read(json: &str) -> DateTime<Utc> {
+        serde_json::from_str::<Stamped>(json).unwrap().at
+    }
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        rfc3339.parse().unwrap()
+    }
+
+    #[test]
+    fn test_writes_epoch_millis() {
+        let stamped = Stamped {
+            at: at("2025-03-01T12:00:00.123Z"),
+        };
+
+        let json = serde_json::to_string(&stamped).unwrap();
+
+        assert_eq!(json, r#"{"at":1740830400123}"#);
+        assert_eq!(serde_json::from_str::<Stamped>(&json).unwrap(), stamped);
+    }
+
+    #[test]
+    fn test_reads_legacy_rfc3339_strings() {
+        assert_eq!(
+            read(r#"{"at":"2025-03-01T12:00:00.123456789Z"}"#),
+            at("2025-03-01T12:00:00.123Z")
+        );
+        assert_eq!(
+            read(r#"{"at":"2025-03-01T09:00:00-03:00"}"#),
+            at("2025-03-01T12:00:00Z")
+        );
+        assert!(serde_json::from_str::<Stamped>(r#"{"at":"yesterday"}"#).is_err());
+    }
+
+    #[test]
+    fn test_clamps_out_of_range_values() {
+        let max = at("9999-12-31T23:59:59.999Z");
+
+        assert_eq!(read(r#"{"at":-1}"#), DateTime::UNIX_EPOCH);
+        assert_eq!(
+            read(r#"{"at":"1969-07-20T20:17:00Z"}"#),
+            DateTime::UNIX_EPOCH
+        );
+        assert_eq!(
+            read(&format!(r#"{{"at":{}}}"#, i64::MIN)),
+            DateTime::UNIX_EPOCH
+        );
+        assert_eq!(read(&format!(r#"{{"at":{}}}"#, i64::MAX)), max);
+        assert_eq!(read(&format!(r#"{{"at":{}}}"#, u64::MAX)), max);
+        assert_eq!(read(r#"{"at":1e300}"#), max);
+    }
+}